@@ -0,0 +1,160 @@
+use futures::future::join_all;
+use kollider_hedge_client::client::HedgeClient;
+use kollider_hedge_domain::api::{CurrencyStats, Stats};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Stats of a single hedge instance or the reason why they are unknown
+#[derive(Serialize)]
+pub struct InstanceStats {
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stats: Option<Stats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Figures combined over instances. Sats are summed over all of them, while fiat values are
+/// summed only for instances that hedge in the same currency.
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct AggregatedStats {
+    /// Amount of instances that reported stats
+    pub instances: usize,
+    pub channels_sats: u64,
+    pub position_sats: u64,
+    /// Negative means overhedge
+    pub unhedged_sats: i64,
+    pub short_orders_sats: u64,
+    pub long_orders_sats: u64,
+    /// Balance in BTC on Kollider accounts
+    pub account_balance: f64,
+    pub realized_pnl: f64,
+    pub unrealized_pnl: f64,
+    /// Fiat values by hedge currency
+    pub currencies: BTreeMap<String, CurrencyTotals>,
+    /// Urls of instances that have an alarm raised, e.x. margin shortage or hedge drift
+    pub alarms: Vec<String>,
+}
+
+/// Fiat values of instances that hedge in the currency
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct CurrencyTotals {
+    pub instances: usize,
+    pub channels: f64,
+    pub position: f64,
+    pub unhedged: f64,
+    pub account_balance: f64,
+}
+
+/// Combined and per-instance stats
+#[derive(Serialize)]
+pub struct StatsReport {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<AggregatedStats>,
+    pub instances: Vec<InstanceStats>,
+}
+
+/// Request stats of all instances concurrently. Unreachable instances are reported with the
+/// error and don't prevent collecting the others.
+pub async fn fetch_stats(urls: &[String]) -> Vec<InstanceStats> {
+    join_all(urls.iter().map(|url| async move {
+        let res = HedgeClient::new(url).query_stats().await;
+        InstanceStats {
+            url: url.clone(),
+            error: res.as_ref().err().map(|e| e.to_string()),
+            stats: res.ok(),
+        }
+    }))
+    .await
+}
+
+/// Fiat values in the hedge currency. Servers that don't report the currency hedge in USD.
+fn hedge_currency(stats: &Stats) -> CurrencyStats {
+    if stats.hedge_currency.currency.is_empty() {
+        CurrencyStats {
+            currency: "USD".to_owned(),
+            ticker: stats.ticker,
+            channels: stats.channels_usd,
+            position: stats.position_usd as f64,
+            unhedged: stats.unhedged_usd,
+            account_balance: stats.account_balance * stats.ticker.unwrap_or(0.0),
+        }
+    } else {
+        stats.hedge_currency.clone()
+    }
+}
+
+impl AggregatedStats {
+    pub fn new(instances: &[InstanceStats]) -> Self {
+        let mut total = AggregatedStats::default();
+        for (url, stats) in instances
+            .iter()
+            .filter_map(|i| i.stats.as_ref().map(|s| (&i.url, s)))
+        {
+            total.instances += 1;
+            total.channels_sats += stats.channels_sats;
+            total.position_sats += stats.position_sats;
+            total.unhedged_sats += stats.unhedged_sats;
+            total.short_orders_sats += stats.short_orders_sats;
+            total.long_orders_sats += stats.long_orders_sats;
+            total.account_balance += stats.account_balance;
+            total.realized_pnl += stats.realized_pnl;
+            total.unrealized_pnl += stats.unrealized_pnl;
+
+            let fiat = hedge_currency(stats);
+            let totals = total.currencies.entry(fiat.currency).or_default();
+            totals.instances += 1;
+            totals.channels += fiat.channels;
+            totals.position += fiat.position;
+            totals.unhedged += fiat.unhedged;
+            totals.account_balance += fiat.account_balance;
+
+            if stats.over_limit || stats.margin_shortage || stats.drift_alarm {
+                total.alarms.push(url.clone());
+            }
+        }
+        total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aggregate_stats() {
+        let instance = |url: &str, currency: &str, sats: u64, fiat: f64| InstanceStats {
+            url: url.to_owned(),
+            stats: Some(Stats {
+                channels_sats: sats,
+                unhedged_sats: -10,
+                hedge_currency: CurrencyStats {
+                    currency: currency.to_owned(),
+                    channels: fiat,
+                    ..CurrencyStats::default()
+                },
+                margin_shortage: currency == "EUR",
+                ..Stats::new()
+            }),
+            error: None,
+        };
+        let instances = vec![
+            instance("http://a", "USD", 100, 40.0),
+            instance("http://b", "USD", 200, 80.0),
+            instance("http://c", "EUR", 300, 100.0),
+            InstanceStats {
+                url: "http://d".to_owned(),
+                stats: None,
+                error: Some("Connection refused".to_owned()),
+            },
+        ];
+        let total = AggregatedStats::new(&instances);
+        assert_eq!(total.instances, 3);
+        assert_eq!(total.channels_sats, 600);
+        assert_eq!(total.unhedged_sats, -30);
+        assert_eq!(total.currencies["USD"].instances, 2);
+        assert_eq!(total.currencies["USD"].channels, 120.0);
+        assert_eq!(total.currencies["EUR"].channels, 100.0);
+        assert_eq!(total.alarms, vec!["http://c".to_owned()]);
+    }
+}
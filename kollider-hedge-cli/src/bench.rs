@@ -0,0 +1,128 @@
+use kollider_hedge_client::client::HedgeClient;
+use kollider_hedge_domain::api::HtlcInfo;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::time::{interval, MissedTickBehavior};
+
+/// Parse duration like `60s`, `5m`, `1h`. Plain number is seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (value, multiplier) = match s.chars().last() {
+        Some('s') => (&s[..s.len() - 1], 1),
+        Some('m') => (&s[..s.len() - 1], 60),
+        Some('h') => (&s[..s.len() - 1], 3600),
+        _ => (s, 1),
+    };
+    let value: u64 = value
+        .parse()
+        .map_err(|_| format!("Invalid duration {}, expected e.x. 60s, 5m or 1h", s))?;
+    Ok(Duration::from_secs(value * multiplier))
+}
+
+#[derive(Debug, Clone)]
+pub struct BenchConfig {
+    /// Requests per second
+    pub rps: u64,
+    pub duration: Duration,
+    /// Amount of synthetic channels that HTLCs are spread over
+    pub channels: u64,
+    /// Sats of incoming HTLC, outgoing HTLCs take half of it
+    pub sats: i64,
+    /// Rate of HTLCs in sats/USD
+    pub rate: u64,
+}
+
+#[derive(Debug)]
+pub struct BenchReport {
+    pub sent: usize,
+    pub errors: usize,
+    pub elapsed: Duration,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl BenchReport {
+    fn new(mut latencies: Vec<Duration>, errors: usize, elapsed: Duration) -> Self {
+        latencies.sort();
+        let percentile = |p: f64| -> Duration {
+            if latencies.is_empty() {
+                return Duration::default();
+            }
+            let i = ((latencies.len() - 1) as f64 * p).round() as usize;
+            latencies[i]
+        };
+        BenchReport {
+            sent: latencies.len(),
+            errors,
+            elapsed,
+            p50: percentile(0.5),
+            p90: percentile(0.9),
+            p99: percentile(0.99),
+            max: latencies.last().cloned().unwrap_or_default(),
+        }
+    }
+
+    pub fn print(&self) {
+        let rps = self.sent as f64 / self.elapsed.as_secs_f64();
+        let error_rate = if self.sent > 0 {
+            100. * self.errors as f64 / self.sent as f64
+        } else {
+            0.
+        };
+        println!(
+            "Requests: {} in {:?} ({:.1}/s)",
+            self.sent, self.elapsed, rps
+        );
+        println!("Errors: {} ({:.2}%)", self.errors, error_rate);
+        println!(
+            "Latency: p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+            self.p50, self.p90, self.p99, self.max
+        );
+    }
+}
+
+/// Fire synthetic HTLCs at the constant rate. Incoming and outgoing HTLCs alternate, so the
+/// channels grow slowly.
+pub async fn run_bench(client: HedgeClient, config: BenchConfig) -> BenchReport {
+    let client = Arc::new(client);
+    let total = config.rps * config.duration.as_secs();
+    let mut ticker = interval(Duration::from_secs_f64(1. / config.rps as f64));
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Burst);
+    let start = Instant::now();
+    let mut requests = Vec::with_capacity(total as usize);
+    for i in 0..total {
+        ticker.tick().await;
+        let sats = if i % 2 == 0 {
+            config.sats
+        } else {
+            -config.sats / 2
+        };
+        let info = HtlcInfo {
+            channel_id: format!("bench-{}", (i / 2) % config.channels.max(1)),
+            sats,
+            rate: config.rate,
+        };
+        let client = client.clone();
+        requests.push(tokio::spawn(async move {
+            let sent = Instant::now();
+            let res = client.hedge_htlc(info).await;
+            (sent.elapsed(), res)
+        }));
+    }
+    let mut latencies = Vec::with_capacity(requests.len());
+    let mut errors = 0;
+    for request in requests {
+        match request.await {
+            Ok((latency, res)) => {
+                if let Err(e) = res {
+                    log::debug!("Request failed: {}", e);
+                    errors += 1;
+                }
+                latencies.push(latency);
+            }
+            Err(_) => errors += 1,
+        }
+    }
+    BenchReport::new(latencies, errors, start.elapsed())
+}
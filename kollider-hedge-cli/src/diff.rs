@@ -0,0 +1,185 @@
+use kollider_hedge_client::client::HedgeClient;
+use kollider_hedge_domain::api::parse_dump;
+use kollider_hedge_domain::state::{HedgeConfig, State};
+use kollider_hedge_domain::update::{ChannelHedge, ChannelId, StateUpdate};
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::path::Path;
+
+/// Channel which hedge differs between the instances. Missing channel is the same as an empty
+/// one, as empty channels are dropped from the state after a while.
+#[derive(Debug, PartialEq)]
+pub struct ChannelDiff {
+    pub channel_id: ChannelId,
+    pub a: Option<ChannelHedge>,
+    pub b: Option<ChannelHedge>,
+}
+
+/// Figures of the whole channel map
+#[derive(Debug, PartialEq)]
+pub struct Totals {
+    /// Channels with non zero hedge
+    pub channels: usize,
+    pub sats: i64,
+    /// Sats of channels that are hedged, see `HedgeConfig::unhedged_channels`
+    pub hedge_capacity: u64,
+    /// Average weighted rate of hedged channels in sats/USD, `None` if it overflows
+    pub avg_rate: Option<i64>,
+}
+
+impl Totals {
+    pub fn new(state: &State) -> Self {
+        Totals {
+            channels: state
+                .channels_hedge
+                .values()
+                .filter(|h| h.sats != 0)
+                .count(),
+            sats: state.channels_hedge.values().map(|h| h.sats).sum(),
+            hedge_capacity: state.hedge_capacity(),
+            avg_rate: state.total_hedge().ok().map(|h| h.rate),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct StateDiff {
+    pub channels: Vec<ChannelDiff>,
+    pub totals_a: Totals,
+    pub totals_b: Totals,
+}
+
+impl StateDiff {
+    pub fn new(a: &State, b: &State) -> Self {
+        let ids: BTreeSet<&ChannelId> = a
+            .channels_hedge
+            .keys()
+            .chain(b.channels_hedge.keys())
+            .collect();
+        let nonempty = |state: &State, id: &ChannelId| {
+            state
+                .channels_hedge
+                .get(id)
+                .filter(|h| h.sats != 0)
+                .cloned()
+        };
+        let channels = ids
+            .into_iter()
+            .map(|id| ChannelDiff {
+                channel_id: id.clone(),
+                a: nonempty(a, id),
+                b: nonempty(b, id),
+            })
+            .filter(|diff| diff.a != diff.b)
+            .collect();
+        StateDiff {
+            channels,
+            totals_a: Totals::new(a),
+            totals_b: Totals::new(b),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.channels.is_empty() && self.totals_a == self.totals_b
+    }
+
+    pub fn print(&self, name_a: &str, name_b: &str) {
+        println!("A: {}", name_a);
+        println!("B: {}", name_b);
+        let show = |hedge: &Option<ChannelHedge>| match hedge {
+            Some(h) => format!("{} sats at {} sats/USD", h.sats, h.rate),
+            None => "missing".to_owned(),
+        };
+        for diff in self.channels.iter() {
+            println!(
+                "Channel {}: A {}, B {}",
+                diff.channel_id,
+                show(&diff.a),
+                show(&diff.b)
+            );
+        }
+        let (a, b) = (&self.totals_a, &self.totals_b);
+        let mark = |same: bool| if same { "" } else { "  <- differs" };
+        println!(
+            "Channels: A {}, B {}{}",
+            a.channels,
+            b.channels,
+            mark(a.channels == b.channels)
+        );
+        println!("Sats: A {}, B {}{}", a.sats, b.sats, mark(a.sats == b.sats));
+        println!(
+            "Hedge capacity: A {}, B {}{}",
+            a.hedge_capacity,
+            b.hedge_capacity,
+            mark(a.hedge_capacity == b.hedge_capacity)
+        );
+        let rate = |r: Option<i64>| r.map_or("overflow".to_owned(), |r| r.to_string());
+        println!(
+            "Average rate: A {}, B {}{}",
+            rate(a.avg_rate),
+            rate(b.avg_rate),
+            mark(a.avg_rate == b.avg_rate)
+        );
+    }
+}
+
+/// Collect the state from the dump of the update log. Config changes from the dump are applied
+/// to the default config, exchange data is only known from snapshots.
+pub fn dump_state(path: &Path) -> Result<State, Box<dyn Error>> {
+    let records = parse_dump(&std::fs::read_to_string(path)?)?;
+    let updates = records
+        .iter()
+        .map(|r| {
+            Ok(StateUpdate {
+                created: r.created,
+                body: r.decode()?,
+            })
+        })
+        .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+    Ok(State::collect(HedgeConfig::default(), updates)?)
+}
+
+/// Query the state of the running instance
+pub async fn url_state(url: &str) -> Result<State, Box<dyn Error>> {
+    Ok(HedgeClient::new(url).query_state().await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_diff() {
+        let state = |channels: &[(&str, i64, i64)]| {
+            let mut state = State::new(HedgeConfig::default());
+            for (id, sats, rate) in channels {
+                state.channels_hedge.insert(
+                    id.to_string(),
+                    ChannelHedge {
+                        sats: *sats,
+                        rate: *rate,
+                    },
+                );
+            }
+            state
+        };
+        let a = state(&[("1", 100, 2500), ("2", 0, 2500), ("3", 50, 2000)]);
+        let b = state(&[("1", 100, 2500), ("3", 60, 2000), ("4", 10, 3000)]);
+        assert!(StateDiff::new(&a, &a).is_empty());
+        assert!(StateDiff::new(&a, &state(&[("1", 100, 2500), ("3", 50, 2000)])).is_empty());
+
+        let diff = StateDiff::new(&a, &b);
+        assert!(!diff.is_empty());
+        let ids: Vec<&str> = diff
+            .channels
+            .iter()
+            .map(|d| d.channel_id.as_str())
+            .collect();
+        assert_eq!(ids, vec!["3", "4"]);
+        assert_eq!(diff.channels[1].a, None);
+        assert_eq!(diff.totals_a.channels, 2);
+        assert_eq!(diff.totals_b.channels, 3);
+        assert_eq!(diff.totals_a.sats, 150);
+        assert_eq!(diff.totals_b.sats, 170);
+    }
+}
@@ -1,14 +1,40 @@
+mod aggregate;
+mod bench;
+mod diff;
+
+use aggregate::{fetch_stats, AggregatedStats, StatsReport};
+use bench::{parse_duration, run_bench, BenchConfig};
 use clap::Parser;
+use diff::{dump_state, url_state, StateDiff};
+use std::collections::HashMap;
 use std::error::Error;
+use std::path::PathBuf;
+use std::time::Duration;
 
+use chrono::NaiveDateTime;
 use kollider_hedge_client::client::HedgeClient;
-use kollider_hedge_domain::api::HtlcInfo;
+use kollider_hedge_domain::api::{
+    CorrectionRequest, DumpQuery, DumpRecord, HedgeTarget, HistoryQuery, HtlcInfo,
+    StatsHistoryQuery, SymbolMigrationRequest,
+};
+use kollider_hedge_domain::chain::verify_chain;
+use kollider_hedge_domain::update::ConfigChange;
 
 #[derive(Parser, Debug)]
 #[clap(about, version, author)]
 struct Args {
-    #[clap(long, default_value = "http://127.0.0.1:8081")]
-    url: String,
+    /// Url of the service. The `stats` command accepts it several times to query many
+    /// instances, other commands use the first one.
+    #[clap(
+        long,
+        global = true,
+        multiple_occurrences = true,
+        default_value = "http://127.0.0.1:8081"
+    )]
+    url: Vec<String>,
+    /// Token for admin endpoints of the service
+    #[clap(long, env = "KOLLIDER_HEDGE_ADMIN_TOKEN", hide_env_values = true)]
+    admin_token: Option<String>,
     #[clap(subcommand)]
     subcmd: SubCommand,
 }
@@ -19,8 +45,203 @@ enum SubCommand {
     State,
     /// Add or remove sats from hedge position
     Htlc(HtlcCmd),
-    /// Get summary from plugin about current metrics
-    Stats,
+    /// Set hedge of the channel to the absolute amount of sats
+    SetHedge(SetHedgeCmd),
+    /// Get summary from plugin about current metrics. With several `--url` prints stats of
+    /// each instance.
+    Stats {
+        /// Sum up stats of all instances. Fiat values are summed per hedge currency.
+        #[clap(long)]
+        aggregate: bool,
+    },
+    /// Get hedge and fiat value of each channel
+    Channels,
+    /// Estimate margin, quantity and price of an order that increases the hedge by the sats
+    Estimate {
+        /// Size of the order
+        sats: u64,
+    },
+    /// Get status of the websocket connection to Kollider
+    WsStatus,
+    /// Check health of the service. Exits with 0 if the service is ready, 1 if it is live but
+    /// not ready and 2 if it is not live or unreachable.
+    Health,
+    /// Get the last messages received from Kollider
+    DebugMessages,
+    /// Query history of state updates
+    History {
+        /// Start of the time range, e.x. 2022-01-01T00:00:00
+        #[clap(long)]
+        from: Option<NaiveDateTime>,
+        /// End of the time range, e.x. 2022-01-02T00:00:00
+        #[clap(long)]
+        to: Option<NaiveDateTime>,
+    },
+    /// Export the chain of updates to a JSONL file for archival or migration to another instance
+    Dump {
+        /// Export updates created after the moment, e.x. 2022-01-01T00:00:00
+        #[clap(long)]
+        since: Option<NaiveDateTime>,
+        /// Path of the output file
+        #[clap(long)]
+        out: PathBuf,
+    },
+    /// Append updates from a JSONL file produced by the dump command
+    Restore {
+        /// Path of the input file
+        #[clap(long = "in")]
+        input: PathBuf,
+    },
+    /// Check that the chain of updates wasn't rewritten. Verifies the dump file or the full log
+    /// of the service if the file is not set.
+    VerifyLog {
+        /// Path of a file produced by the dump command
+        #[clap(long = "in")]
+        input: Option<PathBuf>,
+    },
+    /// Compare channels and their totals of two instances or of an instance and a dump, e.x.
+    /// to verify a standby or a migration. Exits with 1 if they differ.
+    Diff {
+        /// Url of the first instance, the global one if not set
+        #[clap(long)]
+        url_a: Option<String>,
+        /// Url of the second instance
+        #[clap(long, required_unless_present = "dump", conflicts_with = "dump")]
+        url_b: Option<String>,
+        /// Path of a file produced by the dump command to compare with instead of the second
+        /// instance
+        #[clap(long)]
+        dump: Option<PathBuf>,
+    },
+    /// Query history of updates of a single channel
+    ChannelHistory {
+        /// Id of the channel
+        channel_id: String,
+    },
+    /// Get recorded statistics in the given time range
+    StatsHistory(StatsHistoryCmd),
+    /// Request Lightning invoice that tops up margin on Kollider
+    Deposit {
+        /// Amount of sats to deposit
+        sats: u64,
+    },
+    /// Withdraw excess margin from Kollider to the Lightning invoice
+    Withdraw {
+        /// Invoice of the node to pay
+        invoice: String,
+        /// Amount of sats, all margin above the buffer if not set
+        #[clap(long)]
+        sats: Option<u64>,
+    },
+    /// Void or replace an erroneous HTLC update
+    Correct(CorrectCmd),
+    /// Reset hedge of channels to the state at the moment, e.x. after a bad batch of updates.
+    /// The state is appended to the chain as a new snapshot.
+    Rollback {
+        /// Moment of the state to restore, e.x. 2022-01-01T00:00:00
+        #[clap(long)]
+        to: NaiveDateTime,
+    },
+    /// Move the hedge to another contract, e.x. from an expiring one to a new perp. The service
+    /// closes orders and position on the old symbol and then hedges on the new one.
+    MigrateSymbol {
+        /// Symbol of the new contract
+        symbol: String,
+        /// Index pair of the new contract, the current one is kept if not set
+        #[clap(long)]
+        pair: Option<String>,
+    },
+    /// Change hedging configuration of the service
+    Config(ConfigCmd),
+    /// Fire synthetic HTLCs at the service and report latency percentiles and error rate. Run
+    /// it against a server in dry-run setup, e.x. a testnet account, as it changes the hedge.
+    Bench(BenchCmd),
+    /// Download OpenAPI spec of the running server to generate clients against the deployed
+    /// version
+    Swagger {
+        /// Path of the output file, the spec is printed if not set
+        #[clap(long)]
+        out: Option<PathBuf>,
+    },
+    /// Print version of the CLI
+    Version {
+        /// Compare with version of the remote service
+        #[clap(long)]
+        remote: bool,
+    },
+}
+
+#[derive(Parser, Debug)]
+struct CorrectCmd {
+    /// ID of channel
+    pub channel_id: String,
+    /// Moment of the erroneous update as it is printed by history command
+    #[clap(long)]
+    pub created: NaiveDateTime,
+    /// Correct amount of satoshis, the update is voided if not set
+    #[clap(long)]
+    pub sats: Option<i64>,
+    /// Correct exchange rate of the HTLC sats/USD
+    #[clap(long)]
+    pub rate: Option<u64>,
+    /// Why the update is corrected
+    #[clap(long)]
+    pub reason: String,
+    /// Who corrects the update
+    #[clap(long)]
+    pub author: String,
+}
+
+#[derive(Parser, Debug)]
+struct ConfigCmd {
+    /// New index pair, e.x. .BTCUSD
+    #[clap(long)]
+    pub pair: Option<String>,
+    /// New spread percent
+    #[clap(long)]
+    pub spread_percent: Option<f64>,
+    /// New leverage * 100
+    #[clap(long)]
+    pub leverage: Option<u64>,
+    /// Comma separated channels that are tracked but not hedged, replaces the current list.
+    /// Empty value hedges all channels.
+    #[clap(long, use_value_delimiter = true)]
+    pub unhedged_channels: Option<Vec<String>>,
+    /// Leverage * 100 of a channel as `<channel id>=<leverage>`, can be repeated. Replaces all
+    /// current overrides.
+    #[clap(long, parse(try_from_str = parse_channel_leverage))]
+    pub channel_leverage: Option<Vec<(String, u64)>>,
+    /// Remove leverage overrides of all channels
+    #[clap(long, conflicts_with = "channel-leverage")]
+    pub reset_channel_leverage: bool,
+}
+
+fn parse_channel_leverage(s: &str) -> Result<(String, u64), String> {
+    let invalid = || {
+        format!(
+            "Invalid channel leverage {}, expected <channel id>=<leverage>",
+            s
+        )
+    };
+    let (chan_id, leverage) = s.split_once('=').ok_or_else(invalid)?;
+    let leverage = leverage.trim().parse::<u64>().map_err(|_| invalid())?;
+    if chan_id.trim().is_empty() || leverage == 0 {
+        return Err(invalid());
+    }
+    Ok((chan_id.trim().to_owned(), leverage))
+}
+
+#[derive(Parser, Debug)]
+struct StatsHistoryCmd {
+    /// Start of the time range, e.x. 2022-01-01T00:00:00
+    #[clap(long)]
+    pub from: Option<NaiveDateTime>,
+    /// End of the time range, e.x. 2022-01-02T00:00:00
+    #[clap(long)]
+    pub to: Option<NaiveDateTime>,
+    /// Minimal distance between records in seconds
+    #[clap(long)]
+    pub step: Option<u64>,
 }
 
 #[derive(Parser, Debug)]
@@ -31,7 +252,8 @@ struct HtlcCmd {
     #[clap(long)]
     pub sats: i64,
     /// Current exchange rate of the HTLC sats/USD, cannot be specified alongside with price option.
-    #[clap(long)]
+    /// The current price of the service is used if neither is set.
+    #[clap(long, conflicts_with = "price")]
     pub rate: Option<u64>,
     /// Current exchange rate of the HTLC USD/BTC, cannot be specified alongside with rate option.
     #[clap(long)]
@@ -39,21 +261,50 @@ struct HtlcCmd {
 }
 
 impl HtlcCmd {
-    fn rate(&self) -> u64 {
-        match (self.rate, self.price) {
-            (Some(r), None) => r,
-            (None, Some(p)) => (100_000_000. / (p as f64)).round() as u64,
-            _ => panic!("Specify only rate or only price parameter!"),
-        }
+    /// Rate given by the options, `None` if the rate should be fetched from the service
+    fn rate(&self) -> Option<u64> {
+        self.rate
+            .or_else(|| self.price.map(|p| (100_000_000. / p).round() as u64))
     }
 }
 
+#[derive(Parser, Debug)]
+struct BenchCmd {
+    /// Requests per second
+    #[clap(long, default_value = "10")]
+    pub rps: u64,
+    /// Duration of the test, e.x. 60s, 5m
+    #[clap(long, default_value = "60s", parse(try_from_str = parse_duration))]
+    pub duration: Duration,
+    /// Amount of synthetic channels
+    #[clap(long, default_value = "10")]
+    pub channels: u64,
+    /// Satoshis of incoming HTLCs, outgoing HTLCs take half of it
+    #[clap(long, default_value = "1000")]
+    pub sats: i64,
+    /// Exchange rate of HTLCs sats/USD
+    #[clap(long, default_value = "2500")]
+    pub rate: u64,
+}
+
+#[derive(Parser, Debug)]
+struct SetHedgeCmd {
+    /// ID of channel
+    pub channel_id: String,
+    /// Amount of satoshis in the channel
+    #[clap(long)]
+    pub sats: u64,
+    /// Average exchange rate of the channel sats/USD
+    #[clap(long)]
+    pub rate: u64,
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let args = Args::parse();
 
     env_logger::init();
-    let client = HedgeClient::new(&args.url);
+    let client = HedgeClient::new(&args.url[0]);
 
     match args.subcmd {
         SubCommand::State => {
@@ -62,7 +313,17 @@ async fn main() -> Result<(), Box<dyn Error>> {
             println!("{}", pretty);
         }
         SubCommand::Htlc(cmd) => {
-            let rate = cmd.rate();
+            let rate = match cmd.rate() {
+                Some(rate) => rate,
+                None => {
+                    let price = client.query_price().await?;
+                    println!(
+                        "Using current price {} USD ({} sats/USD)",
+                        price.price, price.rate
+                    );
+                    price.rate
+                }
+            };
             client
                 .hedge_htlc(HtlcInfo {
                     channel_id: cmd.channel_id,
@@ -72,11 +333,300 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 .await?;
             println!("Done");
         }
-        SubCommand::Stats => {
+        SubCommand::SetHedge(cmd) => {
+            let hedge = client
+                .hedge_set(HedgeTarget {
+                    channel_id: cmd.channel_id,
+                    sats: cmd.sats,
+                    rate: cmd.rate,
+                })
+                .await?;
+            println!(
+                "Channel hedge: {} sats at {} sats/USD",
+                hedge.sats, hedge.rate
+            );
+        }
+        SubCommand::Bench(cmd) => {
+            if cmd.rps == 0 {
+                return Err("--rps must be positive".into());
+            }
+            let report = run_bench(
+                client,
+                BenchConfig {
+                    rps: cmd.rps,
+                    duration: cmd.duration,
+                    channels: cmd.channels,
+                    sats: cmd.sats,
+                    rate: cmd.rate,
+                },
+            )
+            .await;
+            report.print();
+        }
+        SubCommand::Stats { aggregate } if !aggregate && args.url.len() == 1 => {
             let stats = client.query_stats().await?;
             let pretty = serde_json::to_string_pretty(&stats)?;
             println!("{}", pretty);
         }
+        SubCommand::Stats { aggregate } => {
+            let instances = fetch_stats(&args.url).await;
+            for instance in instances.iter() {
+                if let Some(e) = &instance.error {
+                    eprintln!("Failed to query stats of {}: {}", instance.url, e);
+                }
+            }
+            if instances.iter().all(|i| i.stats.is_none()) {
+                return Err("No instance returned stats".into());
+            }
+            let report = StatsReport {
+                total: aggregate.then(|| AggregatedStats::new(&instances)),
+                instances,
+            };
+            let pretty = serde_json::to_string_pretty(&report)?;
+            println!("{}", pretty);
+        }
+        SubCommand::Estimate { sats } => {
+            let estimate = client.estimate(sats).await?;
+            let pretty = serde_json::to_string_pretty(&estimate)?;
+            println!("{}", pretty);
+        }
+        SubCommand::Deposit { sats } => {
+            let token = args
+                .admin_token
+                .ok_or("Admin token is required, pass --admin-token")?;
+            let deposit = client.deposit_invoice(&token, sats).await?;
+            println!("{}", deposit.invoice);
+        }
+        SubCommand::Withdraw { invoice, sats } => {
+            let token = args
+                .admin_token
+                .ok_or("Admin token is required, pass --admin-token")?;
+            let withdrawal = client.withdraw(&token, invoice, sats).await?;
+            println!("Withdrawn {} sats", withdrawal.sats);
+        }
+        SubCommand::Correct(cmd) => {
+            let token = args
+                .admin_token
+                .ok_or("Admin token is required, pass --admin-token")?;
+            let request = CorrectionRequest {
+                channel_id: cmd.channel_id,
+                created: cmd.created,
+                sats: cmd.sats,
+                rate: cmd.rate,
+                reason: cmd.reason,
+                author: cmd.author,
+            };
+            let correction = client.correct_update(&token, request).await?;
+            let pretty = serde_json::to_string_pretty(&correction)?;
+            println!("{}", pretty);
+        }
+        SubCommand::Rollback { to } => {
+            let token = args
+                .admin_token
+                .ok_or("Admin token is required, pass --admin-token")?;
+            let snapshot = client.rollback(&token, to).await?;
+            let pretty = serde_json::to_string_pretty(&snapshot)?;
+            println!("{}", pretty);
+        }
+        SubCommand::MigrateSymbol { symbol, pair } => {
+            let token = args
+                .admin_token
+                .ok_or("Admin token is required, pass --admin-token")?;
+            let migration = client
+                .migrate_symbol(&token, SymbolMigrationRequest { symbol, pair })
+                .await?;
+            let pretty = serde_json::to_string_pretty(&migration)?;
+            println!("{}", pretty);
+        }
+        SubCommand::StatsHistory(cmd) => {
+            let history = client
+                .query_stats_history(&StatsHistoryQuery {
+                    from: cmd.from,
+                    to: cmd.to,
+                    step: cmd.step,
+                })
+                .await?;
+            let pretty = serde_json::to_string_pretty(&history)?;
+            println!("{}", pretty);
+        }
+        SubCommand::Config(cmd) => {
+            let token = args
+                .admin_token
+                .ok_or("Admin token is required, pass --admin-token")?;
+            let config = client
+                .update_config(
+                    &token,
+                    ConfigChange {
+                        hedge_pair: cmd.pair,
+                        hedge_sym: None,
+                        spread_percent: cmd.spread_percent,
+                        hedge_leverage: cmd.leverage,
+                        unhedged_channels: cmd
+                            .unhedged_channels
+                            .map(|ids| ids.into_iter().filter(|id| !id.is_empty()).collect()),
+                        channel_leverage: if cmd.reset_channel_leverage {
+                            Some(HashMap::new())
+                        } else {
+                            cmd.channel_leverage.map(|v| v.into_iter().collect())
+                        },
+                        startup: false,
+                    },
+                )
+                .await?;
+            let pretty = serde_json::to_string_pretty(&config)?;
+            println!("{}", pretty);
+        }
+        SubCommand::Health => {
+            let code = match client.query_health().await {
+                Ok(health) if health.ready => {
+                    println!("READY");
+                    0
+                }
+                Ok(health) if health.live => {
+                    println!(
+                        "NOT READY: authenticated {}, exchange state {}",
+                        health.authenticated, health.exchange_state
+                    );
+                    1
+                }
+                Ok(_) => {
+                    println!("NOT LIVE: database is unreachable");
+                    2
+                }
+                Err(e) => {
+                    println!("NOT LIVE: {}", e);
+                    2
+                }
+            };
+            std::process::exit(code);
+        }
+        SubCommand::WsStatus => {
+            let status = client.query_ws_status().await?;
+            let pretty = serde_json::to_string_pretty(&status)?;
+            println!("{}", pretty);
+        }
+        SubCommand::DebugMessages => {
+            let token = args
+                .admin_token
+                .ok_or("Admin token is required, pass --admin-token")?;
+            for msg in client.query_debug_messages(&token).await? {
+                println!("{} {}", msg.received, msg.message);
+            }
+        }
+        SubCommand::Channels => {
+            let channels = client.query_channels().await?;
+            let pretty = serde_json::to_string_pretty(&channels)?;
+            println!("{}", pretty);
+        }
+        SubCommand::Swagger { out } => {
+            let spec = client.swagger().await?;
+            let pretty = serde_json::to_string_pretty(&spec)?;
+            match out {
+                Some(path) => {
+                    std::fs::write(&path, pretty)?;
+                    println!("Spec is written to {}", path.display());
+                }
+                None => println!("{}", pretty),
+            }
+        }
+        SubCommand::Version { remote } => {
+            let local_version = env!("CARGO_PKG_VERSION");
+            println!("Client version: {}", local_version);
+            if remote {
+                let info = client.query_version().await?;
+                println!(
+                    "Server version: {} (git {}, features: [{}])",
+                    info.version,
+                    info.git_hash,
+                    info.features.join(", ")
+                );
+                if info.version != local_version {
+                    eprintln!(
+                        "Warning: client version {} doesn't match server version {}",
+                        local_version, info.version
+                    );
+                }
+            }
+        }
+        SubCommand::History { from, to } => {
+            let history = client
+                .query_history_range(&HistoryQuery { from, to })
+                .await?;
+            let pretty = serde_json::to_string_pretty(&history)?;
+            println!("{}", pretty);
+        }
+        SubCommand::Dump { since, out } => {
+            let token = args
+                .admin_token
+                .ok_or("Admin token is required, pass --admin-token")?;
+            let dump = client.dump_updates(&token, &DumpQuery { since }).await?;
+            std::fs::write(&out, &dump)?;
+            println!(
+                "Dumped {} updates to {}",
+                dump.lines().count(),
+                out.display()
+            );
+        }
+        SubCommand::Restore { input } => {
+            let token = args
+                .admin_token
+                .ok_or("Admin token is required, pass --admin-token")?;
+            let dump = std::fs::read_to_string(&input)?;
+            let summary = client.restore_updates(&token, dump).await?;
+            match summary.last {
+                Some(last) => println!("Restored {} updates up to {}", summary.restored, last),
+                None => println!("Dump is empty, nothing is restored"),
+            }
+        }
+        SubCommand::VerifyLog { input } => {
+            let dump = match input {
+                Some(input) => std::fs::read_to_string(&input)?,
+                None => {
+                    let token = args
+                        .admin_token
+                        .ok_or("Admin token is required, pass --admin-token")?;
+                    client.dump_updates(&token, &DumpQuery::default()).await?
+                }
+            };
+            let records = dump
+                .lines()
+                .filter(|l| !l.trim().is_empty())
+                .map(serde_json::from_str)
+                .collect::<Result<Vec<DumpRecord>, _>>()?;
+            match verify_chain(&records) {
+                Ok(summary) => {
+                    println!("OK: {} updates", summary.records);
+                    println!("Anchor: {}", summary.anchor.unwrap_or_default());
+                    println!("Head: {}", summary.head.unwrap_or_default());
+                }
+                Err(e) => {
+                    println!("FAILED: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        SubCommand::Diff { url_a, url_b, dump } => {
+            let url_a = url_a.unwrap_or_else(|| args.url[0].clone());
+            let state_a = url_state(&url_a).await?;
+            let (name_b, state_b) = match (url_b, dump) {
+                (Some(url_b), _) => (url_b.clone(), url_state(&url_b).await?),
+                (None, Some(dump)) => (dump.display().to_string(), dump_state(&dump)?),
+                (None, None) => return Err("Pass --url-b or --dump".into()),
+            };
+            let diff = StateDiff::new(&state_a, &state_b);
+            diff.print(&url_a, &name_b);
+            if diff.is_empty() {
+                println!("OK: states match");
+            } else {
+                println!("DIFFERS: {} channels", diff.channels.len());
+                std::process::exit(1);
+            }
+        }
+        SubCommand::ChannelHistory { channel_id } => {
+            let history = client.query_channel_history(&channel_id).await?;
+            let pretty = serde_json::to_string_pretty(&history)?;
+            println!("{}", pretty);
+        }
     }
     Ok(())
 }
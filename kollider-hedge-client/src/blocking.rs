@@ -0,0 +1,476 @@
+//! Synchronous variant of [`HedgeClient`](crate::client::HedgeClient) for tools that don't run
+//! an async runtime.
+use crate::client::{status_error, Result};
+use chrono::NaiveDateTime;
+use kollider_hedge_domain::api::*;
+use kollider_hedge_domain::encoding::Encoding;
+use kollider_hedge_domain::state::*;
+use kollider_hedge_domain::update::*;
+use log::*;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Replacement of `error_for_status` that keeps the error body of the service
+trait CheckStatus: Sized {
+    fn check_status(self) -> Result<Self>;
+}
+
+impl CheckStatus for reqwest::blocking::Response {
+    fn check_status(self) -> Result<Self> {
+        let status = self.status();
+        if status.is_client_error() || status.is_server_error() {
+            Err(status_error(status, self.text()?))
+        } else {
+            Ok(self)
+        }
+    }
+}
+
+pub struct HedgeClientBlocking {
+    pub client: reqwest::blocking::Client,
+    pub server: String,
+    /// Encoding requested for `/state` and `/history` responses
+    pub encoding: Encoding,
+}
+
+impl HedgeClientBlocking {
+    pub fn new(url: &str) -> Self {
+        HedgeClientBlocking {
+            client: reqwest::blocking::Client::new(),
+            server: url.to_owned(),
+            encoding: Encoding::default(),
+        }
+    }
+
+    /// Request `/state` and `/history` in a compact binary encoding
+    pub fn with_encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    pub fn hedge_htlc(&self, info: HtlcInfo) -> Result<()> {
+        let path = "/hedge/htlc";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self.client.post(endpoint).json(&info).build()?;
+        self.client.execute(request)?.check_status()?.text()?;
+        Ok(())
+    }
+
+    /// Show how the HTLC would change the hedge without recording it
+    pub fn hedge_htlc_preview(&self, info: HtlcInfo) -> Result<HtlcPreview> {
+        let path = "/hedge/htlc/preview";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self.client.post(endpoint).json(&info).build()?;
+        let response = self.client.execute(request)?.check_status()?.text()?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    /// Forward payment event of Eclair node, returns HTLCs recorded for fiat channels
+    pub fn hedge_eclair(&self, event: &serde_json::Value) -> Result<Vec<HtlcInfo>> {
+        let path = "/hedge/eclair";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self.client.post(endpoint).json(event).build()?;
+        let response = self.client.execute(request)?.check_status()?.text()?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    pub fn hedge_set(&self, target: HedgeTarget) -> Result<ChannelHedge> {
+        let path = "/hedge/set";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self.client.post(endpoint).json(&target).build()?;
+        let response = self.client.execute(request)?.check_status()?.text()?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    pub fn update_config(&self, admin_token: &str, change: ConfigChange) -> Result<HedgeConfig> {
+        let path = "/config";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self
+            .client
+            .post(endpoint)
+            .bearer_auth(admin_token)
+            .json(&change)
+            .build()?;
+        let response = self.client.execute(request)?.check_status()?.text()?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    pub fn deposit_invoice(&self, admin_token: &str, sats: u64) -> Result<DepositInvoice> {
+        let path = "/admin/deposit-invoice";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self
+            .client
+            .post(endpoint)
+            .bearer_auth(admin_token)
+            .json(&DepositRequest { sats })
+            .build()?;
+        let response = self.client.execute(request)?.check_status()?.text()?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    pub fn withdraw(
+        &self,
+        admin_token: &str,
+        invoice: String,
+        sats: Option<u64>,
+    ) -> Result<Withdrawal> {
+        let path = "/admin/withdraw";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self
+            .client
+            .post(endpoint)
+            .bearer_auth(admin_token)
+            .json(&WithdrawRequest { invoice, sats })
+            .build()?;
+        let response = self.client.execute(request)?.check_status()?.text()?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    pub fn correct_update(
+        &self,
+        admin_token: &str,
+        request: CorrectionRequest,
+    ) -> Result<Correction> {
+        let path = "/admin/correction";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self
+            .client
+            .post(endpoint)
+            .bearer_auth(admin_token)
+            .json(&request)
+            .build()?;
+        let response = self.client.execute(request)?.check_status()?.text()?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    /// Move the hedge to another symbol, returns the started migration
+    pub fn migrate_symbol(
+        &self,
+        admin_token: &str,
+        request: SymbolMigrationRequest,
+    ) -> Result<Option<SymbolMigration>> {
+        let path = "/admin/migrate-symbol";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self
+            .client
+            .post(endpoint)
+            .bearer_auth(admin_token)
+            .json(&request)
+            .build()?;
+        let response = self.client.execute(request)?.check_status()?.text()?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    /// Reset hedge of channels to the state at the moment, returns the written snapshot
+    pub fn rollback(&self, admin_token: &str, to: NaiveDateTime) -> Result<StateSnapshot> {
+        let path = "/admin/rollback";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self
+            .client
+            .post(endpoint)
+            .bearer_auth(admin_token)
+            .json(&RollbackRequest { to })
+            .build()?;
+        let response = self.client.execute(request)?.check_status()?.text()?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    /// Export updates created after the moment as JSONL text
+    pub fn dump_updates(&self, admin_token: &str, filter: &DumpQuery) -> Result<String> {
+        let path = "/admin/dump";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self
+            .client
+            .get(endpoint)
+            .bearer_auth(admin_token)
+            .query(filter)
+            .build()?;
+        let response = self.client.execute(request)?.check_status()?.text()?;
+        Ok(response)
+    }
+
+    /// Append updates from JSONL text produced by `dump_updates`
+    pub fn restore_updates(&self, admin_token: &str, dump: String) -> Result<RestoreSummary> {
+        let path = "/admin/restore";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self
+            .client
+            .post(endpoint)
+            .bearer_auth(admin_token)
+            .header(reqwest::header::CONTENT_TYPE, "application/x-ndjson")
+            .body(dump)
+            .build()?;
+        let response = self.client.execute(request)?.check_status()?.text()?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    pub fn query_state(&self) -> Result<State> {
+        let path = "/state";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self
+            .client
+            .get(endpoint)
+            .header(reqwest::header::ACCEPT, self.encoding.content_type())
+            .build()?;
+        let response = self.client.execute(request)?.check_status()?.bytes()?;
+        debug!(
+            "Response: {} bytes of {}",
+            response.len(),
+            self.encoding.content_type()
+        );
+        Ok(self.encoding.decode(&response)?)
+    }
+
+    /// Fetch only the listed top-level fields of the state, e.x. `["ticker", "opened_position"]`
+    pub fn query_state_fields(
+        &self,
+        fields: &[&str],
+    ) -> Result<serde_json::Map<String, serde_json::Value>> {
+        let path = "/state";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self
+            .client
+            .get(endpoint)
+            .query(&StateFieldsQuery {
+                fields: fields.join(","),
+            })
+            .header(reqwest::header::ACCEPT, self.encoding.content_type())
+            .build()?;
+        let response = self.client.execute(request)?.check_status()?.bytes()?;
+        debug!(
+            "Response: {} bytes of {}",
+            response.len(),
+            self.encoding.content_type()
+        );
+        Ok(self.encoding.decode(&response)?)
+    }
+
+    /// Fetch the state only if its revision differs from the given one, e.x. `revision` of the
+    /// previously fetched state. Returns `None` if the state is not changed.
+    pub fn query_state_if_changed(&self, revision: u64) -> Result<Option<State>> {
+        let path = "/state";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self
+            .client
+            .get(endpoint)
+            .header(reqwest::header::ACCEPT, self.encoding.content_type())
+            .header(reqwest::header::IF_NONE_MATCH, format!("\"{}\"", revision))
+            .build()?;
+        let response = self.client.execute(request)?.check_status()?;
+        if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+            debug!("State is not changed since revision {}", revision);
+            return Ok(None);
+        }
+        let response = response.bytes()?;
+        debug!(
+            "Response: {} bytes of {}",
+            response.len(),
+            self.encoding.content_type()
+        );
+        Ok(Some(self.encoding.decode(&response)?))
+    }
+
+    /// Wait until the state revision advances past `since_rev`. Returns the new revision or
+    /// `None` if the state is not changed within the timeout.
+    pub fn wait_state(&self, since_rev: u64, timeout: Duration) -> Result<Option<u64>> {
+        let path = "/state/wait";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self
+            .client
+            .get(endpoint)
+            .query(&WaitQuery {
+                since_rev,
+                timeout: Some(format!("{}s", timeout.as_secs())),
+            })
+            // Blocking client times out in 30 seconds by default
+            .timeout(timeout + Duration::from_secs(10))
+            .build()?;
+        let response = self.client.execute(request)?.check_status()?;
+        if response.status() == reqwest::StatusCode::NO_CONTENT {
+            return Ok(None);
+        }
+        let response = response.text()?;
+        debug!("Response: {}", response);
+        let revision: StateRevision = serde_json::from_str(&response)?;
+        Ok(Some(revision.revision))
+    }
+
+    pub fn query_channels(&self) -> Result<HashMap<ChannelId, ChannelInfo>> {
+        let path = "/channels";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self.client.get(endpoint).build()?;
+        let response = self.client.execute(request)?.check_status()?.text()?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    pub fn query_stats(&self) -> Result<Stats> {
+        let path = "/stats";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self.client.get(endpoint).build()?;
+        let response = self.client.execute(request)?.check_status()?.text()?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    pub fn simulate_price(&self, price: f64) -> Result<Simulation> {
+        let path = "/simulate";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self
+            .client
+            .get(endpoint)
+            .query(&SimulateQuery { price })
+            .build()?;
+        let response = self.client.execute(request)?.check_status()?.text()?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    pub fn estimate(&self, sats: u64) -> Result<OrderEstimate> {
+        let path = "/estimate";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self
+            .client
+            .get(endpoint)
+            .query(&EstimateQuery { sats })
+            .build()?;
+        let response = self.client.execute(request)?.check_status()?.text()?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    pub fn query_price(&self) -> Result<PriceInfo> {
+        let path = "/price";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self.client.get(endpoint).build()?;
+        let response = self.client.execute(request)?.check_status()?.text()?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    pub fn query_stats_history(&self, filter: &StatsHistoryQuery) -> Result<Vec<StatsRecord>> {
+        let path = "/stats/history";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self.client.get(endpoint).query(filter).build()?;
+        let response = self.client.execute(request)?.check_status()?.text()?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    pub fn query_htlc_requests(&self, filter: &RequestsQuery) -> Result<Vec<HtlcRequestRecord>> {
+        let path = "/requests";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self.client.get(endpoint).query(filter).build()?;
+        let response = self.client.execute(request)?.check_status()?.text()?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    pub fn query_channel_history(&self, channel_id: &str) -> Result<Vec<ChannelHistoryEntry>> {
+        let path = format!("/channel/{}/history", channel_id);
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self.client.get(endpoint).build()?;
+        let response = self.client.execute(request)?.check_status()?.text()?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    pub fn query_ws_status(&self) -> Result<WsStatus> {
+        let path = "/ws-status";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self.client.get(endpoint).build()?;
+        let response = self.client.execute(request)?.check_status()?.text()?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    pub fn query_debug_messages(&self, admin_token: &str) -> Result<Vec<RecordedMessage>> {
+        let path = "/debug/messages";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self.client.get(endpoint).bearer_auth(admin_token).build()?;
+        let response = self.client.execute(request)?.check_status()?.text()?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    pub fn query_history(&self) -> Result<Vec<StateUpdate>> {
+        let path = "/history";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self
+            .client
+            .get(endpoint)
+            .header(reqwest::header::ACCEPT, self.encoding.content_type())
+            .build()?;
+        let response = self.client.execute(request)?.check_status()?.bytes()?;
+        debug!(
+            "Response: {} bytes of {}",
+            response.len(),
+            self.encoding.content_type()
+        );
+        Ok(self.encoding.decode(&response)?)
+    }
+
+    pub fn query_history_range(&self, filter: &HistoryQuery) -> Result<Vec<StateUpdate>> {
+        let path = "/history";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self
+            .client
+            .get(endpoint)
+            .query(filter)
+            .header(reqwest::header::ACCEPT, self.encoding.content_type())
+            .build()?;
+        let response = self.client.execute(request)?.check_status()?.bytes()?;
+        debug!(
+            "Response: {} bytes of {}",
+            response.len(),
+            self.encoding.content_type()
+        );
+        Ok(self.encoding.decode(&response)?)
+    }
+
+    /// OpenAPI spec of the server
+    pub fn swagger(&self) -> Result<serde_json::Value> {
+        let path = "/swagger.json";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self.client.get(endpoint).build()?;
+        let response = self.client.execute(request)?.check_status()?.text()?;
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    pub fn query_version(&self) -> Result<VersionInfo> {
+        let path = "/version";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self.client.get(endpoint).build()?;
+        let response = self.client.execute(request)?.check_status()?.text()?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    pub fn query_health(&self) -> Result<Health> {
+        let path = "/health";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self.client.get(endpoint).build()?;
+        let response = self.client.execute(request)?.check_status()?.text()?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    pub fn query_metrics(&self) -> Result<Metrics> {
+        let path = "/metrics";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self.client.get(endpoint).build()?;
+        let response = self.client.execute(request)?.check_status()?.text()?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+}
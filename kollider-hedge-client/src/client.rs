@@ -1,6 +1,12 @@
+use chrono::NaiveDateTime;
 use kollider_hedge_domain::api::*;
+use kollider_hedge_domain::encoding::{Encoding, EncodingErr};
+use kollider_hedge_domain::error::ErrorMessage;
 use kollider_hedge_domain::state::*;
+use kollider_hedge_domain::update::*;
 use log::*;
+use std::collections::HashMap;
+use std::time::Duration;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -9,14 +15,56 @@ pub enum Error {
     Reqwest(#[from] reqwest::Error),
     #[error("JSON encoding/decoding error: {0}")]
     Json(#[from] serde_json::Error),
+    #[error("Response decoding error: {0}")]
+    Encoding(#[from] EncodingErr),
+    #[error("Server rejected the request: {0}")]
+    Api(ErrorMessage),
+    #[error("Server responded with status {0}: {1}")]
+    Status(u16, String),
+}
+
+impl Error {
+    /// Error body of the service, clients pick retry behavior by its `error_code`
+    pub fn api_error(&self) -> Option<&ErrorMessage> {
+        match self {
+            Error::Api(message) => Some(message),
+            _ => None,
+        }
+    }
 }
 
 /// Alias for a `Result` with the error type `self::Error`.
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Error body of the failed response, or the raw body if it is not the error of the service
+pub(crate) fn status_error(status: reqwest::StatusCode, body: String) -> Error {
+    match serde_json::from_str::<ErrorMessage>(&body) {
+        Ok(message) => Error::Api(message),
+        Err(_) => Error::Status(status.as_u16(), body),
+    }
+}
+
+/// Replacement of `error_for_status` that keeps the error body of the service
+trait CheckStatus: Sized {
+    async fn check_status(self) -> Result<Self>;
+}
+
+impl CheckStatus for reqwest::Response {
+    async fn check_status(self) -> Result<Self> {
+        let status = self.status();
+        if status.is_client_error() || status.is_server_error() {
+            Err(status_error(status, self.text().await?))
+        } else {
+            Ok(self)
+        }
+    }
+}
+
 pub struct HedgeClient {
     pub client: reqwest::Client,
     pub server: String,
+    /// Encoding requested for `/state` and `/history` responses
+    pub encoding: Encoding,
 }
 
 impl HedgeClient {
@@ -24,9 +72,16 @@ impl HedgeClient {
         HedgeClient {
             client: reqwest::Client::new(),
             server: url.to_owned(),
+            encoding: Encoding::default(),
         }
     }
 
+    /// Request `/state` and `/history` in a compact binary encoding
+    pub fn with_encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
     pub async fn hedge_htlc(&self, info: HtlcInfo) -> Result<()> {
         let path = "/hedge/htlc";
         let endpoint = format!("{}{}", self.server, path);
@@ -34,21 +89,365 @@ impl HedgeClient {
         self.client
             .execute(request)
             .await?
-            .error_for_status()?
+            .check_status()
+            .await?
             .text()
             .await?;
         Ok(())
     }
 
+    /// Show how the HTLC would change the hedge without recording it
+    pub async fn hedge_htlc_preview(&self, info: HtlcInfo) -> Result<HtlcPreview> {
+        let path = "/hedge/htlc/preview";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self.client.post(endpoint).json(&info).build()?;
+        let response = self
+            .client
+            .execute(request)
+            .await?
+            .check_status()
+            .await?
+            .text()
+            .await?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    /// Forward payment event of Eclair node, returns HTLCs recorded for fiat channels
+    pub async fn hedge_eclair(&self, event: &serde_json::Value) -> Result<Vec<HtlcInfo>> {
+        let path = "/hedge/eclair";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self.client.post(endpoint).json(event).build()?;
+        let response = self
+            .client
+            .execute(request)
+            .await?
+            .check_status()
+            .await?
+            .text()
+            .await?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    pub async fn hedge_set(&self, target: HedgeTarget) -> Result<ChannelHedge> {
+        let path = "/hedge/set";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self.client.post(endpoint).json(&target).build()?;
+        let response = self
+            .client
+            .execute(request)
+            .await?
+            .check_status()
+            .await?
+            .text()
+            .await?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    pub async fn update_config(
+        &self,
+        admin_token: &str,
+        change: ConfigChange,
+    ) -> Result<HedgeConfig> {
+        let path = "/config";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self
+            .client
+            .post(endpoint)
+            .bearer_auth(admin_token)
+            .json(&change)
+            .build()?;
+        let response = self
+            .client
+            .execute(request)
+            .await?
+            .check_status()
+            .await?
+            .text()
+            .await?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    pub async fn deposit_invoice(&self, admin_token: &str, sats: u64) -> Result<DepositInvoice> {
+        let path = "/admin/deposit-invoice";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self
+            .client
+            .post(endpoint)
+            .bearer_auth(admin_token)
+            .json(&DepositRequest { sats })
+            .build()?;
+        let response = self
+            .client
+            .execute(request)
+            .await?
+            .check_status()
+            .await?
+            .text()
+            .await?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    pub async fn withdraw(
+        &self,
+        admin_token: &str,
+        invoice: String,
+        sats: Option<u64>,
+    ) -> Result<Withdrawal> {
+        let path = "/admin/withdraw";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self
+            .client
+            .post(endpoint)
+            .bearer_auth(admin_token)
+            .json(&WithdrawRequest { invoice, sats })
+            .build()?;
+        let response = self
+            .client
+            .execute(request)
+            .await?
+            .check_status()
+            .await?
+            .text()
+            .await?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    pub async fn correct_update(
+        &self,
+        admin_token: &str,
+        request: CorrectionRequest,
+    ) -> Result<Correction> {
+        let path = "/admin/correction";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self
+            .client
+            .post(endpoint)
+            .bearer_auth(admin_token)
+            .json(&request)
+            .build()?;
+        let response = self
+            .client
+            .execute(request)
+            .await?
+            .check_status()
+            .await?
+            .text()
+            .await?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    /// Move the hedge to another symbol, returns the started migration
+    pub async fn migrate_symbol(
+        &self,
+        admin_token: &str,
+        request: SymbolMigrationRequest,
+    ) -> Result<Option<SymbolMigration>> {
+        let path = "/admin/migrate-symbol";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self
+            .client
+            .post(endpoint)
+            .bearer_auth(admin_token)
+            .json(&request)
+            .build()?;
+        let response = self
+            .client
+            .execute(request)
+            .await?
+            .check_status()
+            .await?
+            .text()
+            .await?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    /// Reset hedge of channels to the state at the moment, returns the written snapshot
+    pub async fn rollback(&self, admin_token: &str, to: NaiveDateTime) -> Result<StateSnapshot> {
+        let path = "/admin/rollback";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self
+            .client
+            .post(endpoint)
+            .bearer_auth(admin_token)
+            .json(&RollbackRequest { to })
+            .build()?;
+        let response = self
+            .client
+            .execute(request)
+            .await?
+            .check_status()
+            .await?
+            .text()
+            .await?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    /// Export updates created after the moment as JSONL text
+    pub async fn dump_updates(&self, admin_token: &str, filter: &DumpQuery) -> Result<String> {
+        let path = "/admin/dump";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self
+            .client
+            .get(endpoint)
+            .bearer_auth(admin_token)
+            .query(filter)
+            .build()?;
+        let response = self
+            .client
+            .execute(request)
+            .await?
+            .check_status()
+            .await?
+            .text()
+            .await?;
+        Ok(response)
+    }
+
+    /// Append updates from JSONL text produced by `dump_updates`
+    pub async fn restore_updates(&self, admin_token: &str, dump: String) -> Result<RestoreSummary> {
+        let path = "/admin/restore";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self
+            .client
+            .post(endpoint)
+            .bearer_auth(admin_token)
+            .header(reqwest::header::CONTENT_TYPE, "application/x-ndjson")
+            .body(dump)
+            .build()?;
+        let response = self
+            .client
+            .execute(request)
+            .await?
+            .check_status()
+            .await?
+            .text()
+            .await?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
     pub async fn query_state(&self) -> Result<State> {
         let path = "/state";
         let endpoint = format!("{}{}", self.server, path);
+        let request = self
+            .client
+            .get(endpoint)
+            .header(reqwest::header::ACCEPT, self.encoding.content_type())
+            .build()?;
+        let response = self
+            .client
+            .execute(request)
+            .await?
+            .check_status()
+            .await?
+            .bytes()
+            .await?;
+        debug!(
+            "Response: {} bytes of {}",
+            response.len(),
+            self.encoding.content_type()
+        );
+        Ok(self.encoding.decode(&response)?)
+    }
+
+    /// Fetch only the listed top-level fields of the state, e.x. `["ticker", "opened_position"]`
+    pub async fn query_state_fields(
+        &self,
+        fields: &[&str],
+    ) -> Result<serde_json::Map<String, serde_json::Value>> {
+        let path = "/state";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self
+            .client
+            .get(endpoint)
+            .query(&StateFieldsQuery {
+                fields: fields.join(","),
+            })
+            .header(reqwest::header::ACCEPT, self.encoding.content_type())
+            .build()?;
+        let response = self
+            .client
+            .execute(request)
+            .await?
+            .check_status()
+            .await?
+            .bytes()
+            .await?;
+        debug!(
+            "Response: {} bytes of {}",
+            response.len(),
+            self.encoding.content_type()
+        );
+        Ok(self.encoding.decode(&response)?)
+    }
+
+    /// Fetch the state only if its revision differs from the given one, e.x. `revision` of the
+    /// previously fetched state. Returns `None` if the state is not changed.
+    pub async fn query_state_if_changed(&self, revision: u64) -> Result<Option<State>> {
+        let path = "/state";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self
+            .client
+            .get(endpoint)
+            .header(reqwest::header::ACCEPT, self.encoding.content_type())
+            .header(reqwest::header::IF_NONE_MATCH, format!("\"{}\"", revision))
+            .build()?;
+        let response = self.client.execute(request).await?.check_status().await?;
+        if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+            debug!("State is not changed since revision {}", revision);
+            return Ok(None);
+        }
+        let response = response.bytes().await?;
+        debug!(
+            "Response: {} bytes of {}",
+            response.len(),
+            self.encoding.content_type()
+        );
+        Ok(Some(self.encoding.decode(&response)?))
+    }
+
+    /// Wait until the state revision advances past `since_rev`. Returns the new revision or
+    /// `None` if the state is not changed within the timeout.
+    pub async fn wait_state(&self, since_rev: u64, timeout: Duration) -> Result<Option<u64>> {
+        let path = "/state/wait";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self
+            .client
+            .get(endpoint)
+            .query(&WaitQuery {
+                since_rev,
+                timeout: Some(format!("{}s", timeout.as_secs())),
+            })
+            .build()?;
+        let response = self.client.execute(request).await?.check_status().await?;
+        if response.status() == reqwest::StatusCode::NO_CONTENT {
+            return Ok(None);
+        }
+        let response = response.text().await?;
+        debug!("Response: {}", response);
+        let revision: StateRevision = serde_json::from_str(&response)?;
+        Ok(Some(revision.revision))
+    }
+
+    pub async fn query_channels(&self) -> Result<HashMap<ChannelId, ChannelInfo>> {
+        let path = "/channels";
+        let endpoint = format!("{}{}", self.server, path);
         let request = self.client.get(endpoint).build()?;
         let response = self
             .client
             .execute(request)
             .await?
-            .error_for_status()?
+            .check_status()
+            .await?
             .text()
             .await?;
         debug!("Response: {}", response);
@@ -63,7 +462,266 @@ impl HedgeClient {
             .client
             .execute(request)
             .await?
-            .error_for_status()?
+            .check_status()
+            .await?
+            .text()
+            .await?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    pub async fn simulate_price(&self, price: f64) -> Result<Simulation> {
+        let path = "/simulate";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self
+            .client
+            .get(endpoint)
+            .query(&SimulateQuery { price })
+            .build()?;
+        let response = self
+            .client
+            .execute(request)
+            .await?
+            .check_status()
+            .await?
+            .text()
+            .await?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    pub async fn estimate(&self, sats: u64) -> Result<OrderEstimate> {
+        let path = "/estimate";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self
+            .client
+            .get(endpoint)
+            .query(&EstimateQuery { sats })
+            .build()?;
+        let response = self
+            .client
+            .execute(request)
+            .await?
+            .check_status()
+            .await?
+            .text()
+            .await?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    pub async fn query_price(&self) -> Result<PriceInfo> {
+        let path = "/price";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self.client.get(endpoint).build()?;
+        let response = self
+            .client
+            .execute(request)
+            .await?
+            .check_status()
+            .await?
+            .text()
+            .await?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    pub async fn query_stats_history(
+        &self,
+        filter: &StatsHistoryQuery,
+    ) -> Result<Vec<StatsRecord>> {
+        let path = "/stats/history";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self.client.get(endpoint).query(filter).build()?;
+        let response = self
+            .client
+            .execute(request)
+            .await?
+            .check_status()
+            .await?
+            .text()
+            .await?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    pub async fn query_htlc_requests(
+        &self,
+        filter: &RequestsQuery,
+    ) -> Result<Vec<HtlcRequestRecord>> {
+        let path = "/requests";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self.client.get(endpoint).query(filter).build()?;
+        let response = self
+            .client
+            .execute(request)
+            .await?
+            .check_status()
+            .await?
+            .text()
+            .await?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    pub async fn query_channel_history(
+        &self,
+        channel_id: &str,
+    ) -> Result<Vec<ChannelHistoryEntry>> {
+        let path = format!("/channel/{}/history", channel_id);
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self.client.get(endpoint).build()?;
+        let response = self
+            .client
+            .execute(request)
+            .await?
+            .check_status()
+            .await?
+            .text()
+            .await?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    pub async fn query_ws_status(&self) -> Result<WsStatus> {
+        let path = "/ws-status";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self.client.get(endpoint).build()?;
+        let response = self
+            .client
+            .execute(request)
+            .await?
+            .check_status()
+            .await?
+            .text()
+            .await?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    pub async fn query_debug_messages(&self, admin_token: &str) -> Result<Vec<RecordedMessage>> {
+        let path = "/debug/messages";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self.client.get(endpoint).bearer_auth(admin_token).build()?;
+        let response = self
+            .client
+            .execute(request)
+            .await?
+            .check_status()
+            .await?
+            .text()
+            .await?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    pub async fn query_history(&self) -> Result<Vec<StateUpdate>> {
+        let path = "/history";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self
+            .client
+            .get(endpoint)
+            .header(reqwest::header::ACCEPT, self.encoding.content_type())
+            .build()?;
+        let response = self
+            .client
+            .execute(request)
+            .await?
+            .check_status()
+            .await?
+            .bytes()
+            .await?;
+        debug!(
+            "Response: {} bytes of {}",
+            response.len(),
+            self.encoding.content_type()
+        );
+        Ok(self.encoding.decode(&response)?)
+    }
+
+    pub async fn query_history_range(&self, filter: &HistoryQuery) -> Result<Vec<StateUpdate>> {
+        let path = "/history";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self
+            .client
+            .get(endpoint)
+            .query(filter)
+            .header(reqwest::header::ACCEPT, self.encoding.content_type())
+            .build()?;
+        let response = self
+            .client
+            .execute(request)
+            .await?
+            .check_status()
+            .await?
+            .bytes()
+            .await?;
+        debug!(
+            "Response: {} bytes of {}",
+            response.len(),
+            self.encoding.content_type()
+        );
+        Ok(self.encoding.decode(&response)?)
+    }
+
+    pub async fn query_version(&self) -> Result<VersionInfo> {
+        let path = "/version";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self.client.get(endpoint).build()?;
+        let response = self
+            .client
+            .execute(request)
+            .await?
+            .check_status()
+            .await?
+            .text()
+            .await?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    /// OpenAPI spec of the server
+    pub async fn swagger(&self) -> Result<serde_json::Value> {
+        let path = "/swagger.json";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self.client.get(endpoint).build()?;
+        let response = self
+            .client
+            .execute(request)
+            .await?
+            .check_status()
+            .await?
+            .text()
+            .await?;
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    pub async fn query_health(&self) -> Result<Health> {
+        let path = "/health";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self.client.get(endpoint).build()?;
+        let response = self
+            .client
+            .execute(request)
+            .await?
+            .check_status()
+            .await?
+            .text()
+            .await?;
+        debug!("Response: {}", response);
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    pub async fn query_metrics(&self) -> Result<Metrics> {
+        let path = "/metrics";
+        let endpoint = format!("{}{}", self.server, path);
+        let request = self.client.get(endpoint).build()?;
+        let response = self
+            .client
+            .execute(request)
+            .await?
+            .check_status()
+            .await?
             .text()
             .await?;
         debug!("Response: {}", response);
@@ -0,0 +1,7 @@
+create table stats(
+    id serial primary key,
+    created timestamp not null,
+    body jsonb not null
+);
+
+create index stats_created_idx on stats(created);
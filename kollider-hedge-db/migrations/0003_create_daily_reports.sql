@@ -0,0 +1,7 @@
+create table daily_reports(
+    id serial primary key,
+    created timestamp not null,
+    body jsonb not null
+);
+
+create index daily_reports_created_idx on daily_reports(created);
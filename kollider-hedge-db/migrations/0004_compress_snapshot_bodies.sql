@@ -0,0 +1,4 @@
+-- Large snapshot bodies are stored compressed by zstd instead of jsonb
+alter table updates alter column body drop not null;
+alter table updates add column body_zstd bytea;
+alter table updates add constraint updates_body_present check (body is not null or body_zstd is not null);
@@ -0,0 +1,54 @@
+-- Partition updates by month of creation, rows are moved to the new partitioned table
+alter table updates rename to updates_unpartitioned;
+alter index updates_pkey rename to updates_unpartitioned_pkey;
+alter table updates_unpartitioned rename constraint updates_body_present to updates_unpartitioned_body_present;
+alter sequence updates_id_seq owned by none;
+
+create table updates(
+    id integer not null default nextval('updates_id_seq'),
+    created timestamp not null,
+    version smallint not null,
+    tag text not null,
+    body jsonb,
+    body_zstd bytea,
+    primary key (id, created),
+    constraint updates_body_present check (body is not null or body_zstd is not null)
+) partition by range (created);
+
+alter sequence updates_id_seq owned by updates.id;
+
+-- Catches rows when the service didn't create the monthly partition in time
+create table updates_default partition of updates default;
+
+-- Create partition for the month of the given moment if it doesn't exist
+create function create_updates_partition(moment timestamp) returns void as $$
+declare
+    month_start timestamp := date_trunc('month', moment);
+    partition_name text := 'updates_' || to_char(month_start, 'YYYY_MM');
+begin
+    execute format(
+        'create table if not exists %I partition of updates for values from (%L) to (%L)',
+        partition_name, month_start, month_start + interval '1 month'
+    );
+end;
+$$ language plpgsql;
+
+do $$
+declare
+    m timestamp;
+begin
+    for m in select distinct date_trunc('month', created) from updates_unpartitioned loop
+        perform create_updates_partition(m);
+    end loop;
+    perform create_updates_partition((now() at time zone 'utc'));
+    perform create_updates_partition((now() at time zone 'utc') + interval '1 month');
+end;
+$$;
+
+insert into updates (id, created, version, tag, body, body_zstd)
+    select id, created, version, tag, body, body_zstd from updates_unpartitioned;
+
+drop table updates_unpartitioned;
+
+create index updates_created_idx on updates(created);
+create index updates_tag_created_idx on updates(tag, created);
@@ -0,0 +1,4 @@
+-- Tamper-evident chain of updates: hash = sha256(prev_hash, created, tag, version, canonical body).
+-- Existing updates are sealed by the service on start.
+alter table updates add column prev_hash bytea;
+alter table updates add column hash bytea;
@@ -0,0 +1,7 @@
+-- Current hedge of each channel, updated in the same transaction as the chain of updates
+create table channel_hedges(
+    channel_id text primary key,
+    sats bigint not null,
+    rate bigint not null,
+    updated timestamp not null
+);
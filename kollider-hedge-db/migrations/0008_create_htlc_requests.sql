@@ -0,0 +1,11 @@
+-- Raw requests to `/hedge/htlc` with their outcome, kept independently of the chain of updates
+create table htlc_requests(
+    id serial primary key,
+    created timestamp not null,
+    source_ip text,
+    body text not null,
+    accepted boolean not null,
+    error text
+);
+
+create index htlc_requests_created_idx on htlc_requests(created);
@@ -0,0 +1,15 @@
+-- Actions that are persisted before their messages are sent to Kollider, so messages that are
+-- not confirmed are sent again after restart
+create table outbox(
+    id serial primary key,
+    created timestamp not null,
+    -- `ext_order_id` of an order or `cancel:<order id>` of a cancel, confirmations are matched by it
+    key text not null,
+    action jsonb not null,
+    messages jsonb not null,
+    sent timestamp,
+    acked timestamp
+);
+
+create index outbox_key_idx on outbox(key);
+create index outbox_unacked_idx on outbox(created) where acked is null;
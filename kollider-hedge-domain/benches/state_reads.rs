@@ -0,0 +1,73 @@
+//! Throughput of `/stats`-like reads while the action executor is busy. Compares the lock held
+//! across execution of actions (previous design) with the lock released before execution.
+use criterion::{criterion_group, criterion_main, Criterion};
+use kollider_hedge_domain::api::Stats;
+use kollider_hedge_domain::state::State;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::sync::{Mutex, RwLock};
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+
+/// Time that the executor spends on sending a single action
+const EXECUTION_TIME: Duration = Duration::from_millis(1);
+/// Reads per iteration of the benchmark
+const READS: usize = 100;
+
+fn busy_mutex_executor(state: Arc<Mutex<State>>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            let mut state = state.lock().await;
+            state.scheduled_actions = vec![];
+            sleep(EXECUTION_TIME).await;
+        }
+    })
+}
+
+fn busy_rwlock_executor(state: Arc<RwLock<State>>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            {
+                let mut state = state.write().await;
+                state.scheduled_actions = vec![];
+            }
+            sleep(EXECUTION_TIME).await;
+        }
+    })
+}
+
+fn bench_state_reads(c: &mut Criterion) {
+    let runtime = Runtime::new().expect("Tokio runtime");
+    let mut group = c.benchmark_group("stats_reads_with_busy_executor");
+    group.sample_size(10);
+
+    let state = Arc::new(Mutex::new(State::default()));
+    let executor = runtime.block_on(async { busy_mutex_executor(state.clone()) });
+    group.bench_function("mutex", |b| {
+        b.to_async(&runtime).iter(|| async {
+            for _ in 0..READS {
+                let state = state.lock().await;
+                criterion::black_box(Stats::from_state(&state));
+            }
+        })
+    });
+    executor.abort();
+
+    let state = Arc::new(RwLock::new(State::default()));
+    let executor = runtime.block_on(async { busy_rwlock_executor(state.clone()) });
+    group.bench_function("rwlock", |b| {
+        b.to_async(&runtime).iter(|| async {
+            for _ in 0..READS {
+                let state = state.read().await;
+                criterion::black_box(Stats::from_state(&state));
+            }
+        })
+    });
+    executor.abort();
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_state_reads);
+criterion_main!(benches);
@@ -1,8 +1,17 @@
+use super::chain::*;
+use super::maintenance::*;
+use super::product::{ProductErr, ProductSpec};
+use super::state::*;
+use super::strategy::HedgeStrategy;
 use super::update::*;
+use chrono::prelude::*;
+use kollider_api::kollider::api::OrderSide;
 use rweb::Schema;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use thiserror::Error;
 
-#[derive(Serialize, Deserialize, Schema)]
+#[derive(Serialize, Deserialize, Schema, Debug, Clone)]
 pub struct HtlcInfo {
     pub channel_id: String,
     pub sats: i64,
@@ -17,9 +26,116 @@ impl HtlcInfo {
             rate: self.rate as i64,
         }
     }
+
+    /// Check that the HTLC info is sane before it is applied to the state
+    pub fn validate(&self, limits: &HtlcLimits) -> Result<(), HtlcValidationErr> {
+        if self.channel_id.trim().is_empty() {
+            return Err(HtlcValidationErr::EmptyChannelId);
+        }
+        if self.sats == 0 {
+            return Err(HtlcValidationErr::ZeroSats);
+        }
+        if self.sats.unsigned_abs() > limits.max_sats {
+            return Err(HtlcValidationErr::SatsTooLarge(self.sats, limits.max_sats));
+        }
+        if self.rate < limits.min_rate || self.rate > limits.max_rate {
+            return Err(HtlcValidationErr::RateOutOfBounds(
+                self.rate,
+                limits.min_rate,
+                limits.max_rate,
+            ));
+        }
+        Ok(())
+    }
 }
 
-#[derive(Serialize, Deserialize, Schema)]
+/// Absolute hedge of a channel that the node wants to have
+#[derive(Serialize, Deserialize, Schema, Debug, Clone, PartialEq)]
+pub struct HedgeTarget {
+    pub channel_id: String,
+    pub sats: u64,
+    /// Average rate of the channel in sats/USD
+    pub rate: u64,
+}
+
+impl HedgeTarget {
+    /// Check that the target is sane. Zero sats are allowed and empty the channel.
+    pub fn validate(&self, limits: &HtlcLimits) -> Result<(), HtlcValidationErr> {
+        if self.channel_id.trim().is_empty() {
+            return Err(HtlcValidationErr::EmptyChannelId);
+        }
+        if self.sats > limits.max_sats {
+            return Err(HtlcValidationErr::SatsTooLarge(
+                self.sats as i64,
+                limits.max_sats,
+            ));
+        }
+        if self.sats > 0 && (self.rate < limits.min_rate || self.rate > limits.max_rate) {
+            return Err(HtlcValidationErr::RateOutOfBounds(
+                self.rate,
+                limits.min_rate,
+                limits.max_rate,
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn hedge(&self) -> ChannelHedge {
+        ChannelHedge {
+            sats: self.sats as i64,
+            rate: self.rate as i64,
+        }
+    }
+}
+
+/// Bounds for sane values of incoming HTLCs
+#[derive(Debug, Clone, PartialEq)]
+pub struct HtlcLimits {
+    /// Maximum absolute amount of sats in a single HTLC
+    pub max_sats: u64,
+    /// Minimum rate in sats/USD
+    pub min_rate: u64,
+    /// Maximum rate in sats/USD
+    pub max_rate: u64,
+}
+
+impl Default for HtlcLimits {
+    fn default() -> Self {
+        HtlcLimits {
+            max_sats: 1_000_000_000,
+            min_rate: 10,
+            max_rate: 1_000_000,
+        }
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Clone)]
+pub enum HtlcValidationErr {
+    #[error("Channel ID is empty")]
+    EmptyChannelId,
+    #[error("HTLC with zero amount of sats")]
+    ZeroSats,
+    #[error("Amount of sats {0} is above the limit {1}")]
+    SatsTooLarge(i64, u64),
+    #[error("Rate {0} is outside of allowed bounds [{1}, {2}]")]
+    RateOutOfBounds(u64, u64, u64),
+}
+
+impl HtlcValidationErr {
+    /// API error message that points to the field that failed the validation
+    pub fn field_error(&self) -> &'static str {
+        match self {
+            HtlcValidationErr::EmptyChannelId => "FIELD_ERROR: channel_id",
+            HtlcValidationErr::ZeroSats => "FIELD_ERROR: sats",
+            HtlcValidationErr::SatsTooLarge(..) => "FIELD_ERROR: sats",
+            HtlcValidationErr::RateOutOfBounds(..) => "FIELD_ERROR: rate",
+        }
+    }
+}
+
+impl rweb::reject::Reject for HtlcValidationErr {}
+
+#[derive(Serialize, Deserialize, Schema, Clone)]
 pub struct Stats {
     pub channels_sats: u64,
     pub channels_usd: f64,
@@ -28,6 +144,63 @@ pub struct Stats {
     pub position_usd: u64,
 
     pub account_balance: f64,
+
+    /// Sats in channels that are not covered by the position. Negative means overhedge.
+    pub unhedged_sats: i64,
+    /// Unhedged sats valued at the current ticker
+    pub unhedged_usd: f64,
+    /// Price of BTC/USD reported by Kollider
+    pub ticker: Option<f64>,
+    /// Average weighted rate over all hedged channels in sats/USD
+    pub avg_hedge_rate: u64,
+    /// Sats requested by opened orders that increase the position
+    pub short_orders_sats: u64,
+    /// Sats requested by opened orders that decrease the position
+    pub long_orders_sats: u64,
+    /// Sats in orders that are sent to Kollider, but not confirmed yet and increase the position
+    pub opening_shorts_sats: u64,
+    /// Sats in orders that are sent to Kollider, but not confirmed yet and decrease the position
+    pub opening_longs_sats: u64,
+    /// Time of the last update of channels
+    pub last_changed: NaiveDateTime,
+    /// Channels require more hedge than the position limit allows
+    #[serde(default)]
+    pub over_limit: bool,
+    /// Kollider balance is not enough to hedge all channels
+    #[serde(default)]
+    pub margin_shortage: bool,
+    /// Limit of orders that increase the position after Kollider rejected an order for
+    /// insufficient margin
+    #[serde(default)]
+    pub margin_order_cap: Option<u64>,
+    /// Margin committed on Kollider and the balance left for new orders
+    #[serde(default)]
+    pub margin: MarginLedger,
+    /// PnL in sats of closed parts of positions
+    #[serde(default)]
+    pub realized_pnl: f64,
+    /// PnL in sats of the opened position at the current ticker
+    #[serde(default)]
+    pub unrealized_pnl: f64,
+    /// Fiat values in the hedge currency. The `_usd` fields hold the same values whatever
+    /// the currency is.
+    #[serde(default)]
+    pub hedge_currency: CurrencyStats,
+    /// Fiat values in the reporting currency, known when both indices are received
+    #[serde(default)]
+    pub reporting_currency: Option<CurrencyStats>,
+    /// Whether new orders are held by a maintenance window
+    #[serde(default)]
+    pub maintenance: MaintenanceStatus,
+    /// The last order was not placed as its price is too far from the index
+    #[serde(default)]
+    pub slippage_exceeded: bool,
+    /// Unhedged amount stays above the drift threshold longer than allowed
+    #[serde(default)]
+    pub drift_alarm: bool,
+    /// Moment when the unhedged amount exceeded the drift threshold
+    #[serde(default)]
+    pub drift_since: Option<NaiveDateTime>,
 }
 
 impl Stats {
@@ -38,12 +211,1570 @@ impl Stats {
             position_sats: 0,
             position_usd: 0,
             account_balance: 0.,
+            unhedged_sats: 0,
+            unhedged_usd: 0.,
+            ticker: None,
+            avg_hedge_rate: 0,
+            short_orders_sats: 0,
+            long_orders_sats: 0,
+            opening_shorts_sats: 0,
+            opening_longs_sats: 0,
+            last_changed: NaiveDateTime::from_timestamp(0, 0),
+            over_limit: false,
+            margin_shortage: false,
+            margin_order_cap: None,
+            margin: MarginLedger::default(),
+            realized_pnl: 0.,
+            unrealized_pnl: 0.,
+            hedge_currency: CurrencyStats::default(),
+            reporting_currency: None,
+            maintenance: MaintenanceStatus::default(),
+            slippage_exceeded: false,
+            drift_alarm: false,
+            drift_since: None,
+        }
+    }
+
+    /// Calculate statistics for the given state
+    pub fn from_state(state: &State) -> Stats {
+        let channels_sats = state.hedge_capacity();
+        let avg_price = state.hedge_avg_price().unwrap_or(0);
+        let position_sats = state.position_volume();
+        let unhedged_sats = channels_sats as i64 - position_sats as i64;
+        let cur_price = state.current_price().unwrap_or(avg_price);
+        let channels_usd = safe_divide(channels_sats as f64, avg_price as f64);
+        let unhedged_usd = safe_divide(unhedged_sats as f64, cur_price as f64);
+        let hedge_currency = CurrencyStats {
+            currency: state.config.hedge_currency(),
+            ticker: state.ticker,
+            channels: channels_usd,
+            position: state.position_quantity() as f64,
+            unhedged: unhedged_usd,
+            account_balance: state.balance.unwrap_or(0.) * state.ticker.unwrap_or(0.),
+        };
+        let reporting_currency = match (&state.config.reporting_pair, state.reporting_ticker()) {
+            (Some(pair), Some(ticker)) => hedge_currency.convert(pair_currency(pair), ticker),
+            _ => None,
+        };
+        Stats {
+            channels_sats,
+            channels_usd,
+            position_sats,
+            position_usd: state.position_quantity(),
+            account_balance: state.balance.unwrap_or(0.),
+            unhedged_sats,
+            unhedged_usd,
+            ticker: state.ticker,
+            avg_hedge_rate: avg_price,
+            short_orders_sats: state.short_orders().unwrap_or(0),
+            long_orders_sats: state.long_orders().unwrap_or(0),
+            opening_shorts_sats: state.opening_shorts(),
+            opening_longs_sats: state.opening_longs(),
+            last_changed: state.last_changed,
+            over_limit: state.over_limit,
+            margin_shortage: state.margin_shortage,
+            margin_order_cap: state.margin_order_cap,
+            margin: state.margin_ledger(),
+            realized_pnl: state.pnl.realized,
+            unrealized_pnl: state.unrealized_pnl().unwrap_or(0.),
+            hedge_currency,
+            reporting_currency,
+            maintenance: MaintenanceStatus::new(
+                &state.config.maintenance_windows,
+                Utc::now().naive_utc(),
+            ),
+            slippage_exceeded: state.slippage_exceeded,
+            drift_alarm: state.drift_alarm,
+            drift_since: state.drift_since,
         }
     }
 }
 
+/// Fiat values of the stats in a single currency
+#[derive(Serialize, Deserialize, Schema, Debug, Clone, PartialEq, Default)]
+pub struct CurrencyStats {
+    pub currency: String,
+    /// Price of BTC in the currency
+    pub ticker: Option<f64>,
+    /// Channels valued at the rates of hedged HTLCs
+    pub channels: f64,
+    pub position: f64,
+    /// Unhedged sats valued at the current ticker
+    pub unhedged: f64,
+    /// Kollider balance valued at the current ticker
+    pub account_balance: f64,
+}
+
+impl CurrencyStats {
+    /// Convert the values to another currency with the given price of BTC. Returns `None`
+    /// when price of BTC in the current currency is unknown.
+    pub fn convert(&self, currency: String, ticker: f64) -> Option<CurrencyStats> {
+        let rate = ticker / self.ticker.filter(|t| *t > 0.0)?;
+        Some(CurrencyStats {
+            currency,
+            ticker: Some(ticker),
+            channels: self.channels * rate,
+            position: self.position * rate,
+            unhedged: self.unhedged * rate,
+            account_balance: self.account_balance * rate,
+        })
+    }
+}
+
 impl Default for Stats {
     fn default() -> Stats {
         Stats::new()
     }
-}
\ No newline at end of file
+}
+
+/// Request of Lightning invoice that tops up Kollider margin
+#[derive(Serialize, Deserialize, Schema, Debug, Clone, PartialEq)]
+pub struct DepositRequest {
+    pub sats: u64,
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, Clone, PartialEq)]
+pub struct DepositInvoice {
+    pub sats: u64,
+    /// Lightning invoice to pay
+    pub invoice: String,
+}
+
+/// Request to withdraw excess margin from Kollider to the invoice of our node
+#[derive(Serialize, Deserialize, Schema, Debug, Clone, PartialEq)]
+pub struct WithdrawRequest {
+    pub invoice: String,
+    /// Amount to withdraw, all excess margin if not set
+    pub sats: Option<u64>,
+}
+
+/// Requested withdrawal is larger than margin above the buffer
+#[derive(Error, Debug, Clone, PartialEq)]
+#[error("Requested {requested} sats, but only {excess} sats are above the margin buffer")]
+pub struct WithdrawExceedsExcess {
+    pub requested: u64,
+    pub excess: u64,
+}
+
+impl rweb::reject::Reject for WithdrawExceedsExcess {}
+
+/// Build information of the service
+#[derive(Serialize, Deserialize, Schema, Debug, Clone, PartialEq)]
+pub struct VersionInfo {
+    /// Version of the crate
+    pub version: String,
+    /// Git commit the binary is built from
+    pub git_hash: String,
+    /// Cargo features enabled at build time
+    pub features: Vec<String>,
+}
+
+/// Hedge and fiat value of a channel
+#[derive(Serialize, Deserialize, Schema, Debug, Clone, PartialEq)]
+pub struct ChannelInfo {
+    pub sats: Sats,
+    pub rate: Sats,
+    /// Value at the weighted rate of the channel HTLCs
+    pub usd: f64,
+    /// Value at the current ticker
+    pub usd_at_ticker: Option<f64>,
+    /// False if the channel is tracked but not hedged
+    #[serde(default = "default_hedged")]
+    pub hedged: bool,
+}
+
+fn default_hedged() -> bool {
+    true
+}
+
+impl ChannelInfo {
+    /// Collect information about all channels of the state
+    pub fn from_state(state: &State) -> HashMap<ChannelId, ChannelInfo> {
+        state
+            .channels_hedge
+            .iter()
+            .map(|(id, h)| {
+                let value = ChannelUsd::new(h, state.ticker);
+                let info = ChannelInfo {
+                    sats: h.sats,
+                    rate: h.rate,
+                    usd: value.usd,
+                    usd_at_ticker: value.usd_at_ticker,
+                    hedged: state.config.is_hedged(id),
+                };
+                (id.clone(), info)
+            })
+            .collect()
+    }
+}
+
+/// Request to void or replace an erroneous HTLC update
+#[derive(Serialize, Deserialize, Schema, Debug, Clone, PartialEq)]
+pub struct CorrectionRequest {
+    pub channel_id: String,
+    /// Moment of the erroneous update as it is returned by `/history`
+    pub created: NaiveDateTime,
+    /// Correct amount of the HTLC. The update is voided if not set.
+    pub sats: Option<i64>,
+    /// Correct rate of the HTLC, required with `sats`
+    pub rate: Option<u64>,
+    pub reason: String,
+    /// Admin who requests the correction
+    pub author: String,
+}
+
+#[derive(Error, Debug, PartialEq, Clone)]
+pub enum CorrectionErr {
+    #[error("Reason and author of the correction are required")]
+    NotSigned,
+    #[error("Rate is required for the replacement HTLC")]
+    MissingRate,
+    #[error("HTLC update of {0} at {1} is not found")]
+    NotFound(String, NaiveDateTime),
+    #[error("HTLC update of {0} at {1} is already corrected")]
+    AlreadyCorrected(String, NaiveDateTime),
+}
+
+impl CorrectionErr {
+    /// API error message for the rejection
+    pub fn message(&self) -> &'static str {
+        match self {
+            CorrectionErr::NotSigned => "FIELD_ERROR: reason",
+            CorrectionErr::MissingRate => "FIELD_ERROR: rate",
+            CorrectionErr::NotFound(..) => "UPDATE_NOT_FOUND",
+            CorrectionErr::AlreadyCorrected(..) => "ALREADY_CORRECTED",
+        }
+    }
+}
+
+impl rweb::reject::Reject for CorrectionErr {}
+
+impl CorrectionRequest {
+    /// Replacement HTLC in the form of API request to reuse validation
+    pub fn replacement(&self) -> Result<Option<HtlcInfo>, CorrectionErr> {
+        match (self.sats, self.rate) {
+            (None, _) => Ok(None),
+            (Some(_), None) => Err(CorrectionErr::MissingRate),
+            (Some(sats), Some(rate)) => Ok(Some(HtlcInfo {
+                channel_id: self.channel_id.clone(),
+                sats,
+                rate,
+            })),
+        }
+    }
+
+    /// Find the erroneous update in the full history and build the compensating update
+    pub fn into_correction(self, history: &[StateUpdate]) -> Result<Correction, CorrectionErr> {
+        if self.reason.trim().is_empty() || self.author.trim().is_empty() {
+            return Err(CorrectionErr::NotSigned);
+        }
+        let replacement = self.replacement()?.map(|h| h.into_update());
+        let original = history
+            .iter()
+            .find_map(|u| match &u.body {
+                UpdateBody::Htlc(htlc)
+                    if u.created == self.created && htlc.channel_id == self.channel_id =>
+                {
+                    Some(htlc.clone())
+                }
+                _ => None,
+            })
+            .ok_or_else(|| CorrectionErr::NotFound(self.channel_id.clone(), self.created))?;
+        let corrected = history.iter().any(|u| match &u.body {
+            UpdateBody::Correction(c) => {
+                c.corrected == self.created && c.original.channel_id == self.channel_id
+            }
+            _ => false,
+        });
+        if corrected {
+            return Err(CorrectionErr::AlreadyCorrected(
+                self.channel_id,
+                self.created,
+            ));
+        }
+        Ok(Correction {
+            corrected: self.created,
+            original,
+            replacement,
+            reason: self.reason,
+            author: self.author,
+        })
+    }
+}
+
+/// Update from the chain that changed a single channel
+#[derive(Serialize, Deserialize, Schema, Debug, Clone, PartialEq)]
+pub struct ChannelHistoryEntry {
+    pub created: NaiveDateTime,
+    /// Tag of the update
+    pub tag: String,
+    /// Amount and rate of the HTLC for `htlc` updates
+    pub htlc: Option<HtlcUpdate>,
+    /// Hedge of the channel after the update. `None` if the channel is removed.
+    pub hedge: Option<ChannelHedge>,
+    /// Fiat value of the hedge after the update
+    pub usd: f64,
+}
+
+impl ChannelHistoryEntry {
+    /// Replay full history of updates and collect the ones that touch the channel
+    pub fn from_updates(
+        channel_id: &str,
+        updates: Vec<StateUpdate>,
+    ) -> Result<Vec<ChannelHistoryEntry>, StateUpdateErr> {
+        let mut state = State::default();
+        let mut res = vec![];
+        for update in updates {
+            let created = update.created;
+            let tag = update.body.tag().to_string();
+            let (related, htlc) = match &update.body {
+                UpdateBody::Htlc(htlc) => (htlc.channel_id == channel_id, Some(htlc.clone())),
+                UpdateBody::Snapshot(snapshot) => (
+                    snapshot.channels_hedge.get(channel_id) != state.channels_hedge.get(channel_id),
+                    None,
+                ),
+                UpdateBody::ChannelsRemoved(removal) => {
+                    (removal.channel_ids.iter().any(|id| id == channel_id), None)
+                }
+                UpdateBody::Correction(correction) => (
+                    correction.original.channel_id == channel_id,
+                    correction.replacement.clone(),
+                ),
+                _ => (false, None),
+            };
+            state.apply_update(update)?;
+            if related {
+                let hedge = state.channels_hedge.get(channel_id).cloned();
+                let usd = hedge.as_ref().map_or(0., |h| ChannelUsd::new(h, None).usd);
+                res.push(ChannelHistoryEntry {
+                    created,
+                    tag,
+                    htlc,
+                    hedge,
+                    usd,
+                });
+            }
+        }
+        Ok(res)
+    }
+}
+
+/// Statistics recorded at the given moment of time
+#[derive(Serialize, Deserialize, Schema)]
+pub struct StatsRecord {
+    pub created: NaiveDateTime,
+    pub stats: Stats,
+}
+
+/// Filter for the `/stats/history` endpoint
+#[derive(Serialize, Deserialize, Schema, Debug, Clone, Default)]
+pub struct StatsHistoryQuery {
+    /// Return records that are created after the moment
+    pub from: Option<NaiveDateTime>,
+    /// Return records that are created before the moment
+    pub to: Option<NaiveDateTime>,
+    /// Minimal distance in seconds between returned records
+    pub step: Option<u64>,
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum PreviewErr {
+    #[error("HTLC cannot be applied: {0}")]
+    Update(#[from] StateUpdateErr),
+    #[error("Orders cannot be planned: {0}")]
+    Plan(#[from] NextActionError),
+}
+
+impl rweb::reject::Reject for PreviewErr {}
+
+/// Outcome of an HTLC that is not recorded, returned by `/hedge/htlc/preview`
+#[derive(Serialize, Deserialize, Schema, Debug, Clone, PartialEq)]
+pub struct HtlcPreview {
+    /// Hedge of channels after the HTLC
+    pub channels_hedge: HashMap<ChannelId, ChannelHedge>,
+    /// Actions that the planner would schedule
+    pub actions: Vec<StateAction>,
+    /// Sats of margin that the scheduled orders increasing the position require
+    pub required_margin: u64,
+}
+
+impl HtlcPreview {
+    /// Apply the HTLC to a copy of the state and plan orders for it
+    pub fn new(state: &State, htlc: HtlcUpdate) -> Result<Self, PreviewErr> {
+        let mut preview = state.clone();
+        preview.scheduled_actions.clear();
+        preview.apply_update(StateUpdate {
+            created: Utc::now().naive_utc(),
+            body: UpdateBody::Htlc(htlc),
+        })?;
+        preview.calculate_next_actions()?;
+        let required_margin = preview
+            .scheduled_actions
+            .iter()
+            .filter_map(|a| match a {
+                StateAction::OpenOrder(o) if o.is_short_order() => Some(o.required_margin()),
+                _ => None,
+            })
+            .sum();
+        Ok(HtlcPreview {
+            channels_hedge: preview.channels_hedge,
+            actions: preview.scheduled_actions,
+            required_margin,
+        })
+    }
+}
+
+/// Time `/state/wait` holds the request if the timeout is not set
+pub const DEFAULT_WAIT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+/// Longest timeout of `/state/wait`
+pub const MAX_WAIT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(300);
+
+/// Parameters of the `/state/wait` endpoint
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WaitQuery {
+    /// Revision of the state the client has seen
+    pub since_rev: u64,
+    /// How long to wait for a change, e.x. 30s or 2m
+    pub timeout: Option<String>,
+}
+
+#[derive(Error, Debug, PartialEq, Clone)]
+pub enum WaitErr {
+    #[error("Invalid timeout {0}, expected e.x. 30s or 2m up to 5m")]
+    Timeout(String),
+}
+
+impl rweb::reject::Reject for WaitErr {}
+
+impl WaitQuery {
+    pub fn timeout(&self) -> Result<std::time::Duration, WaitErr> {
+        let s = match &self.timeout {
+            Some(s) => s.trim(),
+            None => return Ok(DEFAULT_WAIT_TIMEOUT),
+        };
+        let (value, multiplier) = match s.chars().last() {
+            Some('s') => (&s[..s.len() - 1], 1),
+            Some('m') => (&s[..s.len() - 1], 60),
+            _ => (s, 1),
+        };
+        let secs = value
+            .parse::<u64>()
+            .map_err(|_| WaitErr::Timeout(s.to_owned()))?
+            .saturating_mul(multiplier);
+        let timeout = std::time::Duration::from_secs(secs);
+        if timeout > MAX_WAIT_TIMEOUT {
+            return Err(WaitErr::Timeout(s.to_owned()));
+        }
+        Ok(timeout)
+    }
+}
+
+/// Revision of the state returned by `/state/wait`
+#[derive(Serialize, Deserialize, Schema, Debug, Clone, PartialEq)]
+pub struct StateRevision {
+    pub revision: u64,
+}
+
+/// Parameters of `/state` that limit the response to the listed top-level fields
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StateFieldsQuery {
+    /// Comma separated fields of the state, e.x. `channels_hedge,ticker,opened_position`
+    pub fields: String,
+}
+
+#[derive(Error, Debug, PartialEq, Clone)]
+pub enum StateFieldsErr {
+    #[error("Unknown field '{0}' of the state")]
+    Unknown(String),
+    #[error("Failed to serialize the state: {0}")]
+    Serialize(String),
+}
+
+impl rweb::reject::Reject for StateFieldsErr {}
+
+impl StateFieldsQuery {
+    /// Serialize only the requested fields of the state
+    pub fn select(
+        &self,
+        state: &State,
+    ) -> Result<serde_json::Map<String, serde_json::Value>, StateFieldsErr> {
+        let mut full = match serde_json::to_value(state) {
+            Ok(serde_json::Value::Object(full)) => full,
+            Ok(_) => return Err(StateFieldsErr::Serialize("not an object".to_owned())),
+            Err(e) => return Err(StateFieldsErr::Serialize(e.to_string())),
+        };
+        let mut res = serde_json::Map::new();
+        for field in self
+            .fields
+            .split(',')
+            .map(str::trim)
+            .filter(|f| !f.is_empty())
+        {
+            let value = full
+                .remove(field)
+                .ok_or_else(|| StateFieldsErr::Unknown(field.to_owned()))?;
+            res.insert(field.to_owned(), value);
+        }
+        Ok(res)
+    }
+}
+
+/// Current index price reported by Kollider, returned by `/price`
+#[derive(Serialize, Deserialize, Schema, Debug, Clone, PartialEq)]
+pub struct PriceInfo {
+    /// Price of BTC in USD
+    pub price: f64,
+    /// Exchange rate in sats/USD
+    pub rate: u64,
+}
+
+impl PriceInfo {
+    /// Returns `None` until the first ticker is received
+    pub fn new(state: &State) -> Option<Self> {
+        Some(PriceInfo {
+            price: state.ticker?,
+            rate: state.current_price()?,
+        })
+    }
+}
+
+/// Parameters of the `/simulate` endpoint
+#[derive(Serialize, Deserialize, Schema, Debug, Clone)]
+pub struct SimulateQuery {
+    /// Price of BTC in USD
+    pub price: f64,
+}
+
+#[derive(Error, Debug, PartialEq, Clone)]
+pub enum SimulationErr {
+    #[error("Price {0} must be positive")]
+    InvalidPrice(f64),
+}
+
+impl rweb::reject::Reject for SimulationErr {}
+
+/// Figures of the current state at a hypothetical price, returned by `/simulate`
+#[derive(Serialize, Deserialize, Schema, Clone)]
+pub struct Simulation {
+    /// Price of BTC in USD the figures are calculated at
+    pub price: f64,
+    /// Statistics as if the price was the current ticker
+    pub stats: Stats,
+    /// Sats locked as margin of the position
+    pub position_margin: u64,
+    /// Share of the position margin that the unrealized loss takes at the price, 1 means
+    /// liquidation
+    pub margin_usage: f64,
+    /// Price of BTC in USD at which the position is liquidated
+    pub liquidation_price: Option<f64>,
+    /// Distance from the price to the liquidation in percents of the price
+    pub liquidation_distance: Option<f64>,
+}
+
+impl Simulation {
+    pub fn new(state: &State, price: f64) -> Result<Self, SimulationErr> {
+        if price.is_nan() || price <= 0.0 {
+            return Err(SimulationErr::InvalidPrice(price));
+        }
+        let mut simulated = state.clone();
+        simulated.ticker_ema = None;
+        simulated.update_ticker(price, Utc::now().naive_utc());
+        let stats = Stats::from_state(&simulated);
+        let position_margin = simulated.position_margin();
+        let loss = -stats.unrealized_pnl.min(0.0);
+        let liquidation_price = simulated.liquidation_price();
+        Ok(Simulation {
+            price,
+            position_margin,
+            margin_usage: safe_divide(loss, position_margin as f64),
+            liquidation_price,
+            liquidation_distance: liquidation_price.map(|liq| 100.0 * (liq - price) / price),
+            stats,
+        })
+    }
+}
+
+/// Parameters of the `/estimate` endpoint
+#[derive(Serialize, Deserialize, Schema, Debug, Clone)]
+pub struct EstimateQuery {
+    /// Size of the order that increases the hedge
+    pub sats: u64,
+}
+
+#[derive(Error, Debug, PartialEq, Clone)]
+pub enum EstimateErr {
+    #[error("Amount of sats must be positive")]
+    ZeroSats,
+    #[error("Kollider index is unknown, the order cannot be priced")]
+    NoTicker,
+    #[error("{0}")]
+    Product(#[from] ProductErr),
+}
+
+impl rweb::reject::Reject for EstimateErr {}
+
+/// What an order that increases the hedge would require at the current price, leverage and
+/// spread, returned by `/estimate`
+#[derive(Serialize, Deserialize, Schema, Debug, Clone, PartialEq)]
+pub struct OrderEstimate {
+    pub sats: u64,
+    /// Price of the order in sats/USD
+    pub price: u64,
+    /// Price as it is sent to Kollider, see `ProductSpec::wire_price`
+    pub wire_price: u64,
+    /// Amount of contracts
+    pub quantity: u64,
+    /// Leverage * 100 of the order
+    pub leverage: u64,
+    /// Sats of margin the order commits
+    pub margin: u64,
+    /// Balance that new orders can commit, `None` until Kollider balance is received
+    pub available_margin: Option<u64>,
+}
+
+impl OrderEstimate {
+    pub fn new(state: &State, sats: u64) -> Result<Self, EstimateErr> {
+        if sats == 0 {
+            return Err(EstimateErr::ZeroSats);
+        }
+        let base_price = state.order_base_price().ok_or(EstimateErr::NoTicker)?;
+        let price =
+            state
+                .config
+                .strategy
+                .strategy()
+                .order_price(&state.config, base_price, OrderSide::Bid);
+        let order = OpeningOrder {
+            ext_id: String::new(),
+            symbol: state.config.hedge_sym.clone(),
+            sats,
+            price,
+            side: OrderSide::Bid,
+            leverage: state.order_leverage(),
+        };
+        let spec = ProductSpec::find(&state.products, &order.symbol);
+        Ok(OrderEstimate {
+            sats,
+            price,
+            wire_price: spec.wire_price(price)?,
+            quantity: spec.quantity(sats, price)?,
+            leverage: order.leverage,
+            margin: order.required_margin(),
+            available_margin: state.margin_ledger().available,
+        })
+    }
+}
+
+/// Request to `/hedge/htlc` as it was received by the service
+#[derive(Serialize, Deserialize, Schema, Debug, Clone, PartialEq)]
+pub struct HtlcRequestRecord {
+    pub id: i32,
+    pub created: NaiveDateTime,
+    /// Address of the caller, if known
+    pub source_ip: Option<String>,
+    /// Raw body of the request
+    pub body: String,
+    /// The HTLC was recorded to the chain of updates
+    pub accepted: bool,
+    /// Reason of the rejection
+    pub error: Option<String>,
+}
+
+/// Default amount of records returned by `/requests`
+pub const DEFAULT_REQUESTS_LIMIT: u64 = 100;
+
+/// Filter for the `/requests` endpoint
+#[derive(Serialize, Deserialize, Schema, Debug, Clone, Default)]
+pub struct RequestsQuery {
+    /// Return records that are created after the moment
+    pub from: Option<NaiveDateTime>,
+    /// Return records that are created before the moment
+    pub to: Option<NaiveDateTime>,
+    /// Maximum amount of the latest records to return, 100 by default
+    pub limit: Option<u64>,
+}
+
+/// Figures of the state at the boundary of a reporting period
+#[derive(Serialize, Deserialize, Schema, Debug, Clone, PartialEq)]
+pub struct ReportSnapshot {
+    /// Sats in channels that require hedging
+    pub hedge_sats: u64,
+    pub position_sats: u64,
+    /// Balance on Kollider in sats
+    pub balance_sats: i64,
+    /// Realized PnL in sats accumulated by the moment
+    pub realized_pnl: f64,
+}
+
+impl ReportSnapshot {
+    pub fn from_state(state: &State) -> Self {
+        ReportSnapshot {
+            hedge_sats: state.hedge_capacity(),
+            position_sats: state.position_volume(),
+            balance_sats: (state.balance.unwrap_or(0.) * 100_000_000.0).round() as i64,
+            realized_pnl: state.pnl.realized,
+        }
+    }
+}
+
+/// End of day accounting figures
+#[derive(Serialize, Deserialize, Schema, Debug, Clone, PartialEq)]
+pub struct DailyReport {
+    pub from: NaiveDateTime,
+    pub to: NaiveDateTime,
+    pub opening: ReportSnapshot,
+    pub closing: ReportSnapshot,
+    pub orders_placed: u64,
+    pub fills: u64,
+    pub filled_contracts: u64,
+    pub deposits_sats: u64,
+    pub withdrawals_sats: u64,
+    /// Fees and funding paid over the period. Kollider doesn't report them separately, so the
+    /// value is the change of balance that is not explained by PnL, deposits and withdrawals.
+    pub fees_funding_sats: i64,
+    /// PnL in sats realized over the period
+    pub realized_pnl: f64,
+    /// PnL in sats of the opened position at the end of the period
+    pub unrealized_pnl: f64,
+}
+
+impl DailyReport {
+    pub fn new(
+        opening: ReportSnapshot,
+        closing: ReportSnapshot,
+        counters: DailyCounters,
+        unrealized_pnl: f64,
+        to: NaiveDateTime,
+    ) -> Self {
+        let realized_pnl = closing.realized_pnl - opening.realized_pnl;
+        let fees_funding_sats =
+            opening.balance_sats + realized_pnl.round() as i64 + counters.deposits_sats as i64
+                - counters.withdrawals_sats as i64
+                - closing.balance_sats;
+        DailyReport {
+            from: counters.since,
+            to,
+            opening,
+            closing,
+            orders_placed: counters.orders_placed,
+            fills: counters.fills,
+            filled_contracts: counters.filled_contracts,
+            deposits_sats: counters.deposits_sats,
+            withdrawals_sats: counters.withdrawals_sats,
+            fees_funding_sats,
+            realized_pnl,
+            unrealized_pnl,
+        }
+    }
+}
+
+/// Filter for the `/reports/daily` endpoint
+#[derive(Serialize, Deserialize, Schema, Debug, Clone, Default)]
+pub struct DailyReportQuery {
+    /// Return reports that are closed after the moment
+    pub from: Option<NaiveDateTime>,
+    /// Return reports that are closed before the moment
+    pub to: Option<NaiveDateTime>,
+}
+
+/// Filter for the `/history` endpoint
+#[derive(Serialize, Deserialize, Schema, Debug, Clone, Default)]
+pub struct HistoryQuery {
+    /// Return updates created after the moment
+    pub from: Option<NaiveDateTime>,
+    /// Return updates created before the moment
+    pub to: Option<NaiveDateTime>,
+}
+
+/// Filter for the `/admin/dump` endpoint
+#[derive(Serialize, Deserialize, Schema, Debug, Clone, Default)]
+pub struct DumpQuery {
+    /// Export updates created after the moment (inclusive)
+    pub since: Option<NaiveDateTime>,
+}
+
+/// Single line of the update log dump. The body is kept in the version it was stored with, so
+/// dumps of older instances can be restored by newer ones.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DumpRecord {
+    pub created: NaiveDateTime,
+    pub tag: String,
+    pub version: u16,
+    pub body: serde_json::Value,
+    /// Hex encoded hash of the previous update in the chain
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prev_hash: Option<String>,
+    /// Hex encoded hash of the update, see `chain::update_hash`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+impl DumpRecord {
+    /// Decode the body to check that the record can be replayed
+    pub fn decode(&self) -> Result<UpdateBody, UpdateBodyError> {
+        UpdateTag::from_tag(&self.tag, self.version, self.body.clone())
+    }
+}
+
+/// Render records as JSONL, one record per line
+pub fn render_dump(records: &[DumpRecord]) -> String {
+    let mut out = String::new();
+    for record in records {
+        out.push_str(&serde_json::to_string(record).expect("Dump record is serializable"));
+        out.push('\n');
+    }
+    out
+}
+
+/// Parse JSONL dump. Empty lines are skipped, records must be ordered by creation time.
+pub fn parse_dump(text: &str) -> Result<Vec<DumpRecord>, RestoreErr> {
+    let mut records: Vec<DumpRecord> = vec![];
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: DumpRecord =
+            serde_json::from_str(line).map_err(|e| RestoreErr::Malformed(i + 1, e.to_string()))?;
+        record
+            .decode()
+            .map_err(|e| RestoreErr::Malformed(i + 1, e.to_string()))?;
+        if let Some(prev) = records.last() {
+            if record.created < prev.created {
+                return Err(RestoreErr::Unordered(i + 1));
+            }
+        }
+        records.push(record);
+    }
+    if records.iter().any(|r| r.hash.is_some()) {
+        verify_chain(&records)?;
+    }
+    Ok(records)
+}
+
+#[derive(Error, Debug, PartialEq, Clone)]
+pub enum RestoreErr {
+    #[error("Line {0} of the dump is malformed: {1}")]
+    Malformed(usize, String),
+    #[error("Line {0} of the dump is older than the previous one")]
+    Unordered(usize),
+    #[error("Dump starts at {0} that is not after the latest stored update {1}")]
+    Overlap(NaiveDateTime, NaiveDateTime),
+    #[error("Hash chain of the dump is invalid: {0}")]
+    Chain(#[from] ChainErr),
+}
+
+impl rweb::reject::Reject for RestoreErr {}
+
+/// Result of the `/admin/restore` endpoint
+#[derive(Serialize, Deserialize, Schema, Debug, Clone, PartialEq)]
+pub struct RestoreSummary {
+    /// Amount of inserted updates
+    pub restored: u64,
+    /// Creation time of the last inserted update
+    pub last: Option<NaiveDateTime>,
+}
+
+/// Request of the `/admin/migrate-symbol` endpoint
+#[derive(Serialize, Deserialize, Schema, Debug, Clone, PartialEq)]
+pub struct SymbolMigrationRequest {
+    /// Symbol of the contract to hedge with, e.x. `BTCUSD.PERP`
+    pub symbol: String,
+    /// Index pair of the new contract, the current one is kept if not set
+    pub pair: Option<String>,
+}
+
+#[derive(Error, Debug, PartialEq, Clone)]
+pub enum SymbolMigrationErr {
+    #[error("Hedge is already on {0}")]
+    Same(String),
+    #[error("Migration from {0} to {1} is in progress")]
+    InProgress(String, String),
+}
+
+impl rweb::reject::Reject for SymbolMigrationErr {}
+
+impl SymbolMigrationRequest {
+    pub fn validate(&self, state: &State) -> Result<(), SymbolMigrationErr> {
+        if let Some(migration) = &state.symbol_migration {
+            return Err(SymbolMigrationErr::InProgress(
+                migration.from.clone(),
+                migration.to.clone(),
+            ));
+        }
+        if self.symbol == state.config.hedge_sym {
+            return Err(SymbolMigrationErr::Same(self.symbol.clone()));
+        }
+        Ok(())
+    }
+
+    /// Config change that starts the migration when it is applied to the state
+    pub fn config_change(self) -> ConfigChange {
+        ConfigChange {
+            hedge_sym: Some(self.symbol),
+            hedge_pair: self.pair,
+            ..ConfigChange::default()
+        }
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Clone)]
+pub enum ConfigChangeErr {
+    #[error("Symbol {0} can be changed only by symbol migration")]
+    SymbolChange(String),
+    #[error("{}", .0.iter().map(|e| e.to_string()).collect::<Vec<_>>().join("; "))]
+    Invalid(Vec<ConfigErr>),
+}
+
+impl rweb::reject::Reject for ConfigChangeErr {}
+
+/// Check a runtime change of the config and return the config after the change. The symbol
+/// is changed only by `/admin/migrate-symbol` that is restricted to admins.
+pub fn check_config_change(
+    state: &State,
+    change: &ConfigChange,
+) -> Result<HedgeConfig, ConfigChangeErr> {
+    if let Some(symbol) = &change.hedge_sym {
+        if *symbol != state.config.hedge_sym {
+            return Err(ConfigChangeErr::SymbolChange(
+                state.config.hedge_sym.clone(),
+            ));
+        }
+    }
+    let mut config = state.config.clone();
+    config.apply_change(change);
+    let mut errs = config.validate(&state.products);
+    // Zero overrides are dropped by the config, but a request with them is a mistake
+    if let Some(overrides) = &change.channel_leverage {
+        errs.extend(
+            overrides
+                .iter()
+                .filter(|(_, leverage)| **leverage == 0)
+                .map(|(id, _)| ConfigErr::ZeroLeverage(format!("channel {}", id))),
+        );
+    }
+    if errs.is_empty() {
+        Ok(config)
+    } else {
+        Err(ConfigChangeErr::Invalid(errs))
+    }
+}
+
+/// Request of the `/admin/rollback` endpoint
+#[derive(Serialize, Deserialize, Schema, Debug, Clone, PartialEq)]
+pub struct RollbackRequest {
+    /// Hedge of channels is reset to the state at the moment
+    pub to: NaiveDateTime,
+}
+
+#[derive(Error, Debug, PartialEq, Clone)]
+pub enum RollbackErr {
+    #[error("Moment of rollback {0} is in the future")]
+    Future(NaiveDateTime),
+}
+
+impl rweb::reject::Reject for RollbackErr {}
+
+impl RollbackRequest {
+    pub fn validate(&self, now: NaiveDateTime) -> Result<(), RollbackErr> {
+        if self.to > now {
+            return Err(RollbackErr::Future(self.to));
+        }
+        Ok(())
+    }
+
+    /// Replay updates created until the moment. Later updates are skipped, so the history can
+    /// be loaded and folded before the state is locked.
+    pub fn fold_history(&self, history: Vec<StateUpdate>) -> Result<State, StateUpdateErr> {
+        let updates = history.into_iter().filter(|u| u.created <= self.to);
+        State::collect(HedgeConfig::default(), updates)
+    }
+
+    /// Take hedge of channels of the replayed past state as the new head snapshot. Config and
+    /// other replayed state are taken from the current state, as the snapshot becomes the start
+    /// of replay. Exchange data is not included, so it stays as is after the rollback.
+    pub fn into_snapshot(self, past: State, current: &State) -> StateSnapshot {
+        StateSnapshot {
+            channels_hedge: past.channels_hedge,
+            opened_orders: None,
+            opened_position: None,
+            balance: None,
+            ticker: None,
+            ..current.snapshot()
+        }
+    }
+}
+
+fn safe_divide(numerator: f64, denominator: f64) -> f64 {
+    if denominator == 0.0 {
+        0.
+    } else {
+        numerator / denominator
+    }
+}
+
+/// State of the websocket connection to Kollider
+#[derive(Serialize, Deserialize, Schema, Debug, Clone, PartialEq, Default)]
+pub struct WsStatus {
+    /// We receive messages from the current connection
+    pub connected: bool,
+    pub authenticated: bool,
+    /// Moment of the first message in the current connection
+    pub connected_since: Option<NaiveDateTime>,
+    /// Channels that we subscribed to
+    pub subscriptions: Vec<String>,
+    /// Moment of the last received message by channel
+    pub last_message: HashMap<String, NaiveDateTime>,
+    /// Amount of times the connection was restarted
+    pub reconnects: u64,
+    /// Moment since we are not authenticated, `None` while authenticated
+    pub unauthenticated_since: Option<NaiveDateTime>,
+}
+
+impl WsStatus {
+    pub fn received(&mut self, channel: &str) {
+        let now = Utc::now().naive_utc();
+        self.connected = true;
+        self.connected_since.get_or_insert(now);
+        self.last_message.insert(channel.to_owned(), now);
+    }
+
+    pub fn subscribed(&mut self, channels: Vec<String>) {
+        self.authenticated = true;
+        self.unauthenticated_since = None;
+        self.subscriptions = channels;
+    }
+
+    /// Connection is closed and will be restarted. Timestamps of last messages are kept.
+    pub fn disconnected(&mut self) {
+        self.connected = false;
+        self.authenticated = false;
+        self.connected_since = None;
+        self.subscriptions = vec![];
+        self.reconnects += 1;
+        self.unauthenticated_since
+            .get_or_insert_with(|| Utc::now().naive_utc());
+    }
+}
+
+/// Liveness and readiness of the service
+#[derive(Serialize, Deserialize, Schema, Debug, Clone, PartialEq)]
+pub struct Health {
+    /// The service responds and the database is reachable
+    pub live: bool,
+    /// The service is authenticated on Kollider and knows everything to hedge
+    pub ready: bool,
+    pub database: bool,
+    pub authenticated: bool,
+    /// Ticker, opened orders and position are received from Kollider
+    pub exchange_state: bool,
+    /// Error that pauses placement of orders
+    pub planning_error: Option<String>,
+    /// Milliseconds that the clock of Kollider is ahead of the local one
+    #[serde(default)]
+    pub clock_skew_ms: Option<i64>,
+    /// Clock skew is above the configured bound and orders are held
+    #[serde(default)]
+    pub clock_skew_exceeded: bool,
+}
+
+impl Health {
+    pub fn new(database: bool, authenticated: bool, state: &State) -> Self {
+        let exchange_state = state.ticker.is_some()
+            && state.opened_orders.is_some()
+            && state.opened_position.is_some();
+        Health {
+            live: database,
+            ready: database
+                && authenticated
+                && exchange_state
+                && state.planning_error.is_none()
+                && !state.clock_skew_exceeded,
+            database,
+            authenticated,
+            exchange_state,
+            planning_error: state.planning_error.clone(),
+            clock_skew_ms: state.clock_skew_ms,
+            clock_skew_exceeded: state.clock_skew_exceeded,
+        }
+    }
+}
+
+/// Response of the `/ready` probe
+#[derive(Serialize, Deserialize, Schema, Debug, Clone, PartialEq)]
+pub struct Readiness {
+    /// The service can receive traffic
+    pub ready: bool,
+    pub database: bool,
+    pub authenticated: bool,
+    /// State is reconstructed from the chain of updates
+    pub state_loaded: bool,
+}
+
+impl Readiness {
+    pub fn new(database: bool, authenticated: bool, state_loaded: bool) -> Self {
+        Readiness {
+            ready: database && authenticated && state_loaded,
+            database,
+            authenticated,
+            state_loaded,
+        }
+    }
+}
+
+/// Message from Kollider websocket kept for debugging
+#[derive(Serialize, Deserialize, Schema, Debug, Clone, PartialEq)]
+pub struct RecordedMessage {
+    pub received: NaiveDateTime,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, Clone, PartialEq)]
+pub struct Metrics {
+    /// Amount of connections currently opened to the database
+    pub db_pool_size: u32,
+    /// Amount of opened connections that are not used at the moment
+    pub db_pool_idle: u32,
+    /// Queue of messages received from Kollider
+    #[serde(default)]
+    pub ws_inbound: QueueStats,
+    /// Queue of messages to send to Kollider
+    #[serde(default)]
+    pub ws_outbound: QueueStats,
+    /// Durations of database queries, API requests and execution of actions by operation
+    #[serde(default)]
+    pub latency: HashMap<String, LatencyHistogram>,
+    /// Sats that the position with open orders lacks to the required hedge at the last minute
+    /// check, negative when it is above the hedge
+    #[serde(default)]
+    pub position_drift_sats: Option<i64>,
+    /// Consecutive checks that found the position drift outside of the allowed gap
+    #[serde(default)]
+    pub position_drift_checks: u32,
+}
+
+/// Histogram of durations of an operation since the start of the service
+#[derive(Serialize, Deserialize, Schema, Debug, Clone, PartialEq, Default)]
+pub struct LatencyHistogram {
+    /// Amount of observed operations
+    pub count: u64,
+    /// Total duration of the operations in milliseconds
+    pub sum_ms: f64,
+    /// Cumulative buckets as in Prometheus, operations above the last bound are only in `count`
+    pub buckets: Vec<LatencyBucket>,
+}
+
+#[derive(Serialize, Deserialize, Schema, Debug, Clone, PartialEq)]
+pub struct LatencyBucket {
+    /// Upper bound of the bucket in milliseconds
+    pub le_ms: u64,
+    /// Operations that took less or equal to the bound
+    pub count: u64,
+}
+
+/// Counters of a bounded message queue
+#[derive(Serialize, Deserialize, Schema, Debug, Clone, PartialEq, Default)]
+pub struct QueueStats {
+    pub capacity: usize,
+    /// Messages waiting in the queue
+    pub len: usize,
+    /// The longest the queue was since the start of the service
+    pub max_len: usize,
+    /// Messages rejected because the queue was full
+    pub dropped: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_htlc_validation() {
+        let limits = HtlcLimits::default();
+        let htlc = HtlcInfo {
+            channel_id: "aboba".to_owned(),
+            sats: 20000,
+            rate: 2500,
+        };
+        assert_eq!(htlc.validate(&limits), Ok(()));
+
+        let htlc = HtlcInfo {
+            channel_id: " ".to_owned(),
+            sats: 20000,
+            rate: 2500,
+        };
+        assert_eq!(
+            htlc.validate(&limits),
+            Err(HtlcValidationErr::EmptyChannelId)
+        );
+
+        let htlc = HtlcInfo {
+            channel_id: "aboba".to_owned(),
+            sats: -2_000_000_000,
+            rate: 2500,
+        };
+        assert_eq!(
+            htlc.validate(&limits),
+            Err(HtlcValidationErr::SatsTooLarge(
+                -2_000_000_000,
+                1_000_000_000
+            ))
+        );
+
+        let htlc = HtlcInfo {
+            channel_id: "aboba".to_owned(),
+            sats: 20000,
+            rate: 0,
+        };
+        assert_eq!(
+            htlc.validate(&limits),
+            Err(HtlcValidationErr::RateOutOfBounds(0, 10, 1_000_000))
+        );
+    }
+
+    #[test]
+    fn test_channel_history() {
+        let created = NaiveDateTime::from_timestamp(0, 0);
+        let htlc = |channel_id: &str, sats, rate| StateUpdate {
+            created,
+            body: UpdateBody::Htlc(HtlcUpdate {
+                channel_id: channel_id.to_owned(),
+                sats,
+                rate,
+            }),
+        };
+        let updates = vec![
+            htlc("aboba", 1000, 2500),
+            htlc("other", 5000, 2500),
+            htlc("aboba", -1000, 3000),
+            StateUpdate {
+                created,
+                body: UpdateBody::ChannelsRemoved(ChannelsRemoval {
+                    channel_ids: vec!["aboba".to_owned()],
+                }),
+            },
+        ];
+        let history = ChannelHistoryEntry::from_updates("aboba", updates).unwrap();
+        assert_eq!(history.len(), 3);
+        assert_eq!(history[0].hedge.as_ref().map(|h| h.sats), Some(1000));
+        assert_eq!(history[0].usd, 0.4);
+        assert_eq!(history[1].hedge.as_ref().map(|h| h.sats), Some(0));
+        assert_eq!(history[2].tag, "channels_removed");
+        assert_eq!(history[2].hedge, None);
+    }
+
+    #[test]
+    fn test_correction() {
+        let created = NaiveDateTime::from_timestamp(10, 0);
+        let htlc = HtlcUpdate {
+            channel_id: "aboba".to_owned(),
+            sats: 1000,
+            rate: 2500,
+        };
+        let mut history = vec![
+            StateUpdate {
+                created: NaiveDateTime::from_timestamp(0, 0),
+                body: UpdateBody::Htlc(HtlcUpdate {
+                    sats: 2000,
+                    ..htlc.clone()
+                }),
+            },
+            StateUpdate {
+                created,
+                body: UpdateBody::Htlc(htlc.clone()),
+            },
+        ];
+        let request = CorrectionRequest {
+            channel_id: "aboba".to_owned(),
+            created,
+            sats: Some(100),
+            rate: Some(2500),
+            reason: "Wrong amount".to_owned(),
+            author: "admin".to_owned(),
+        };
+        let correction = request.clone().into_correction(&history).unwrap();
+        assert_eq!(correction.original, htlc);
+
+        history.push(StateUpdate {
+            created: NaiveDateTime::from_timestamp(20, 0),
+            body: UpdateBody::Correction(correction),
+        });
+        let state = State::collect(HedgeConfig::default(), history.clone()).unwrap();
+        assert_eq!(state.channels_hedge["aboba"].sats, 2100);
+        assert_eq!(state.channels_hedge["aboba"].rate, 2500);
+        assert_eq!(
+            request.into_correction(&history),
+            Err(CorrectionErr::AlreadyCorrected("aboba".to_owned(), created))
+        );
+    }
+
+    #[test]
+    fn test_currency_stats() {
+        let mut state = State {
+            config: HedgeConfig {
+                reporting_pair: Some(".BTCEUR".to_owned()),
+                ..HedgeConfig::default()
+            },
+            ticker: Some(40000.),
+            ..State::default()
+        };
+        state.channels_hedge.insert(
+            "aboba".to_owned(),
+            ChannelHedge {
+                sats: 100_000,
+                rate: 2500,
+            },
+        );
+        let stats = Stats::from_state(&state);
+        assert_eq!(stats.hedge_currency.currency, "USD");
+        assert_eq!(stats.hedge_currency.channels, 40.);
+        assert!(stats.reporting_currency.is_none());
+
+        state.index_tickers.insert(".BTCEUR".to_owned(), 36000.);
+        let stats = Stats::from_state(&state);
+        let eur = stats.reporting_currency.unwrap();
+        assert_eq!(eur.currency, "EUR");
+        assert_eq!(eur.channels, 36.);
+    }
+
+    #[test]
+    fn test_htlc_preview() {
+        let state = State {
+            opened_orders: Some(vec![]),
+            ticker: Some(40000.),
+            ..State::default()
+        };
+        let htlc = HtlcInfo {
+            channel_id: "aboba".to_owned(),
+            sats: 100_000,
+            rate: 2500,
+        };
+        let preview = HtlcPreview::new(&state, htlc.into_update()).unwrap();
+        assert_eq!(preview.channels_hedge["aboba"].sats, 100_000);
+        match &preview.actions[..] {
+            [StateAction::OpenOrder(order)] => assert_eq!(order.sats, 100_000),
+            actions => panic!("Unexpected actions: {:?}", actions),
+        }
+        assert_eq!(preview.required_margin, 100_000);
+        // Nothing is changed in the original state
+        assert!(state.channels_hedge.is_empty());
+    }
+
+    #[test]
+    fn test_wait_timeout() {
+        let query = |timeout: Option<&str>| WaitQuery {
+            since_rev: 0,
+            timeout: timeout.map(str::to_owned),
+        };
+        assert_eq!(query(None).timeout(), Ok(DEFAULT_WAIT_TIMEOUT));
+        assert_eq!(
+            query(Some("45s")).timeout(),
+            Ok(std::time::Duration::from_secs(45))
+        );
+        assert_eq!(
+            query(Some("2m")).timeout(),
+            Ok(std::time::Duration::from_secs(120))
+        );
+        assert!(query(Some("10m")).timeout().is_err());
+        assert!(query(Some("soon")).timeout().is_err());
+    }
+
+    #[test]
+    fn test_simulation() {
+        let state = State {
+            opened_position: Some(KolliderPosition::new(200, 100_000, 40000, 40, 0.0)),
+            ticker: Some(40000.),
+            ..State::default()
+        };
+        let sim = Simulation::new(&state, 60000.).unwrap();
+        assert_eq!(sim.position_margin, 50_000);
+        assert_eq!(sim.liquidation_price, Some(80000.));
+        assert!((sim.margin_usage - 2.0 / 3.0).abs() < 1e-6);
+        assert!((sim.liquidation_distance.unwrap() - 100.0 / 3.0).abs() < 1e-6);
+        assert_eq!(state.ticker, Some(40000.));
+        assert!(Simulation::new(&state, 0.).is_err());
+    }
+
+    #[test]
+    fn test_daily_report() {
+        let opening = ReportSnapshot {
+            hedge_sats: 100_000,
+            position_sats: 100_000,
+            balance_sats: 50_000,
+            realized_pnl: 100.0,
+        };
+        let closing = ReportSnapshot {
+            balance_sats: 69_700,
+            realized_pnl: 300.0,
+            ..opening.clone()
+        };
+        let mut counters = DailyCounters::new(NaiveDateTime::from_timestamp(0, 0));
+        counters.deposits_sats = 30_000;
+        counters.withdrawals_sats = 10_000;
+        let to = NaiveDateTime::from_timestamp(86400, 0);
+        let report = DailyReport::new(opening, closing, counters, -50.0, to);
+        assert_eq!(report.realized_pnl, 200.0);
+        assert_eq!(report.fees_funding_sats, 500);
+    }
+
+    #[test]
+    fn test_state_fields() {
+        let state = State {
+            ticker: Some(40000.0),
+            ..State::default()
+        };
+        let query = |fields: &str| StateFieldsQuery {
+            fields: fields.to_owned(),
+        };
+        let selected = query("ticker, opened_position").select(&state).unwrap();
+        assert_eq!(selected.len(), 2);
+        assert_eq!(selected["ticker"], serde_json::json!(40000.0));
+        assert_eq!(selected["opened_position"], serde_json::Value::Null);
+        assert_eq!(
+            query("ticker,aboba").select(&state),
+            Err(StateFieldsErr::Unknown("aboba".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_order_estimate() {
+        let mut state = State::default();
+        assert_eq!(
+            OrderEstimate::new(&state, 100_000),
+            Err(EstimateErr::NoTicker)
+        );
+        state.update_ticker(50000.0, Utc::now().naive_utc());
+        assert_eq!(OrderEstimate::new(&state, 0), Err(EstimateErr::ZeroSats));
+
+        let estimate = OrderEstimate::new(&state, 100_000).unwrap();
+        // Spread 0.1% over 2000 sats/USD
+        assert_eq!(estimate.price, 2002);
+        assert_eq!(estimate.quantity, 50);
+        assert_eq!(estimate.leverage, 100);
+        assert_eq!(estimate.margin, 100_000);
+        assert_eq!(estimate.available_margin, None);
+    }
+
+    #[test]
+    fn test_rollback() {
+        let at = |secs| NaiveDateTime::from_timestamp(secs, 0);
+        let htlc = |sats| {
+            UpdateBody::Htlc(HtlcUpdate {
+                channel_id: "aboba".to_owned(),
+                sats,
+                rate: 2500,
+            })
+        };
+        let history = vec![
+            StateUpdate {
+                created: at(1),
+                body: htlc(1000),
+            },
+            StateUpdate {
+                created: at(2),
+                body: htlc(500),
+            },
+            StateUpdate {
+                created: at(3),
+                body: htlc(-1500),
+            },
+            StateUpdate {
+                created: at(3),
+                body: UpdateBody::ConfigChange(ConfigChange {
+                    hedge_leverage: Some(200),
+                    ..ConfigChange::default()
+                }),
+            },
+        ];
+        let current = State::collect(HedgeConfig::default(), history.clone()).unwrap();
+        let request = RollbackRequest { to: at(2) };
+        assert!(request.validate(at(3)).is_ok());
+        assert_eq!(request.validate(at(1)), Err(RollbackErr::Future(at(2))));
+        let past = request.fold_history(history).unwrap();
+        let snapshot = request.into_snapshot(past, &current);
+        assert_eq!(snapshot.channels_hedge["aboba"].sats, 1500);
+        assert_eq!(snapshot.opened_position, None);
+
+        // Replay starts from the snapshot, the config changed after the moment is kept
+        let replayed = State::collect(
+            HedgeConfig::default(),
+            vec![StateUpdate {
+                created: at(4),
+                body: UpdateBody::Snapshot(snapshot),
+            }],
+        )
+        .unwrap();
+        assert_eq!(replayed.channels_hedge["aboba"].sats, 1500);
+        assert_eq!(replayed.config.hedge_leverage, 200);
+        assert_eq!(replayed.config_changed, Some(at(3)));
+    }
+
+    #[test]
+    fn test_config_change_check() {
+        let state = State::default();
+        let change = ConfigChange {
+            hedge_leverage: Some(200),
+            hedge_sym: Some(state.config.hedge_sym.clone()),
+            ..ConfigChange::default()
+        };
+        assert_eq!(
+            check_config_change(&state, &change).map(|c| c.hedge_leverage),
+            Ok(200)
+        );
+
+        let change = ConfigChange {
+            hedge_sym: Some("BTCUSD.PERP2".to_owned()),
+            ..ConfigChange::default()
+        };
+        assert_eq!(
+            check_config_change(&state, &change),
+            Err(ConfigChangeErr::SymbolChange(
+                state.config.hedge_sym.clone()
+            ))
+        );
+
+        let change = ConfigChange {
+            hedge_leverage: Some(0),
+            channel_leverage: Some(HashMap::from([("aboba".to_owned(), 0)])),
+            ..ConfigChange::default()
+        };
+        assert_eq!(
+            check_config_change(&state, &change),
+            Err(ConfigChangeErr::Invalid(vec![
+                ConfigErr::ZeroLeverage("hedge".to_owned()),
+                ConfigErr::ZeroLeverage("channel aboba".to_owned()),
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_dump_roundtrip() {
+        let body = UpdateBody::Htlc(
+            HtlcInfo {
+                channel_id: "aboba".to_owned(),
+                sats: 20000,
+                rate: 2500,
+            }
+            .into_update(),
+        );
+        let record = |secs| DumpRecord {
+            created: NaiveDateTime::from_timestamp(secs, 0),
+            tag: body.tag().to_string(),
+            version: CURRENT_BODY_VERSION,
+            body: body.json().unwrap(),
+            prev_hash: None,
+            hash: None,
+        };
+        let records = vec![record(1), record(2)];
+        let text = render_dump(&records);
+        assert_eq!(text.lines().count(), 2);
+        assert_eq!(parse_dump(&format!("{}\n", text)), Ok(records));
+        assert_eq!(
+            parse_dump(&render_dump(&[record(2), record(1)])),
+            Err(RestoreErr::Unordered(2))
+        );
+        assert!(matches!(
+            parse_dump("{\"created\": 1}"),
+            Err(RestoreErr::Malformed(1, _))
+        ));
+    }
+}
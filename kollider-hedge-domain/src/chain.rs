@@ -0,0 +1,190 @@
+use super::api::DumpRecord;
+use chrono::prelude::*;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+/// Previous hash of the first update in the chain
+pub const GENESIS_HASH: [u8; 32] = [0; 32];
+
+/// Hash of an update over the previous hash, creation time, tag, body version and
+/// canonicalized JSON body. Each field is terminated by a newline.
+pub fn update_hash(
+    prev_hash: &[u8],
+    created: NaiveDateTime,
+    tag: &str,
+    version: u16,
+    body: &Value,
+) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(prev_hash);
+    hasher.update(b"\n");
+    hasher.update(created.format("%Y-%m-%dT%H:%M:%S%.6f").to_string());
+    hasher.update(b"\n");
+    hasher.update(tag);
+    hasher.update(b"\n");
+    hasher.update(version.to_string());
+    hasher.update(b"\n");
+    let mut canonical = String::new();
+    write_canonical(body, &mut canonical);
+    hasher.update(canonical);
+    hasher.finalize().to_vec()
+}
+
+/// Drop nanoseconds that the database doesn't store, so the hash is computed over the stored
+/// creation time.
+pub fn truncate_micros(t: NaiveDateTime) -> NaiveDateTime {
+    t.with_nanosecond(t.nanosecond() / 1000 * 1000)
+        .expect("Truncated nanoseconds are valid")
+}
+
+/// Compact JSON with keys of objects sorted, independent from the order of keys in storage
+fn write_canonical(value: &Value, out: &mut String) {
+    match value {
+        Value::Object(map) => {
+            let mut keys: Vec<&String> = map.keys().collect();
+            keys.sort();
+            out.push('{');
+            for (i, key) in keys.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&Value::String(key.clone()).to_string());
+                out.push(':');
+                write_canonical(&map[key], out);
+            }
+            out.push('}');
+        }
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical(item, out);
+            }
+            out.push(']');
+        }
+        scalar => out.push_str(&scalar.to_string()),
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Clone)]
+pub enum ChainErr {
+    #[error("Record {0} has no hash")]
+    Unsealed(usize),
+    #[error("Record {0} has malformed hash")]
+    Malformed(usize),
+    #[error("Record {0} doesn't link to the previous record, the log was rewritten")]
+    Broken(usize),
+    #[error("Hash of record {0} doesn't match its content, the record was changed")]
+    Mismatch(usize),
+}
+
+/// Result of successful verification of the chain
+#[derive(Debug, PartialEq, Clone)]
+pub struct ChainSummary {
+    /// Amount of verified records
+    pub records: usize,
+    /// Previous hash of the first record, equals to `GENESIS_HASH` if the log is not pruned
+    pub anchor: Option<String>,
+    /// Hash of the last record
+    pub head: Option<String>,
+}
+
+/// Check that each record links to the previous one and its hash matches the content. Records
+/// are numbered from 1 in errors.
+pub fn verify_chain(records: &[DumpRecord]) -> Result<ChainSummary, ChainErr> {
+    let mut prev: Option<Vec<u8>> = None;
+    let mut anchor = None;
+    for (i, record) in records.iter().enumerate() {
+        let n = i + 1;
+        let (prev_hash, hash) = match (&record.prev_hash, &record.hash) {
+            (Some(prev_hash), Some(hash)) => (
+                hex::decode(prev_hash).map_err(|_| ChainErr::Malformed(n))?,
+                hex::decode(hash).map_err(|_| ChainErr::Malformed(n))?,
+            ),
+            _ => return Err(ChainErr::Unsealed(n)),
+        };
+        match &prev {
+            Some(prev) if *prev != prev_hash => return Err(ChainErr::Broken(n)),
+            Some(_) => (),
+            None => anchor = record.prev_hash.clone(),
+        }
+        let expected = update_hash(
+            &prev_hash,
+            record.created,
+            &record.tag,
+            record.version,
+            &record.body,
+        );
+        if expected != hash {
+            return Err(ChainErr::Mismatch(n));
+        }
+        prev = Some(hash);
+    }
+    Ok(ChainSummary {
+        records: records.len(),
+        anchor,
+        head: records.last().and_then(|r| r.hash.clone()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn seal(records: &mut [DumpRecord]) {
+        let mut prev = GENESIS_HASH.to_vec();
+        for record in records.iter_mut() {
+            let hash = update_hash(
+                &prev,
+                record.created,
+                &record.tag,
+                record.version,
+                &record.body,
+            );
+            record.prev_hash = Some(hex::encode(&prev));
+            record.hash = Some(hex::encode(&hash));
+            prev = hash;
+        }
+    }
+
+    #[test]
+    fn test_canonical_body() {
+        let mut out = String::new();
+        write_canonical(
+            &json!({"b": [1, {"d": null, "c": "x"}], "a": 2.5}),
+            &mut out,
+        );
+        assert_eq!(out, r#"{"a":2.5,"b":[1,{"c":"x","d":null}]}"#);
+    }
+
+    #[test]
+    fn test_verify_chain() {
+        let record = |secs, sats| DumpRecord {
+            created: NaiveDateTime::from_timestamp(secs, 0),
+            tag: "htlc".to_owned(),
+            version: 0,
+            body: json!({"channel_id": "aboba", "sats": sats, "rate": 2500}),
+            prev_hash: None,
+            hash: None,
+        };
+        let mut records = vec![record(1, 100), record(2, 200), record(3, 300)];
+        assert_eq!(verify_chain(&records), Err(ChainErr::Unsealed(1)));
+        seal(&mut records);
+        let summary = verify_chain(&records).unwrap();
+        assert_eq!(summary.records, 3);
+        assert_eq!(summary.anchor, Some(hex::encode(GENESIS_HASH)));
+        assert_eq!(verify_chain(&records[1..]).unwrap().records, 2);
+
+        let mut changed = records.clone();
+        changed[1].body = json!({"channel_id": "aboba", "sats": 100500, "rate": 2500});
+        assert_eq!(verify_chain(&changed), Err(ChainErr::Mismatch(2)));
+
+        let mut removed = records.clone();
+        removed.remove(1);
+        assert_eq!(verify_chain(&removed), Err(ChainErr::Broken(2)));
+    }
+}
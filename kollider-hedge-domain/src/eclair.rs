@@ -0,0 +1,178 @@
+use super::api::HtlcInfo;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Eclair channel ids mapped to ids of fiat channels that are hedged
+pub type ChannelMap = HashMap<String, String>;
+
+/// Mapping of a single Eclair channel given as `<eclair channel id>=<fiat channel id>`
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ChannelMapping {
+    pub eclair: String,
+    pub hedge: String,
+}
+
+impl FromStr for ChannelMapping {
+    type Err = EclairErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((eclair, hedge)) if !eclair.trim().is_empty() && !hedge.trim().is_empty() => {
+                Ok(ChannelMapping {
+                    eclair: eclair.trim().to_owned(),
+                    hedge: hedge.trim().to_owned(),
+                })
+            }
+            _ => Err(EclairErr::Mapping(s.to_owned())),
+        }
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Clone)]
+pub enum EclairErr {
+    #[error("Channel mapping '{0}' must be <eclair channel id>=<fiat channel id>")]
+    Mapping(String),
+    #[error("Malformed Eclair event: {0}")]
+    Malformed(String),
+    #[error("Kollider index is unknown, Eclair event cannot be priced")]
+    NoTicker,
+}
+
+impl rweb::reject::Reject for EclairErr {}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ReceivedPart {
+    pub amount: i64,
+    pub from_channel_id: String,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SentPart {
+    pub amount: i64,
+    #[serde(default)]
+    pub fees_paid: i64,
+    pub to_channel_id: String,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct RelayedPart {
+    pub amount: i64,
+    pub channel_id: String,
+}
+
+/// Payment event of Eclair websocket or webhook. Amounts are in msat.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum EclairEvent {
+    #[serde(rename_all = "camelCase")]
+    PaymentRelayed {
+        amount_in: i64,
+        amount_out: i64,
+        from_channel_id: String,
+        to_channel_id: String,
+    },
+    TrampolinePaymentRelayed {
+        incoming: Vec<RelayedPart>,
+        outgoing: Vec<RelayedPart>,
+    },
+    PaymentReceived {
+        parts: Vec<ReceivedPart>,
+    },
+    PaymentSent {
+        parts: Vec<SentPart>,
+    },
+    /// Events that don't move funds through channels
+    #[serde(other)]
+    Other,
+}
+
+impl EclairEvent {
+    /// Msat moved through channels, positive for amounts that go into the channel
+    fn channel_flows(&self) -> Vec<(&str, i64)> {
+        match self {
+            EclairEvent::PaymentRelayed {
+                amount_in,
+                amount_out,
+                from_channel_id,
+                to_channel_id,
+            } => vec![
+                (from_channel_id.as_str(), -amount_in),
+                (to_channel_id.as_str(), *amount_out),
+            ],
+            EclairEvent::TrampolinePaymentRelayed { incoming, outgoing } => incoming
+                .iter()
+                .map(|p| (p.channel_id.as_str(), -p.amount))
+                .chain(outgoing.iter().map(|p| (p.channel_id.as_str(), p.amount)))
+                .collect(),
+            EclairEvent::PaymentReceived { parts } => parts
+                .iter()
+                .map(|p| (p.from_channel_id.as_str(), -p.amount))
+                .collect(),
+            EclairEvent::PaymentSent { parts } => parts
+                .iter()
+                .map(|p| (p.to_channel_id.as_str(), p.amount + p.fees_paid))
+                .collect(),
+            EclairEvent::Other => vec![],
+        }
+    }
+
+    /// Convert the event to HTLCs of mapped fiat channels priced at the rate in sats/USD.
+    /// Amounts that go into a fiat channel are positive, out of the channel are negative.
+    pub fn htlcs(&self, channels: &ChannelMap, rate: u64) -> Vec<HtlcInfo> {
+        self.channel_flows()
+            .into_iter()
+            .filter_map(|(chan_id, msat)| {
+                let channel_id = channels.get(chan_id)?.clone();
+                let sats = (msat + msat.signum() * 500) / 1000;
+                if sats == 0 {
+                    return None;
+                }
+                Some(HtlcInfo {
+                    channel_id,
+                    sats,
+                    rate,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eclair_events() {
+        let channels: ChannelMap = vec!["mapping=fiat".parse::<ChannelMapping>().unwrap()]
+            .into_iter()
+            .map(|m| (m.eclair, m.hedge))
+            .collect();
+        assert!("fiat".parse::<ChannelMapping>().is_err());
+
+        let relayed: EclairEvent = serde_json::from_str(
+            r#"{"type":"payment-relayed","amountIn":1001000,"amountOut":1000000,"paymentHash":"00","fromChannelId":"other","toChannelId":"mapping","timestamp":1553784963659}"#,
+        )
+        .unwrap();
+        let htlcs = relayed.htlcs(&channels, 2500);
+        assert_eq!(htlcs.len(), 1);
+        assert_eq!(htlcs[0].channel_id, "fiat");
+        assert_eq!(htlcs[0].sats, 1000);
+
+        let received: EclairEvent = serde_json::from_str(
+            r#"{"type":"payment-received","paymentHash":"00","parts":[{"amount":500400,"fromChannelId":"mapping","timestamp":1553784963659}]}"#,
+        )
+        .unwrap();
+        let htlcs = received.htlcs(&channels, 2500);
+        assert_eq!(htlcs[0].sats, -500);
+
+        let other: EclairEvent =
+            serde_json::from_str(r#"{"type":"channel-opened","remoteNodeId":"00"}"#).unwrap();
+        assert_eq!(other, EclairEvent::Other);
+        assert!(other.htlcs(&channels, 2500).is_empty());
+    }
+}
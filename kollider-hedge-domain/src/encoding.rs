@@ -0,0 +1,112 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use thiserror::Error;
+
+/// Encoding of large API responses (`/state`, `/history`) negotiated by `Accept` header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Json,
+    Cbor,
+    MsgPack,
+}
+
+impl Default for Encoding {
+    fn default() -> Self {
+        Encoding::Json
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum EncodingErr {
+    #[error("JSON encoding/decoding error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("CBOR encoding/decoding error: {0}")]
+    Cbor(String),
+    #[error("MessagePack encoding/decoding error: {0}")]
+    MsgPack(String),
+}
+
+impl rweb::reject::Reject for EncodingErr {}
+
+impl Encoding {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Encoding::Json => "application/json",
+            Encoding::Cbor => "application/cbor",
+            Encoding::MsgPack => "application/msgpack",
+        }
+    }
+
+    /// The first supported media type of `Accept` header, parameters and quality are ignored
+    pub fn from_accept(accept: &str) -> Option<Self> {
+        accept
+            .split(',')
+            .filter_map(|media| media.split(';').next())
+            .find_map(|media| match media.trim() {
+                "application/json" | "application/*" | "*/*" => Some(Encoding::Json),
+                "application/cbor" => Some(Encoding::Cbor),
+                "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                    Some(Encoding::MsgPack)
+                }
+                _ => None,
+            })
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, EncodingErr> {
+        match self {
+            Encoding::Json => Ok(serde_json::to_vec(value)?),
+            Encoding::Cbor => {
+                let mut buf = vec![];
+                ciborium::ser::into_writer(value, &mut buf)
+                    .map_err(|e| EncodingErr::Cbor(e.to_string()))?;
+                Ok(buf)
+            }
+            // Structs are encoded as maps as internally tagged enums can't be decoded from arrays
+            Encoding::MsgPack => {
+                rmp_serde::to_vec_named(value).map_err(|e| EncodingErr::MsgPack(e.to_string()))
+            }
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, EncodingErr> {
+        match self {
+            Encoding::Json => Ok(serde_json::from_slice(bytes)?),
+            Encoding::Cbor => {
+                ciborium::de::from_reader(bytes).map_err(|e| EncodingErr::Cbor(e.to_string()))
+            }
+            Encoding::MsgPack => {
+                rmp_serde::from_slice(bytes).map_err(|e| EncodingErr::MsgPack(e.to_string()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::State;
+
+    #[test]
+    fn test_accept_negotiation() {
+        assert_eq!(
+            Encoding::from_accept("application/cbor"),
+            Some(Encoding::Cbor)
+        );
+        assert_eq!(
+            Encoding::from_accept("text/html, application/x-msgpack;q=0.9, */*;q=0.1"),
+            Some(Encoding::MsgPack)
+        );
+        assert_eq!(Encoding::from_accept("*/*"), Some(Encoding::Json));
+        assert_eq!(Encoding::from_accept("text/html"), None);
+    }
+
+    #[test]
+    fn test_state_roundtrip() {
+        let state = State::default();
+        for encoding in [Encoding::Json, Encoding::Cbor, Encoding::MsgPack] {
+            let bytes = encoding.encode(&state).unwrap();
+            let decoded: State = encoding.decode(&bytes).unwrap();
+            assert_eq!(decoded, state);
+        }
+    }
+}
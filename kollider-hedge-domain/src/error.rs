@@ -0,0 +1,200 @@
+use crate::api::{CorrectionErr, EstimateErr, HtlcValidationErr, PreviewErr, RestoreErr};
+use crate::eclair::EclairErr;
+use crate::state::{NextActionError, StateUpdateErr};
+use crate::update::HtlcUpdateErr;
+use rweb::Schema;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Machine-readable reason of a failed API request. Clients choose retry behavior by it:
+/// `is_transient` errors can be retried as is, others require a change of the request.
+#[derive(Serialize, Deserialize, Schema, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    NotFound,
+    MethodNotAllowed,
+    BadRequest,
+    /// A field of the request is invalid, the field is named in `message`
+    FieldError,
+    ReadOnlyMode,
+    IpNotAllowed,
+    Unauthorized,
+    /// Wallet requests without configured Kollider REST client
+    ExchangeDisabled,
+    /// Kollider rejected or failed the request
+    ExchangeError,
+    /// Price of Kollider index is not received yet
+    NoTicker,
+    WithdrawExceedsExcess,
+    UpdateNotFound,
+    AlreadyCorrected,
+    DumpOverlap,
+    DumpChainBroken,
+    MalformedDump,
+    MalformedEvent,
+    /// HTLC takes more sats from the channel than it has
+    InsufficientSatsBalance,
+    /// HTLC takes more fiat from the channel than it has
+    InsufficientFiatBalance,
+    /// Average rate of the channel doesn't fit in 64 bits after the HTLC
+    RateOverflow,
+    /// Target hedge can't be reached with a single HTLC
+    UnreachableTarget,
+    /// Orders can't be calculated for the state
+    PlanningError,
+    /// Order doesn't fit the limits of the product
+    ProductLimits,
+    EncodingError,
+    DatabaseError,
+    Unhandled,
+}
+
+impl ErrorCode {
+    /// The same request may succeed later without changes
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            ErrorCode::ExchangeError
+                | ErrorCode::NoTicker
+                | ErrorCode::DatabaseError
+                | ErrorCode::Unhandled
+        )
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Serialized name of the unit variant is a plain JSON string
+        let name = serde_json::to_string(self).map_err(|_| fmt::Error)?;
+        write!(f, "{}", name.trim_matches('"'))
+    }
+}
+
+/// Body of all error responses of the API
+#[derive(Serialize, Deserialize, Schema, Debug, Clone, PartialEq)]
+pub struct ErrorMessage {
+    /// HTTP status code
+    pub code: u16,
+    /// Short error string, e.g. `FIELD_ERROR: sats`. Kept for older clients, prefer `error_code`.
+    pub message: String,
+    pub error_code: ErrorCode,
+    /// Human readable description of the failure
+    pub details: Option<String>,
+}
+
+impl fmt::Display for ErrorMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let details = self.details.as_deref().unwrap_or(&self.message);
+        write!(f, "{} {}: {}", self.code, self.error_code, details)
+    }
+}
+
+impl From<&HtlcUpdateErr> for ErrorCode {
+    fn from(err: &HtlcUpdateErr) -> Self {
+        match err {
+            HtlcUpdateErr::InsufficientSatsBalance(..) => ErrorCode::InsufficientSatsBalance,
+            HtlcUpdateErr::InsufficientFiatBalance(..) => ErrorCode::InsufficientFiatBalance,
+            HtlcUpdateErr::RateOverflow(..) => ErrorCode::RateOverflow,
+            HtlcUpdateErr::UnreachableTarget(..) => ErrorCode::UnreachableTarget,
+        }
+    }
+}
+
+impl From<&StateUpdateErr> for ErrorCode {
+    fn from(err: &StateUpdateErr) -> Self {
+        match err {
+            StateUpdateErr::Htlc(e) => e.into(),
+        }
+    }
+}
+
+impl From<&NextActionError> for ErrorCode {
+    fn from(err: &NextActionError) -> Self {
+        match err {
+            NextActionError::TotalHedge(e) => e.into(),
+            _ => ErrorCode::PlanningError,
+        }
+    }
+}
+
+impl From<&PreviewErr> for ErrorCode {
+    fn from(err: &PreviewErr) -> Self {
+        match err {
+            PreviewErr::Update(e) => e.into(),
+            PreviewErr::Plan(e) => e.into(),
+        }
+    }
+}
+
+impl From<&HtlcValidationErr> for ErrorCode {
+    fn from(_: &HtlcValidationErr) -> Self {
+        ErrorCode::FieldError
+    }
+}
+
+impl From<&CorrectionErr> for ErrorCode {
+    fn from(err: &CorrectionErr) -> Self {
+        match err {
+            CorrectionErr::NotSigned | CorrectionErr::MissingRate => ErrorCode::FieldError,
+            CorrectionErr::NotFound(..) => ErrorCode::UpdateNotFound,
+            CorrectionErr::AlreadyCorrected(..) => ErrorCode::AlreadyCorrected,
+        }
+    }
+}
+
+impl From<&RestoreErr> for ErrorCode {
+    fn from(err: &RestoreErr) -> Self {
+        match err {
+            RestoreErr::Overlap(..) => ErrorCode::DumpOverlap,
+            RestoreErr::Chain(..) => ErrorCode::DumpChainBroken,
+            _ => ErrorCode::MalformedDump,
+        }
+    }
+}
+
+impl From<&EclairErr> for ErrorCode {
+    fn from(err: &EclairErr) -> Self {
+        match err {
+            EclairErr::NoTicker => ErrorCode::NoTicker,
+            _ => ErrorCode::MalformedEvent,
+        }
+    }
+}
+
+impl From<&EstimateErr> for ErrorCode {
+    fn from(err: &EstimateErr) -> Self {
+        match err {
+            EstimateErr::ZeroSats => ErrorCode::FieldError,
+            EstimateErr::NoTicker => ErrorCode::NoTicker,
+            EstimateErr::Product(..) => ErrorCode::ProductLimits,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_codes() {
+        let err = StateUpdateErr::Htlc(HtlcUpdateErr::InsufficientSatsBalance(10, -20, -10));
+        let code = ErrorCode::from(&err);
+        assert_eq!(code, ErrorCode::InsufficientSatsBalance);
+        assert_eq!(code.to_string(), "INSUFFICIENT_SATS_BALANCE");
+        assert!(!code.is_transient());
+        assert!(ErrorCode::from(&EstimateErr::NoTicker).is_transient());
+
+        let message = ErrorMessage {
+            code: 400,
+            message: "STATE_UPDATE_ERROR".to_owned(),
+            error_code: code,
+            details: Some(err.to_string()),
+        };
+        let json = serde_json::to_value(&message).unwrap();
+        assert_eq!(json["error_code"], "INSUFFICIENT_SATS_BALANCE");
+        assert_eq!(
+            serde_json::from_value::<ErrorMessage>(json).unwrap(),
+            message
+        );
+    }
+}
@@ -1,3 +1,12 @@
 pub mod api;
+pub mod chain;
+pub mod eclair;
+pub mod encoding;
+pub mod error;
+pub mod maintenance;
+pub mod order;
+pub mod product;
+pub mod replay;
 pub mod state;
+pub mod strategy;
 pub mod update;
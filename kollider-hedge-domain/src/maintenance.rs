@@ -0,0 +1,227 @@
+use chrono::prelude::*;
+use chrono::Duration;
+use rweb::Schema;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Windows are searched no further ahead, enough to find a window on 29 February
+const SEARCH_DAYS: u32 = 366 * 4 + 1;
+
+/// Period of time when no new orders are placed, e.g. known maintenance of the exchange.
+/// Parsed from a cron expression of the window start in UTC followed by the duration in
+/// minutes: `0 2 * * 0 60` is one hour each Sunday from 02:00.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Schema, Clone)]
+pub struct MaintenanceWindow {
+    /// Expression the window is parsed from
+    pub spec: String,
+    pub minutes: Vec<u32>,
+    pub hours: Vec<u32>,
+    pub days: Vec<u32>,
+    pub months: Vec<u32>,
+    /// Days of week, 0 is Sunday
+    pub weekdays: Vec<u32>,
+    /// Both day of month and day of week are restricted, so as in cron any of them matches
+    pub either_day: bool,
+    pub duration_mins: u64,
+}
+
+#[derive(Error, Debug, PartialEq, Eq, Clone)]
+pub enum WindowErr {
+    #[error("Maintenance window '{0}' must have 5 cron fields and duration in minutes")]
+    Fields(String),
+    #[error("Invalid field '{0}' of maintenance window, expected values in {1}-{2}")]
+    Field(String, u32, u32),
+    #[error("Invalid duration '{0}' of maintenance window")]
+    Duration(String),
+}
+
+/// Parse cron field with lists, ranges and steps. Returns sorted values and whether the field
+/// is restricted, i.e. doesn't start with `*`.
+fn parse_field(field: &str, min: u32, max: u32) -> Result<(Vec<u32>, bool), WindowErr> {
+    let invalid = || WindowErr::Field(field.to_owned(), min, max);
+    let mut values = vec![];
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().map_err(|_| invalid())?),
+            None => (part, 1),
+        };
+        let (from, to) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((from, to)) => (
+                from.parse().map_err(|_| invalid())?,
+                to.parse().map_err(|_| invalid())?,
+            ),
+            None => {
+                let from = range.parse().map_err(|_| invalid())?;
+                (from, if step > 1 { max } else { from })
+            }
+        };
+        if step == 0 || from < min || to > max || from > to {
+            return Err(invalid());
+        }
+        values.extend((from..=to).step_by(step as usize));
+    }
+    values.sort_unstable();
+    values.dedup();
+    Ok((values, !field.starts_with('*')))
+}
+
+impl FromStr for MaintenanceWindow {
+    type Err = WindowErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        if fields.len() != 6 {
+            return Err(WindowErr::Fields(s.to_owned()));
+        }
+        let (minutes, _) = parse_field(fields[0], 0, 59)?;
+        let (hours, _) = parse_field(fields[1], 0, 23)?;
+        let (days, days_restricted) = parse_field(fields[2], 1, 31)?;
+        let (months, _) = parse_field(fields[3], 1, 12)?;
+        let (weekdays, weekdays_restricted) = parse_field(fields[4], 0, 7)?;
+        // Both 0 and 7 are Sunday
+        let mut weekdays: Vec<u32> = weekdays.into_iter().map(|d| d % 7).collect();
+        weekdays.sort_unstable();
+        weekdays.dedup();
+        let duration_mins = fields[5]
+            .parse::<u64>()
+            .ok()
+            .filter(|d| *d > 0)
+            .ok_or_else(|| WindowErr::Duration(fields[5].to_owned()))?;
+        Ok(MaintenanceWindow {
+            spec: fields.join(" "),
+            minutes,
+            hours,
+            days,
+            months,
+            weekdays,
+            either_day: days_restricted && weekdays_restricted,
+            duration_mins,
+        })
+    }
+}
+
+impl MaintenanceWindow {
+    pub fn duration(&self) -> Duration {
+        Duration::minutes(self.duration_mins as i64)
+    }
+
+    fn day_matches(&self, date: NaiveDate) -> bool {
+        if !self.months.contains(&date.month()) {
+            return false;
+        }
+        let day = self.days.contains(&date.day());
+        let weekday = self
+            .weekdays
+            .contains(&date.weekday().num_days_from_sunday());
+        if self.either_day {
+            day || weekday
+        } else {
+            day && weekday
+        }
+    }
+
+    /// First start of the window at or after the moment
+    pub fn next_start(&self, from: NaiveDateTime) -> Option<NaiveDateTime> {
+        let mut date = from.date();
+        for _ in 0..SEARCH_DAYS {
+            if self.day_matches(date) {
+                for hour in self.hours.iter() {
+                    for minute in self.minutes.iter() {
+                        let start = date.and_hms(*hour, *minute, 0);
+                        if start >= from {
+                            return Some(start);
+                        }
+                    }
+                }
+            }
+            date = date.succ();
+        }
+        None
+    }
+
+    /// End of the window if it is active at the moment
+    pub fn active_until(&self, now: NaiveDateTime) -> Option<NaiveDateTime> {
+        let start = self.next_start(now - self.duration() + Duration::nanoseconds(1))?;
+        if start <= now {
+            Some(start + self.duration())
+        } else {
+            None
+        }
+    }
+}
+
+/// Whether orders are held by a maintenance window
+#[derive(Serialize, Deserialize, Schema, Debug, Clone, PartialEq, Default)]
+pub struct MaintenanceStatus {
+    pub active: bool,
+    /// End of the active window
+    pub until: Option<NaiveDateTime>,
+    /// Start of the next window
+    pub next: Option<NaiveDateTime>,
+}
+
+impl MaintenanceStatus {
+    pub fn new(windows: &[MaintenanceWindow], now: NaiveDateTime) -> Self {
+        let until = windows.iter().filter_map(|w| w.active_until(now)).max();
+        MaintenanceStatus {
+            active: until.is_some(),
+            until,
+            next: windows
+                .iter()
+                .filter_map(|w| w.next_start(until.unwrap_or(now)))
+                .min(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    #[test]
+    fn test_window_parse() {
+        let window: MaintenanceWindow = "*/15 1-3,5 * * 7 30".parse().unwrap();
+        assert_eq!(window.minutes, vec![0, 15, 30, 45]);
+        assert_eq!(window.hours, vec![1, 2, 3, 5]);
+        assert_eq!(window.days.len(), 31);
+        assert_eq!(window.weekdays, vec![0]);
+        assert!(!window.either_day);
+        assert!("0 2 * * 0".parse::<MaintenanceWindow>().is_err());
+        assert!("60 2 * * 0 30".parse::<MaintenanceWindow>().is_err());
+        assert!("0 2 * * 0 0".parse::<MaintenanceWindow>().is_err());
+    }
+
+    #[test]
+    fn test_window_schedule() {
+        // 2022-01-02 is Sunday
+        let window: MaintenanceWindow = "0 2 * * 0 60".parse().unwrap();
+        assert_eq!(
+            window.next_start(at("2022-01-01 12:00:00")),
+            Some(at("2022-01-02 02:00:00"))
+        );
+        assert_eq!(window.active_until(at("2022-01-02 01:59:59")), None);
+        assert_eq!(
+            window.active_until(at("2022-01-02 02:30:00")),
+            Some(at("2022-01-02 03:00:00"))
+        );
+        assert_eq!(window.active_until(at("2022-01-02 03:00:00")), None);
+
+        let status = MaintenanceStatus::new(&[window], at("2022-01-02 02:30:00"));
+        assert!(status.active);
+        assert_eq!(status.next, Some(at("2022-01-09 02:00:00")));
+
+        // Day of month or day of week as in cron
+        let window: MaintenanceWindow = "0 0 1 * 1 10".parse().unwrap();
+        assert!(window.either_day);
+        assert_eq!(
+            window.next_start(at("2022-01-01 12:00:00")),
+            Some(at("2022-01-03 00:00:00"))
+        );
+    }
+}
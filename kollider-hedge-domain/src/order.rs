@@ -0,0 +1,265 @@
+use super::state::OpeningOrder;
+use chrono::prelude::*;
+use kollider_api::kollider::api::SettlementType;
+use rweb::Schema;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// How Kollider settles PnL of orders. With instant settlement realized PnL is credited to the
+/// balance right away and can be withdrawn to Lightning before the position is closed.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Schema, Clone, Copy)]
+pub enum Settlement {
+    Instant,
+    Delayed,
+}
+
+impl Default for Settlement {
+    fn default() -> Self {
+        Settlement::Delayed
+    }
+}
+
+impl Settlement {
+    pub fn settlement_type(&self) -> SettlementType {
+        match self {
+            Settlement::Instant => SettlementType::Instant,
+            Settlement::Delayed => SettlementType::Delayed,
+        }
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Eq, Clone)]
+#[error("Given settlement '{0}' is unknown, valid are: instant, delayed")]
+pub struct UnknownSettlement(String);
+
+impl fmt::Display for Settlement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Settlement::Instant => write!(f, "instant"),
+            Settlement::Delayed => write!(f, "delayed"),
+        }
+    }
+}
+
+impl FromStr for Settlement {
+    type Err = UnknownSettlement;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "instant" => Ok(Settlement::Instant),
+            "delayed" => Ok(Settlement::Delayed),
+            _ => Err(UnknownSettlement(s.to_owned())),
+        }
+    }
+}
+
+/// Stage of the order that we place on the Kollider
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Schema, Clone, Copy)]
+pub enum OrderStatus {
+    /// Decided to place the order, but not yet sent
+    Scheduled,
+    /// Sent to the Kollider, no confirmation yet
+    Sent,
+    /// Kollider acknowledged the order
+    Received,
+    /// The order is in the order book
+    Open,
+    PartiallyFilled,
+    Filled,
+    Cancelled,
+    Rejected,
+}
+
+impl OrderStatus {
+    /// No more transitions are possible from the status
+    pub fn is_final(&self) -> bool {
+        matches!(
+            self,
+            OrderStatus::Filled | OrderStatus::Cancelled | OrderStatus::Rejected
+        )
+    }
+
+    /// Order that the Kollider didn't confirm yet
+    pub fn is_pending(&self) -> bool {
+        matches!(self, OrderStatus::Scheduled | OrderStatus::Sent)
+    }
+
+    /// Position of the status in the lifecycle, used to detect duplicate messages
+    fn rank(&self) -> u8 {
+        match self {
+            OrderStatus::Scheduled => 0,
+            OrderStatus::Sent => 1,
+            OrderStatus::Received => 2,
+            OrderStatus::Open => 3,
+            OrderStatus::PartiallyFilled => 4,
+            OrderStatus::Filled | OrderStatus::Cancelled | OrderStatus::Rejected => 5,
+        }
+    }
+}
+
+/// Event that moves the order through the lifecycle. Mostly driven by websocket messages.
+#[derive(Debug, PartialEq, Clone)]
+pub enum OrderEvent {
+    Sent,
+    Received {
+        order_id: u64,
+        quantity: u64,
+    },
+    Open {
+        order_id: u64,
+    },
+    Fill {
+        quantity: u64,
+    },
+    /// The order left the order book
+    Done,
+    Cancel,
+    Reject,
+}
+
+#[derive(Error, Debug, PartialEq, Clone)]
+pub enum OrderTransitionErr {
+    #[error("Order {0} cannot move from {1:?} with event {2:?}")]
+    Invalid(String, OrderStatus, OrderEvent),
+}
+
+/// Order placed by the service with its current stage
+#[derive(Debug, PartialEq, Serialize, Deserialize, Schema, Clone)]
+pub struct TrackedOrder {
+    pub order: OpeningOrder,
+    pub status: OrderStatus,
+    /// Kollider id of the order, known after the order is received
+    pub order_id: Option<u64>,
+    /// Amount of contracts in the order
+    pub quantity: Option<u64>,
+    /// Amount of filled contracts
+    pub filled: u64,
+    pub updated: NaiveDateTime,
+}
+
+impl TrackedOrder {
+    pub fn new(order: OpeningOrder) -> Self {
+        TrackedOrder {
+            order,
+            status: OrderStatus::Scheduled,
+            order_id: None,
+            quantity: None,
+            filled: 0,
+            updated: Utc::now().naive_utc(),
+        }
+    }
+
+    /// Move the order to the next status. Returns `false` for duplicate or late events that
+    /// don't change the order.
+    pub fn apply(&mut self, event: OrderEvent) -> Result<bool, OrderTransitionErr> {
+        let next = match (&self.status, &event) {
+            (s, _) if s.is_final() => return Ok(false),
+            (OrderStatus::Scheduled, OrderEvent::Sent) => OrderStatus::Sent,
+            (OrderStatus::Sent, OrderEvent::Received { order_id, quantity }) => {
+                self.order_id = Some(*order_id);
+                self.quantity = Some(*quantity);
+                OrderStatus::Received
+            }
+            (OrderStatus::Sent | OrderStatus::Received, OrderEvent::Open { order_id }) => {
+                self.order_id = Some(*order_id);
+                OrderStatus::Open
+            }
+            (
+                OrderStatus::Received | OrderStatus::Open | OrderStatus::PartiallyFilled,
+                OrderEvent::Fill { quantity },
+            ) => {
+                self.filled += quantity;
+                if self.quantity.map_or(false, |q| self.filled >= q) {
+                    OrderStatus::Filled
+                } else {
+                    OrderStatus::PartiallyFilled
+                }
+            }
+            (s, OrderEvent::Done) if s.rank() >= OrderStatus::Received.rank() => {
+                if self.quantity.map_or(false, |q| self.filled >= q) {
+                    OrderStatus::Filled
+                } else {
+                    OrderStatus::Cancelled
+                }
+            }
+            (OrderStatus::Scheduled | OrderStatus::Sent, OrderEvent::Reject) => {
+                OrderStatus::Rejected
+            }
+            (_, OrderEvent::Cancel) => OrderStatus::Cancelled,
+            (s, OrderEvent::Sent) if s.rank() >= OrderStatus::Sent.rank() => return Ok(false),
+            (s, OrderEvent::Received { .. }) if s.rank() >= OrderStatus::Received.rank() => {
+                return Ok(false)
+            }
+            (s, OrderEvent::Open { .. }) if s.rank() >= OrderStatus::Open.rank() => {
+                return Ok(false)
+            }
+            _ => {
+                return Err(OrderTransitionErr::Invalid(
+                    self.order.ext_id.clone(),
+                    self.status,
+                    event.clone(),
+                ))
+            }
+        };
+        self.status = next;
+        self.updated = Utc::now().naive_utc();
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kollider_api::kollider::api::OrderSide;
+
+    fn tracked() -> TrackedOrder {
+        TrackedOrder::new(OpeningOrder {
+            ext_id: OpeningOrder::new_id(),
+            symbol: "BTCUSD.PERP".to_owned(),
+            sats: 100_000,
+            price: 2500,
+            side: OrderSide::Bid,
+            leverage: 100,
+        })
+    }
+
+    #[test]
+    fn test_order_lifecycle() {
+        let mut order = tracked();
+        assert_eq!(order.apply(OrderEvent::Sent), Ok(true));
+        assert_eq!(
+            order.apply(OrderEvent::Received {
+                order_id: 42,
+                quantity: 40
+            }),
+            Ok(true)
+        );
+        assert_eq!(order.apply(OrderEvent::Open { order_id: 42 }), Ok(true));
+        assert_eq!(order.apply(OrderEvent::Fill { quantity: 10 }), Ok(true));
+        assert_eq!(order.status, OrderStatus::PartiallyFilled);
+        assert_eq!(order.apply(OrderEvent::Fill { quantity: 30 }), Ok(true));
+        assert_eq!(order.status, OrderStatus::Filled);
+        assert_eq!(order.apply(OrderEvent::Done), Ok(false));
+        assert_eq!(order.status, OrderStatus::Filled);
+    }
+
+    #[test]
+    fn test_order_duplicates() {
+        let mut order = tracked();
+        order.apply(OrderEvent::Sent).unwrap();
+        order.apply(OrderEvent::Open { order_id: 42 }).unwrap();
+        assert_eq!(
+            order.apply(OrderEvent::Received {
+                order_id: 42,
+                quantity: 40
+            }),
+            Ok(false)
+        );
+        assert_eq!(order.apply(OrderEvent::Open { order_id: 42 }), Ok(false));
+        assert!(order.apply(OrderEvent::Reject).is_err());
+        assert_eq!(order.apply(OrderEvent::Done), Ok(true));
+        assert_eq!(order.status, OrderStatus::Cancelled);
+    }
+}
@@ -0,0 +1,166 @@
+use rweb::Schema;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
+use thiserror::Error;
+
+/// Specs of exchange products indexed by symbol
+pub type Products = HashMap<String, ProductSpec>;
+
+/// Contract specification of a Kollider product as returned by `/market/products`
+#[derive(Debug, PartialEq, Serialize, Deserialize, Schema, Clone)]
+pub struct ProductSpec {
+    pub symbol: String,
+    /// Minimal step of the price in USD
+    #[serde(deserialize_with = "number_or_string")]
+    pub tick_size: f64,
+    /// Prices are sent as integers multiplied by 10 to the power of `price_dp`
+    pub price_dp: u32,
+    /// Value of a single contract in USD
+    #[serde(deserialize_with = "number_or_string")]
+    pub contract_size: f64,
+    /// Minimal amount of contracts in an order
+    #[serde(default = "default_min_quantity")]
+    pub min_quantity: u64,
+    /// Maximal amount of contracts in an order, `None` means no limit
+    #[serde(default)]
+    pub max_quantity: Option<u64>,
+    /// Maximal leverage of the product, e.g. 100.0 is 100x. `None` means unknown.
+    #[serde(default, deserialize_with = "opt_number_or_string")]
+    pub max_leverage: Option<f64>,
+}
+
+fn default_min_quantity() -> u64 {
+    1
+}
+
+/// Kollider encodes decimals as strings in some fields
+fn number_or_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Raw {
+        Number(f64),
+        String(String),
+    }
+    match Raw::deserialize(deserializer)? {
+        Raw::Number(v) => Ok(v),
+        Raw::String(s) => s.parse().map_err(serde::de::Error::custom),
+    }
+}
+
+fn opt_number_or_string<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<f64>, D::Error> {
+    number_or_string(deserializer).map(Some)
+}
+
+#[derive(Error, Debug, PartialEq, Clone)]
+pub enum ProductErr {
+    #[error("Price of order for {0} is zero")]
+    ZeroPrice(String),
+    #[error("Order for {0} of {1} contracts is below minimal quantity {2}")]
+    QuantityTooSmall(String, u64, u64),
+    #[error("Order for {0} of {1} contracts is above maximal quantity {2}")]
+    QuantityTooLarge(String, u64, u64),
+}
+
+impl ProductSpec {
+    /// Spec that reproduces the hard-coded behavior before specs were fetched from Kollider
+    pub fn fallback(symbol: &str) -> Self {
+        ProductSpec {
+            symbol: symbol.to_owned(),
+            tick_size: 1.0,
+            price_dp: if symbol == "BTCUSD.PERP" { 1 } else { 0 },
+            contract_size: 1.0,
+            min_quantity: 1,
+            max_quantity: None,
+            max_leverage: None,
+        }
+    }
+
+    /// Spec of the symbol from the fetched products or the fallback one
+    pub fn find(products: &Products, symbol: &str) -> Self {
+        products
+            .get(symbol)
+            .cloned()
+            .unwrap_or_else(|| ProductSpec::fallback(symbol))
+    }
+
+    /// Convert price in sats/USD to the integer price of the exchange rounded to the tick size
+    pub fn wire_price(&self, price: u64) -> Result<u64, ProductErr> {
+        if price == 0 {
+            return Err(ProductErr::ZeroPrice(self.symbol.clone()));
+        }
+        let usd = 100_000_000.0 / price as f64;
+        let ticks = if self.tick_size > 0.0 {
+            (usd / self.tick_size).round() * self.tick_size
+        } else {
+            usd
+        };
+        Ok((ticks * 10_f64.powi(self.price_dp as i32)).round() as u64)
+    }
+
+    /// Amount of contracts that covers the sats at the price in sats/USD
+    pub fn quantity(&self, sats: u64, price: u64) -> Result<u64, ProductErr> {
+        if price == 0 {
+            return Err(ProductErr::ZeroPrice(self.symbol.clone()));
+        }
+        let quantity = (sats as f64 / price as f64 / self.contract_size).ceil() as u64;
+        if quantity < self.min_quantity {
+            return Err(ProductErr::QuantityTooSmall(
+                self.symbol.clone(),
+                quantity,
+                self.min_quantity,
+            ));
+        }
+        if let Some(max) = self.max_quantity {
+            if quantity > max {
+                return Err(ProductErr::QuantityTooLarge(
+                    self.symbol.clone(),
+                    quantity,
+                    max,
+                ));
+            }
+        }
+        Ok(quantity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_product_spec() {
+        let spec: ProductSpec = serde_json::from_str(
+            r#"{"symbol": "BTCUSD.PERP", "contract_size": "1", "tick_size": "0.5", "price_dp": 1,
+                "max_leverage": "100.00", "is_inverse_priced": true}"#,
+        )
+        .unwrap();
+        assert_eq!(spec.tick_size, 0.5);
+        assert_eq!(spec.min_quantity, 1);
+        assert_eq!(spec.max_leverage, Some(100.0));
+        assert_eq!(spec.wire_price(2500), Ok(400_000));
+        // 39984.006 USD is rounded to 39984.0
+        assert_eq!(spec.wire_price(2501), Ok(399_840));
+        assert_eq!(spec.quantity(100_000, 2500), Ok(40));
+        assert_eq!(
+            spec.quantity(0, 2500),
+            Err(ProductErr::QuantityTooSmall("BTCUSD.PERP".to_owned(), 0, 1))
+        );
+
+        let spec = ProductSpec {
+            contract_size: 10.0,
+            max_quantity: Some(3),
+            ..spec
+        };
+        assert_eq!(spec.quantity(50_000, 2500), Ok(2));
+        assert!(matches!(
+            spec.quantity(100_000, 2500),
+            Err(ProductErr::QuantityTooLarge(_, 4, 3))
+        ));
+        assert_eq!(
+            ProductSpec::fallback("BTCUSD.PERP").wire_price(2500),
+            Ok(400_000)
+        );
+    }
+}
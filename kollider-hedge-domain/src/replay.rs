@@ -0,0 +1,250 @@
+use super::state::*;
+use super::update::*;
+use chrono::prelude::*;
+use kollider_api::kollider::api::OrderSide;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Index price at the given moment of time
+#[derive(Debug, PartialEq, Clone)]
+pub struct PricePoint {
+    pub time: NaiveDateTime,
+    /// Price of BTC in USD
+    pub price: f64,
+}
+
+#[derive(Error, Debug)]
+pub enum PriceParseError {
+    #[error("Line {0} has wrong format, expected 'timestamp,price': {1}")]
+    Format(usize, String),
+    #[error("Line {0} has wrong timestamp: {1}")]
+    Timestamp(usize, String),
+    #[error("Line {0} has wrong price: {1}")]
+    Price(usize, String),
+}
+
+/// Parse price series from CSV with `timestamp,price` lines. Timestamp is either unix
+/// seconds or ISO 8601 date time without timezone. Header line is skipped.
+pub fn parse_prices(csv: &str) -> Result<Vec<PricePoint>, PriceParseError> {
+    let mut res = vec![];
+    for (i, line) in csv.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || (i == 0 && line.starts_with("timestamp")) {
+            continue;
+        }
+        let (time_str, price_str) = line
+            .split_once(',')
+            .ok_or_else(|| PriceParseError::Format(i + 1, line.to_owned()))?;
+        let time_str = time_str.trim();
+        let time = if let Ok(secs) = time_str.parse::<i64>() {
+            NaiveDateTime::from_timestamp(secs, 0)
+        } else {
+            time_str
+                .parse::<NaiveDateTime>()
+                .map_err(|_| PriceParseError::Timestamp(i + 1, time_str.to_owned()))?
+        };
+        let price = price_str
+            .trim()
+            .parse::<f64>()
+            .map_err(|_| PriceParseError::Price(i + 1, price_str.to_owned()))?;
+        res.push(PricePoint { time, price });
+    }
+    res.sort_by_key(|p| p.time);
+    Ok(res)
+}
+
+/// Order that the strategy placed during replay. Orders are filled immediately.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct ReplayOrder {
+    pub time: NaiveDateTime,
+    pub side: OrderSide,
+    pub sats: u64,
+    /// Amount of USD contracts
+    pub quantity: u64,
+    /// Price of the order in sats/USD
+    pub price: u64,
+    pub fee_sats: f64,
+}
+
+/// Outcome of the strategy over the replayed history
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct ReplayReport {
+    pub orders: Vec<ReplayOrder>,
+    pub fees_sats: f64,
+    /// Profit of the closed part of the position
+    pub realized_pnl_sats: f64,
+    /// Profit of the open position at the last price
+    pub unrealized_pnl_sats: f64,
+    pub final_position_usd: u64,
+    pub final_hedge_sats: u64,
+}
+
+/// Simulated short position on the inverse contract
+#[derive(Debug, Default)]
+struct SimPosition {
+    quantity: u64,
+    entry_value: f64,
+    rpnl: f64,
+}
+
+impl SimPosition {
+    fn fill(&mut self, side: OrderSide, quantity: u64, price: u64) {
+        match side {
+            OrderSide::Bid => {
+                self.quantity += quantity;
+                self.entry_value += quantity as f64 * price as f64;
+            }
+            OrderSide::Ask => {
+                let closed = quantity.min(self.quantity);
+                if closed == 0 {
+                    return;
+                }
+                let closed_value = self.entry_value * closed as f64 / self.quantity as f64;
+                self.rpnl += closed as f64 * price as f64 - closed_value;
+                self.entry_value -= closed_value;
+                self.quantity -= closed;
+            }
+        }
+    }
+
+    fn unrealized(&self, price: u64) -> f64 {
+        self.quantity as f64 * price as f64 - self.entry_value
+    }
+
+    fn to_kollider(&self, leverage: u64) -> KolliderPosition {
+        let entry_price = if self.entry_value > 0.0 {
+            (self.quantity as f64 * 100_000_000.0 / self.entry_value) as u64
+        } else {
+            0
+        };
+        KolliderPosition::new(
+            leverage,
+            self.entry_value.round() as u64,
+            entry_price,
+            self.quantity,
+            self.rpnl,
+        )
+    }
+}
+
+/// Replay HTLC updates against the price series through the hedging logic. Each order is
+/// filled immediately at its price with taker fee `fee_percent`.
+pub fn replay<I>(
+    config: HedgeConfig,
+    updates: I,
+    prices: &[PricePoint],
+    fee_percent: f64,
+) -> Result<ReplayReport, StateUpdateErr>
+where
+    I: IntoIterator<Item = StateUpdate>,
+{
+    let mut updates = updates.into_iter().peekable();
+    let mut state = State::new(config);
+    let mut position = SimPosition::default();
+    let mut orders = vec![];
+    let mut fees_sats = 0.0;
+    let mut last_price = 0;
+
+    for point in prices {
+        while let Some(upd) = updates.next_if(|u| u.created <= point.time) {
+            state.apply_update(upd)?;
+        }
+        state.update_ticker(point.price, point.time);
+        state.opened_orders = Some(vec![]);
+        state.opened_position = Some(position.to_kollider(state.order_leverage()));
+        if let Err(e) = state.calculate_next_actions() {
+            log::warn!("Failed to calculate actions at {}: {}", point.time, e);
+            continue;
+        }
+        for action in std::mem::take(&mut state.scheduled_actions) {
+            let (side, sats, quantity, price) = match action {
+                StateAction::OpenOrder(order) => {
+                    let quantity = (order.sats as f64 / order.price as f64).ceil() as u64;
+                    (order.side, order.sats, quantity, order.price)
+                }
+                // Market order is filled at the index price
+                StateAction::ClosePosition { quantity, .. } => {
+                    let price = state.current_price().unwrap_or(last_price);
+                    (OrderSide::Ask, quantity * price, quantity, price)
+                }
+                StateAction::CloseOrder { .. } => continue,
+            };
+            let fee_sats = quantity as f64 * price as f64 * fee_percent * 0.01;
+            position.fill(side, quantity, price);
+            fees_sats += fee_sats;
+            orders.push(ReplayOrder {
+                time: point.time,
+                side,
+                sats,
+                quantity,
+                price,
+                fee_sats,
+            });
+        }
+        last_price = state.current_price().unwrap_or(last_price);
+    }
+    for upd in updates {
+        state.apply_update(upd)?;
+    }
+
+    Ok(ReplayReport {
+        orders,
+        fees_sats,
+        realized_pnl_sats: position.rpnl,
+        unrealized_pnl_sats: position.unrealized(last_price),
+        final_position_usd: position.quantity,
+        final_hedge_sats: state.hedge_capacity(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_prices() {
+        let csv = "timestamp,price\n1640995200,47000.5\n2022-01-01T00:01:00,47100\n";
+        let prices = parse_prices(csv).unwrap();
+        assert_eq!(
+            prices,
+            vec![
+                PricePoint {
+                    time: NaiveDateTime::from_timestamp(1640995200, 0),
+                    price: 47000.5,
+                },
+                PricePoint {
+                    time: NaiveDateTime::from_timestamp(1640995260, 0),
+                    price: 47100.0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_replay_single_htlc() {
+        let start = NaiveDateTime::from_timestamp(1640995200, 0);
+        let updates = vec![StateUpdate {
+            created: start,
+            body: UpdateBody::Htlc(HtlcUpdate {
+                channel_id: "aboba".to_owned(),
+                sats: 100_000,
+                rate: 2500,
+            }),
+        }];
+        let prices = vec![
+            PricePoint {
+                time: start,
+                price: 40000.0,
+            },
+            PricePoint {
+                time: start + chrono::Duration::minutes(1),
+                price: 40000.0,
+            },
+        ];
+        let report = replay(HedgeConfig::default(), updates, &prices, 0.0).unwrap();
+        assert_eq!(report.orders.len(), 1);
+        assert_eq!(report.orders[0].side, OrderSide::Bid);
+        assert_eq!(report.final_hedge_sats, 100_000);
+        assert_eq!(report.realized_pnl_sats, 0.0);
+    }
+}
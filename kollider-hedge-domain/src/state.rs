@@ -1,16 +1,20 @@
+use super::maintenance::*;
+use super::order::*;
+use super::product::*;
+use super::strategy::*;
 use super::update::*;
 use chrono::prelude::*;
 use futures::Future;
-use kollider_api::kollider::api::{MarginType, OrderSide, OrderType, SettlementType};
+use kollider_api::kollider::api::{MarginType, OrderSide, OrderType};
 use kollider_api::kollider::websocket::data::*;
 use log::*;
 use rweb::Schema;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::sync::Arc;
 use thiserror::Error;
-use tokio::sync::{Mutex, Notify};
+use tokio::sync::{Notify, RwLock, RwLockWriteGuard};
 use uuid::Uuid;
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Schema, Clone)]
@@ -24,6 +28,198 @@ pub struct HedgeConfig {
     /// Leverage * 100 defines multiplyier of losses and profit. If you hedge with 2x, you need 1/2 of
     /// sats to hedge all sats in the channels.
     pub hedge_leverage: u64,
+    /// Unhedged sats below the amount are accumulated and order is placed only when
+    /// `htlc_aggregation_secs` seconds passed. Zero means that orders are placed immediately.
+    pub min_htlc_aggregate: u64,
+    /// Maximum time in seconds we accumulate small unhedged amounts before placing an order
+    pub htlc_aggregation_secs: u64,
+    /// Maximum deviation in percents of Kollider index from the external price feed. Orders are
+    /// not placed when the deviation is larger or the external price is unknown. Zero disables
+    /// the check.
+    pub max_index_deviation: f64,
+    /// Orders larger than the amount of sats are split into chunks. Zero means no limit.
+    pub max_order_sats: u64,
+    /// Seconds between placing consecutive chunks of a large order. Zero means that all
+    /// chunks are placed at once.
+    pub order_chunk_secs: u64,
+    /// Maximum amount of orders placed per minute. Changes of the hedge above the limit are
+    /// accumulated and placed in the next free slot. Zero means no limit.
+    #[serde(default)]
+    pub max_orders_per_minute: u64,
+    /// How orders are priced and placed
+    pub strategy: StrategyKind,
+    /// Hard cap on the position in sats. Position is not increased above the value even if
+    /// channels require more. Zero means no limit.
+    pub max_position_sats: u64,
+    /// Half-life in seconds of the exponential moving average of the index that orders are
+    /// priced from. Zero means that orders are priced from the last tick.
+    pub ema_half_life_secs: u64,
+    /// Periods when no new orders are placed. Changes of channels are hedged after the
+    /// window ends.
+    pub maintenance_windows: Vec<MaintenanceWindow>,
+    /// Maximum deviation in percents of the order price from the index. Orders priced further
+    /// are not placed and alert is raised. Zero disables the check.
+    pub max_slippage_percent: f64,
+    /// Settlement of orders and cancels sent to Kollider
+    pub settlement: Settlement,
+    /// Maximum difference in seconds between clocks of Kollider and the service. Orders are not
+    /// placed when the measured skew is larger. Zero disables the check.
+    #[serde(default)]
+    pub max_clock_skew_secs: u64,
+    /// Unhedged amount in USD in either direction that raises the drift alarm when it lasts
+    /// longer than `drift_alarm_secs`. Zero disables the alarm.
+    #[serde(default)]
+    pub drift_alarm_usd: f64,
+    /// Seconds the unhedged amount must stay above `drift_alarm_usd` before the alarm is raised,
+    /// so that normal latency of order fills is not reported
+    #[serde(default)]
+    pub drift_alarm_secs: u64,
+    /// Index of BTC in the currency that stats are additionally reported in, e.g. `.BTCEUR`
+    pub reporting_pair: Option<String>,
+    /// Orders rejected by Kollider for insufficient margin are retried with half of the size,
+    /// but not below the amount of sats. Zero disables retries.
+    pub min_downsize_sats: u64,
+    /// Sats of Kollider balance that are never committed to new orders
+    pub margin_buffer_sats: u64,
+    /// Channels that are tracked but not hedged, e.g. small channels that are self-insured.
+    /// Changed only at runtime with `/config`, so it is not overwritten by options on restart.
+    #[serde(default)]
+    pub unhedged_channels: Vec<ChannelId>,
+    /// Leverage * 100 of channels that overrides `hedge_leverage`. Changed only at runtime with
+    /// `/config` as `unhedged_channels`.
+    #[serde(default)]
+    pub channel_leverage: HashMap<ChannelId, u64>,
+}
+
+/// Problems of the configuration found by `HedgeConfig::validate`
+#[derive(Error, Debug, PartialEq, Clone)]
+pub enum ConfigErr {
+    #[error("Symbol {0} is not listed on Kollider")]
+    UnknownSymbol(String),
+    #[error("Leverage of {0} must be positive")]
+    ZeroLeverage(String),
+    #[error("Leverage {1} of {0} is above maximal {2}x of the product")]
+    LeverageTooHigh(String, u64, f64),
+    #[error("Spread {0}% must not be negative")]
+    NegativeSpread(f64),
+    #[error("Symbol {0} is not priced in currency {1} of the pair")]
+    CurrencyMismatch(String, String),
+}
+
+impl HedgeConfig {
+    /// Check the config against contract specs of Kollider. Symbol existence and leverage
+    /// limits are checked only when the specs are fetched.
+    pub fn validate(&self, products: &Products) -> Vec<ConfigErr> {
+        let mut errs = vec![];
+        if self.spread_percent < 0.0 {
+            errs.push(ConfigErr::NegativeSpread(self.spread_percent));
+        }
+        let currency = self.hedge_currency();
+        if !self.hedge_sym.starts_with(&format!("BTC{}", currency)) {
+            errs.push(ConfigErr::CurrencyMismatch(
+                self.hedge_sym.clone(),
+                currency,
+            ));
+        }
+        let leverages = std::iter::once(("hedge".to_owned(), self.hedge_leverage)).chain(
+            self.channel_leverage
+                .iter()
+                .map(|(id, leverage)| (format!("channel {}", id), *leverage)),
+        );
+        let max_leverage = products
+            .get(&self.hedge_sym)
+            .and_then(|spec| spec.max_leverage);
+        for (name, leverage) in leverages {
+            if leverage == 0 {
+                errs.push(ConfigErr::ZeroLeverage(name));
+            } else if let Some(max) = max_leverage {
+                if leverage as f64 > max * 100.0 {
+                    errs.push(ConfigErr::LeverageTooHigh(name, leverage, max));
+                }
+            }
+        }
+        if !products.is_empty() && !products.contains_key(&self.hedge_sym) {
+            errs.push(ConfigErr::UnknownSymbol(self.hedge_sym.clone()));
+        }
+        errs
+    }
+
+    /// Fiat currency of the hedge, `.BTCEUR` pair is hedged in EUR
+    pub fn hedge_currency(&self) -> String {
+        pair_currency(&self.hedge_pair)
+    }
+
+    /// Set fields that are provided by the change
+    pub fn apply_change(&mut self, change: &ConfigChange) {
+        if let Some(v) = &change.hedge_pair {
+            self.hedge_pair = v.clone();
+        }
+        if let Some(v) = &change.hedge_sym {
+            self.hedge_sym = v.clone();
+        }
+        if let Some(v) = change.spread_percent {
+            self.spread_percent = v;
+        }
+        if let Some(v) = change.hedge_leverage {
+            self.hedge_leverage = v;
+        }
+        if let Some(v) = &change.unhedged_channels {
+            let mut channels = v.clone();
+            channels.sort();
+            channels.dedup();
+            self.unhedged_channels = channels;
+        }
+        if let Some(v) = &change.channel_leverage {
+            // Zero leverage can't hedge anything, such overrides are dropped
+            self.channel_leverage = v
+                .iter()
+                .filter(|(_, leverage)| **leverage > 0)
+                .map(|(id, leverage)| (id.clone(), *leverage))
+                .collect();
+        }
+    }
+
+    /// Leverage * 100 the channel is hedged with
+    pub fn leverage_of(&self, chan_id: &str) -> u64 {
+        self.channel_leverage
+            .get(chan_id)
+            .copied()
+            .unwrap_or(self.hedge_leverage)
+    }
+
+    /// Whether HTLCs of the channel are hedged on the exchange
+    pub fn is_hedged(&self, chan_id: &str) -> bool {
+        !self.unhedged_channels.iter().any(|id| id == chan_id)
+    }
+
+    /// Make change that requests all fields of the config that are set by command line flags
+    pub fn full_change(&self) -> ConfigChange {
+        ConfigChange {
+            hedge_pair: Some(self.hedge_pair.clone()),
+            hedge_sym: Some(self.hedge_sym.clone()),
+            spread_percent: Some(self.spread_percent),
+            hedge_leverage: Some(self.hedge_leverage),
+            unhedged_channels: None,
+            channel_leverage: None,
+            startup: true,
+        }
+    }
+
+    /// Make change that restores all fields of the config that updates can change
+    pub fn recorded_change(&self) -> ConfigChange {
+        ConfigChange {
+            unhedged_channels: Some(self.unhedged_channels.clone()),
+            channel_leverage: Some(self.channel_leverage.clone()),
+            startup: false,
+            ..self.full_change()
+        }
+    }
+}
+
+/// Currency that BTC is priced in by the index symbol
+pub fn pair_currency(pair: &str) -> String {
+    let pair = pair.trim_start_matches('.');
+    pair.strip_prefix("BTC").unwrap_or(pair).to_owned()
 }
 
 impl Default for HedgeConfig {
@@ -33,6 +229,26 @@ impl Default for HedgeConfig {
             hedge_sym: "BTCUSD.PERP".to_string(),
             spread_percent: 0.1,
             hedge_leverage: 100,
+            min_htlc_aggregate: 0,
+            htlc_aggregation_secs: 0,
+            max_index_deviation: 0.0,
+            max_order_sats: 0,
+            order_chunk_secs: 0,
+            max_orders_per_minute: 0,
+            strategy: StrategyKind::default(),
+            max_position_sats: 0,
+            ema_half_life_secs: 0,
+            maintenance_windows: vec![],
+            max_slippage_percent: 0.0,
+            settlement: Settlement::default(),
+            max_clock_skew_secs: 0,
+            drift_alarm_usd: 0.0,
+            drift_alarm_secs: 0,
+            reporting_pair: None,
+            min_downsize_sats: 1000,
+            margin_buffer_sats: 0,
+            unhedged_channels: vec![],
+            channel_leverage: HashMap::new(),
         }
     }
 }
@@ -40,19 +256,278 @@ impl Default for HedgeConfig {
 #[derive(Debug, PartialEq, Serialize, Deserialize, Schema, Clone)]
 pub struct State {
     pub last_changed: NaiveDateTime,
+    /// Incremented each time the state is locked for writing, see `write_state`. Served as ETag
+    /// of `/state`.
+    #[serde(default)]
+    pub revision: u64,
     pub config: HedgeConfig,
     /// Balance in BTC on Kollider
     pub balance: Option<f64>,
     /// Price of BTC/USD reported by Kollider
     pub ticker: Option<f64>,
+    /// Smoothed price of BTC/USD, tracked when `ema_half_life_secs` is set
+    pub ticker_ema: Option<TickerEma>,
+    /// The last values of all indices reported by Kollider by symbol
+    pub index_tickers: HashMap<String, f64>,
     pub channels_hedge: HashMap<ChannelId, ChannelHedge>,
     pub opened_orders: Option<Vec<KolliderOrder>>,
     pub opened_position: Option<KolliderPosition>,
-    /// Here the orders that are sent to the Kollider but are not yet reported as opened are placed.
-    pub opening_orders: HashMap<String, OpeningOrder>,
-    // TODO: put orders in progress of opening here
+    /// Orders that we sent to the Kollider indexed by external id. Orders are removed when they
+    /// reach a final status. Orders that are not yet confirmed by the Kollider are opening ones.
+    pub orders: HashMap<String, TrackedOrder>,
     /// Cache actions that we need to execute to avoid replaying them before they are completed
     pub scheduled_actions: Vec<StateAction>,
+    /// Moment when we first noticed unhedged amount that is too small to place an order
+    pub unhedged_since: Option<NaiveDateTime>,
+    /// Moment when the config was changed the last time in the chain of updates
+    pub config_changed: Option<NaiveDateTime>,
+    /// Config requested by command line flags at the last startup, see `ConfigChange::startup`
+    #[serde(default)]
+    pub startup_config: Option<ConfigChange>,
+    /// Moments when balances of channels dropped to zero
+    pub empty_channels: HashMap<ChannelId, NaiveDateTime>,
+    /// Fiat value of channels, recalculated on each change of channels or ticker
+    pub channels_usd: HashMap<ChannelId, ChannelUsd>,
+    /// Position that was opened with previous leverage and is being reopened with the new one
+    pub leverage_migration: Option<LeverageMigration>,
+    /// Orders and position on the previous `hedge_sym` that are being closed before hedging on
+    /// the new symbol
+    #[serde(default)]
+    pub symbol_migration: Option<SymbolMigration>,
+    /// Price of BTC/USD reported by the secondary price source
+    pub external_ticker: Option<ExternalTicker>,
+    /// Moment when we can place the next chunk of a large order
+    pub next_chunk_at: Option<NaiveDateTime>,
+    /// Moments when orders were scheduled during the last minute, see `max_orders_per_minute`
+    #[serde(default)]
+    pub recent_orders: Vec<NaiveDateTime>,
+    /// Hedge capacity exceeds `max_position_sats` and the position is capped
+    pub over_limit: bool,
+    /// End of the active maintenance window, orders are held until the moment
+    pub maintenance_end: Option<NaiveDateTime>,
+    /// The last order was not placed as its price is too far from the index
+    pub slippage_exceeded: bool,
+    /// Milliseconds that the clock of Kollider is ahead of the local one, negative when it is
+    /// behind. `None` until it is measured.
+    #[serde(default)]
+    pub clock_skew_ms: Option<i64>,
+    /// Clock skew is above `max_clock_skew_secs` and orders are held
+    #[serde(default)]
+    pub clock_skew_exceeded: bool,
+    /// Moment when the unhedged amount exceeded `drift_alarm_usd`
+    #[serde(default)]
+    pub drift_since: Option<NaiveDateTime>,
+    /// Unhedged amount stays above `drift_alarm_usd` longer than `drift_alarm_secs`
+    #[serde(default)]
+    pub drift_alarm: bool,
+    /// Sats that the position with open orders lacks to the required hedge at the last drift
+    /// check, negative when it is above the hedge. `None` until orders and price are known.
+    #[serde(default)]
+    pub position_drift: Option<i64>,
+    /// Consecutive drift checks that found the position outside of the allowed gap
+    #[serde(default)]
+    pub position_drift_checks: u32,
+    /// Kollider ids of orders that we requested to cancel
+    pub cancelling_orders: HashSet<u64>,
+    /// Balance on Kollider is not enough to open orders that are required for full hedge
+    pub margin_shortage: bool,
+    /// Limit of orders that increase the position after Kollider rejected an order for
+    /// insufficient margin. Lifted when the balance grows.
+    pub margin_order_cap: Option<u64>,
+    /// Error of the last calculation of actions. Orders are not placed until the calculation
+    /// succeeds again.
+    pub planning_error: Option<String>,
+    /// Invoices that top up Kollider balance and are not paid yet
+    pub pending_deposits: Vec<PendingDeposit>,
+    /// Sats of withdrawals that wait for Kollider to pay the invoice, they can't be withdrawn
+    /// twice
+    #[serde(default)]
+    pub withdrawing_sats: u64,
+    /// HTLC updates that passed the checks and wait for the commit to the database. New
+    /// updates are checked on top of them.
+    #[serde(default)]
+    pub staged_updates: Vec<StateUpdate>,
+    /// Realized profit and loss collected from position updates
+    pub pnl: PnlTracker,
+    /// Contract specs of Kollider products, fetched at startup
+    pub products: Products,
+    /// Activity since the last daily report
+    pub daily: DailyCounters,
+}
+
+/// Exponential moving average of the index price
+#[derive(Debug, PartialEq, Serialize, Deserialize, Schema, Clone)]
+pub struct TickerEma {
+    /// Price of BTC/USD
+    pub value: f64,
+    pub updated: NaiveDateTime,
+}
+
+impl TickerEma {
+    /// Move the average towards the tick. Weight of the previous average halves each
+    /// `half_life_secs` seconds, so frequency of ticks doesn't affect smoothing.
+    pub fn update(&mut self, tick: f64, now: NaiveDateTime, half_life_secs: u64) {
+        let dt = (now - self.updated).num_milliseconds().max(0) as f64 / 1000.0;
+        let alpha = 1.0 - 0.5_f64.powf(dt / half_life_secs as f64);
+        self.value += alpha * (tick - self.value);
+        self.updated = now;
+    }
+}
+
+/// Margin in sats committed on Kollider. Kollider balance already excludes margin of open orders
+/// and the position, so only orders that are not confirmed yet are reserved from it.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Schema, Clone, Default)]
+pub struct MarginLedger {
+    /// Kollider balance, `None` until it is received
+    pub balance: Option<u64>,
+    /// Orders that increase the position and are sent but not confirmed by Kollider
+    pub opening_orders: u64,
+    /// Orders that increase the position and wait for sending
+    pub scheduled_orders: u64,
+    /// Orders resting in the order book
+    pub open_orders: u64,
+    /// Opened position
+    pub position: u64,
+    /// Part of the balance that is never committed to new orders
+    pub buffer: u64,
+    /// Balance that new orders can commit
+    pub available: Option<u64>,
+}
+
+/// Counters of activity for the daily accounting report
+#[derive(Debug, PartialEq, Serialize, Deserialize, Schema, Clone)]
+pub struct DailyCounters {
+    /// Start of the reporting period
+    pub since: NaiveDateTime,
+    pub orders_placed: u64,
+    pub fills: u64,
+    /// Amount of filled contracts
+    pub filled_contracts: u64,
+    /// Paid deposit invoices
+    pub deposits_sats: u64,
+    pub withdrawals_sats: u64,
+}
+
+impl DailyCounters {
+    pub fn new(since: NaiveDateTime) -> Self {
+        DailyCounters {
+            since,
+            orders_placed: 0,
+            fills: 0,
+            filled_contracts: 0,
+            deposits_sats: 0,
+            withdrawals_sats: 0,
+        }
+    }
+}
+
+impl Default for DailyCounters {
+    fn default() -> Self {
+        DailyCounters::new(Utc::now().naive_utc())
+    }
+}
+
+/// Realized PnL accumulated over positions. Kollider reports `rpnl` only for the current
+/// position, so we sum up its changes.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Schema, Clone, Default)]
+pub struct PnlTracker {
+    /// Realized PnL in sats over all positions
+    pub realized: f64,
+    /// Last `rpnl` of the current position
+    pub last_rpnl: f64,
+    /// Change of entry price in USD on the last position update
+    pub entry_price_delta: i64,
+    /// Settled PnL in sats that is not seen in the balance yet
+    #[serde(default)]
+    pub pending_settlement: i64,
+}
+
+/// Unpaid deposit invoices are forgotten after the amount of seconds
+pub const DEPOSIT_EXPIRY_SECS: i64 = 3600;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Schema, Clone)]
+pub struct PendingDeposit {
+    pub sats: u64,
+    /// Lightning invoice issued by Kollider
+    pub invoice: String,
+    pub created: NaiveDateTime,
+}
+
+/// External price is not trusted if it wasn't updated for the amount of seconds
+pub const EXTERNAL_TICKER_STALE_SECS: i64 = 300;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Schema, Clone)]
+pub struct ExternalTicker {
+    /// Price of BTC in USD
+    pub price: f64,
+    pub updated: NaiveDateTime,
+}
+
+/// Change of the hedge drift alarm, see `drift_alarm_usd`
+#[derive(Debug, PartialEq, Serialize, Deserialize, Schema, Clone)]
+pub struct DriftAlarm {
+    /// The alarm is raised, otherwise it is cleared
+    pub raised: bool,
+    /// Unhedged amount in USD, negative means overhedge
+    pub unhedged_usd: f64,
+    /// Moment when the unhedged amount exceeded the threshold
+    pub since: Option<NaiveDateTime>,
+}
+
+/// Migration of opened position to new leverage. The position is closed with the old
+/// leverage and reopened by usual hedging logic with the new one.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Schema, Clone)]
+pub struct LeverageMigration {
+    /// Leverage * 100 the position was opened with
+    pub from: u64,
+    /// Leverage * 100 the position is reopened with
+    pub to: u64,
+    /// Margin in sats the reopened position will require
+    pub required_margin: u64,
+    pub started: NaiveDateTime,
+}
+
+/// Migration of the hedge to another symbol, e.g. from an expiring contract to a new perp.
+/// Orders and the position on the old symbol are closed first, then the hedge is opened on the
+/// new symbol by usual hedging logic.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Schema, Clone)]
+pub struct SymbolMigration {
+    pub from: String,
+    pub to: String,
+    /// Opened orders on the old symbol, `None` until Kollider reports them
+    pub orders: Option<Vec<KolliderOrder>>,
+    /// Position on the old symbol, `None` until Kollider reports it
+    pub position: Option<KolliderPosition>,
+    pub started: NaiveDateTime,
+}
+
+impl SymbolMigration {
+    /// Whether the message of Kollider is about the old symbol
+    fn tracks(&self, symbol: &str) -> bool {
+        self.from == symbol
+    }
+}
+
+/// Fiat value of a channel
+#[derive(Debug, PartialEq, Serialize, Deserialize, Schema, Clone)]
+pub struct ChannelUsd {
+    /// Value at the weighted rate of the channel HTLCs
+    pub usd: f64,
+    /// Value at the current ticker
+    pub usd_at_ticker: Option<f64>,
+}
+
+impl ChannelUsd {
+    pub fn new(hedge: &ChannelHedge, ticker: Option<f64>) -> Self {
+        ChannelUsd {
+            usd: if hedge.rate == 0 {
+                0.0
+            } else {
+                hedge.sats as f64 / hedge.rate as f64
+            },
+            usd_at_ticker: ticker.map(|t| hedge.sats as f64 * t / 100_000_000.0),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Schema, Clone)]
@@ -97,6 +572,25 @@ pub struct KolliderPosition {
     rpnl: f64,
 }
 
+impl KolliderPosition {
+    pub fn new(
+        leverage: u64,
+        entry_value: u64,
+        entry_price: u64,
+        quantity: u64,
+        rpnl: f64,
+    ) -> Self {
+        KolliderPosition {
+            liquidation_price: 0.0,
+            leverage,
+            entry_value,
+            entry_price,
+            quantity,
+            rpnl,
+        }
+    }
+}
+
 impl std::convert::From<Position> for KolliderPosition {
     fn from(pos: Position) -> Self {
         KolliderPosition {
@@ -126,30 +620,587 @@ impl State {
     pub fn new(config: HedgeConfig) -> Self {
         State {
             last_changed: Utc::now().naive_utc(),
+            revision: 0,
             config,
             balance: None,
             ticker: None,
+            ticker_ema: None,
+            index_tickers: HashMap::new(),
             channels_hedge: HashMap::new(),
             opened_orders: None,
             opened_position: None,
             scheduled_actions: vec![],
-            opening_orders: HashMap::new(),
+            orders: HashMap::new(),
+            unhedged_since: None,
+            config_changed: None,
+            startup_config: None,
+            empty_channels: HashMap::new(),
+            channels_usd: HashMap::new(),
+            leverage_migration: None,
+            symbol_migration: None,
+            external_ticker: None,
+            next_chunk_at: None,
+            recent_orders: vec![],
+            over_limit: false,
+            maintenance_end: None,
+            slippage_exceeded: false,
+            clock_skew_ms: None,
+            clock_skew_exceeded: false,
+            drift_since: None,
+            drift_alarm: false,
+            position_drift: None,
+            position_drift_checks: 0,
+            cancelling_orders: HashSet::new(),
+            margin_shortage: false,
+            margin_order_cap: None,
+            planning_error: None,
+            pending_deposits: vec![],
+            withdrawing_sats: 0,
+            staged_updates: vec![],
+            pnl: PnlTracker::default(),
+            products: Products::new(),
+            daily: DailyCounters::default(),
         }
     }
 
     pub fn apply_update(&mut self, update: StateUpdate) -> Result<(), StateUpdateErr> {
-        match update.body {
+        let res = match update.body {
             UpdateBody::Htlc(htlc) => {
+                let chan_id = htlc.channel_id.clone();
                 self.with_htlc(htlc)?;
+                self.track_empty_channel(&chan_id, update.created);
                 self.last_changed = update.created;
                 Ok(())
             }
             UpdateBody::Snapshot(snaphsot) => {
                 self.channels_hedge = snaphsot.channels_hedge;
+                self.empty_channels = self
+                    .channels_hedge
+                    .iter()
+                    .filter(|(_, h)| h.sats == 0)
+                    .map(|(id, _)| (id.clone(), update.created))
+                    .collect();
+                // Exchange data is replaced by fresh Kollider messages after restart
+                if snaphsot.opened_orders.is_some() {
+                    self.opened_orders = snaphsot.opened_orders;
+                }
+                if let Some(position) = snaphsot.opened_position {
+                    self.pnl.last_rpnl = position.rpnl;
+                    self.opened_position = Some(position);
+                }
+                if snaphsot.balance.is_some() {
+                    self.balance = snaphsot.balance;
+                }
+                if let Some(ticker) = snaphsot.ticker {
+                    self.update_ticker(ticker, update.created);
+                }
+                // Config is restored as is, migrations to it are already done
+                self.config.apply_change(&snaphsot.config);
+                if snaphsot.startup_config.is_some() {
+                    self.startup_config = snaphsot.startup_config;
+                }
+                if snaphsot.config_changed.is_some() {
+                    self.config_changed = snaphsot.config_changed;
+                }
+                if let Some(sats) = snaphsot.pending_settlement {
+                    self.pnl.pending_settlement = sats;
+                }
+                if let Some(sats) = snaphsot.withdrawals_sats {
+                    if update.created >= self.daily.since {
+                        self.daily.withdrawals_sats = sats;
+                    }
+                }
+                if let Some(deposits) = snaphsot.pending_deposits {
+                    self.pending_deposits = deposits;
+                }
+                self.last_changed = update.created;
+                Ok(())
+            }
+            UpdateBody::ConfigChange(change) => {
+                let change = if change.startup {
+                    self.startup_change(change)
+                } else {
+                    change
+                };
+                let old_leverage = self.target_leverage();
+                let old_sym = self.config.hedge_sym.clone();
+                self.config.apply_change(&change);
+                self.config_changed = Some(update.created);
+                if old_sym != self.config.hedge_sym {
+                    self.start_symbol_migration(old_sym, update.created);
+                }
+                if old_leverage != self.target_leverage() {
+                    self.start_leverage_migration(old_leverage);
+                }
+                Ok(())
+            }
+            UpdateBody::ChannelsRemoved(removal) => {
+                for chan_id in removal.channel_ids.iter() {
+                    if self
+                        .channels_hedge
+                        .get(chan_id)
+                        .map_or(false, |h| h.sats != 0)
+                    {
+                        warn!("Skipping removal of non empty channel {}", chan_id);
+                        continue;
+                    }
+                    self.channels_hedge.remove(chan_id);
+                    self.empty_channels.remove(chan_id);
+                }
+                self.last_changed = update.created;
+                Ok(())
+            }
+            UpdateBody::OrderRejected(rejection) => {
+                // Next actions calculation reschedules the order with the actual price and the
+                // remaining unhedged amount.
+                if !self.update_order(&rejection.ext_id, OrderEvent::Reject) {
+                    debug!("Rejected order {} is not opening", rejection.ext_id);
+                } else if rejection.reason.contains("InsufficientMargin") {
+                    if let Some(order) = rejection.order.as_ref().filter(|o| o.is_short_order()) {
+                        self.downsize_after_rejection(order.sats);
+                    }
+                }
+                Ok(())
+            }
+            UpdateBody::Correction(correction) => {
+                let chan_id = correction.original.channel_id.clone();
+                let hedge = self
+                    .channels_hedge
+                    .remove(&chan_id)
+                    .unwrap_or(ChannelHedge { sats: 0, rate: 0 });
+                let hedge = hedge.without_htlc(&correction.original)?;
+                if hedge.sats != 0 {
+                    self.channels_hedge.insert(chan_id.clone(), hedge);
+                }
+                if let Some(htlc) = correction.replacement {
+                    self.with_htlc(htlc)?;
+                }
+                self.channels_hedge
+                    .entry(chan_id.clone())
+                    .or_insert(ChannelHedge { sats: 0, rate: 0 });
+                info!(
+                    "Corrected HTLC update of {}: {}",
+                    chan_id, correction.reason
+                );
+                self.track_empty_channel(&chan_id, update.created);
                 self.last_changed = update.created;
                 Ok(())
             }
+            UpdateBody::Withdrawal(withdrawal) => {
+                // Balance is updated by the next balances message from Kollider
+                let counted = update.created >= self.daily.since;
+                if withdrawal.failed {
+                    debug!("Withdrawal of {} sats is not paid", withdrawal.sats);
+                    if counted {
+                        self.daily.withdrawals_sats =
+                            self.daily.withdrawals_sats.saturating_sub(withdrawal.sats);
+                    }
+                } else {
+                    debug!("Withdrawn {} sats from Kollider", withdrawal.sats);
+                    if counted {
+                        self.daily.withdrawals_sats += withdrawal.sats;
+                    }
+                }
+                Ok(())
+            }
+            UpdateBody::Funding(funding) => {
+                info!(
+                    "Funding of {} sats for {} contracts of {}",
+                    funding.sats, funding.position_quantity, funding.symbol
+                );
+                Ok(())
+            }
+            UpdateBody::Settlement(settlement) => {
+                // Kollider resets `rpnl` of the position, the settled PnL is already realized
+                info!(
+                    "Settled {} sats of realized PnL of {}",
+                    settlement.sats, settlement.symbol
+                );
+                self.pnl.last_rpnl = 0.0;
+                self.pnl.pending_settlement += settlement.sats;
+                Ok(())
+            }
+            UpdateBody::Deposit(deposit) => {
+                debug!("Requested deposit of {} sats", deposit.sats);
+                self.pending_deposits.push(PendingDeposit {
+                    sats: deposit.sats,
+                    invoice: deposit.invoice,
+                    created: update.created,
+                });
+                Ok(())
+            }
+        };
+        self.refresh_channels_usd();
+        res
+    }
+
+    /// Part of the config requested at startup that the operator changed since the previous
+    /// startup. Other fields keep the values set at runtime, e.x. by symbol migration.
+    fn startup_change(&mut self, requested: ConfigChange) -> ConfigChange {
+        match self.startup_config.replace(requested.clone()) {
+            Some(prev) => requested.changed_since(&prev),
+            // The first startup sets the whole config
+            None if self.config_changed.is_none() => requested,
+            // Changes recorded before startups were marked can't be told apart from runtime
+            // ones, so the replayed config is kept
+            None => ConfigChange::default(),
+        }
+    }
+
+    /// Schedule reopening of the current position with the new leverage from config
+    fn start_leverage_migration(&mut self, old_leverage: u64) {
+        if self.position_quantity() == 0 {
+            return;
+        }
+        let from = self
+            .leverage_migration
+            .as_ref()
+            .map_or(old_leverage, |m| m.from);
+        let to = self.target_leverage();
+        if from == to {
+            info!("Leverage is changed back to {}, cancel migration", to);
+            self.leverage_migration = None;
+            return;
+        }
+        let required_margin = (self.position_volume() as f64 * 100.0 / to as f64).ceil() as u64;
+        info!(
+            "Migrating position from leverage {} to {}, new position requires {} sats of margin",
+            from, to, required_margin
+        );
+        if let Some(balance) = self.balance {
+            let balance_sats = (balance * 100_000_000.0) as u64;
+            if balance_sats + self.position_volume() < required_margin {
+                warn!(
+                    "Balance {} sats is not enough to reopen position with leverage {}",
+                    balance_sats, to
+                );
+            }
+        }
+        self.leverage_migration = Some(LeverageMigration {
+            from,
+            to,
+            required_margin,
+            started: Utc::now().naive_utc(),
+        });
+    }
+
+    /// Get deviation in percents of Kollider index from the fresh external price
+    pub fn index_deviation(&self) -> Option<f64> {
+        let ext = self.external_ticker.as_ref()?;
+        let age = Utc::now().naive_utc() - ext.updated;
+        if age > chrono::Duration::seconds(EXTERNAL_TICKER_STALE_SECS) || ext.price <= 0.0 {
+            return None;
+        }
+        self.ticker
+            .map(|ticker| 100.0 * (ticker - ext.price).abs() / ext.price)
+    }
+
+    /// Check that Kollider index agrees with the external price feed
+    pub(crate) fn index_trusted(&self) -> bool {
+        if self.config.max_index_deviation <= 0.0 {
+            return true;
+        }
+        match self.index_deviation() {
+            Some(dev) if dev <= self.config.max_index_deviation => true,
+            Some(dev) => {
+                warn!(
+                    "Kollider index {:?} deviates from external price by {:.2}% > {}%, skipping orders",
+                    self.ticker, dev, self.config.max_index_deviation
+                );
+                false
+            }
+            None => {
+                warn!("No fresh external price to cross-check Kollider index, skipping orders");
+                false
+            }
+        }
+    }
+
+    /// Record the measured skew of Kollider clock and raise alert when it leaves the bound
+    pub fn update_clock_skew(&mut self, skew_ms: i64) {
+        self.clock_skew_ms = Some(skew_ms);
+        let max = self.config.max_clock_skew_secs;
+        let exceeded = max > 0 && skew_ms.unsigned_abs() > max * 1000;
+        if exceeded && !self.clock_skew_exceeded {
+            error!(
+                "ALERT: clock of Kollider differs from the local one by {} ms > {} s, orders are held",
+                skew_ms, max
+            );
+        } else if !exceeded && self.clock_skew_exceeded {
+            info!(
+                "Clock skew {} ms is back within bound, resuming orders",
+                skew_ms
+            );
+        }
+        self.clock_skew_exceeded = exceeded;
+    }
+
+    /// Check that timestamps of the service and Kollider can be compared, e.g. for aggregation
+    /// deadlines and order timeouts
+    pub(crate) fn clock_skew_allowed(&self) -> bool {
+        if self.clock_skew_exceeded {
+            warn!(
+                "Clock skew {:?} ms with Kollider is above {} s, skipping orders",
+                self.clock_skew_ms, self.config.max_clock_skew_secs
+            );
+        }
+        !self.clock_skew_exceeded
+    }
+
+    /// Check whether a maintenance window is active. Changes of channels accumulate while the
+    /// window lasts and are hedged after it ends.
+    pub(crate) fn in_maintenance(&mut self) -> bool {
+        let now = Utc::now().naive_utc();
+        let end = MaintenanceStatus::new(&self.config.maintenance_windows, now).until;
+        match (end, self.maintenance_end) {
+            (Some(end), None) => info!("Maintenance window until {}, holding orders", end),
+            (None, Some(_)) => info!("Maintenance window is over, hedging accumulated changes"),
+            _ => (),
+        }
+        self.maintenance_end = end;
+        end.is_some()
+    }
+
+    /// How long we have to wait until the active maintenance window ends
+    pub fn maintenance_timeout(&self) -> Option<std::time::Duration> {
+        self.maintenance_end.map(|end| {
+            (end - Utc::now().naive_utc())
+                .to_std()
+                .unwrap_or_else(|_| std::time::Duration::from_secs(0))
+        })
+    }
+
+    /// Move orders and position of the old symbol to the migration. Orders and position on the
+    /// new symbol are unknown until Kollider reports them.
+    fn start_symbol_migration(&mut self, from: String, started: NaiveDateTime) {
+        let to = self.config.hedge_sym.clone();
+        if let Some(migration) = self.symbol_migration.take() {
+            if migration.from == to {
+                info!("Hedge symbol is changed back to {}, cancel migration", to);
+                self.opened_orders = migration.orders;
+                self.opened_position = migration.position;
+                return;
+            }
+            info!(
+                "Hedge symbol is changed to {} while migrating from {}",
+                to, migration.from
+            );
+            self.symbol_migration = Some(SymbolMigration { to, ..migration });
+            return;
+        }
+        info!("Migrating hedge from {} to {}", from, to);
+        self.symbol_migration = Some(SymbolMigration {
+            from,
+            to,
+            orders: self.opened_orders.take(),
+            position: self.opened_position.take(),
+            started,
+        });
+    }
+
+    /// Cancel orders on the old symbol, then close its position with a market order. New
+    /// orders are held until Kollider reports neither orders nor position on the old symbol.
+    pub(crate) fn migrate_symbol(&mut self) {
+        let migration = match &self.symbol_migration {
+            Some(migration) => migration.clone(),
+            None => return,
+        };
+        let (orders, position) = match (&migration.orders, &migration.position) {
+            (Some(orders), Some(position)) => (orders, position),
+            _ => {
+                debug!("Waiting for orders and position on {}", migration.from);
+                return;
+            }
+        };
+        for order in orders
+            .iter()
+            .filter(|o| !self.cancelling_orders.contains(&o.id))
+        {
+            info!("Cancelling order {} on {}", order.id, migration.from);
+            self.scheduled_actions.push(StateAction::CloseOrder {
+                order_id: order.id,
+                symbol: migration.from.clone(),
+            });
+        }
+        if !orders.is_empty() || self.has_opening_orders() {
+            return;
+        }
+        if position.quantity > 0 {
+            info!(
+                "Closing position of {} contracts on {}",
+                position.quantity, migration.from
+            );
+            self.scheduled_actions.push(StateAction::ClosePosition {
+                ext_id: OpeningOrder::new_id(),
+                symbol: migration.from.clone(),
+                quantity: position.quantity,
+                leverage: position.leverage,
+            });
+            return;
+        }
+        info!(
+            "Hedge is migrated from {} to {}, resuming on the new symbol",
+            migration.from, migration.to
+        );
+        self.symbol_migration = None;
+    }
+
+    /// Finish leverage migration when the old position is fully closed
+    pub(crate) fn check_leverage_migration(&mut self) {
+        if let Some(migration) = &self.leverage_migration {
+            let no_orders = self.opened_orders.as_ref().map_or(false, |o| o.is_empty())
+                && !self.has_opening_orders();
+            if self.position_quantity() == 0 && no_orders {
+                info!(
+                    "Position with leverage {} is closed, reopening with leverage {}",
+                    migration.from, migration.to
+                );
+                self.leverage_migration = None;
+            }
+        }
+    }
+
+    /// Leverage for new orders. Old leverage is used while the position is migrating.
+    pub fn order_leverage(&self) -> u64 {
+        self.leverage_migration
+            .as_ref()
+            .map_or_else(|| self.target_leverage(), |m| m.from)
+    }
+
+    /// Leverage * 100 of the whole hedge that requires the same margin as hedging each channel
+    /// with its own leverage. Changes of channels affect only new orders, the position is
+    /// migrated only when the config is changed.
+    pub fn target_leverage(&self) -> u64 {
+        let (sats, margin) = self.hedged_channels().filter(|(_, h)| h.sats > 0).fold(
+            (0.0, 0.0),
+            |(sats, margin), (id, h)| {
+                let leverage = self.config.leverage_of(id) as f64;
+                (sats + h.sats as f64, margin + h.sats as f64 / leverage)
+            },
+        );
+        if margin <= 0.0 {
+            return self.config.hedge_leverage;
+        }
+        (sats / margin).round() as u64
+    }
+
+    /// Recalculate fiat value of channels
+    fn refresh_channels_usd(&mut self) {
+        let ticker = self.ticker;
+        self.channels_usd = self
+            .channels_hedge
+            .iter()
+            .map(|(id, h)| (id.clone(), ChannelUsd::new(h, ticker)))
+            .collect();
+    }
+
+    /// Remember when the channel balance dropped to zero
+    fn track_empty_channel(&mut self, chan_id: &ChannelId, created: NaiveDateTime) {
+        match self.channels_hedge.get(chan_id) {
+            Some(h) if h.sats == 0 => {
+                self.empty_channels
+                    .entry(chan_id.clone())
+                    .or_insert(created);
+            }
+            _ => {
+                self.empty_channels.remove(chan_id);
+            }
+        }
+    }
+
+    /// Get channels that have zero balance for longer than the retention period
+    pub fn expired_empty_channels(&self, retention: chrono::Duration) -> Vec<ChannelId> {
+        let now = Utc::now().naive_utc();
+        let mut res: Vec<ChannelId> = self
+            .empty_channels
+            .iter()
+            .filter(|(_, since)| now - **since >= retention)
+            .map(|(id, _)| id.clone())
+            .collect();
+        res.sort();
+        res
+    }
+
+    /// Check that updates can be applied in order after the staged ones without changing the
+    /// state, so they are committed to the database before the state is changed. Only HTLCs
+    /// and corrections can fail to apply, other updates are always accepted.
+    pub fn check_updates(&self, updates: &[StateUpdate]) -> Result<(), StateUpdateErr> {
+        self.staged_channels(self.staged_updates.iter().chain(updates))?;
+        Ok(())
+    }
+
+    /// Check updates and keep them staged until they are committed, the lock of the state can
+    /// be released meanwhile. Staged updates are applied in the order they are staged.
+    pub fn stage_updates(&mut self, updates: &[StateUpdate]) -> Result<(), StateUpdateErr> {
+        self.check_updates(updates)?;
+        self.staged_updates.extend_from_slice(updates);
+        Ok(())
+    }
+
+    /// Forget staged updates after their commit either failed or succeeded before they are
+    /// applied
+    pub fn unstage_updates(&mut self, updates: &[StateUpdate]) {
+        for update in updates {
+            if let Some(i) = self.staged_updates.iter().position(|u| u == update) {
+                self.staged_updates.remove(i);
+            }
+        }
+    }
+
+    /// Hedge of the channel after the staged updates are applied
+    pub fn staged_hedge(&self, chan_id: &str) -> Result<Option<ChannelHedge>, StateUpdateErr> {
+        let staged = self.staged_channels(self.staged_updates.iter())?;
+        Ok(match staged.get(chan_id) {
+            Some(chan) => chan.clone(),
+            None => self.channels_hedge.get(chan_id).cloned(),
+        })
+    }
+
+    /// Hedge of channels that are changed by the updates. `None` marks a channel that is
+    /// removed from the state.
+    fn staged_channels<'a>(
+        &'a self,
+        updates: impl Iterator<Item = &'a StateUpdate>,
+    ) -> Result<HashMap<&'a str, Option<ChannelHedge>>, StateUpdateErr> {
+        let mut staged: HashMap<&str, Option<ChannelHedge>> = HashMap::new();
+        let current = |staged: &HashMap<&str, Option<ChannelHedge>>, chan_id: &str| match staged
+            .get(chan_id)
+        {
+            Some(chan) => chan.clone(),
+            None => self.channels_hedge.get(chan_id).cloned(),
+        };
+        let with_htlc = |chan: Option<ChannelHedge>, htlc: &HtlcUpdate| match chan {
+            Some(chan) => chan.with_htlc(htlc.clone()),
+            None => Ok(ChannelHedge {
+                sats: htlc.sats,
+                rate: htlc.rate,
+            }),
+        };
+        for update in updates {
+            match &update.body {
+                UpdateBody::Htlc(htlc) => {
+                    let chan_id = htlc.channel_id.as_str();
+                    let new_chan = with_htlc(current(&staged, chan_id), htlc)?;
+                    staged.insert(chan_id, Some(new_chan));
+                }
+                UpdateBody::Correction(correction) => {
+                    let chan_id = correction.original.channel_id.as_str();
+                    let hedge = current(&staged, chan_id)
+                        .unwrap_or(ChannelHedge { sats: 0, rate: 0 })
+                        .without_htlc(&correction.original)?;
+                    staged.insert(chan_id, Some(hedge).filter(|h| h.sats != 0));
+                    if let Some(htlc) = &correction.replacement {
+                        let new_chan = with_htlc(current(&staged, &htlc.channel_id), htlc)?;
+                        staged.insert(&htlc.channel_id, Some(new_chan));
+                    }
+                    if let Some(chan) = staged.get_mut(chan_id) {
+                        chan.get_or_insert(ChannelHedge { sats: 0, rate: 0 });
+                    }
+                }
+                _ => (),
+            }
         }
+        Ok(staged)
     }
 
     fn with_htlc(&mut self, htlc: HtlcUpdate) -> Result<(), HtlcUpdateErr> {
@@ -167,6 +1218,23 @@ impl State {
         Ok(())
     }
 
+    /// Snapshot of channels, config and the last known state of the exchange
+    pub fn snapshot(&self) -> StateSnapshot {
+        StateSnapshot {
+            channels_hedge: self.channels_hedge.clone(),
+            opened_orders: self.opened_orders.clone(),
+            opened_position: self.opened_position.clone(),
+            balance: self.balance,
+            ticker: self.ticker,
+            config: self.config.recorded_change(),
+            startup_config: self.startup_config.clone(),
+            config_changed: self.config_changed,
+            pending_settlement: Some(self.pnl.pending_settlement),
+            withdrawals_sats: Some(self.daily.withdrawals_sats),
+            pending_deposits: Some(self.pending_deposits.clone()),
+        }
+    }
+
     /// Take ordered chain of updates and collect the accumulated state.
     /// Order should be from the earliest to the latest.
     pub fn collect<I>(config: HedgeConfig, updates: I) -> Result<Self, StateUpdateErr>
@@ -180,14 +1248,47 @@ impl State {
         Ok(state)
     }
 
+    /// Replace opened orders and position with the ones fetched via REST API. The JSON has the
+    /// same shape as the data of websocket messages.
+    pub fn apply_rest_snapshot(
+        &mut self,
+        open_orders: serde_json::Value,
+        positions: serde_json::Value,
+    ) -> Result<(), serde_json::Error> {
+        self.apply_kollider_message(KolliderMsg::Tagged(KolliderTaggedMsg::OpenOrders {
+            open_orders: serde_json::from_value(open_orders)?,
+        }));
+        self.apply_kollider_message(KolliderMsg::Tagged(KolliderTaggedMsg::Positions {
+            positions: serde_json::from_value(positions)?,
+        }));
+        Ok(())
+    }
+
     /// Save information from Kollider WS API, return true fi the state is modified
     pub fn apply_kollider_message(&mut self, msg: KolliderMsg) -> bool {
         if let KolliderMsg::Tagged(tmsg) = msg {
             match tmsg {
                 KolliderTaggedMsg::OpenOrders { open_orders } => {
+                    if let Some(migration) = &mut self.symbol_migration {
+                        let orders = open_orders
+                            .get(migration.from.as_str())
+                            .map_or_else(Vec::new, |orders| {
+                                orders.iter().map(|o| o.clone().into()).collect()
+                            });
+                        migration.orders = Some(orders);
+                    }
                     if let Some(orders) = open_orders.get(self.config.hedge_sym.as_str()) {
-                        let mut res = vec![];
+                        let mut res: Vec<KolliderOrder> = vec![];
                         orders.iter().for_each(|o| res.push(o.clone().into()));
+                        let migrating = self
+                            .symbol_migration
+                            .as_ref()
+                            .and_then(|m| m.orders.as_ref());
+                        self.cancelling_orders.retain(|id| {
+                            res.iter()
+                                .chain(migrating.into_iter().flatten())
+                                .any(|o| o.id == *id)
+                        });
 
                         self.opened_orders = Some(res);
                         return true;
@@ -196,20 +1297,29 @@ impl State {
                     }
                 }
                 KolliderTaggedMsg::Positions { positions } => {
-                    if let Some(position) = positions.get(self.config.hedge_sym.as_str()) {
-                        self.opened_position = Some(position.clone().into());
-                        return true;
-                    } else {
-                        self.opened_position = Some(KolliderPosition {
-                            liquidation_price: 0.0,
-                            leverage: 100,
-                            entry_value: 0,
-                            entry_price: 0,
-                            quantity: 0,
-                            rpnl: 0.0,
-                        });
-                        return true;
+                    if let Some(migration) = &mut self.symbol_migration {
+                        migration.position =
+                            Some(positions.get(migration.from.as_str()).map_or_else(
+                                || KolliderPosition::new(100, 0, 0, 0, 0.0),
+                                |p| p.clone().into(),
+                            ));
                     }
+                    let position =
+                        if let Some(position) = positions.get(self.config.hedge_sym.as_str()) {
+                            position.clone().into()
+                        } else {
+                            KolliderPosition {
+                                liquidation_price: 0.0,
+                                leverage: 100,
+                                entry_value: 0,
+                                entry_price: 0,
+                                quantity: 0,
+                                rpnl: 0.0,
+                            }
+                        };
+                    self.track_pnl(&position);
+                    self.opened_position = Some(position);
+                    return true;
                 }
                 KolliderTaggedMsg::Open {
                     symbol,
@@ -221,6 +1331,15 @@ impl State {
                     side,
                     ..
                 } if symbol == self.config.hedge_sym => {
+                    // Orders that are already received are in the opened orders list
+                    let already_opened = self
+                        .orders
+                        .get(&ext_order_id)
+                        .map_or(false, |o| !o.status.is_pending());
+                    self.update_order(&ext_order_id, OrderEvent::Open { order_id });
+                    if already_opened {
+                        return false;
+                    }
                     let order = KolliderOrder {
                         id: order_id,
                         ext_id: ext_order_id,
@@ -237,34 +1356,128 @@ impl State {
 
                     return true;
                 }
-                KolliderTaggedMsg::Balances { cash, .. } => {
-                    self.balance = Some(cash);
-                    return true;
-                }
-                KolliderTaggedMsg::IndexValues(IndexValue { symbol, value, .. })
-                    if symbol == self.config.hedge_pair =>
-                {
-                    self.ticker = Some(value);
-                    return true;
-                }
-                KolliderTaggedMsg::Received {
-                    order_id,
+                KolliderTaggedMsg::Open {
+                    symbol,
+                    order_id,
+                    ext_order_id,
+                    leverage,
+                    price,
+                    quantity,
+                    side,
+                    ..
+                } if self
+                    .symbol_migration
+                    .as_ref()
+                    .map_or(false, |m| m.tracks(&symbol)) =>
+                {
+                    self.update_order(&ext_order_id, OrderEvent::Open { order_id });
+                    let order = KolliderOrder {
+                        id: order_id,
+                        ext_id: ext_order_id,
+                        leverage,
+                        price,
+                        quantity,
+                        side,
+                    };
+                    if let Some(orders) = self
+                        .symbol_migration
+                        .as_mut()
+                        .and_then(|m| m.orders.as_mut())
+                    {
+                        if !orders.iter().any(|o| o.id == order_id) {
+                            orders.push(order);
+                        }
+                    }
+                    return true;
+                }
+                KolliderTaggedMsg::Fill {
+                    ext_order_id,
+                    quantity,
+                    ..
+                } => {
+                    if self.update_order(&ext_order_id, OrderEvent::Fill { quantity }) {
+                        self.daily.fills += 1;
+                        self.daily.filled_contracts += quantity;
+                    }
+                    let migrating = self
+                        .symbol_migration
+                        .as_mut()
+                        .and_then(|m| m.orders.as_mut());
+                    for orders in self.opened_orders.iter_mut().chain(migrating) {
+                        if let Some(order) = orders.iter_mut().find(|o| o.ext_id == ext_order_id) {
+                            order.quantity = order.quantity.saturating_sub(quantity);
+                        }
+                        orders.retain(|o| o.quantity > 0);
+                    }
+                    return true;
+                }
+                KolliderTaggedMsg::Done { order_id, .. } => {
+                    if let Some(ext_id) = self.order_ext_id(order_id) {
+                        self.update_order(&ext_id, OrderEvent::Done);
+                    }
+                    let migrating = self
+                        .symbol_migration
+                        .as_mut()
+                        .and_then(|m| m.orders.as_mut());
+                    for orders in self.opened_orders.iter_mut().chain(migrating) {
+                        orders.retain(|o| o.id != order_id);
+                    }
+                    self.cancelling_orders.remove(&order_id);
+                    return true;
+                }
+                KolliderTaggedMsg::Balances { cash, .. } => {
+                    if let Some(old) = self.balance {
+                        self.settle_deposits(cash - old);
+                        if cash > old && self.margin_order_cap.take().is_some() {
+                            info!("Kollider balance grew, lifting the limit of order size");
+                        }
+                    }
+                    self.balance = Some(cash);
+                    self.pnl.pending_settlement = 0;
+                    return true;
+                }
+                KolliderTaggedMsg::IndexValues(IndexValue { symbol, value, .. }) => {
+                    let hedged = symbol == self.config.hedge_pair;
+                    self.index_tickers.insert(symbol, value);
+                    if hedged {
+                        self.update_ticker(value, Utc::now().naive_utc());
+                        return true;
+                    }
+                }
+                KolliderTaggedMsg::Received {
+                    order_id,
                     price,
                     quantity,
                     leverage,
                     ext_order_id,
                     ..
                 } => {
-                    if let Some(order) = self.opening_orders.get(&ext_order_id) {
-                        let side = order.side;
-                        self.set_order_opened(KolliderOrder {
-                            id: order_id,
-                            ext_id: ext_order_id,
-                            leverage,
-                            price,
-                            quantity,
-                            side,
-                        });
+                    if let Some(order) = self.orders.get(&ext_order_id) {
+                        let side = order.order.side;
+                        let symbol = order.order.symbol.clone();
+                        let event = OrderEvent::Received { order_id, quantity };
+                        if self.update_order(&ext_order_id, event) {
+                            let order = KolliderOrder {
+                                id: order_id,
+                                ext_id: ext_order_id,
+                                leverage,
+                                price,
+                                quantity,
+                                side,
+                            };
+                            // Orders on the old symbol, e.g. closing of its position
+                            match self.symbol_migration.as_mut().filter(|m| m.tracks(&symbol)) {
+                                Some(migration) => {
+                                    if let Some(orders) = &mut migration.orders {
+                                        orders.push(KolliderOrder {
+                                            side: side.inverse(),
+                                            ..order
+                                        });
+                                    }
+                                }
+                                None => self.set_order_opened(order),
+                            }
+                        }
                         return true;
                     }
                 }
@@ -277,32 +1490,159 @@ impl State {
     /// Calculate total hedge position across all channels
     pub fn total_hedge(&self) -> Result<ChannelHedge, HtlcUpdateErr> {
         let init_hedge = ChannelHedge { sats: 0, rate: 1 };
+        self.hedged_channels()
+            .try_fold(init_hedge, |acc, (_, h)| acc.combine(h))
+    }
+
+    /// Channels which HTLCs are hedged, see `HedgeConfig::unhedged_channels`
+    pub fn hedged_channels(&self) -> impl Iterator<Item = (&ChannelId, &ChannelHedge)> {
         self.channels_hedge
             .iter()
-            .try_fold(init_hedge, |acc, (_, h)| acc.combine(h))
+            .filter(move |(id, _)| self.config.is_hedged(id))
     }
 
     /// Get total amount of sats that we need to hedge at the moment
     pub fn hedge_capacity(&self) -> u64 {
-        self.channels_hedge.iter().map(|(_, v)| v.sats as u64).sum()
+        self.hedged_channels().map(|(_, v)| v.sats as u64).sum()
     }
 
     /// Get average weighted price over all hedged channels
-    pub fn hedge_avg_price(&self) -> Result<u64, HtlcUpdateErr> {
+    pub fn hedge_avg_price(&self) -> Result<u64, NextActionError> {
         let final_hedge = self.total_hedge()?;
-        assert!(
-            final_hedge.rate > 0,
-            "Total rate is negative! Rate: {}",
-            final_hedge.rate
-        );
+        if final_hedge.rate <= 0 {
+            return Err(NextActionError::NonPositiveRate(final_hedge.rate));
+        }
         Ok(final_hedge.rate as u64)
     }
 
+    /// Unhedged sats valued at the current ticker in USD, negative means overhedge
+    pub fn unhedged_usd(&self) -> Option<f64> {
+        let price = self.current_price().filter(|p| *p > 0)?;
+        let unhedged = self.hedge_capacity() as i64 - self.position_volume() as i64;
+        Some(unhedged as f64 / price as f64)
+    }
+
+    /// Track how long the unhedged amount stays above `drift_alarm_usd`. Drifts shorter than
+    /// `drift_alarm_secs` are normal while orders are filled, so the alarm is raised only for a
+    /// hedger that is stuck. Returns the change of the alarm.
+    pub fn check_drift(&mut self, now: NaiveDateTime) -> Option<DriftAlarm> {
+        let max = self.config.drift_alarm_usd;
+        if max <= 0.0 {
+            return None;
+        }
+        let unhedged_usd = self.unhedged_usd()?;
+        if unhedged_usd.abs() <= max {
+            self.drift_since = None;
+            if !self.drift_alarm {
+                return None;
+            }
+            info!(
+                "Unhedged amount {:.2} USD is back within {} USD",
+                unhedged_usd, max
+            );
+            self.drift_alarm = false;
+            return Some(DriftAlarm {
+                raised: false,
+                unhedged_usd,
+                since: None,
+            });
+        }
+        let since = *self.drift_since.get_or_insert(now);
+        let duration = chrono::Duration::seconds(self.config.drift_alarm_secs as i64);
+        if self.drift_alarm || now - since < duration {
+            return None;
+        }
+        error!(
+            "ALERT: unhedged amount {:.2} USD is above {} USD since {}",
+            unhedged_usd, max, since
+        );
+        self.drift_alarm = true;
+        Some(DriftAlarm {
+            raised: true,
+            unhedged_usd,
+            since: Some(since),
+        })
+    }
+
+    /// Sats between the required hedge and the position with open and opening orders, positive
+    /// when the position should grow. `None` until orders and price are known.
+    pub fn calc_position_drift(&self) -> Option<i64> {
+        let short_orders = self.short_orders()? as i64;
+        let long_orders = self.long_orders()? as i64;
+        self.current_price()?;
+        let max = self.config.max_position_sats as i64;
+        let target = match self.hedge_capacity() as i64 {
+            _ if self.leverage_migration.is_some() => 0,
+            hcap if max > 0 => hcap.min(max),
+            hcap => hcap,
+        };
+        let opening = self.opening_shorts() as i64 - self.opening_longs() as i64;
+        let position = self.position_volume() as i64 + short_orders - long_orders + opening;
+        Some(target - position)
+    }
+
+    /// Measure the position drift and count consecutive checks when it is outside of the
+    /// allowed gap. The planner should have closed such gap already, so a growing count means
+    /// that it is stalled. Checks while orders are held on purpose are not counted. Returns the
+    /// count.
+    pub fn check_position_drift(&mut self) -> u32 {
+        self.position_drift = self.calc_position_drift();
+        let outside = match (self.position_drift, self.current_price()) {
+            (Some(drift), Some(price)) => drift.abs() > ALLOWED_POSITION_GAP * price as i64,
+            _ => false,
+        };
+        let held = self.maintenance_end.is_some()
+            || self.clock_skew_exceeded
+            || self.symbol_migration.is_some()
+            || !self.index_trusted();
+        if outside && !held {
+            self.position_drift_checks += 1;
+        } else {
+            self.position_drift_checks = 0;
+        }
+        self.position_drift_checks
+    }
+
     /// Get current price in sats/USD
     pub fn current_price(&self) -> Option<u64> {
         self.ticker.map(|v| (100_000_000.0 / v).round() as u64)
     }
 
+    /// Record new index value and move the moving average towards it
+    pub fn update_ticker(&mut self, value: f64, now: NaiveDateTime) {
+        self.ticker = Some(value);
+        let half_life = self.config.ema_half_life_secs;
+        if half_life > 0 {
+            match &mut self.ticker_ema {
+                Some(ema) => ema.update(value, now, half_life),
+                None => {
+                    self.ticker_ema = Some(TickerEma {
+                        value,
+                        updated: now,
+                    })
+                }
+            }
+        }
+        self.refresh_channels_usd();
+    }
+
+    /// Price of BTC in the reporting currency
+    pub fn reporting_ticker(&self) -> Option<f64> {
+        let pair = self.config.reporting_pair.as_ref()?;
+        self.index_tickers.get(pair).copied()
+    }
+
+    /// Price in sats/USD that orders are priced from. The moving average of the index if it is
+    /// enabled, the last tick otherwise.
+    pub fn order_base_price(&self) -> Option<u64> {
+        match &self.ticker_ema {
+            Some(ema) if self.config.ema_half_life_secs > 0 => {
+                Some((100_000_000.0 / ema.value).round() as u64)
+            }
+            _ => self.current_price(),
+        }
+    }
+
     /// Get total amount of sats that we request for short positions (buying stables)
     pub fn short_orders(&self) -> Option<u64> {
         self.opened_orders.as_ref().map(|orders| {
@@ -345,29 +1685,19 @@ impl State {
 
     /// Get total amount of sats we are placing to the Kollider right now short position (buying stable)
     pub fn opening_shorts(&self) -> u64 {
-        self.opening_orders
-            .iter()
-            .filter_map(|(_, a)| {
-                if a.is_short_order() {
-                    Some(a.sats)
-                } else {
-                    None
-                }
-            })
+        self.orders
+            .values()
+            .filter(|o| o.status.is_pending() && o.order.is_short_order())
+            .map(|o| o.order.sats)
             .sum()
     }
 
     /// Get total amount of sats we are placing to the Kollider right now into long position (selling stables)
     pub fn opening_longs(&self) -> u64 {
-        self.opening_orders
-            .iter()
-            .filter_map(|(_, a)| {
-                if a.is_long_order() {
-                    Some(a.sats)
-                } else {
-                    None
-                }
-            })
+        self.orders
+            .values()
+            .filter(|o| o.status.is_pending() && o.order.is_long_order())
+            .map(|o| o.order.sats)
             .sum()
     }
 
@@ -376,19 +1706,195 @@ impl State {
         self.opened_position.as_ref().map_or(0, |p| p.entry_value)
     }
 
+    /// Funding payment or settlement of realized PnL revealed by the Kollider message, which
+    /// should be recorded before the message is applied. Kollider doesn't report them
+    /// separately.
+    pub fn balance_event(&self, msg: &KolliderMsg) -> Option<UpdateBody> {
+        match msg {
+            KolliderMsg::Tagged(KolliderTaggedMsg::Positions { positions }) => {
+                let position = positions.get(self.config.hedge_sym.as_str())?.clone();
+                self.settlement_event(&position.into())
+            }
+            KolliderMsg::Tagged(KolliderTaggedMsg::Balances { cash, .. }) => {
+                self.funding_event(*cash)
+            }
+            _ => None,
+        }
+    }
+
+    /// Kollider settles realized PnL by resetting `rpnl` of the unchanged position
+    fn settlement_event(&self, position: &KolliderPosition) -> Option<UpdateBody> {
+        let prev = self.opened_position.as_ref().filter(|p| p.quantity > 0)?;
+        if position.quantity == prev.quantity && position.rpnl == 0.0 && prev.rpnl != 0.0 {
+            Some(UpdateBody::Settlement(PnlSettlement {
+                symbol: self.config.hedge_sym.clone(),
+                sats: prev.rpnl.round() as i64,
+            }))
+        } else {
+            None
+        }
+    }
+
+    /// Change of balance while no orders or deposits are in flight is funding of the position
+    fn funding_event(&self, cash: f64) -> Option<UpdateBody> {
+        let prev = self.opened_position.as_ref().filter(|p| p.quantity > 0)?;
+        let in_flight = !self.pending_deposits.is_empty()
+            || !self.cancelling_orders.is_empty()
+            || self.opened_orders.as_ref().map_or(false, |o| !o.is_empty())
+            || self.orders.values().any(|o| o.status.is_pending());
+        if in_flight {
+            return None;
+        }
+        let change = ((cash - self.balance?) * 100_000_000.0).round() as i64;
+        let sats = change - self.pnl.pending_settlement;
+        if sats == 0 {
+            return None;
+        }
+        Some(UpdateBody::Funding(FundingPayment {
+            symbol: self.config.hedge_sym.clone(),
+            sats,
+            position_quantity: prev.quantity,
+        }))
+    }
+
     /// Get amount of usd locked in the position
     pub fn position_quantity(&self) -> u64 {
         self.opened_position.as_ref().map_or(0, |p| p.quantity)
     }
 
+    /// Accumulate changes of `rpnl` and entry price before the position is replaced
+    fn track_pnl(&mut self, position: &KolliderPosition) {
+        let prev = self.opened_position.as_ref().filter(|p| p.quantity > 0);
+        if position.quantity == 0 {
+            // Kollider forgets the position when it is closed
+            self.pnl.last_rpnl = 0.0;
+            self.pnl.entry_price_delta = 0;
+            return;
+        }
+        match prev {
+            Some(prev) => {
+                self.pnl.realized += position.rpnl - self.pnl.last_rpnl;
+                self.pnl.entry_price_delta = position.entry_price as i64 - prev.entry_price as i64;
+            }
+            None => {
+                self.pnl.realized += position.rpnl;
+                self.pnl.entry_price_delta = 0;
+            }
+        }
+        self.pnl.last_rpnl = position.rpnl;
+    }
+
+    /// Sats locked as margin of the opened position
+    pub fn position_margin(&self) -> u64 {
+        self.opened_position
+            .as_ref()
+            .filter(|p| p.leverage > 0)
+            .map_or(0, |p| p.entry_value * 100 / p.leverage)
+    }
+
+    /// Price of BTC in USD at which the short position is liquidated. Reported by Kollider or
+    /// estimated as bankruptcy price from the entry price and leverage. `None` if there is no
+    /// position or it can't be liquidated with leverage 1x.
+    pub fn liquidation_price(&self) -> Option<f64> {
+        let position = self.opened_position.as_ref().filter(|p| p.quantity > 0)?;
+        if position.liquidation_price > 0.0 {
+            return Some(position.liquidation_price);
+        }
+        let leverage = position.leverage as f64 / 100.0;
+        if leverage <= 1.0 {
+            return None;
+        }
+        Some(position.entry_price as f64 * leverage / (leverage - 1.0))
+    }
+
+    /// Mark-to-market PnL of the short position at the current ticker in sats
+    pub fn unrealized_pnl(&self) -> Option<f64> {
+        let position = self.opened_position.as_ref()?;
+        let ticker = self.ticker?;
+        if position.quantity == 0 || position.entry_price == 0 || ticker <= 0.0 {
+            return Some(0.0);
+        }
+        let quantity = position.quantity as f64;
+        Some(quantity * 100_000_000.0 * (1.0 / ticker - 1.0 / position.entry_price as f64))
+    }
+
     /// Remember that the order is now opening
     pub fn add_opening_order(&mut self, order: OpeningOrder) {
-        self.opening_orders.insert(order.ext_id.clone(), order);
+        let ext_id = order.ext_id.clone();
+        let mut tracked = TrackedOrder::new(order);
+        if let Err(e) = tracked.apply(OrderEvent::Sent) {
+            warn!("{}", e);
+        }
+        self.orders.insert(ext_id, tracked);
+        self.daily.orders_placed += 1;
+    }
+
+    /// Get counters for the daily report and start the next reporting period
+    pub fn take_daily_counters(&mut self) -> DailyCounters {
+        std::mem::take(&mut self.daily)
+    }
+
+    /// Whether there are orders that are sent, but not confirmed by the Kollider
+    pub fn has_opening_orders(&self) -> bool {
+        self.orders.values().any(|o| o.status.is_pending())
+    }
+
+    /// Find external id of tracked order by the Kollider id
+    pub fn order_ext_id(&self, order_id: u64) -> Option<String> {
+        self.orders
+            .iter()
+            .find(|(_, o)| o.order_id == Some(order_id))
+            .map(|(ext_id, _)| ext_id.clone())
+    }
+
+    /// Move tracked order to the next status. Orders in final status are forgotten. Returns
+    /// `true` if the order was changed.
+    pub fn update_order(&mut self, ext_id: &str, event: OrderEvent) -> bool {
+        let tracked = match self.orders.get_mut(ext_id) {
+            Some(tracked) => tracked,
+            None => {
+                debug!("Got {:?} for unknown order {}", event, ext_id);
+                return false;
+            }
+        };
+        match tracked.apply(event) {
+            Ok(true) => {
+                debug!("Order {} is {:?}", ext_id, tracked.status);
+                if tracked.status.is_final() {
+                    self.orders.remove(ext_id);
+                }
+                true
+            }
+            Ok(false) => false,
+            Err(e) => {
+                warn!("{}", e);
+                false
+            }
+        }
+    }
+
+    /// Remove opening orders that weren't confirmed by the Kollider within the timeout. That
+    /// happens when a websocket message is lost, so we forget the order and hedge again.
+    pub fn evict_stale_opening_orders(&mut self, timeout: chrono::Duration) -> Vec<OpeningOrder> {
+        let now = Utc::now().naive_utc();
+        let stale: Vec<String> = self
+            .orders
+            .iter()
+            .filter(|(_, o)| o.status.is_pending() && now - o.updated > timeout)
+            .map(|(ext_id, _)| ext_id.clone())
+            .collect();
+        stale
+            .into_iter()
+            .filter_map(|ext_id| {
+                let order = self.orders.get(&ext_id).map(|o| o.order.clone());
+                self.update_order(&ext_id, OrderEvent::Cancel);
+                order
+            })
+            .collect()
     }
 
     /// Resolve that the order is now opened on the Kollider
     pub fn set_order_opened(&mut self, mut order: KolliderOrder) {
-        self.opening_orders.remove(&order.ext_id);
         order.side = order.side.inverse();
         if let Some(ref mut orders) = self.opened_orders {
             orders.push(order);
@@ -397,75 +1903,433 @@ impl State {
         }
     }
 
-    /// Return actions that we need to execute based on current state of service
-    ///
-    /// TODO: React to situation when we have Bid and Ask orders that negate each other.
+    /// Return actions that we need to execute based on current state of service. The actions
+    /// are decided by the strategy from the config.
     pub fn calculate_next_actions(&mut self) -> Result<(), NextActionError> {
-        trace!("Calculation if we need to open new order");
-        if let (Some(short_orders), Some(long_orders), Some(cur_price)) = (
-            self.short_orders(),
-            self.long_orders(),
-            self.current_price(),
-        ) {
-            let hcap = self.hedge_capacity() as i64;
-            let scheduled_shorts = self.scheduled_shorts() as i64;
-            let scheduled_longs = self.scheduled_longs() as i64;
-            let opening_shorts = self.opening_shorts() as i64;
-            let opening_longs = self.opening_longs() as i64;
-            let pos_volume: i64 = self.position_volume() as i64;
-            let pos_short = pos_volume + short_orders as i64 + scheduled_shorts + opening_shorts;
-            let pos_long = pos_volume - long_orders as i64 - scheduled_longs - opening_longs;
-            let gap = ALLOWED_POSITION_GAP * cur_price as i64;
-            trace!("hcap {} > pos_short {} + gap {}", hcap, pos_short, gap);
-            trace!("hcap {} < pos_long {} - gap {}", hcap, pos_long, gap);
-            if hcap > pos_short + gap {
-                debug!(
-                    "Decided to open short position as hcap {} > pos_short {} + gap {}",
-                    hcap, pos_short, gap
-                );
-                let price = (cur_price as f64 * (1.0 + 0.01 * self.config.spread_percent)).round() as u64;
-                debug!("Current price {}, price of order {}", cur_price, price);
-                assert!(
-                    pos_short <= hcap,
-                    "Sats overflow in order opening: {} <= {}",
-                    pos_short,
-                    hcap
-                );
-                let action = StateAction::OpenOrder(OpeningOrder {
-                    ext_id: OpeningOrder::new_id(),
-                    symbol: self.config.hedge_sym.clone(),
-                    sats: (hcap - pos_short) as u64,
-                    price,
-                    side: OrderSide::Bid,
-                    leverage: self.config.hedge_leverage,
-                });
-                self.scheduled_actions.push(action);
-            } else if hcap < pos_long - gap {
-                debug!(
-                    "Decided to close position as hcap {} < pos_long {} - gap {}",
-                    hcap, pos_long, gap
+        let strategy = self.config.strategy.strategy();
+        self.calculate_next_actions_with(strategy.as_ref())
+    }
+
+    /// Return actions that we need to execute based on the given strategy
+    pub fn calculate_next_actions_with<S: HedgeStrategy + ?Sized>(
+        &mut self,
+        strategy: &S,
+    ) -> Result<(), NextActionError> {
+        strategy.next_actions(self)
+    }
+
+    /// Cap required hedge by `max_position_sats` and raise alert when the limit is reached
+    pub(crate) fn limit_hedge_capacity(&mut self, hcap: i64) -> i64 {
+        let max = self.config.max_position_sats as i64;
+        let over_limit = max > 0 && hcap > max;
+        if over_limit && !self.over_limit {
+            error!(
+                "ALERT: required hedge {} sats exceeds position limit {} sats, position is capped",
+                hcap, max
+            );
+        } else if !over_limit && self.over_limit {
+            info!("Required hedge {} sats is back under position limit", hcap);
+        }
+        self.over_limit = over_limit;
+        if over_limit {
+            max
+        } else {
+            hcap
+        }
+    }
+
+    /// Check that the order price deviates from the index by no more than
+    /// `max_slippage_percent` and raise alert when the order is refused
+    pub(crate) fn slippage_allowed(&mut self, price: u64) -> bool {
+        let max = self.config.max_slippage_percent;
+        if max <= 0.0 {
+            return true;
+        }
+        let index = match self.current_price() {
+            Some(index) if index > 0 => index,
+            _ => return false,
+        };
+        let slippage = 100.0 * (price as f64 - index as f64).abs() / index as f64;
+        if slippage > max {
+            if !self.slippage_exceeded {
+                error!(
+                    "ALERT: order price {} sats/USD deviates from index {} sats/USD by {:.2}% > {}%, order is not placed",
+                    price, index, slippage, max
                 );
-                let price = (cur_price as f64 * (1.0 - 0.01 * self.config.spread_percent)).round() as u64;
-                debug!("Current price {}, price of order {}", cur_price, price);
-                assert!(
-                    hcap <= pos_long,
-                    "Sats overflow in order opening: {} <= {}",
-                    hcap,
-                    pos_long
+            }
+            self.slippage_exceeded = true;
+            false
+        } else {
+            if self.slippage_exceeded {
+                info!(
+                    "Order price {} sats/USD is back within slippage bound",
+                    price
                 );
-                let action = StateAction::OpenOrder(OpeningOrder {
+            }
+            self.slippage_exceeded = false;
+            true
+        }
+    }
+
+    /// Cancel resting Bid and Ask orders that negate each other, smallest first, for the
+    /// overlapping amount on both sides. Returns `true` while such cancels are in progress, so
+    /// the orders are not counted into the position.
+    pub(crate) fn net_opposing_orders(&mut self) -> bool {
+        let orders: Vec<&KolliderOrder> = self
+            .opened_orders
+            .iter()
+            .flatten()
+            .filter(|o| !self.cancelling_orders.contains(&o.id))
+            .collect();
+        let side_sats = |side: OrderSide| -> u64 {
+            orders
+                .iter()
+                .filter(|o| o.side == side)
+                .map(|o| o.required_margin())
+                .sum()
+        };
+        let overlap = std::cmp::min(side_sats(OrderSide::Bid), side_sats(OrderSide::Ask));
+        let mut to_cancel = vec![];
+        if overlap > 0 {
+            for side in [OrderSide::Bid, OrderSide::Ask] {
+                let mut side_orders: Vec<&&KolliderOrder> =
+                    orders.iter().filter(|o| o.side == side).collect();
+                side_orders.sort_by_key(|o| o.required_margin());
+                let mut cancelled = 0;
+                for order in side_orders {
+                    if cancelled + order.required_margin() > overlap {
+                        break;
+                    }
+                    cancelled += order.required_margin();
+                    to_cancel.push(order.id);
+                }
+            }
+            info!(
+                "Opened Bid and Ask orders negate each other by {} sats, cancelling orders {:?}",
+                overlap, to_cancel
+            );
+        }
+        for order_id in to_cancel.iter() {
+            self.scheduled_actions.push(StateAction::CloseOrder {
+                order_id: *order_id,
+                symbol: self.config.hedge_sym.clone(),
+            });
+        }
+        !to_cancel.is_empty()
+            || self
+                .opened_orders
+                .iter()
+                .flatten()
+                .any(|o| self.cancelling_orders.contains(&o.id))
+    }
+
+    /// All channels are empty, so cancel orders that increase the position and close the
+    /// residual position completely, even if it is smaller than the allowed gap.
+    pub(crate) fn close_residual_position(&mut self, price: u64) {
+        self.unhedged_since = None;
+        self.next_chunk_at = None;
+        let to_cancel: Vec<u64> = self
+            .opened_orders
+            .iter()
+            .flatten()
+            .filter(|o| o.side == OrderSide::Ask && !self.cancelling_orders.contains(&o.id))
+            .map(|o| o.id)
+            .collect();
+        for order_id in to_cancel {
+            info!("No channels to hedge, cancelling order {}", order_id);
+            self.scheduled_actions.push(StateAction::CloseOrder {
+                order_id,
+                symbol: self.config.hedge_sym.clone(),
+            });
+        }
+        let closing =
+            self.long_orders().unwrap_or(0) + self.scheduled_longs() + self.opening_longs();
+        let quantity = self.position_quantity();
+        if quantity > 0 && closing == 0 && self.slippage_allowed(price) {
+            info!(
+                "No channels to hedge, closing residual position of {} USD",
+                quantity
+            );
+            self.scheduled_actions.push(StateAction::ClosePosition {
+                ext_id: OpeningOrder::new_id(),
+                symbol: self.config.hedge_sym.clone(),
+                quantity,
+                leverage: self.order_leverage(),
+            });
+        }
+    }
+
+    /// Remove pending deposits that are covered by the increase of balance in BTC, oldest first.
+    /// Expired deposits are removed too.
+    fn settle_deposits(&mut self, increase: f64) {
+        let mut increase = (increase * 100_000_000.0).round() as i64;
+        let now = Utc::now().naive_utc();
+        self.pending_deposits.retain(|d| {
+            if increase >= d.sats as i64 {
+                info!("Deposit of {} sats is paid", d.sats);
+                increase -= d.sats as i64;
+                self.daily.deposits_sats += d.sats;
+                false
+            } else if now - d.created > chrono::Duration::seconds(DEPOSIT_EXPIRY_SECS) {
+                warn!("Deposit of {} sats is expired", d.sats);
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    /// Margin committed by orders and the position and the balance left for new orders
+    pub fn margin_ledger(&self) -> MarginLedger {
+        let balance = self.balance.map(|b| (b * 100_000_000.0).floor() as u64);
+        let opening_orders: u64 = self
+            .orders
+            .values()
+            .filter(|o| o.status.is_pending() && o.order.is_short_order())
+            .map(|o| o.order.required_margin())
+            .sum();
+        let scheduled_orders: u64 = self
+            .scheduled_actions
+            .iter()
+            .filter_map(|a| match a {
+                StateAction::OpenOrder(o) if o.is_short_order() => Some(o.required_margin()),
+                _ => None,
+            })
+            .sum();
+        let open_orders = self
+            .opened_orders
+            .iter()
+            .flatten()
+            .map(|o| o.required_margin())
+            .sum();
+        let buffer = self.config.margin_buffer_sats;
+        MarginLedger {
+            balance,
+            opening_orders,
+            scheduled_orders,
+            open_orders,
+            position: self.position_margin(),
+            buffer,
+            available: balance
+                .map(|b| b.saturating_sub(opening_orders + scheduled_orders + buffer)),
+        }
+    }
+
+    /// Get sats on the Kollider balance that are not reserved by opening and scheduled orders
+    pub fn free_margin(&self) -> Option<u64> {
+        let ledger = self.margin_ledger();
+        ledger
+            .balance
+            .map(|b| b.saturating_sub(ledger.opening_orders + ledger.scheduled_orders))
+    }
+
+    /// Get sats that can be withdrawn from Kollider keeping `buffer` sats of free margin.
+    /// Withdrawals in progress are not excess.
+    pub fn excess_margin(&self, buffer: u64) -> Option<u64> {
+        self.free_margin()
+            .map(|free| free.saturating_sub(buffer + self.withdrawing_sats))
+    }
+
+    /// Halve orders that increase the position after Kollider rejected one of the size for
+    /// insufficient margin. Orders below `min_downsize_sats` are not placed until the balance
+    /// grows.
+    fn downsize_after_rejection(&mut self, sats: u64) {
+        let halved = sats / 2;
+        let floor = self.config.min_downsize_sats;
+        self.margin_shortage = true;
+        if floor == 0 || halved < floor {
+            error!(
+                "ALERT: order of {} sats is rejected for insufficient margin on Kollider, not retrying below {} sats",
+                sats, floor
+            );
+            self.margin_order_cap = Some(0);
+        } else {
+            error!(
+                "ALERT: order of {} sats is rejected for insufficient margin on Kollider, retrying with {} sats",
+                sats, halved
+            );
+            self.margin_order_cap = Some(halved);
+        }
+    }
+
+    /// Downsize order that increases the position to the free margin minus the buffer and raise
+    /// alert if the balance is not enough. Unknown balance doesn't limit the order.
+    fn fit_to_margin(&mut self, sats: u64, leverage: u64) -> u64 {
+        let sats = self.margin_order_cap.map_or(sats, |cap| sats.min(cap));
+        let free = match self.margin_ledger().available {
+            Some(free) => free,
+            None => return sats,
+        };
+        let affordable = free * leverage / 100;
+        if sats <= affordable {
+            self.margin_shortage = self.margin_order_cap.is_some();
+            return sats;
+        }
+        if !self.margin_shortage {
+            error!(
+                "ALERT: insufficient margin on Kollider, order of {} sats requires {} sats, free {} sats. Downsizing to {} sats",
+                sats,
+                sats * 100 / leverage,
+                free,
+                affordable
+            );
+        }
+        self.margin_shortage = true;
+        affordable
+    }
+
+    /// Schedule orders for the given amount of sats. Amounts above `max_order_sats` are split
+    /// into chunks that are placed at once or one by one each `order_chunk_secs`.
+    pub(crate) fn schedule_orders(
+        &mut self,
+        sats: u64,
+        price: u64,
+        side: OrderSide,
+        leverage: u64,
+    ) {
+        let now = Utc::now().naive_utc();
+        if let Some(next) = self.next_chunk_at {
+            if now < next {
+                trace!("Waiting until {} to place next order chunk", next);
+                return;
+            }
+            self.next_chunk_at = None;
+        }
+        let slots = self.order_slots(now);
+        if slots == Some(0) {
+            trace!(
+                "Order limit per minute is reached, accumulating {} sats",
+                sats
+            );
+            return;
+        }
+        if !self.slippage_allowed(price) {
+            return;
+        }
+        let sats = if side == OrderSide::Bid {
+            self.fit_to_margin(sats, leverage)
+        } else {
+            sats
+        };
+        if sats == 0 {
+            return;
+        }
+        let max_sats = self.config.max_order_sats;
+        let mut chunks = if max_sats == 0 || sats <= max_sats {
+            vec![sats]
+        } else if self.config.order_chunk_secs > 0 {
+            self.next_chunk_at =
+                Some(now + chrono::Duration::seconds(self.config.order_chunk_secs as i64));
+            vec![max_sats]
+        } else {
+            let mut chunks = vec![max_sats; (sats / max_sats) as usize];
+            if sats % max_sats > 0 {
+                chunks.push(sats % max_sats);
+            }
+            chunks
+        };
+        if let Some(slots) = slots {
+            chunks.truncate(slots);
+        }
+        if chunks.len() > 1 || chunks[0] < sats {
+            debug!("Splitting order of {} sats into chunks {:?}", sats, chunks);
+        }
+        let spec = ProductSpec::find(&self.products, &self.config.hedge_sym);
+        for chunk in chunks {
+            if let Err(e) = spec.quantity(chunk, price) {
+                warn!("Order of {} sats is not placed: {}", chunk, e);
+                continue;
+            }
+            if self.config.max_orders_per_minute > 0 {
+                self.recent_orders.push(now);
+            }
+            self.scheduled_actions
+                .push(StateAction::OpenOrder(OpeningOrder {
                     ext_id: OpeningOrder::new_id(),
                     symbol: self.config.hedge_sym.clone(),
-                    sats: (pos_long - hcap) as u64,
+                    sats: chunk,
                     price,
-                    side: OrderSide::Ask,
-                    leverage: self.config.hedge_leverage,
-                });
-                self.scheduled_actions.push(action);
-            }
+                    side,
+                    leverage,
+                }));
         }
+    }
 
-        Ok(())
+    /// How long we have to wait before the next chunk of a large order can be placed
+    pub fn chunk_timeout(&self) -> Option<std::time::Duration> {
+        self.next_chunk_at.map(|next| {
+            (next - Utc::now().naive_utc())
+                .to_std()
+                .unwrap_or_else(|_| std::time::Duration::from_secs(0))
+        })
+    }
+
+    /// Amount of orders that can be placed now without exceeding `max_orders_per_minute`.
+    /// Returns `None` if there is no limit.
+    fn order_slots(&mut self, now: NaiveDateTime) -> Option<usize> {
+        let limit = self.config.max_orders_per_minute;
+        if limit == 0 {
+            self.recent_orders.clear();
+            return None;
+        }
+        self.prune_recent_orders(now);
+        Some((limit as usize).saturating_sub(self.recent_orders.len()))
+    }
+
+    /// Forget orders that were placed outside of the throttle window
+    fn prune_recent_orders(&mut self, now: NaiveDateTime) {
+        let minute_ago = now - chrono::Duration::minutes(1);
+        self.recent_orders.retain(|at| *at > minute_ago);
+    }
+
+    /// How long we have to wait until the limit of orders per minute frees a slot
+    pub fn throttle_timeout(&mut self) -> Option<std::time::Duration> {
+        let limit = self.config.max_orders_per_minute;
+        if limit == 0 {
+            return None;
+        }
+        let now = Utc::now().naive_utc();
+        self.prune_recent_orders(now);
+        if (self.recent_orders.len() as u64) < limit {
+            return None;
+        }
+        // Pruned entries are inside of the window, so the slot is freed in the future
+        self.recent_orders.iter().min().map(|first| {
+            (*first + chrono::Duration::minutes(1) - now)
+                .to_std()
+                .unwrap_or_default()
+                .max(std::time::Duration::from_millis(1))
+        })
+    }
+
+    /// Check whether the unhedged amount is large or old enough to place an order for it.
+    /// Resets aggregation timer when the check passes.
+    pub(crate) fn aggregation_passed(&mut self, unhedged: u64) -> bool {
+        if unhedged >= self.config.min_htlc_aggregate {
+            self.unhedged_since = None;
+            return true;
+        }
+        let now = Utc::now().naive_utc();
+        let since = *self.unhedged_since.get_or_insert(now);
+        let passed =
+            now - since >= chrono::Duration::seconds(self.config.htlc_aggregation_secs as i64);
+        if passed {
+            self.unhedged_since = None;
+        } else {
+            trace!("Accumulating {} unhedged sats since {}", unhedged, since);
+        }
+        passed
+    }
+
+    /// How long we can wait before accumulated small unhedged amount has to be hedged
+    pub fn aggregation_timeout(&self) -> Option<std::time::Duration> {
+        self.unhedged_since.map(|since| {
+            let deadline =
+                since + chrono::Duration::seconds(self.config.htlc_aggregation_secs as i64);
+            (deadline - Utc::now().naive_utc())
+                .to_std()
+                .unwrap_or_else(|_| std::time::Duration::from_secs(0))
+        })
     }
 
     /// After action was executed we can update state to save required information. E.x.
@@ -473,7 +2337,26 @@ impl State {
     pub fn finalize_action(&mut self, action: &StateAction) {
         match action {
             StateAction::OpenOrder(order) => self.add_opening_order(order.clone()),
-            StateAction::CloseOrder { .. } => (),
+            StateAction::CloseOrder { order_id, .. } => {
+                self.cancelling_orders.insert(*order_id);
+            }
+            // Tracked as a closing order at the current price until Kollider fills it
+            StateAction::ClosePosition {
+                ext_id,
+                symbol,
+                quantity,
+                leverage,
+            } => {
+                let price = self.current_price().unwrap_or(0);
+                self.add_opening_order(OpeningOrder {
+                    ext_id: ext_id.clone(),
+                    symbol: symbol.clone(),
+                    sats: quantity * price,
+                    price,
+                    side: OrderSide::Ask,
+                    leverage: *leverage,
+                });
+            }
         }
     }
 }
@@ -487,7 +2370,18 @@ impl Default for State {
 #[derive(Debug, Serialize, Deserialize, Schema, PartialEq, Clone)]
 pub enum StateAction {
     OpenOrder(OpeningOrder),
-    CloseOrder { order_id: u64, symbol: String },
+    CloseOrder {
+        order_id: u64,
+        symbol: String,
+    },
+    /// Buy back the amount of contracts of the short position with a market order, so nothing
+    /// is left resting in the order book
+    ClosePosition {
+        ext_id: String,
+        symbol: String,
+        quantity: u64,
+        leverage: u64,
+    },
 }
 
 #[derive(Debug, Serialize, Deserialize, Schema, PartialEq, Clone)]
@@ -514,6 +2408,7 @@ impl StateAction {
     pub fn is_long_order(&self) -> bool {
         match self {
             StateAction::OpenOrder(OpeningOrder { side, .. }) => *side == OrderSide::Ask,
+            StateAction::ClosePosition { .. } => true,
             _ => false,
         }
     }
@@ -526,8 +2421,13 @@ impl StateAction {
         }
     }
 
-    /// Convert action to kollider messages that we need to send
-    pub fn to_kollider_messages(&self) -> Vec<KolliderMsg> {
+    /// Convert action to kollider messages that we need to send. Price and quantity are
+    /// rounded by the spec of the product.
+    pub fn to_kollider_messages(
+        &self,
+        products: &Products,
+        settlement: Settlement,
+    ) -> Result<Vec<KolliderMsg>, ProductErr> {
         match self {
             StateAction::OpenOrder(OpeningOrder {
                 ext_id,
@@ -537,19 +2437,12 @@ impl StateAction {
                 side,
                 leverage,
             }) => {
-                // FIX SOME TIME LATER
-                let mut usd_price = 0;
-                if(symbol == "BTCUSD.PERP") {
-                    usd_price = 10 * 100_000_000 / price;
-                    log::debug!("Price {} 10*USD/BTC", usd_price);
-                } else {
-                    usd_price = 100_000_000 / price;
-                    log::debug!("Price {} 10*USD/BTC", usd_price);
-                }
-                // END
-                let quantity = (*sats as f64 / *price as f64).ceil() as u64;
+                let spec = ProductSpec::find(products, symbol);
+                let usd_price = spec.wire_price(*price)?;
+                log::debug!("Price {} USD/BTC * 10^{}", usd_price, spec.price_dp);
+                let quantity = spec.quantity(*sats, *price)?;
                 log::debug!("Quantity {}", quantity);
-                vec![KolliderMsg::Order {
+                Ok(vec![KolliderMsg::Order {
                     _type: OrderTag::Tag,
                     price: usd_price,
                     quantity,
@@ -558,18 +2451,34 @@ impl StateAction {
                     side: side.inverse(),
                     margin_type: MarginType::Isolated,
                     order_type: OrderType::Limit,
-                    settlement_type: SettlementType::Delayed,
+                    settlement_type: settlement.settlement_type(),
                     ext_order_id: ext_id.clone(),
-                }]
-            }
-            StateAction::CloseOrder { order_id, symbol } => {
-                vec![KolliderMsg::CancelOrder {
-                    _type: CancelOrderTag::Tag,
-                    order_id: *order_id,
-                    symbol: symbol.clone(),
-                    settlement_type: SettlementType::Delayed,
-                }]
+                }])
             }
+            StateAction::CloseOrder { order_id, symbol } => Ok(vec![KolliderMsg::CancelOrder {
+                _type: CancelOrderTag::Tag,
+                order_id: *order_id,
+                symbol: symbol.clone(),
+                settlement_type: settlement.settlement_type(),
+            }]),
+            // Market orders are executed at the best prices of the book, so the price is ignored
+            StateAction::ClosePosition {
+                ext_id,
+                symbol,
+                quantity,
+                leverage,
+            } => Ok(vec![KolliderMsg::Order {
+                _type: OrderTag::Tag,
+                price: 0,
+                quantity: *quantity,
+                symbol: symbol.clone(),
+                leverage: *leverage,
+                side: OrderSide::Bid,
+                margin_type: MarginType::Isolated,
+                order_type: OrderType::Market,
+                settlement_type: settlement.settlement_type(),
+                ext_order_id: ext_id.clone(),
+            }]),
         }
     }
 }
@@ -584,6 +2493,11 @@ impl OpeningOrder {
 }
 
 impl OpeningOrder {
+    /// Sats that Kollider locks as margin for the order
+    pub fn required_margin(&self) -> u64 {
+        (self.sats as f64 * 100.0 / self.leverage as f64).ceil() as u64
+    }
+
     /// Buying stable, selling sats
     pub fn is_short_order(&self) -> bool {
         self.side == OrderSide::Bid
@@ -599,11 +2513,73 @@ impl OpeningOrder {
 pub enum NextActionError {
     #[error("Total hedge position calculation error: {0}")]
     TotalHedge(#[from] HtlcUpdateErr),
+    #[error("Average rate of channels is not positive: {0}")]
+    NonPositiveRate(Sats),
+    #[error("Order size overflows, position {0} sats, target {1} sats")]
+    PositionOverflow(Sats, Sats),
+}
+
+impl rweb::reject::Reject for NextActionError {}
+
+/// Lock the state for writing and bump its revision. Every change of the shared state goes
+/// through the function or `modify_state`, so an unchanged revision means an unchanged state.
+pub async fn write_state(state_mx: &RwLock<State>) -> RwLockWriteGuard<'_, State> {
+    let mut state = state_mx.write().await;
+    state.revision += 1;
+    state
+}
+
+/// Lock the state for writing and bump its revision only if `f` reports a change of the
+/// state. For frequent writers that mostly leave the state as is, so waiters of the next
+/// revision are not woken up for nothing.
+pub async fn modify_state<F, R>(state_mx: &RwLock<State>, f: F) -> R
+where
+    F: FnOnce(&mut State) -> (R, bool),
+{
+    let mut state = state_mx.write().await;
+    let (res, changed) = f(&mut state);
+    if changed {
+        state.revision += 1;
+    }
+    res
+}
+
+/// Fields that the planner changes besides scheduled actions, so the action worker detects
+/// its changes without cloning the whole state
+#[derive(PartialEq)]
+struct PlannerMarks {
+    planning_error: Option<String>,
+    unhedged_since: Option<NaiveDateTime>,
+    next_chunk_at: Option<NaiveDateTime>,
+    recent_orders: Vec<NaiveDateTime>,
+    over_limit: bool,
+    slippage_exceeded: bool,
+    margin_shortage: bool,
+    maintenance_end: Option<NaiveDateTime>,
+    leverage_migration: Option<LeverageMigration>,
+    symbol_migration: Option<SymbolMigration>,
+}
+
+impl PlannerMarks {
+    fn new(state: &State) -> Self {
+        PlannerMarks {
+            planning_error: state.planning_error.clone(),
+            unhedged_since: state.unhedged_since,
+            next_chunk_at: state.next_chunk_at,
+            recent_orders: state.recent_orders.clone(),
+            over_limit: state.over_limit,
+            slippage_exceeded: state.slippage_exceeded,
+            margin_shortage: state.margin_shortage,
+            maintenance_end: state.maintenance_end,
+            leverage_migration: state.leverage_migration.clone(),
+            symbol_migration: state.symbol_migration.clone(),
+        }
+    }
 }
 
 /// Recalculate actions when state is changed
 pub async fn state_action_worker<F, Fut>(
-    state_mx: Arc<Mutex<State>>,
+    state_mx: Arc<RwLock<State>>,
     state_notify: Arc<Notify>,
     execute_action: F,
 ) -> Result<(), Box<dyn Error>>
@@ -612,32 +2588,84 @@ where
     Fut: Future<Output = Result<(), Box<dyn Error>>>,
 {
     loop {
-        {
-            let mut state = state_mx.lock().await;
-            let res = state.calculate_next_actions();
-            trace!("Scheduled actions {:?}", state.scheduled_actions);
-            match res {
-                Ok(_) => {
-                    let actions = state.scheduled_actions.clone();
-                    for action in actions.iter() {
-                        let res = execute_action(action.clone()).await;
-                        if let Err(e) = res {
-                            log::error!("State action worker failed: {}", e);
-                            state.finalize_action(action);
-                            return Err(e);
-                        } else {
-                            state.finalize_action(action);
-                        }
+        // Actions are executed without the lock, so readers don't wait for the exchange
+        let (actions, wait_timeout, notified) = modify_state(&state_mx, |state| {
+            let before = PlannerMarks::new(state);
+            match state.calculate_next_actions() {
+                Err(e) => {
+                    if state.planning_error.is_none() {
+                        error!(
+                            "ALERT: failed to calculate next state action, pausing orders: {}",
+                            e
+                        );
                     }
-                    state.scheduled_actions = vec![];
+                    state.planning_error = Some(e.to_string());
+                    state.scheduled_actions.clear();
                 }
-                Err(e) => {
-                    log::error!("Failed to calculate next state action: {}", e);
-                    return Err(Box::new(e));
+                Ok(()) if state.planning_error.is_some() => {
+                    info!("Calculation of state actions recovered, resuming orders");
+                    state.planning_error = None;
                 }
+                Ok(()) => (),
+            }
+            trace!("Scheduled actions {:?}", state.scheduled_actions);
+            let actions = std::mem::take(&mut state.scheduled_actions);
+            // Orders are tracked before sending, so Kollider responses always find them
+            for action in actions.iter() {
+                state.finalize_action(action);
+            }
+            let wait_timeout = [
+                state.aggregation_timeout(),
+                state.chunk_timeout(),
+                state.throttle_timeout(),
+                state.maintenance_timeout(),
+            ]
+            .into_iter()
+            .flatten()
+            .min();
+            // Tracked actions change the state as well
+            let changed = !actions.is_empty() || PlannerMarks::new(state) != before;
+            // Subscribe while holding the lock to not miss changes made during the execution
+            ((actions, wait_timeout, state_notify.notified()), changed)
+        })
+        .await;
+        for action in actions {
+            if let Err(e) = execute_action(action).await {
+                log::error!("State action worker failed: {}", e);
+                return Err(e);
             }
         }
-        state_notify.notified().await;
+        if let Some(dt) = wait_timeout {
+            let _ = tokio::time::timeout(dt, notified).await;
+        } else {
+            notified.await;
+        }
+    }
+}
+
+/// Periodically evict opening orders that are not confirmed by the Kollider within `timeout`
+/// and trigger recalculation of actions.
+pub async fn opening_orders_watchdog(
+    state_mx: Arc<RwLock<State>>,
+    state_notify: Arc<Notify>,
+    timeout: std::time::Duration,
+) {
+    let check_interval = std::cmp::max(timeout / 2, std::time::Duration::from_secs(1));
+    let timeout =
+        chrono::Duration::from_std(timeout).unwrap_or_else(|_| chrono::Duration::max_value());
+    loop {
+        tokio::time::sleep(check_interval).await;
+        let mut state = write_state(&state_mx).await;
+        let evicted = state.evict_stale_opening_orders(timeout);
+        if !evicted.is_empty() {
+            for order in evicted.iter() {
+                warn!(
+                    "Opening order is not confirmed in time, evicting: {:?}",
+                    order
+                );
+            }
+            state_notify.notify_waiters();
+        }
     }
 }
 
@@ -645,6 +2673,31 @@ where
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_ticker_ema() {
+        let start = NaiveDateTime::from_timestamp(0, 0);
+        let at = |secs| start + chrono::Duration::seconds(secs);
+        let mut state = State::new(HedgeConfig {
+            ema_half_life_secs: 60,
+            ..HedgeConfig::default()
+        });
+        state.update_ticker(40000., at(0));
+        assert_eq!(state.order_base_price(), Some(2500));
+        // Single spike moves the average only partially
+        state.update_ticker(50000., at(60));
+        assert_eq!(state.ticker_ema.as_ref().unwrap().value, 45000.);
+        assert_eq!(state.current_price(), Some(2000));
+        assert_eq!(state.order_base_price(), Some(2222));
+        state.update_ticker(40000., at(60));
+        assert_eq!(state.ticker_ema.as_ref().unwrap().value, 45000.);
+
+        let mut state = State::default();
+        state.update_ticker(40000., at(0));
+        state.update_ticker(50000., at(60));
+        assert_eq!(state.ticker_ema, None);
+        assert_eq!(state.order_base_price(), Some(2000));
+    }
+
     #[test]
     fn test_margin_order() {
         let order = KolliderOrder {
@@ -667,4 +2720,1249 @@ mod tests {
         };
         assert_eq!(order.required_margin(), 1000);
     }
+
+    #[test]
+    fn test_htlc_aggregation() {
+        let mut state = State {
+            config: HedgeConfig {
+                min_htlc_aggregate: 10000,
+                htlc_aggregation_secs: 60,
+                ..HedgeConfig::default()
+            },
+            opened_orders: Some(vec![]),
+            ticker: Some(35000.),
+            ..State::default()
+        };
+        state.channels_hedge.insert(
+            "aboba".to_owned(),
+            ChannelHedge {
+                sats: 5000,
+                rate: 2500,
+            },
+        );
+        state.calculate_next_actions().unwrap();
+        assert_eq!(state.scheduled_actions, vec![]);
+        assert!(state.unhedged_since.is_some());
+
+        // Suspended planning doesn't keep the aggregation timer
+        state.config.max_clock_skew_secs = 5;
+        state.update_clock_skew(-7500);
+        state.calculate_next_actions().unwrap();
+        assert_eq!(state.aggregation_timeout(), None);
+        state.update_clock_skew(0);
+
+        state.unhedged_since = Some(Utc::now().naive_utc() - chrono::Duration::seconds(61));
+        state.calculate_next_actions().unwrap();
+        assert_eq!(state.scheduled_actions.len(), 1);
+        assert_eq!(state.unhedged_since, None);
+    }
+
+    #[test]
+    fn test_config_change_collect() {
+        let created = Utc::now().naive_utc();
+        let updates = vec![
+            StateUpdate {
+                created,
+                body: UpdateBody::ConfigChange(HedgeConfig::default().full_change()),
+            },
+            StateUpdate {
+                created,
+                body: UpdateBody::ConfigChange(ConfigChange {
+                    hedge_leverage: Some(200),
+                    ..ConfigChange::default()
+                }),
+            },
+        ];
+        let state = State::collect(HedgeConfig::default(), updates).unwrap();
+        assert_eq!(state.config.hedge_leverage, 200);
+        assert_eq!(state.config_changed, Some(created));
+
+        assert_eq!(
+            state.startup_config,
+            Some(HedgeConfig::default().full_change())
+        );
+    }
+
+    #[test]
+    fn test_startup_config_change() {
+        let startup = |config: &HedgeConfig| StateUpdate {
+            created: Utc::now().naive_utc(),
+            body: UpdateBody::ConfigChange(config.full_change()),
+        };
+        let flags = HedgeConfig::default();
+        let mut state = State::collect(flags.clone(), vec![startup(&flags)]).unwrap();
+        state
+            .apply_update(StateUpdate {
+                created: Utc::now().naive_utc(),
+                body: UpdateBody::ConfigChange(ConfigChange {
+                    hedge_leverage: Some(200),
+                    ..ConfigChange::default()
+                }),
+            })
+            .unwrap();
+
+        // Restart with the same flags keeps the runtime leverage
+        state.apply_update(startup(&flags)).unwrap();
+        assert_eq!(state.config.hedge_leverage, 200);
+
+        // Only the flag that the operator changed is applied
+        let flags = HedgeConfig {
+            spread_percent: 0.5,
+            ..flags
+        };
+        state.apply_update(startup(&flags)).unwrap();
+        assert_eq!(state.config.spread_percent, 0.5);
+        assert_eq!(state.config.hedge_leverage, 200);
+        assert_eq!(state.startup_config, Some(flags.full_change()));
+
+        // Chains without marked startups keep the replayed config
+        let legacy = vec![StateUpdate {
+            created: Utc::now().naive_utc(),
+            body: UpdateBody::ConfigChange(ConfigChange {
+                hedge_leverage: Some(300),
+                ..ConfigChange::default()
+            }),
+        }];
+        let mut state = State::collect(HedgeConfig::default(), legacy).unwrap();
+        state.apply_update(startup(&flags)).unwrap();
+        assert_eq!(state.config.hedge_leverage, 300);
+        assert_eq!(state.config.spread_percent, 0.1);
+    }
+
+    #[test]
+    fn test_unhedged_channels() {
+        let mut state = State::default();
+        for (chan_id, sats) in [("merchant", 100_000), ("small", 5_000)] {
+            state
+                .apply_update(StateUpdate {
+                    created: Utc::now().naive_utc(),
+                    body: UpdateBody::Htlc(HtlcUpdate {
+                        channel_id: chan_id.to_owned(),
+                        sats,
+                        rate: 2500,
+                    }),
+                })
+                .unwrap();
+        }
+        assert_eq!(state.hedge_capacity(), 105_000);
+
+        state
+            .apply_update(StateUpdate {
+                created: Utc::now().naive_utc(),
+                body: UpdateBody::ConfigChange(ConfigChange {
+                    unhedged_channels: Some(vec!["small".to_owned(), "small".to_owned()]),
+                    ..ConfigChange::default()
+                }),
+            })
+            .unwrap();
+        assert_eq!(state.config.unhedged_channels, vec!["small".to_owned()]);
+        assert_eq!(state.channels_hedge["small"].sats, 5_000);
+        assert_eq!(state.hedge_capacity(), 100_000);
+        // Recorded only by runtime changes, startups don't reset the list
+        state
+            .apply_update(StateUpdate {
+                created: Utc::now().naive_utc(),
+                body: UpdateBody::ConfigChange(HedgeConfig::default().full_change()),
+            })
+            .unwrap();
+        assert_eq!(state.config.unhedged_channels, vec!["small".to_owned()]);
+    }
+
+    #[test]
+    fn test_channel_leverage() {
+        let mut state = State::default();
+        for chan_id in ["merchant", "experimental"] {
+            state.channels_hedge.insert(
+                chan_id.to_owned(),
+                ChannelHedge {
+                    sats: 100_000,
+                    rate: 2500,
+                },
+            );
+        }
+        assert_eq!(state.target_leverage(), 100);
+
+        state.opened_position = Some(KolliderPosition::new(100, 200_000, 40000, 80, 0.0));
+        state
+            .apply_update(StateUpdate {
+                created: Utc::now().naive_utc(),
+                body: UpdateBody::ConfigChange(ConfigChange {
+                    channel_leverage: Some(
+                        vec![("experimental".to_owned(), 300), ("unknown".to_owned(), 0)]
+                            .into_iter()
+                            .collect(),
+                    ),
+                    ..ConfigChange::default()
+                }),
+            })
+            .unwrap();
+        assert_eq!(state.config.channel_leverage.len(), 1);
+        // 100k sats at 1x and 100k sats at 3x require margin of 133333 sats
+        assert_eq!(state.target_leverage(), 150);
+        let migration = state.leverage_migration.as_ref().unwrap();
+        assert_eq!((migration.from, migration.to), (100, 150));
+        assert_eq!(state.order_leverage(), 100);
+    }
+
+    #[test]
+    fn test_empty_channels_gc() {
+        let old = Utc::now().naive_utc() - chrono::Duration::days(2);
+        let htlc = |channel_id: &str, sats| StateUpdate {
+            created: old,
+            body: UpdateBody::Htlc(HtlcUpdate {
+                channel_id: channel_id.to_owned(),
+                sats,
+                rate: 2500,
+            }),
+        };
+        let updates = vec![htlc("a", 100), htlc("a", -100), htlc("b", 100)];
+        let mut state = State::collect(HedgeConfig::default(), updates).unwrap();
+        let expired = state.expired_empty_channels(chrono::Duration::days(1));
+        assert_eq!(expired, vec!["a".to_owned()]);
+
+        state
+            .apply_update(StateUpdate {
+                created: Utc::now().naive_utc(),
+                body: UpdateBody::ChannelsRemoved(ChannelsRemoval {
+                    channel_ids: vec!["a".to_owned(), "b".to_owned()],
+                }),
+            })
+            .unwrap();
+        assert!(!state.channels_hedge.contains_key("a"));
+        assert!(state.channels_hedge.contains_key("b"));
+        assert!(state.empty_channels.is_empty());
+    }
+
+    #[test]
+    fn test_leverage_migration() {
+        let mut state = State {
+            opened_orders: Some(vec![]),
+            ticker: Some(40000.),
+            opened_position: Some(KolliderPosition {
+                liquidation_price: 0.0,
+                leverage: 100,
+                entry_value: 100_000,
+                entry_price: 400_000,
+                quantity: 40,
+                rpnl: 0.0,
+            }),
+            ..State::default()
+        };
+        state.channels_hedge.insert(
+            "aboba".to_owned(),
+            ChannelHedge {
+                sats: 100_000,
+                rate: 2500,
+            },
+        );
+        state
+            .apply_update(StateUpdate {
+                created: Utc::now().naive_utc(),
+                body: UpdateBody::ConfigChange(ConfigChange {
+                    hedge_leverage: Some(200),
+                    ..ConfigChange::default()
+                }),
+            })
+            .unwrap();
+        let migration = state.leverage_migration.clone().unwrap();
+        assert_eq!((migration.from, migration.to), (100, 200));
+        assert_eq!(migration.required_margin, 50_000);
+
+        state.calculate_next_actions().unwrap();
+        match &state.scheduled_actions[..] {
+            [StateAction::OpenOrder(order)] => {
+                assert_eq!(order.side, OrderSide::Ask);
+                assert_eq!(order.sats, 100_000);
+                assert_eq!(order.leverage, 100);
+            }
+            actions => panic!("Unexpected actions: {:?}", actions),
+        }
+
+        state.scheduled_actions = vec![];
+        state.opened_position.as_mut().unwrap().quantity = 0;
+        state.opened_position.as_mut().unwrap().entry_value = 0;
+        state.calculate_next_actions().unwrap();
+        assert_eq!(state.leverage_migration, None);
+        match &state.scheduled_actions[..] {
+            [StateAction::OpenOrder(order)] => {
+                assert_eq!(order.side, OrderSide::Bid);
+                assert_eq!(order.leverage, 200);
+            }
+            actions => panic!("Unexpected actions: {:?}", actions),
+        }
+    }
+
+    #[test]
+    fn test_index_cross_check() {
+        let mut state = State {
+            config: HedgeConfig {
+                max_index_deviation: 1.0,
+                ..HedgeConfig::default()
+            },
+            opened_orders: Some(vec![]),
+            ticker: Some(35000.),
+            ..State::default()
+        };
+        state.channels_hedge.insert(
+            "aboba".to_owned(),
+            ChannelHedge {
+                sats: 100_000,
+                rate: 2500,
+            },
+        );
+        state.calculate_next_actions().unwrap();
+        assert_eq!(state.scheduled_actions, vec![]);
+
+        state.external_ticker = Some(ExternalTicker {
+            price: 40000.,
+            updated: Utc::now().naive_utc(),
+        });
+        state.calculate_next_actions().unwrap();
+        assert_eq!(state.scheduled_actions, vec![]);
+
+        state.external_ticker = Some(ExternalTicker {
+            price: 35100.,
+            updated: Utc::now().naive_utc(),
+        });
+        state.calculate_next_actions().unwrap();
+        assert_eq!(state.scheduled_actions.len(), 1);
+    }
+
+    #[test]
+    fn test_order_rejection() {
+        let mut state = State {
+            opened_orders: Some(vec![]),
+            ticker: Some(40000.),
+            ..State::default()
+        };
+        state.channels_hedge.insert(
+            "aboba".to_owned(),
+            ChannelHedge {
+                sats: 100_000,
+                rate: 2500,
+            },
+        );
+        state.calculate_next_actions().unwrap();
+        let order = match &state.scheduled_actions[..] {
+            [StateAction::OpenOrder(order)] => order.clone(),
+            actions => panic!("Unexpected actions: {:?}", actions),
+        };
+        state.scheduled_actions = vec![];
+        state.add_opening_order(order.clone());
+        state.calculate_next_actions().unwrap();
+        assert_eq!(state.scheduled_actions, vec![]);
+
+        state
+            .apply_update(StateUpdate {
+                created: Utc::now().naive_utc(),
+                body: UpdateBody::OrderRejected(OrderRejection {
+                    ext_id: order.ext_id.clone(),
+                    reason: "InsufficientMargin".to_owned(),
+                    order: Some(order),
+                }),
+            })
+            .unwrap();
+        assert!(state.orders.is_empty());
+        assert!(state.margin_shortage);
+        state.calculate_next_actions().unwrap();
+        assert_eq!(state.scheduled_actions.len(), 1);
+        assert_eq!(state.scheduled_shorts(), 50_000);
+    }
+
+    #[test]
+    fn test_downsize_floor() {
+        let mut state = State {
+            config: HedgeConfig {
+                min_downsize_sats: 60_000,
+                ..HedgeConfig::default()
+            },
+            opened_orders: Some(vec![]),
+            ticker: Some(40000.),
+            ..State::default()
+        };
+        state.channels_hedge.insert(
+            "aboba".to_owned(),
+            ChannelHedge {
+                sats: 100_000,
+                rate: 2500,
+            },
+        );
+        let order = OpeningOrder {
+            ext_id: OpeningOrder::new_id(),
+            symbol: "BTCUSD.PERP".to_owned(),
+            sats: 100_000,
+            price: 2500,
+            side: OrderSide::Bid,
+            leverage: 100,
+        };
+        state.add_opening_order(order.clone());
+        state
+            .apply_update(StateUpdate {
+                created: Utc::now().naive_utc(),
+                body: UpdateBody::OrderRejected(OrderRejection {
+                    ext_id: order.ext_id.clone(),
+                    reason: "InsufficientMargin".to_owned(),
+                    order: Some(order),
+                }),
+            })
+            .unwrap();
+        assert_eq!(state.margin_order_cap, Some(0));
+        state.calculate_next_actions().unwrap();
+        assert_eq!(state.scheduled_actions, vec![]);
+    }
+
+    #[test]
+    fn test_opening_order_eviction() {
+        let mut state = State::default();
+        let order = OpeningOrder {
+            ext_id: OpeningOrder::new_id(),
+            symbol: "BTCUSD.PERP".to_owned(),
+            sats: 100_000,
+            price: 2500,
+            side: OrderSide::Bid,
+            leverage: 100,
+        };
+        state.add_opening_order(order.clone());
+        assert_eq!(
+            state.evict_stale_opening_orders(chrono::Duration::seconds(60)),
+            vec![]
+        );
+        state.orders.get_mut(&order.ext_id).unwrap().updated =
+            Utc::now().naive_utc() - chrono::Duration::seconds(61);
+        assert_eq!(
+            state.evict_stale_opening_orders(chrono::Duration::seconds(60)),
+            vec![order]
+        );
+        assert!(state.orders.is_empty());
+    }
+
+    #[test]
+    fn test_order_chunking() {
+        let mut state = State {
+            config: HedgeConfig {
+                max_order_sats: 40_000,
+                ..HedgeConfig::default()
+            },
+            opened_orders: Some(vec![]),
+            ticker: Some(40000.),
+            ..State::default()
+        };
+        state.channels_hedge.insert(
+            "aboba".to_owned(),
+            ChannelHedge {
+                sats: 100_000,
+                rate: 2500,
+            },
+        );
+        state.calculate_next_actions().unwrap();
+        let chunks: Vec<u64> = state
+            .scheduled_actions
+            .iter()
+            .filter_map(|a| a.order_sats())
+            .collect();
+        assert_eq!(chunks, vec![40_000, 40_000, 20_000]);
+
+        state.scheduled_actions = vec![];
+        state.config.order_chunk_secs = 60;
+        state.calculate_next_actions().unwrap();
+        assert_eq!(state.scheduled_actions.len(), 1);
+        assert!(state.next_chunk_at.is_some());
+        for action in std::mem::take(&mut state.scheduled_actions) {
+            state.finalize_action(&action);
+        }
+        state.calculate_next_actions().unwrap();
+        assert_eq!(state.scheduled_actions, vec![]);
+
+        // The rest of the order is not needed anymore, so it doesn't delay next orders
+        state.channels_hedge.get_mut("aboba").unwrap().sats = 0;
+        state.calculate_next_actions().unwrap();
+        assert_eq!(state.next_chunk_at, None);
+        state.scheduled_actions = vec![];
+        state.channels_hedge.get_mut("aboba").unwrap().sats = 100_000;
+
+        state.next_chunk_at = Some(Utc::now().naive_utc() - chrono::Duration::seconds(1));
+        state.calculate_next_actions().unwrap();
+        assert_eq!(state.scheduled_actions.len(), 1);
+    }
+
+    #[test]
+    fn test_config_validation() {
+        let config = HedgeConfig::default();
+        assert_eq!(config.validate(&Products::new()), vec![]);
+
+        let mut products = Products::new();
+        products.insert(
+            "BTCUSD.PERP".to_owned(),
+            ProductSpec {
+                max_leverage: Some(2.0),
+                ..ProductSpec::fallback("BTCUSD.PERP")
+            },
+        );
+        let config = HedgeConfig {
+            hedge_leverage: 300,
+            ..HedgeConfig::default()
+        };
+        assert_eq!(
+            config.validate(&products),
+            vec![ConfigErr::LeverageTooHigh("hedge".to_owned(), 300, 2.0)]
+        );
+
+        let config = HedgeConfig {
+            hedge_pair: ".BTCEUR".to_owned(),
+            hedge_sym: "BTCEUR.PERP".to_owned(),
+            ..HedgeConfig::default()
+        };
+        assert_eq!(
+            config.validate(&products),
+            vec![ConfigErr::UnknownSymbol("BTCEUR.PERP".to_owned())]
+        );
+
+        let config = HedgeConfig {
+            hedge_pair: ".BTCEUR".to_owned(),
+            ..HedgeConfig::default()
+        };
+        assert_eq!(
+            config.validate(&products),
+            vec![ConfigErr::CurrencyMismatch(
+                "BTCUSD.PERP".to_owned(),
+                "EUR".to_owned()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_order_throttling() {
+        let mut state = State {
+            config: HedgeConfig {
+                max_order_sats: 40_000,
+                max_orders_per_minute: 2,
+                ..HedgeConfig::default()
+            },
+            opened_orders: Some(vec![]),
+            ticker: Some(40000.),
+            ..State::default()
+        };
+        state.channels_hedge.insert(
+            "aboba".to_owned(),
+            ChannelHedge {
+                sats: 100_000,
+                rate: 2500,
+            },
+        );
+        state.calculate_next_actions().unwrap();
+        let chunks: Vec<u64> = state
+            .scheduled_actions
+            .iter()
+            .filter_map(|a| a.order_sats())
+            .collect();
+        assert_eq!(chunks, vec![40_000, 40_000]);
+        assert!(state.throttle_timeout().is_some());
+        for action in std::mem::take(&mut state.scheduled_actions) {
+            state.finalize_action(&action);
+        }
+
+        // The rest of the hedge waits for the next slot
+        state.channels_hedge.get_mut("aboba").unwrap().sats = 120_000;
+        state.calculate_next_actions().unwrap();
+        assert_eq!(state.scheduled_actions, vec![]);
+
+        let minute_ago = Utc::now().naive_utc() - chrono::Duration::minutes(1);
+        state.recent_orders = vec![minute_ago; 2];
+        // Stale entries don't hold the worker in a zero timeout loop
+        assert_eq!(state.throttle_timeout(), None);
+        assert_eq!(state.recent_orders, vec![]);
+        state.recent_orders = vec![minute_ago; 2];
+        state.calculate_next_actions().unwrap();
+        let chunks: Vec<u64> = state
+            .scheduled_actions
+            .iter()
+            .filter_map(|a| a.order_sats())
+            .collect();
+        assert_eq!(chunks, vec![40_000]);
+        assert_eq!(state.recent_orders.len(), 1);
+    }
+
+    #[test]
+    fn test_position_limit() {
+        let mut state = State {
+            config: HedgeConfig {
+                max_position_sats: 60_000,
+                ..HedgeConfig::default()
+            },
+            opened_orders: Some(vec![]),
+            ticker: Some(40000.),
+            ..State::default()
+        };
+        state.channels_hedge.insert(
+            "aboba".to_owned(),
+            ChannelHedge {
+                sats: 100_000,
+                rate: 2500,
+            },
+        );
+        state.calculate_next_actions().unwrap();
+        assert!(state.over_limit);
+        assert_eq!(state.scheduled_shorts(), 60_000);
+
+        for action in std::mem::take(&mut state.scheduled_actions) {
+            state.finalize_action(&action);
+        }
+        state.calculate_next_actions().unwrap();
+        assert_eq!(state.scheduled_actions, vec![]);
+        assert!(state.over_limit);
+    }
+
+    #[test]
+    fn test_slippage_guard() {
+        let mut state = State {
+            config: HedgeConfig {
+                spread_percent: 5.0,
+                max_slippage_percent: 1.0,
+                ..HedgeConfig::default()
+            },
+            opened_orders: Some(vec![]),
+            ticker: Some(40000.),
+            ..State::default()
+        };
+        state.channels_hedge.insert(
+            "aboba".to_owned(),
+            ChannelHedge {
+                sats: 100_000,
+                rate: 2500,
+            },
+        );
+        state.calculate_next_actions().unwrap();
+        assert!(state.slippage_exceeded);
+        assert_eq!(state.scheduled_actions, vec![]);
+
+        state.config.spread_percent = 0.5;
+        state.calculate_next_actions().unwrap();
+        assert!(!state.slippage_exceeded);
+        assert_eq!(state.scheduled_shorts(), 100_000);
+    }
+
+    #[test]
+    fn test_clock_skew_guard() {
+        let mut state = State {
+            config: HedgeConfig {
+                max_clock_skew_secs: 5,
+                ..HedgeConfig::default()
+            },
+            opened_orders: Some(vec![]),
+            ticker: Some(40000.),
+            ..State::default()
+        };
+        state.channels_hedge.insert(
+            "aboba".to_owned(),
+            ChannelHedge {
+                sats: 100_000,
+                rate: 2500,
+            },
+        );
+        state.update_clock_skew(-7500);
+        state.calculate_next_actions().unwrap();
+        assert!(state.clock_skew_exceeded);
+        assert_eq!(state.scheduled_actions, vec![]);
+
+        state.update_clock_skew(1200);
+        state.calculate_next_actions().unwrap();
+        assert!(!state.clock_skew_exceeded);
+        assert_eq!(state.clock_skew_ms, Some(1200));
+        assert_eq!(state.scheduled_shorts(), 100_000);
+    }
+
+    #[test]
+    fn test_drift_alarm() {
+        let start = NaiveDateTime::from_timestamp(0, 0);
+        let at = |secs| start + chrono::Duration::seconds(secs);
+        let mut state = State {
+            config: HedgeConfig {
+                drift_alarm_usd: 10.0,
+                drift_alarm_secs: 300,
+                ..HedgeConfig::default()
+            },
+            ticker: Some(40000.),
+            ..State::default()
+        };
+        state.channels_hedge.insert(
+            "aboba".to_owned(),
+            ChannelHedge {
+                sats: 100_000,
+                rate: 2500,
+            },
+        );
+        assert_eq!(state.unhedged_usd(), Some(40.));
+        assert_eq!(state.check_drift(at(0)), None);
+        assert_eq!(state.drift_since, Some(at(0)));
+        assert_eq!(state.check_drift(at(299)), None);
+        let raised = state.check_drift(at(300)).unwrap();
+        assert!(raised.raised);
+        assert_eq!(raised.since, Some(at(0)));
+        assert_eq!(state.check_drift(at(400)), None);
+
+        state.channels_hedge.clear();
+        let cleared = state.check_drift(at(500)).unwrap();
+        assert!(!cleared.raised);
+        assert!(!state.drift_alarm);
+        assert_eq!(state.drift_since, None);
+    }
+
+    #[test]
+    fn test_symbol_migration() {
+        let mut state = State {
+            opened_orders: Some(vec![KolliderOrder {
+                id: 7,
+                ext_id: OpeningOrder::new_id(),
+                leverage: 100,
+                price: 400000,
+                quantity: 10,
+                side: OrderSide::Ask,
+            }]),
+            opened_position: Some(KolliderPosition::new(100, 100_000, 2500, 40, 0.0)),
+            ticker: Some(40000.),
+            ..State::default()
+        };
+        state.channels_hedge.insert(
+            "aboba".to_owned(),
+            ChannelHedge {
+                sats: 100_000,
+                rate: 2500,
+            },
+        );
+        let old_sym = state.config.hedge_sym.clone();
+        state
+            .apply_update(StateUpdate {
+                created: Utc::now().naive_utc(),
+                body: UpdateBody::ConfigChange(ConfigChange {
+                    hedge_sym: Some("BTCUSD.MAR23".to_owned()),
+                    ..ConfigChange::default()
+                }),
+            })
+            .unwrap();
+        let migration = state.symbol_migration.clone().unwrap();
+        assert_eq!(migration.from, old_sym);
+        assert_eq!(migration.orders.map(|o| o.len()), Some(1));
+        assert_eq!(state.opened_orders, None);
+
+        state.calculate_next_actions().unwrap();
+        match &state.scheduled_actions[..] {
+            [StateAction::CloseOrder { order_id, symbol }] => {
+                assert_eq!(*order_id, 7);
+                assert_eq!(*symbol, old_sym);
+            }
+            actions => panic!("Unexpected actions: {:?}", actions),
+        }
+        for action in std::mem::take(&mut state.scheduled_actions) {
+            state.finalize_action(&action);
+        }
+        state.calculate_next_actions().unwrap();
+        assert_eq!(state.scheduled_actions, vec![]);
+
+        state.symbol_migration.as_mut().unwrap().orders = Some(vec![]);
+        state.calculate_next_actions().unwrap();
+        match &state.scheduled_actions[..] {
+            [StateAction::ClosePosition {
+                symbol, quantity, ..
+            }] => {
+                assert_eq!(*symbol, old_sym);
+                assert_eq!(*quantity, 40);
+            }
+            actions => panic!("Unexpected actions: {:?}", actions),
+        }
+
+        state.scheduled_actions = vec![];
+        state.symbol_migration.as_mut().unwrap().position =
+            Some(KolliderPosition::new(100, 0, 0, 0, 0.0));
+        state.calculate_next_actions().unwrap();
+        assert_eq!(state.symbol_migration, None);
+        assert_eq!(state.scheduled_actions, vec![]);
+
+        // Hedge is opened on the new symbol when Kollider reports it
+        state.opened_orders = Some(vec![]);
+        state.calculate_next_actions().unwrap();
+        match &state.scheduled_actions[..] {
+            [StateAction::OpenOrder(order)] => {
+                assert_eq!(order.symbol, "BTCUSD.MAR23");
+                assert_eq!(order.side, OrderSide::Bid);
+            }
+            actions => panic!("Unexpected actions: {:?}", actions),
+        }
+    }
+
+    #[test]
+    fn test_symbol_migration_restart() {
+        let flags = HedgeConfig::default();
+        let startup = StateUpdate {
+            created: Utc::now().naive_utc(),
+            body: UpdateBody::ConfigChange(flags.full_change()),
+        };
+        let updates = vec![
+            startup.clone(),
+            StateUpdate {
+                created: Utc::now().naive_utc(),
+                body: UpdateBody::ConfigChange(ConfigChange {
+                    hedge_sym: Some("BTCUSD.MAR23".to_owned()),
+                    ..ConfigChange::default()
+                }),
+            },
+            // Restart with the same --symbol flag
+            startup,
+        ];
+        let state = State::collect(flags.clone(), updates).unwrap();
+        assert_eq!(state.config.hedge_sym, "BTCUSD.MAR23");
+        let migration = state.symbol_migration.unwrap();
+        assert_eq!(migration.from, flags.hedge_sym);
+        assert_eq!(migration.to, "BTCUSD.MAR23");
+    }
+
+    #[test]
+    fn test_position_drift() {
+        let mut state = State {
+            ticker: Some(40000.),
+            ..State::default()
+        };
+        state.channels_hedge.insert(
+            "aboba".to_owned(),
+            ChannelHedge {
+                sats: 100_000,
+                rate: 2500,
+            },
+        );
+        assert_eq!(state.check_position_drift(), 0);
+        assert_eq!(state.position_drift, None);
+
+        state.opened_orders = Some(vec![]);
+        assert_eq!(state.check_position_drift(), 1);
+        assert_eq!(state.check_position_drift(), 2);
+        assert_eq!(state.position_drift, Some(100_000));
+
+        state.maintenance_end = Some(Utc::now().naive_utc());
+        assert_eq!(state.check_position_drift(), 0);
+        state.maintenance_end = None;
+
+        state.opened_position = Some(KolliderPosition {
+            liquidation_price: 0.0,
+            leverage: 100,
+            entry_value: 99_000,
+            entry_price: 2500,
+            quantity: 40,
+            rpnl: 0.0,
+        });
+        assert_eq!(state.check_position_drift(), 0);
+        assert_eq!(state.position_drift, Some(1000));
+    }
+
+    #[test]
+    fn test_check_updates() {
+        let htlc = |sats| StateUpdate {
+            created: Utc::now().naive_utc(),
+            body: UpdateBody::Htlc(HtlcUpdate {
+                channel_id: "aboba".to_owned(),
+                sats,
+                rate: 2500,
+            }),
+        };
+        let mut state = State::default();
+        state.apply_update(htlc(100_000)).unwrap();
+        let before = state.clone();
+
+        assert_eq!(state.check_updates(&[htlc(-60_000)]), Ok(()));
+        // Each update is checked against the previous ones
+        assert!(matches!(
+            state.check_updates(&[htlc(-60_000), htlc(-60_000)]),
+            Err(StateUpdateErr::Htlc(
+                HtlcUpdateErr::InsufficientSatsBalance(..)
+            ))
+        ));
+
+        // Voiding the first HTLC is fine until the channel is drained
+        let void = StateUpdate {
+            created: Utc::now().naive_utc(),
+            body: UpdateBody::Correction(Correction {
+                corrected: Utc::now().naive_utc(),
+                original: HtlcUpdate {
+                    channel_id: "aboba".to_owned(),
+                    sats: 100_000,
+                    rate: 2500,
+                },
+                replacement: None,
+                reason: "duplicate".to_owned(),
+                author: "admin".to_owned(),
+            }),
+        };
+        assert_eq!(state.check_updates(&[void.clone()]), Ok(()));
+        assert!(matches!(
+            state.check_updates(&[htlc(-60_000), void]),
+            Err(StateUpdateErr::Htlc(
+                HtlcUpdateErr::InsufficientSatsBalance(..)
+            ))
+        ));
+        assert_eq!(state, before);
+
+        // Staged updates wait for the commit, but the next ones are checked on top of them
+        let first = htlc(-60_000);
+        state.stage_updates(std::slice::from_ref(&first)).unwrap();
+        assert!(state.stage_updates(&[htlc(-60_000)]).is_err());
+        assert_eq!(
+            state.staged_hedge("aboba"),
+            Ok(Some(ChannelHedge {
+                sats: 40_000,
+                rate: 2500
+            }))
+        );
+        assert_eq!(state.channels_hedge["aboba"].sats, 100_000);
+        state.unstage_updates(std::slice::from_ref(&first));
+        assert_eq!(state, before);
+    }
+
+    #[test]
+    fn test_non_positive_rate() {
+        let mut state = State::default();
+        state
+            .channels_hedge
+            .insert("aboba".to_owned(), ChannelHedge { sats: 100, rate: 0 });
+        assert_eq!(
+            state.hedge_avg_price(),
+            Err(NextActionError::NonPositiveRate(0))
+        );
+    }
+
+    #[test]
+    fn test_net_opposing_orders() {
+        let order = |id, quantity, side| KolliderOrder {
+            id,
+            ext_id: OpeningOrder::new_id(),
+            leverage: 100,
+            price: 400000,
+            quantity,
+            side,
+        };
+        let mut state = State {
+            opened_orders: Some(vec![
+                order(1, 60, OrderSide::Bid),
+                order(2, 40, OrderSide::Bid),
+                order(3, 40, OrderSide::Ask),
+            ]),
+            ticker: Some(40000.),
+            ..State::default()
+        };
+        state.channels_hedge.insert(
+            "aboba".to_owned(),
+            ChannelHedge {
+                sats: 100_000,
+                rate: 2500,
+            },
+        );
+        state.calculate_next_actions().unwrap();
+        let mut cancelled: Vec<u64> = state
+            .scheduled_actions
+            .iter()
+            .map(|a| match a {
+                StateAction::CloseOrder { order_id, .. } => *order_id,
+                a => panic!("Unexpected action: {:?}", a),
+            })
+            .collect();
+        cancelled.sort_unstable();
+        assert_eq!(cancelled, vec![2, 3]);
+
+        // Nothing is decided until the cancels are confirmed
+        for action in std::mem::take(&mut state.scheduled_actions) {
+            state.finalize_action(&action);
+        }
+        state.calculate_next_actions().unwrap();
+        assert_eq!(state.scheduled_actions, vec![]);
+    }
+
+    #[test]
+    fn test_close_residual_position() {
+        let mut state = State {
+            opened_orders: Some(vec![KolliderOrder {
+                id: 42,
+                ext_id: OpeningOrder::new_id(),
+                leverage: 100,
+                price: 400000,
+                quantity: 1,
+                side: OrderSide::Ask,
+            }]),
+            opened_position: Some(KolliderPosition::new(100, 2500, 40000, 1, 0.0)),
+            ticker: Some(40000.),
+            ..State::default()
+        };
+        state.calculate_next_actions().unwrap();
+        match &state.scheduled_actions[..] {
+            [StateAction::CloseOrder { order_id, .. }, StateAction::ClosePosition { quantity, .. }] =>
+            {
+                assert_eq!(*order_id, 42);
+                assert_eq!(*quantity, 1);
+            }
+            actions => panic!("Unexpected actions: {:?}", actions),
+        }
+        let msgs = state.scheduled_actions[1]
+            .to_kollider_messages(&state.products, Settlement::default())
+            .unwrap();
+        match &msgs[..] {
+            [KolliderMsg::Order {
+                quantity,
+                side,
+                order_type,
+                ..
+            }] => {
+                assert_eq!(*quantity, 1);
+                assert_eq!(*side, OrderSide::Bid);
+                assert!(matches!(order_type, OrderType::Market));
+            }
+            msgs => panic!("Unexpected messages: {:?}", msgs),
+        }
+
+        for action in std::mem::take(&mut state.scheduled_actions) {
+            state.finalize_action(&action);
+        }
+        assert_eq!(state.opening_longs(), 2500);
+        state.calculate_next_actions().unwrap();
+        assert_eq!(state.scheduled_actions, vec![]);
+    }
+
+    #[test]
+    fn test_margin_aware_sizing() {
+        let mut state = State {
+            config: HedgeConfig {
+                hedge_leverage: 200,
+                ..HedgeConfig::default()
+            },
+            opened_orders: Some(vec![]),
+            ticker: Some(40000.),
+            balance: Some(0.0003),
+            ..State::default()
+        };
+        state.channels_hedge.insert(
+            "aboba".to_owned(),
+            ChannelHedge {
+                sats: 100_000,
+                rate: 2500,
+            },
+        );
+        state.calculate_next_actions().unwrap();
+        assert!(state.margin_shortage);
+        assert_eq!(state.scheduled_shorts(), 60_000);
+        assert_eq!(state.free_margin(), Some(0));
+
+        state.scheduled_actions = vec![];
+        state.balance = Some(0.001);
+        state.calculate_next_actions().unwrap();
+        assert!(!state.margin_shortage);
+        assert_eq!(state.scheduled_shorts(), 100_000);
+
+        state.scheduled_actions = vec![];
+        state.config.margin_buffer_sats = 60_000;
+        state.calculate_next_actions().unwrap();
+        assert!(state.margin_shortage);
+        assert_eq!(state.scheduled_shorts(), 80_000);
+        let ledger = state.margin_ledger();
+        assert_eq!(ledger.balance, Some(100_000));
+        assert_eq!(ledger.scheduled_orders, 40_000);
+        assert_eq!(ledger.available, Some(0));
+    }
+
+    #[test]
+    fn test_pending_deposits() {
+        let mut state = State {
+            balance: Some(0.001),
+            ..State::default()
+        };
+        for (sats, invoice) in [(50_000, "lnbc1"), (70_000, "lnbc2")] {
+            state
+                .apply_update(StateUpdate {
+                    created: Utc::now().naive_utc(),
+                    body: UpdateBody::Deposit(Deposit {
+                        sats,
+                        invoice: invoice.to_owned(),
+                    }),
+                })
+                .unwrap();
+        }
+        // Deposits in flight survive restart from a snapshot
+        let mut restored = State::default();
+        restored
+            .apply_update(StateUpdate {
+                created: Utc::now().naive_utc(),
+                body: UpdateBody::Snapshot(state.snapshot()),
+            })
+            .unwrap();
+        assert_eq!(restored.pending_deposits, state.pending_deposits);
+
+        state.settle_deposits(0.0006);
+        assert_eq!(state.pending_deposits.len(), 1);
+        assert_eq!(state.pending_deposits[0].invoice, "lnbc2");
+
+        state.pending_deposits[0].created =
+            Utc::now().naive_utc() - chrono::Duration::seconds(DEPOSIT_EXPIRY_SECS + 1);
+        state.settle_deposits(0.0);
+        assert!(state.pending_deposits.is_empty());
+    }
+
+    #[test]
+    fn test_withdrawals() {
+        let mut state = State {
+            balance: Some(0.001),
+            ..State::default()
+        };
+        assert_eq!(state.excess_margin(10_000), Some(90_000));
+        state.withdrawing_sats = 50_000;
+        assert_eq!(state.excess_margin(10_000), Some(40_000));
+
+        let withdrawal = |failed| StateUpdate {
+            created: Utc::now().naive_utc(),
+            body: UpdateBody::Withdrawal(Withdrawal {
+                sats: 30_000,
+                invoice: "lnbc1".to_owned(),
+                failed,
+            }),
+        };
+        state.apply_update(withdrawal(false)).unwrap();
+        assert_eq!(state.daily.withdrawals_sats, 30_000);
+        state.apply_update(withdrawal(true)).unwrap();
+        assert_eq!(state.daily.withdrawals_sats, 0);
+    }
+
+    #[test]
+    fn test_balance_events() {
+        let mut state = State {
+            balance: Some(0.001),
+            opened_position: Some(KolliderPosition::new(100, 2500, 40000, 10, 150.0)),
+            opened_orders: Some(vec![]),
+            ..State::default()
+        };
+        state.pnl.last_rpnl = 150.0;
+        assert_eq!(
+            state.settlement_event(&KolliderPosition::new(100, 2500, 40000, 11, 0.0)),
+            None
+        );
+        let settlement = state
+            .settlement_event(&KolliderPosition::new(100, 2500, 40000, 10, 0.0))
+            .unwrap();
+        assert_eq!(
+            settlement,
+            UpdateBody::Settlement(PnlSettlement {
+                symbol: state.config.hedge_sym.clone(),
+                sats: 150,
+            })
+        );
+        state
+            .apply_update(StateUpdate {
+                created: Utc::now().naive_utc(),
+                body: settlement,
+            })
+            .unwrap();
+        state.opened_position = Some(KolliderPosition::new(100, 2500, 40000, 10, 0.0));
+        assert_eq!(state.pnl.last_rpnl, 0.0);
+
+        // Settled PnL is not funding
+        assert_eq!(state.funding_event(0.0010015), None);
+        assert_eq!(
+            state.funding_event(0.0010012),
+            Some(UpdateBody::Funding(FundingPayment {
+                symbol: state.config.hedge_sym.clone(),
+                sats: -30,
+                position_quantity: 10,
+            }))
+        );
+        state.opened_orders = None;
+        state.cancelling_orders.insert(1);
+        assert_eq!(state.funding_event(0.0010012), None);
+    }
+
+    #[test]
+    fn test_full_snapshot() {
+        let mut state = State {
+            balance: Some(0.001),
+            opened_position: Some(KolliderPosition::new(100, 2500, 40000, 10, 5.0)),
+            opened_orders: Some(vec![]),
+            ..State::default()
+        };
+        state.channels_hedge.insert(
+            "aboba".to_owned(),
+            ChannelHedge {
+                sats: 1000,
+                rate: 2500,
+            },
+        );
+        state.update_ticker(40000.0, Utc::now().naive_utc());
+
+        let mut restored = State::default();
+        restored
+            .apply_update(StateUpdate {
+                created: Utc::now().naive_utc(),
+                body: UpdateBody::Snapshot(state.snapshot()),
+            })
+            .unwrap();
+        assert_eq!(restored.channels_hedge, state.channels_hedge);
+        assert_eq!(restored.opened_orders, state.opened_orders);
+        assert_eq!(restored.opened_position, state.opened_position);
+        assert_eq!(restored.balance, Some(0.001));
+        assert_eq!(restored.ticker, Some(40000.0));
+        assert_eq!(restored.pnl.last_rpnl, 5.0);
+    }
+
+    #[test]
+    fn test_pnl_tracking() {
+        let mut state = State {
+            ticker: Some(40000.),
+            ..State::default()
+        };
+        state.track_pnl(&KolliderPosition::new(100, 2500, 40000, 10, 5.0));
+        state.opened_position = Some(KolliderPosition::new(100, 2500, 40000, 10, 5.0));
+        state.track_pnl(&KolliderPosition::new(100, 5000, 42000, 20, 7.0));
+        state.opened_position = Some(KolliderPosition::new(100, 5000, 42000, 20, 7.0));
+        assert_eq!(state.pnl.realized, 7.0);
+        assert_eq!(state.pnl.entry_price_delta, 2000);
+        assert!(state.unrealized_pnl().unwrap() > 0.0);
+
+        state.track_pnl(&KolliderPosition::new(100, 0, 0, 0, 0.0));
+        state.opened_position = Some(KolliderPosition::new(100, 0, 0, 0, 0.0));
+        assert_eq!(state.pnl.realized, 7.0);
+        assert_eq!(state.unrealized_pnl(), Some(0.0));
+
+        state.track_pnl(&KolliderPosition::new(100, 2500, 40000, 10, -1.0));
+        assert_eq!(state.pnl.realized, 6.0);
+    }
+
+    #[tokio::test]
+    async fn test_modify_state_revision() {
+        let state_mx = RwLock::new(State {
+            ticker: Some(40000.),
+            ..State::default()
+        });
+        // Clearing empty actions leaves the state as is
+        modify_state(&state_mx, |state| {
+            let changed = !state.scheduled_actions.is_empty();
+            state.scheduled_actions.clear();
+            ((), changed)
+        })
+        .await;
+        assert_eq!(state_mx.read().await.revision, 0);
+
+        modify_state(&state_mx, |state| {
+            state.ticker = Some(41000.);
+            ((), true)
+        })
+        .await;
+        assert_eq!(state_mx.read().await.revision, 1);
+    }
+
+    #[test]
+    fn test_planner_marks() {
+        let mut state = State {
+            opened_orders: Some(vec![]),
+            ticker: Some(40000.),
+            ..State::default()
+        };
+        // Nothing to hedge, the worker doesn't bump the revision
+        let before = PlannerMarks::new(&state);
+        state.calculate_next_actions().unwrap();
+        assert_eq!(state.scheduled_actions, vec![]);
+        assert!(PlannerMarks::new(&state) == before);
+
+        state.next_chunk_at = Some(Utc::now().naive_utc());
+        assert!(PlannerMarks::new(&state) != before);
+    }
 }
@@ -0,0 +1,221 @@
+use super::state::*;
+use kollider_api::kollider::api::OrderSide;
+use log::*;
+use rweb::Schema;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Execution strategy that is selected by the config
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Schema, Clone, Copy)]
+pub enum StrategyKind {
+    /// Limit orders with price shifted by `spread_percent` to be executed immediately
+    Limit,
+    /// Limit orders at the index price that wait in the order book for a maker fill
+    Passive,
+}
+
+impl Default for StrategyKind {
+    fn default() -> Self {
+        StrategyKind::Limit
+    }
+}
+
+impl StrategyKind {
+    pub fn strategy(&self) -> Box<dyn HedgeStrategy + Send + Sync> {
+        match self {
+            StrategyKind::Limit => Box::new(LimitStrategy),
+            StrategyKind::Passive => Box::new(PassiveStrategy),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
+pub struct UnknownStrategy(String);
+
+impl std::error::Error for UnknownStrategy {}
+
+impl fmt::Display for UnknownStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Given strategy '{}' is unknown, valid are: limit, passive",
+            self.0
+        )
+    }
+}
+
+impl fmt::Display for StrategyKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StrategyKind::Limit => write!(f, "limit"),
+            StrategyKind::Passive => write!(f, "passive"),
+        }
+    }
+}
+
+impl FromStr for StrategyKind {
+    type Err = UnknownStrategy;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "limit" => Ok(StrategyKind::Limit),
+            "passive" => Ok(StrategyKind::Passive),
+            _ => Err(UnknownStrategy(s.to_owned())),
+        }
+    }
+}
+
+/// Decides which orders to place to keep the position equal to the channels balance
+pub trait HedgeStrategy {
+    /// Price of the order in sats/USD given current price in sats/USD
+    fn order_price(&self, config: &HedgeConfig, cur_price: u64, side: OrderSide) -> u64;
+
+    /// Put actions that we need to execute into `scheduled_actions` of the state
+    fn next_actions(&self, state: &mut State) -> Result<(), NextActionError> {
+        follow_hedge_capacity(self, state)
+    }
+}
+
+/// Default strategy that crosses the spread to fill orders fast
+#[derive(Debug, Clone, Copy)]
+pub struct LimitStrategy;
+
+impl HedgeStrategy for LimitStrategy {
+    fn order_price(&self, config: &HedgeConfig, cur_price: u64, side: OrderSide) -> u64 {
+        let spread = match side {
+            OrderSide::Bid => 0.01 * config.spread_percent,
+            OrderSide::Ask => -0.01 * config.spread_percent,
+        };
+        (cur_price as f64 * (1.0 + spread)).round() as u64
+    }
+}
+
+/// Strategy that places orders at the index price and pays maker fees
+#[derive(Debug, Clone, Copy)]
+pub struct PassiveStrategy;
+
+impl HedgeStrategy for PassiveStrategy {
+    fn order_price(&self, _config: &HedgeConfig, cur_price: u64, _side: OrderSide) -> u64 {
+        cur_price
+    }
+}
+
+/// Open or close position when it differs from the hedge capacity by more than allowed gap.
+/// Bid and Ask orders that negate each other are cancelled first.
+pub fn follow_hedge_capacity<S: HedgeStrategy + ?Sized>(
+    strategy: &S,
+    state: &mut State,
+) -> Result<(), NextActionError> {
+    trace!("Calculation if we need to open new order");
+    state.check_leverage_migration();
+    if state.in_maintenance() || !state.index_trusted() || !state.clock_skew_allowed() {
+        // Accumulation restarts when planning resumes, an expired timer would spin the worker
+        state.unhedged_since = None;
+        return Ok(());
+    }
+    if state.symbol_migration.is_some() {
+        state.unhedged_since = None;
+        state.migrate_symbol();
+        return Ok(());
+    }
+    if let (Some(short_orders), Some(long_orders), Some(cur_price)) = (
+        state.short_orders(),
+        state.long_orders(),
+        state.current_price(),
+    ) {
+        let base_price = state.order_base_price().unwrap_or(cur_price);
+        if state.leverage_migration.is_none() && state.hedged_channels().all(|(_, h)| h.sats == 0) {
+            let price = strategy.order_price(&state.config, base_price, OrderSide::Ask);
+            state.close_residual_position(price);
+            return Ok(());
+        }
+        if state.net_opposing_orders() {
+            return Ok(());
+        }
+        // While migrating to new leverage we close the whole position with the old one
+        let hcap = if state.leverage_migration.is_some() {
+            0
+        } else {
+            let hcap = state.hedge_capacity() as i64;
+            state.limit_hedge_capacity(hcap)
+        };
+        let leverage = state.order_leverage();
+        let scheduled_shorts = state.scheduled_shorts() as i64;
+        let scheduled_longs = state.scheduled_longs() as i64;
+        let opening_shorts = state.opening_shorts() as i64;
+        let opening_longs = state.opening_longs() as i64;
+        let pos_volume: i64 = state.position_volume() as i64;
+        let pos_short = pos_volume + short_orders as i64 + scheduled_shorts + opening_shorts;
+        let pos_long = pos_volume - long_orders as i64 - scheduled_longs - opening_longs;
+        let gap = ALLOWED_POSITION_GAP * cur_price as i64;
+        trace!("hcap {} > pos_short {} + gap {}", hcap, pos_short, gap);
+        trace!("hcap {} < pos_long {} - gap {}", hcap, pos_long, gap);
+        if hcap > pos_short + gap {
+            if !state.aggregation_passed((hcap - pos_short) as u64) {
+                return Ok(());
+            }
+            debug!(
+                "Decided to open short position as hcap {} > pos_short {} + gap {}",
+                hcap, pos_short, gap
+            );
+            let price = strategy.order_price(&state.config, base_price, OrderSide::Bid);
+            debug!("Current price {}, price of order {}", cur_price, price);
+            if pos_short > hcap {
+                return Err(NextActionError::PositionOverflow(pos_short, hcap));
+            }
+            state.schedule_orders((hcap - pos_short) as u64, price, OrderSide::Bid, leverage);
+        } else if hcap < pos_long - gap {
+            if !state.aggregation_passed((pos_long - hcap) as u64) {
+                return Ok(());
+            }
+            debug!(
+                "Decided to close position as hcap {} < pos_long {} - gap {}",
+                hcap, pos_long, gap
+            );
+            let price = strategy.order_price(&state.config, base_price, OrderSide::Ask);
+            debug!("Current price {}, price of order {}", cur_price, price);
+            if hcap > pos_long {
+                return Err(NextActionError::PositionOverflow(pos_long, hcap));
+            }
+            state.schedule_orders((pos_long - hcap) as u64, price, OrderSide::Ask, leverage);
+        } else {
+            // Position follows the hedge, the rest of a chunked order is not needed
+            state.unhedged_since = None;
+            state.next_chunk_at = None;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::update::ChannelHedge;
+    use super::*;
+
+    #[test]
+    fn test_passive_strategy() {
+        let mut state = State {
+            config: HedgeConfig {
+                strategy: StrategyKind::Passive,
+                ..HedgeConfig::default()
+            },
+            opened_orders: Some(vec![]),
+            ticker: Some(40000.),
+            ..State::default()
+        };
+        state.channels_hedge.insert(
+            "aboba".to_owned(),
+            ChannelHedge {
+                sats: 100_000,
+                rate: 2500,
+            },
+        );
+        state.calculate_next_actions().unwrap();
+        match &state.scheduled_actions[..] {
+            [StateAction::OpenOrder(order)] => assert_eq!(order.price, 2500),
+            actions => panic!("Unexpected actions: {:?}", actions),
+        }
+    }
+}
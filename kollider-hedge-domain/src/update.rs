@@ -1,3 +1,4 @@
+use super::state::{KolliderOrder, KolliderPosition, OpeningOrder, PendingDeposit};
 use chrono::prelude::*;
 use rweb::Schema;
 use serde::{Deserialize, Serialize};
@@ -9,18 +10,34 @@ use thiserror::Error;
 /// All database updates are collected to a single table that
 /// allows to reconstruct current state of the system by replaying
 /// all events until required timestamp.
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Schema, Clone)]
 pub struct StateUpdate {
     pub created: NaiveDateTime,
     pub body: UpdateBody,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Schema, Clone)]
 pub enum UpdateBody {
     /// Updating state of required hedging for given channel
     Htlc(HtlcUpdate),
     /// Caching current state to database for speeding startup time
     Snapshot(StateSnapshot),
+    /// Runtime change of hedging configuration
+    ConfigChange(ConfigChange),
+    /// Removal of channels with zero balance
+    ChannelsRemoved(ChannelsRemoval),
+    /// Exchange rejected the order that we sent
+    OrderRejected(OrderRejection),
+    /// Withdrawal of excess margin from Kollider
+    Withdrawal(Withdrawal),
+    /// Compensation of an erroneous HTLC update
+    Correction(Correction),
+    /// Funding payment of the opened position
+    Funding(FundingPayment),
+    /// Realized PnL of the position settled into the balance
+    Settlement(PnlSettlement),
+    /// Deposit invoice issued by Kollider, tracked until the balance grows by the amount
+    Deposit(Deposit),
 }
 
 impl UpdateBody {
@@ -28,6 +45,38 @@ impl UpdateBody {
         match self {
             UpdateBody::Htlc(_) => UpdateTag::Htlc,
             UpdateBody::Snapshot(_) => UpdateTag::Snapshot,
+            UpdateBody::ConfigChange(_) => UpdateTag::ConfigChange,
+            UpdateBody::ChannelsRemoved(_) => UpdateTag::ChannelsRemoved,
+            UpdateBody::OrderRejected(_) => UpdateTag::OrderRejected,
+            UpdateBody::Withdrawal(_) => UpdateTag::Withdrawal,
+            UpdateBody::Correction(_) => UpdateTag::Correction,
+            UpdateBody::Funding(_) => UpdateTag::Funding,
+            UpdateBody::Settlement(_) => UpdateTag::Settlement,
+            UpdateBody::Deposit(_) => UpdateTag::Deposit,
+        }
+    }
+
+    /// Channels which hedge is changed by the update, `None` if the update replaces all channels
+    pub fn affected_channels(&self) -> Option<Vec<ChannelId>> {
+        match self {
+            UpdateBody::Htlc(htlc) => Some(vec![htlc.channel_id.clone()]),
+            UpdateBody::Snapshot(_) => None,
+            UpdateBody::ChannelsRemoved(removal) => Some(removal.channel_ids.clone()),
+            UpdateBody::Correction(correction) => {
+                let mut ids = vec![correction.original.channel_id.clone()];
+                if let Some(htlc) = &correction.replacement {
+                    if htlc.channel_id != correction.original.channel_id {
+                        ids.push(htlc.channel_id.clone());
+                    }
+                }
+                Some(ids)
+            }
+            UpdateBody::ConfigChange(_)
+            | UpdateBody::OrderRejected(_)
+            | UpdateBody::Withdrawal(_)
+            | UpdateBody::Funding(_)
+            | UpdateBody::Settlement(_)
+            | UpdateBody::Deposit(_) => Some(vec![]),
         }
     }
 
@@ -35,6 +84,14 @@ impl UpdateBody {
         match self {
             UpdateBody::Htlc(v) => serde_json::to_value(v),
             UpdateBody::Snapshot(v) => serde_json::to_value(v),
+            UpdateBody::ConfigChange(v) => serde_json::to_value(v),
+            UpdateBody::ChannelsRemoved(v) => serde_json::to_value(v),
+            UpdateBody::OrderRejected(v) => serde_json::to_value(v),
+            UpdateBody::Withdrawal(v) => serde_json::to_value(v),
+            UpdateBody::Correction(v) => serde_json::to_value(v),
+            UpdateBody::Funding(v) => serde_json::to_value(v),
+            UpdateBody::Settlement(v) => serde_json::to_value(v),
+            UpdateBody::Deposit(v) => serde_json::to_value(v),
         }
     }
 }
@@ -43,6 +100,14 @@ impl UpdateBody {
 pub enum UpdateTag {
     Htlc,
     Snapshot,
+    ConfigChange,
+    ChannelsRemoved,
+    OrderRejected,
+    Withdrawal,
+    Correction,
+    Funding,
+    Settlement,
+    Deposit,
 }
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
@@ -54,7 +119,7 @@ impl fmt::Display for UnknownUpdateTag {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "Given UpdateTag '{}' is unknown, valid are: Htlc, Snapshot",
+            "Given UpdateTag '{}' is unknown, valid are: Htlc, Snapshot, ConfigChange, ChannelsRemoved, OrderRejected, Withdrawal, Correction, Funding, Settlement, Deposit",
             self.0
         )
     }
@@ -65,6 +130,14 @@ impl fmt::Display for UpdateTag {
         match self {
             UpdateTag::Htlc => write!(f, "htlc"),
             UpdateTag::Snapshot => write!(f, "snapshot"),
+            UpdateTag::ConfigChange => write!(f, "config_change"),
+            UpdateTag::ChannelsRemoved => write!(f, "channels_removed"),
+            UpdateTag::OrderRejected => write!(f, "order_rejected"),
+            UpdateTag::Withdrawal => write!(f, "withdrawal"),
+            UpdateTag::Correction => write!(f, "correction"),
+            UpdateTag::Funding => write!(f, "funding"),
+            UpdateTag::Settlement => write!(f, "settlement"),
+            UpdateTag::Deposit => write!(f, "deposit"),
         }
     }
 }
@@ -76,6 +149,14 @@ impl FromStr for UpdateTag {
         match s.to_lowercase().as_ref() {
             "htlc" => Ok(UpdateTag::Htlc),
             "snapshot" => Ok(UpdateTag::Snapshot),
+            "config_change" => Ok(UpdateTag::ConfigChange),
+            "channels_removed" => Ok(UpdateTag::ChannelsRemoved),
+            "order_rejected" => Ok(UpdateTag::OrderRejected),
+            "withdrawal" => Ok(UpdateTag::Withdrawal),
+            "correction" => Ok(UpdateTag::Correction),
+            "funding" => Ok(UpdateTag::Funding),
+            "settlement" => Ok(UpdateTag::Settlement),
+            "deposit" => Ok(UpdateTag::Deposit),
             _ => Err(UnknownUpdateTag(s.to_owned())),
         }
     }
@@ -91,7 +172,7 @@ pub enum UpdateBodyError {
     UnexpectedVersion(u16),
 }
 
-pub const CURRENT_BODY_VERSION: u16 = 0;
+pub const CURRENT_BODY_VERSION: u16 = 2;
 
 impl UpdateTag {
     pub fn from_tag(
@@ -100,17 +181,74 @@ impl UpdateTag {
         value: serde_json::Value,
     ) -> Result<UpdateBody, UpdateBodyError> {
         let tag = <UpdateTag as FromStr>::from_str(tag)?;
-        if version != CURRENT_BODY_VERSION {
-            return Err(UpdateBodyError::UnexpectedVersion(version));
-        }
+        let value = tag.migrate_body(version, value)?;
         tag.deserialize(value.clone())
             .map_err(|e| UpdateBodyError::Deserialize(version, tag, e, value))
     }
 
+    /// Upgrade JSON body of the given version to the `CURRENT_BODY_VERSION` step by step
+    pub fn migrate_body(
+        &self,
+        version: u16,
+        value: serde_json::Value,
+    ) -> Result<serde_json::Value, UpdateBodyError> {
+        if version > CURRENT_BODY_VERSION {
+            return Err(UpdateBodyError::UnexpectedVersion(version));
+        }
+        let mut value = value;
+        for v in version..CURRENT_BODY_VERSION {
+            value = self.upgrade_body(v, value)?;
+        }
+        Ok(value)
+    }
+
+    /// Upgrade JSON body from the `version` to the `version + 1`. Add a new arm each time
+    /// `CURRENT_BODY_VERSION` is bumped. Bodies of tags that didn't change are passed as is.
+    fn upgrade_body(
+        &self,
+        version: u16,
+        value: serde_json::Value,
+    ) -> Result<serde_json::Value, UpdateBodyError> {
+        match (self, version) {
+            // Snapshots of version 0 have only hedge of channels
+            (UpdateTag::Snapshot, 0) => {
+                let mut value = value;
+                if let Some(body) = value.as_object_mut() {
+                    for field in ["opened_orders", "opened_position", "balance", "ticker"] {
+                        body.entry(field).or_insert(serde_json::Value::Null);
+                    }
+                }
+                Ok(value)
+            }
+            // Snapshots of version 1 don't carry the config, the empty change keeps it
+            (UpdateTag::Snapshot, 1) => {
+                let mut value = value;
+                if let Some(body) = value.as_object_mut() {
+                    body.entry("config")
+                        .or_insert_with(|| serde_json::json!({}));
+                }
+                Ok(value)
+            }
+            _ => Ok(value),
+        }
+    }
+
     pub fn deserialize(&self, value: serde_json::Value) -> Result<UpdateBody, serde_json::Error> {
         match self {
             UpdateTag::Htlc => Ok(UpdateBody::Htlc(serde_json::from_value(value)?)),
             UpdateTag::Snapshot => Ok(UpdateBody::Snapshot(serde_json::from_value(value)?)),
+            UpdateTag::ConfigChange => Ok(UpdateBody::ConfigChange(serde_json::from_value(value)?)),
+            UpdateTag::ChannelsRemoved => {
+                Ok(UpdateBody::ChannelsRemoved(serde_json::from_value(value)?))
+            }
+            UpdateTag::OrderRejected => {
+                Ok(UpdateBody::OrderRejected(serde_json::from_value(value)?))
+            }
+            UpdateTag::Withdrawal => Ok(UpdateBody::Withdrawal(serde_json::from_value(value)?)),
+            UpdateTag::Correction => Ok(UpdateBody::Correction(serde_json::from_value(value)?)),
+            UpdateTag::Funding => Ok(UpdateBody::Funding(serde_json::from_value(value)?)),
+            UpdateTag::Settlement => Ok(UpdateBody::Settlement(serde_json::from_value(value)?)),
+            UpdateTag::Deposit => Ok(UpdateBody::Deposit(serde_json::from_value(value)?)),
         }
     }
 }
@@ -120,7 +258,7 @@ pub type ChannelId = String;
 /// Amount of satoshis
 pub type Sats = i64;
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Schema, Clone)]
 pub struct HtlcUpdate {
     pub channel_id: ChannelId,
     pub sats: Sats,
@@ -141,6 +279,8 @@ pub enum HtlcUpdateErr {
     InsufficientFiatBalance(Sats, Sats, Sats, Sats),
     #[error("New rate cannot fin in the 64 bits. Was {0}/{1}, update {2}/{3}, new rate: {4}")]
     RateOverflow(Sats, Sats, Sats, Sats, i128),
+    #[error("Hedge {0}/{1} cannot be changed to {2}/{3} with a single HTLC")]
+    UnreachableTarget(Sats, Sats, Sats, Sats),
 }
 
 impl ChannelHedge {
@@ -152,6 +292,69 @@ impl ChannelHedge {
         })
     }
 
+    /// Find HTLC that moves the hedge to the target amount of sats and rate. Returns `None` if
+    /// the hedge is already at the target. The resulting rate can differ from the target by
+    /// rounding.
+    pub fn delta_to(
+        &self,
+        channel_id: &str,
+        target: &ChannelHedge,
+    ) -> Result<Option<HtlcUpdate>, HtlcUpdateErr> {
+        let unreachable =
+            || HtlcUpdateErr::UnreachableTarget(self.sats, self.rate, target.sats, target.rate);
+        let htlc = |sats, rate| {
+            Ok(Some(HtlcUpdate {
+                channel_id: channel_id.to_owned(),
+                sats,
+                rate,
+            }))
+        };
+        if self.sats == 0 {
+            return if target.sats == 0 {
+                Ok(None)
+            } else {
+                htlc(target.sats, target.rate)
+            };
+        }
+        if target.sats == 0 {
+            // Withdrawal at exactly the channel rate leaves zero fiat balance that is rejected by
+            // the weighted sum, a slightly higher rate withdraws the same fiat after rounding.
+            return htlc(-self.sats, self.rate + 1);
+        }
+        let delta_sats = target.sats - self.sats;
+        if delta_sats == 0 {
+            return if target.rate == self.rate {
+                Ok(None)
+            } else {
+                Err(unreachable())
+            };
+        }
+        let (s0, r0) = (self.sats as i128, self.rate as i128);
+        let (s, r) = (target.sats as i128, target.rate as i128);
+        let denominator = s * r0 - s0 * r;
+        if denominator == 0 {
+            return Err(unreachable());
+        }
+        let rate = (delta_sats as i128 * r * r0) as f64 / denominator as f64;
+        if rate < 1.0 || rate > i64::MAX as f64 {
+            return Err(unreachable());
+        }
+        htlc(delta_sats, rate.round() as i64)
+    }
+
+    /// Revert the HTLC that was applied to the hedge earlier
+    pub fn without_htlc(self, htlc: &HtlcUpdate) -> Result<ChannelHedge, HtlcUpdateErr> {
+        if self.sats == htlc.sats {
+            // Exact inverse leaves zero fiat balance that is rejected by the weighted sum
+            return Ok(ChannelHedge { sats: 0, rate: 0 });
+        }
+        self.with_htlc(HtlcUpdate {
+            channel_id: htlc.channel_id.clone(),
+            sats: -htlc.sats,
+            rate: htlc.rate,
+        })
+    }
+
     pub fn with_htlc(self, htlc: HtlcUpdate) -> Result<ChannelHedge, HtlcUpdateErr> {
         if self.sats + htlc.sats < 0 {
             return Err(HtlcUpdateErr::InsufficientSatsBalance(
@@ -188,15 +391,202 @@ impl ChannelHedge {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+/// State cached to the database. Besides hedge of channels it has the last known state of the
+/// exchange, so the service can make decisions after restart before Kollider data is refetched.
+/// Replay starts from the latest snapshot, so it also carries everything that updates before it
+/// built up. Fields that are `None` keep the current values.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Schema, Clone, Default)]
 pub struct StateSnapshot {
     pub channels_hedge: HashMap<ChannelId, ChannelHedge>,
+    pub opened_orders: Option<Vec<KolliderOrder>>,
+    pub opened_position: Option<KolliderPosition>,
+    /// Balance on Kollider in BTC
+    pub balance: Option<f64>,
+    pub ticker: Option<f64>,
+    /// Config set by recorded config changes, empty change keeps the current config
+    pub config: ConfigChange,
+    /// Command line flags of the last startup
+    pub startup_config: Option<ConfigChange>,
+    pub config_changed: Option<NaiveDateTime>,
+    /// Settled PnL in sats that is not reported in the balance yet
+    pub pending_settlement: Option<i64>,
+    /// Sats withdrawn since the start of the daily report period
+    pub withdrawals_sats: Option<u64>,
+    /// Deposit invoices that are not paid yet
+    pub pending_deposits: Option<Vec<PendingDeposit>>,
+}
+
+/// Change of hedging configuration. Only provided fields are changed.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Schema, Clone, Default)]
+pub struct ConfigChange {
+    pub hedge_pair: Option<String>,
+    pub hedge_sym: Option<String>,
+    pub spread_percent: Option<f64>,
+    pub hedge_leverage: Option<u64>,
+    /// Replaces the list of channels that are tracked but not hedged
+    pub unhedged_channels: Option<Vec<ChannelId>>,
+    /// Replaces leverage * 100 overrides of channels
+    pub channel_leverage: Option<HashMap<ChannelId, u64>>,
+    /// The change holds command line flags of a startup. Only flags that differ from the
+    /// previous startup are applied, so changes made at runtime survive restarts.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub startup: bool,
+}
+
+impl ConfigChange {
+    pub fn is_empty(&self) -> bool {
+        self.hedge_pair.is_none()
+            && self.hedge_sym.is_none()
+            && self.spread_percent.is_none()
+            && self.hedge_leverage.is_none()
+            && self.unhedged_channels.is_none()
+            && self.channel_leverage.is_none()
+    }
+
+    /// Fields of the change that differ from the previous one
+    pub fn changed_since(&self, prev: &ConfigChange) -> ConfigChange {
+        fn diff<T: PartialEq + Clone>(a: &Option<T>, b: &Option<T>) -> Option<T> {
+            a.clone().filter(|v| b.as_ref() != Some(v))
+        }
+        ConfigChange {
+            hedge_pair: diff(&self.hedge_pair, &prev.hedge_pair),
+            hedge_sym: diff(&self.hedge_sym, &prev.hedge_sym),
+            spread_percent: diff(&self.spread_percent, &prev.spread_percent),
+            hedge_leverage: diff(&self.hedge_leverage, &prev.hedge_leverage),
+            unhedged_channels: diff(&self.unhedged_channels, &prev.unhedged_channels),
+            channel_leverage: diff(&self.channel_leverage, &prev.channel_leverage),
+            startup: self.startup,
+        }
+    }
+}
+
+/// Channels that are removed from the state as their balance is zero for long time
+#[derive(Serialize, Deserialize, Debug, PartialEq, Schema, Clone)]
+pub struct ChannelsRemoval {
+    pub channel_ids: Vec<ChannelId>,
+}
+
+/// Order that was rejected by the exchange
+#[derive(Serialize, Deserialize, Debug, PartialEq, Schema, Clone)]
+pub struct OrderRejection {
+    pub ext_id: String,
+    pub reason: String,
+    /// The order as it was sent, `None` if the order was not tracked as opening
+    pub order: Option<OpeningOrder>,
+}
+
+/// Lightning withdrawal of excess margin from Kollider. It is recorded before Kollider is
+/// asked to pay the invoice.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Schema, Clone)]
+pub struct Withdrawal {
+    pub sats: u64,
+    /// Invoice of our node that Kollider pays
+    pub invoice: String,
+    /// Kollider refused to pay the invoice of the withdrawal recorded before
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub failed: bool,
+}
+
+/// Lightning invoice issued by Kollider to top up the margin. The state tracks it until the
+/// balance grows by the amount or the invoice expires.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Schema, Clone)]
+pub struct Deposit {
+    pub sats: u64,
+    /// Invoice that our node pays
+    pub invoice: String,
+}
+
+/// Funding paid or received by the opened position. Kollider doesn't report funding separately,
+/// so it is the change of balance that is not explained by orders, deposits or settlements.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Schema, Clone)]
+pub struct FundingPayment {
+    pub symbol: String,
+    /// Positive if the funding is received
+    pub sats: i64,
+    /// Contracts in the position when the funding is paid
+    pub position_quantity: u64,
+}
+
+/// Realized PnL of the position that Kollider credited to the balance and reset `rpnl` of the
+/// position
+#[derive(Serialize, Deserialize, Debug, PartialEq, Schema, Clone)]
+pub struct PnlSettlement {
+    pub symbol: String,
+    /// Positive if the balance is credited
+    pub sats: i64,
+}
+
+/// Compensating update that voids an erroneous HTLC update and optionally records the correct
+/// HTLC instead. The erroneous update stays in the chain.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Schema, Clone)]
+pub struct Correction {
+    /// Moment of the erroneous update
+    pub corrected: NaiveDateTime,
+    /// The erroneous HTLC as it was recorded
+    pub original: HtlcUpdate,
+    /// HTLC that should have been recorded, `None` voids the update
+    pub replacement: Option<HtlcUpdate>,
+    pub reason: String,
+    /// Admin who made the correction
+    pub author: String,
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_migrate_body() {
+        let body = serde_json::json!({"channel_id": "aboba", "sats": 100, "rate": 2500});
+        assert_eq!(
+            UpdateTag::from_tag("htlc", CURRENT_BODY_VERSION, body.clone()).unwrap(),
+            UpdateBody::Htlc(HtlcUpdate {
+                channel_id: "aboba".to_owned(),
+                sats: 100,
+                rate: 2500,
+            })
+        );
+        assert!(matches!(
+            UpdateTag::Htlc.migrate_body(CURRENT_BODY_VERSION + 1, body),
+            Err(UpdateBodyError::UnexpectedVersion(_))
+        ));
+
+        let snapshot =
+            serde_json::json!({"channels_hedge": {"aboba": {"sats": 100, "rate": 2500}}});
+        assert_eq!(
+            UpdateTag::from_tag("snapshot", 0, snapshot).unwrap(),
+            UpdateBody::Snapshot(StateSnapshot {
+                channels_hedge: vec![(
+                    "aboba".to_owned(),
+                    ChannelHedge {
+                        sats: 100,
+                        rate: 2500
+                    }
+                )]
+                .into_iter()
+                .collect(),
+                ..StateSnapshot::default()
+            })
+        );
+
+        // Version 1 snapshots lack the config that is required by the current version
+        let snapshot = serde_json::json!({
+            "channels_hedge": {},
+            "opened_orders": null,
+            "opened_position": null,
+            "balance": 0.001,
+            "ticker": null,
+        });
+        assert!(UpdateTag::Snapshot.deserialize(snapshot.clone()).is_err());
+        assert_eq!(
+            UpdateTag::from_tag("snapshot", 1, snapshot).unwrap(),
+            UpdateBody::Snapshot(StateSnapshot {
+                balance: Some(0.001),
+                ..StateSnapshot::default()
+            })
+        );
+    }
+
     #[test]
     fn test_weighted_summ_add_01() {
         let hedge = ChannelHedge {
@@ -407,4 +797,38 @@ mod tests {
             })
         );
     }
+
+    #[test]
+    fn test_hedge_delta() {
+        let hedge = ChannelHedge {
+            sats: 100,
+            rate: 3000,
+        };
+        let target = ChannelHedge {
+            sats: 200,
+            rate: 1500,
+        };
+        let delta = hedge.delta_to("aboba", &target).unwrap().unwrap();
+        assert_eq!(delta.sats, 100);
+        assert_eq!(delta.rate, 1000);
+        assert_eq!(hedge.clone().with_htlc(delta), Ok(target));
+
+        let target = ChannelHedge {
+            sats: 50,
+            rate: 3000,
+        };
+        let delta = hedge.delta_to("aboba", &target).unwrap().unwrap();
+        assert_eq!(hedge.clone().with_htlc(delta), Ok(target));
+
+        let target = ChannelHedge { sats: 0, rate: 0 };
+        let delta = hedge.delta_to("aboba", &target).unwrap().unwrap();
+        assert_eq!(hedge.clone().with_htlc(delta), Ok(target));
+
+        assert_eq!(hedge.delta_to("aboba", &hedge), Ok(None));
+        let target = ChannelHedge {
+            sats: 100,
+            rate: 2000,
+        };
+        assert!(hedge.delta_to("aboba", &target).is_err());
+    }
 }
@@ -0,0 +1,239 @@
+use chrono::prelude::*;
+use kollider_hedge_domain::state::{write_state, DriftAlarm, State};
+use log::*;
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::{Notify, RwLock};
+use tokio::time::sleep;
+
+/// How often the unhedged amount is checked against the drift threshold
+const DRIFT_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often the position with open orders is compared with the required hedge
+const POSITION_DRIFT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Forced recalculations that may fail to close the position drift before the alert
+const FORCED_RECALCULATIONS: u32 = 3;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to send alert: {0}")]
+    Request(#[from] reqwest::Error),
+}
+
+/// Where alerts are sent besides the log
+#[derive(Debug, Clone, Default)]
+pub struct AlertConfig {
+    /// URL that receives alerts as JSON in POST requests
+    pub webhook: Option<String>,
+    pub telegram: Option<TelegramConfig>,
+}
+
+/// Telegram bot that posts alerts to a chat
+#[derive(Debug, Clone)]
+pub struct TelegramConfig {
+    pub token: String,
+    pub chat_id: String,
+}
+
+#[derive(Serialize)]
+struct WebhookBody<'a, T: Serialize> {
+    alert: &'a str,
+    message: &'a str,
+    #[serde(flatten)]
+    details: &'a T,
+}
+
+#[derive(Serialize)]
+struct TelegramMessage<'a> {
+    chat_id: &'a str,
+    text: &'a str,
+}
+
+#[derive(Debug, Clone)]
+pub struct Alerter {
+    client: reqwest::Client,
+    config: AlertConfig,
+}
+
+impl Alerter {
+    pub fn new(config: AlertConfig) -> Self {
+        Alerter {
+            client: reqwest::Client::new(),
+            config,
+        }
+    }
+
+    /// Send the alert to all configured destinations. Failure of one destination doesn't
+    /// prevent sending to others.
+    pub async fn send<T: Serialize>(&self, alert: &str, message: &str, details: &T) {
+        if let Some(url) = &self.config.webhook {
+            let body = WebhookBody {
+                alert,
+                message,
+                details,
+            };
+            if let Err(e) = self.post(url, &body).await {
+                error!("Failed to send {} alert to webhook: {}", alert, e);
+            }
+        }
+        if let Some(telegram) = &self.config.telegram {
+            let url = format!("https://api.telegram.org/bot{}/sendMessage", telegram.token);
+            let body = TelegramMessage {
+                chat_id: &telegram.chat_id,
+                text: message,
+            };
+            if let Err(e) = self.post(&url, &body).await {
+                error!("Failed to send {} alert to Telegram: {}", alert, e);
+            }
+        }
+    }
+
+    async fn post<B: Serialize>(&self, url: &str, body: &B) -> Result<(), Error> {
+        self.client
+            .post(url)
+            .json(body)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Human readable text of the drift alarm
+fn drift_message(alarm: &DriftAlarm, max_usd: f64) -> String {
+    match alarm.since {
+        Some(since) if alarm.raised => format!(
+            "Hedge drift: unhedged amount {:.2} USD exceeds {} USD since {} UTC",
+            alarm.unhedged_usd, max_usd, since
+        ),
+        _ => format!(
+            "Hedge drift resolved: unhedged amount {:.2} USD is within {} USD",
+            alarm.unhedged_usd, max_usd
+        ),
+    }
+}
+
+/// Periodically compare the unhedged amount with `drift_alarm_usd` and send alerts when
+/// the drift alarm is raised or cleared
+pub async fn drift_alarm_worker(
+    alerter: Alerter,
+    state_mx: Arc<RwLock<State>>,
+    state_notify: Arc<Notify>,
+) {
+    loop {
+        sleep(DRIFT_CHECK_INTERVAL).await;
+        let (alarm, max_usd) = {
+            let mut state = write_state(&state_mx).await;
+            (
+                state.check_drift(Utc::now().naive_utc()),
+                state.config.drift_alarm_usd,
+            )
+        };
+        if let Some(alarm) = alarm {
+            state_notify.notify_waiters();
+            let message = drift_message(&alarm, max_usd);
+            alerter.send("hedge_drift", &message, &alarm).await;
+        }
+    }
+}
+
+/// Details of the position drift alert
+#[derive(Debug, Serialize)]
+struct PositionDriftAlert {
+    raised: bool,
+    drift_sats: Option<i64>,
+    checks: u32,
+}
+
+/// Compare the position with open orders and the required hedge every minute. When it stays
+/// outside of the allowed gap for `checks` consecutive checks the planner is woken up, as a
+/// missed notification would otherwise stall it until the next change. An alert is sent if
+/// forced recalculations don't help. 0 `checks` only measures the drift.
+pub async fn position_drift_worker(
+    alerter: Alerter,
+    state_mx: Arc<RwLock<State>>,
+    state_notify: Arc<Notify>,
+    checks: u32,
+) {
+    let mut alerted = false;
+    loop {
+        sleep(POSITION_DRIFT_INTERVAL).await;
+        let (count, drift_sats) = {
+            let mut state = write_state(&state_mx).await;
+            (state.check_position_drift(), state.position_drift)
+        };
+        let drift = drift_sats.unwrap_or_default();
+        if count == 0 {
+            if alerted {
+                info!("Position drift {} sats is back within the gap", drift);
+                let message = format!(
+                    "Position drift resolved: position is {} sats away from the hedge",
+                    drift
+                );
+                let details = PositionDriftAlert {
+                    raised: false,
+                    drift_sats,
+                    checks: count,
+                };
+                alerter.send("position_drift", &message, &details).await;
+                alerted = false;
+            }
+            continue;
+        }
+        if checks == 0 || count % checks != 0 {
+            continue;
+        }
+        warn!(
+            "Position drifts by {} sats from the hedge for {} checks, forcing recalculation",
+            drift, count
+        );
+        state_notify.notify_waiters();
+        if count / checks > FORCED_RECALCULATIONS && !alerted {
+            error!(
+                "ALERT: position drifts by {} sats from the hedge after {} forced recalculations",
+                drift, FORCED_RECALCULATIONS
+            );
+            let message = format!(
+                "Position drift: position is {} sats away from the hedge for {} minutes, recalculation doesn't help",
+                drift, count
+            );
+            let details = PositionDriftAlert {
+                raised: true,
+                drift_sats,
+                checks: count,
+            };
+            alerter.send("position_drift", &message, &details).await;
+            alerted = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drift_alert() {
+        let alarm = DriftAlarm {
+            raised: true,
+            unhedged_usd: -42.5,
+            since: Some(NaiveDate::from_ymd(2022, 3, 1).and_hms(12, 0, 0)),
+        };
+        assert_eq!(
+            drift_message(&alarm, 10.0),
+            "Hedge drift: unhedged amount -42.50 USD exceeds 10 USD since 2022-03-01 12:00:00 UTC"
+        );
+        let body = WebhookBody {
+            alert: "hedge_drift",
+            message: "",
+            details: &alarm,
+        };
+        let json = serde_json::to_value(&body).unwrap();
+        assert_eq!(json["alert"], "hedge_drift");
+        assert_eq!(json["raised"], true);
+        assert_eq!(json["unhedged_usd"], -42.5);
+    }
+}
@@ -1,53 +1,735 @@
+use crate::kollider::hedge::compression::compressed;
 use crate::kollider::hedge::db::queries::{self, insert_update};
-use crate::kollider::hedge::db::Pool;
+use crate::kollider::hedge::db::writer::{UpdateAck, UpdateWriter};
+use crate::kollider::hedge::db::{offline_pool, Pool};
+use crate::kollider::hedge::exchange::{self, KolliderRest};
+use crate::kollider::hedge::latency::{observe_request, LATENCY};
+use crate::kollider::hedge::monitor::WsMonitor;
+use crate::kollider::hedge::stats::StatsSnapshot;
 use ::log::*;
+use bytes::Bytes;
 use chrono::prelude::*;
+use ipnet::IpNet;
 use kollider_hedge_domain::api::*;
+use kollider_hedge_domain::eclair::{ChannelMap, EclairErr, EclairEvent};
+use kollider_hedge_domain::encoding::{Encoding, EncodingErr};
+use kollider_hedge_domain::error::{ErrorCode, ErrorMessage};
 use kollider_hedge_domain::state::*;
 use kollider_hedge_domain::update::*;
 use rweb::openapi::Spec;
 use rweb::*;
 use serde::Serialize;
+use std::collections::HashMap;
 use std::convert::From;
 use std::convert::Infallible;
 use std::error::Error;
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
 use std::sync::Arc;
-use tokio::sync::{Mutex, Notify};
+use tokio::sync::{Mutex, Notify, RwLock, RwLockWriteGuard};
 
-use warp::http::StatusCode;
+use warp::http::{Method, StatusCode};
 use warp::{Filter, Rejection, Reply};
 
 impl rweb::reject::Reject for queries::Error {}
 
+/// Options of the HTTP API that don't affect hedging logic
+#[derive(Debug, Clone, Default)]
+pub struct ApiConfig {
+    /// Reject all requests that modify state of the service
+    pub read_only: bool,
+    /// Bounds for incoming HTLCs
+    pub htlc_limits: HtlcLimits,
+    /// Token for `/admin` endpoints. Admin endpoints are disabled if the token is not set.
+    /// Shared to allow rotation of the token without restart.
+    pub admin_token: Arc<std::sync::RwLock<Option<String>>>,
+    /// Client for wallet operations on Kollider
+    pub exchange: Option<KolliderRest>,
+    /// Sats of free margin that are never withdrawn
+    pub withdraw_buffer: u64,
+    /// Networks that are allowed to call mutating endpoints. Empty list allows all addresses.
+    pub allowed_ips: Vec<IpNet>,
+    /// Origins that browsers are allowed to call the API from, `*` allows any origin. CORS
+    /// headers are not sent if the list is empty.
+    pub cors_origins: Vec<String>,
+    /// Methods allowed for cross-origin requests
+    pub cors_methods: Vec<String>,
+    /// Max amount of HTLC updates that are committed in a single transaction
+    pub write_batch: usize,
+    /// Eclair channels that are hedged by `/hedge/eclair` events
+    pub eclair_channels: ChannelMap,
+    /// Compress responses with gzip or deflate for clients that accept it
+    pub compression: bool,
+}
+
+impl ApiConfig {
+    /// Check `Authorization: Bearer <token>` header of admin request
+    fn check_admin(&self, auth: &str) -> Result<(), Rejection> {
+        let admin_token = self
+            .admin_token
+            .read()
+            .expect("Admin token lock is poisoned");
+        match &*admin_token {
+            Some(token) if auth.strip_prefix("Bearer ") == Some(token.as_str()) => Ok(()),
+            _ => Err(reject::custom(Unauthorized)),
+        }
+    }
+
+    /// CORS filter for browser clients, `None` if no origins are allowed
+    fn cors(&self) -> Option<warp::cors::Builder> {
+        if self.cors_origins.is_empty() {
+            return None;
+        }
+        let cors = warp::cors()
+            .allow_methods(self.cors_methods.iter().map(String::as_str))
+            .allow_headers(vec!["authorization", "content-type"]);
+        if self.cors_origins.iter().any(|origin| origin == "*") {
+            Some(cors.allow_any_origin())
+        } else {
+            Some(cors.allow_origins(self.cors_origins.iter().map(String::as_str)))
+        }
+    }
+
+    /// Check that the address is allowed to call mutating endpoints
+    fn is_allowed(&self, addr: Option<SocketAddr>) -> bool {
+        self.allowed_ips.is_empty()
+            || addr.map_or(false, |addr| {
+                self.allowed_ips.iter().any(|net| net.contains(&addr.ip()))
+            })
+    }
+}
+
+/// Rejection for mutating requests when the service runs in read-only mode
+#[derive(Debug)]
+pub struct ReadOnlyMode;
+
+impl rweb::reject::Reject for ReadOnlyMode {}
+
+/// Rejection for admin requests without valid token
+#[derive(Debug)]
+pub struct Unauthorized;
+
+impl rweb::reject::Reject for Unauthorized {}
+
+/// Rejection for wallet requests when Kollider REST client is not configured
+#[derive(Debug)]
+pub struct ExchangeDisabled;
+
+impl rweb::reject::Reject for ExchangeDisabled {}
+
+/// Rejection for mutating requests from addresses outside of the allowlist
+#[derive(Debug)]
+pub struct IpNotAllowed;
+
+impl rweb::reject::Reject for IpNotAllowed {}
+
+/// Reject requests other than `GET` (`/hedge/*`, `/admin/*`, `/config`) from addresses outside of
+/// the allowlist. Read endpoints stay open.
+fn ip_allowlist(api_config: ApiConfig) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::method()
+        .and(warp::addr::remote())
+        .and_then(move |method: Method, addr: Option<SocketAddr>| {
+            let allowed = method == Method::GET || api_config.is_allowed(addr);
+            async move {
+                if allowed {
+                    Ok(())
+                } else {
+                    warn!(
+                        "Rejecting {} request from {:?} by IP allowlist",
+                        method, addr
+                    );
+                    Err(reject::custom(IpNotAllowed))
+                }
+            }
+        })
+        .untuple_one()
+}
+
+/// Rejection for HTLC requests with body that is not a valid `HtlcInfo`
+#[derive(Debug)]
+pub struct MalformedHtlc(String);
+
+impl rweb::reject::Reject for MalformedHtlc {}
+
+/// Rejection for requests that need the price before the first ticker is received
+#[derive(Debug)]
+pub struct NoTicker;
+
+impl rweb::reject::Reject for NoTicker {}
+
+/// Address of the caller
+fn remote_addr() -> impl Filter<Extract = (Option<SocketAddr>,), Error = Infallible> + Clone {
+    warp::addr::remote()
+}
+
+/// Human readable reason of the rejection that is recorded to the audit of requests
+fn rejection_reason(err: &Rejection) -> String {
+    if let Some(e) = err.find::<HtlcValidationErr>() {
+        e.to_string()
+    } else if let Some(e) = err.find::<StateUpdateErr>() {
+        e.to_string()
+    } else if let Some(e) = err.find::<queries::Error>() {
+        e.to_string()
+    } else if let Some(MalformedHtlc(e)) = err.find::<MalformedHtlc>() {
+        format!("Malformed HTLC: {}", e)
+    } else if err.find::<ReadOnlyMode>().is_some() {
+        "Service is in read-only mode".to_owned()
+    } else {
+        format!("{:?}", err)
+    }
+}
+
 #[post("/hedge/htlc")]
 #[openapi(
     tags("node"),
     summary = "Update state of position to adjust to the new HTLC incoming or outcoming from a fiat channel.",
-    description = "When Eclar node receives a new HTLC to a fiat channel the endpoint is called with positive amount. If the HTLC is outcoming from the channel, the provided amount has to be negative."
+    description = "When Eclar node receives a new HTLC to a fiat channel the endpoint is called with positive amount. If the HTLC is outcoming from the channel, the provided amount has to be negative. The body is `HtlcInfo` JSON, each request is recorded as is to the audit available at `/requests`."
 )]
 async fn hedge_htlc(
     #[data] pool: Pool,
-    #[data] state_mx: Arc<Mutex<State>>,
+    #[data] writer: UpdateWriter,
+    #[data] state_mx: Arc<RwLock<State>>,
     #[data] state_notify: Arc<Notify>,
-    body: Json<HtlcInfo>,
+    #[data] api_config: ApiConfig,
+    #[filter = "remote_addr"] addr: Option<SocketAddr>,
+    #[body] body: Bytes,
 ) -> Result<Json<()>, Rejection> {
-    let htlc = body.into_inner();
+    let created = Utc::now().naive_utc();
+    let res = record_htlc(&writer, &state_mx, &state_notify, &api_config, &body).await;
+    if api_config.read_only {
+        return res.map(Json::from);
+    }
+    let error = res.as_ref().err().map(rejection_reason);
+    let raw = String::from_utf8_lossy(&body);
+    let source_ip = addr.map(|a| a.ip().to_string());
+    if let Err(e) = queries::insert_htlc_request(&pool, created, source_ip, &raw, error).await {
+        error!("Failed to record HTLC request {}: {}", raw, e);
+    }
+    res.map(Json::from)
+}
+
+/// Commit HTLC from the raw request body to the database and apply it to the state
+async fn record_htlc(
+    writer: &UpdateWriter,
+    state_mx: &RwLock<State>,
+    state_notify: &Notify,
+    api_config: &ApiConfig,
+    body: &[u8],
+) -> Result<(), Rejection> {
+    if api_config.read_only {
+        return Err(reject::custom(ReadOnlyMode));
+    }
+    let htlc: HtlcInfo =
+        serde_json::from_slice(body).map_err(|e| reject::custom(MalformedHtlc(e.to_string())))?;
+    htlc.validate(&api_config.htlc_limits)?;
     let update = StateUpdate {
         created: Utc::now().naive_utc(),
         body: UpdateBody::Htlc(htlc.into_update()),
     };
     debug!("Calling hedge_htlc");
-    {
-        let mut state = state_mx.lock().await;
-        state.apply_update(update.clone())?;
-        insert_update(&pool, update.body).await?;
-        state_notify.notify_one();
-        debug!("New state {:?}", state);
+    let updates = vec![update];
+    let ack = {
+        let mut state = write_state(&state_mx).await;
+        state.stage_updates(&updates)?;
+        writer.enqueue(updates[0].body.clone())
+    };
+    // The state is changed only after the commit, so a failed write doesn't leave the HTLC in
+    // memory. The next HTLC is checked against the staged one while the lock is released.
+    let state = apply_committed(state_mx, ack, updates).await?;
+    state_notify.notify_waiters();
+    debug!("New state {:?}", state);
+
+    Ok(())
+}
+
+/// Wait for the commit of staged updates and apply them to the state. Updates are dropped from
+/// the state if the commit fails.
+async fn apply_committed<'a>(
+    state_mx: &'a RwLock<State>,
+    ack: UpdateAck,
+    updates: Vec<StateUpdate>,
+) -> Result<RwLockWriteGuard<'a, State>, Rejection> {
+    let res = ack.committed().await;
+    let mut state = write_state(state_mx).await;
+    state.unstage_updates(&updates);
+    let _turn = res?;
+    for update in updates {
+        state.apply_update(update)?;
+    }
+    Ok(state)
+}
+
+#[post("/hedge/htlc/preview")]
+#[openapi(
+    tags("node"),
+    summary = "Show how a HTLC would change the hedge without recording it.",
+    description = "The HTLC is applied to a temporary copy of the state. Returns hedge of channels after the HTLC, actions that the planner would schedule and margin in sats that the orders require. Nothing is persisted, so the endpoint is safe to check sign conventions of an integration."
+)]
+async fn hedge_htlc_preview(
+    #[data] state_mx: Arc<RwLock<State>>,
+    #[data] api_config: ApiConfig,
+    body: Json<HtlcInfo>,
+) -> Result<Json<HtlcPreview>, Rejection> {
+    let htlc = body.into_inner();
+    htlc.validate(&api_config.htlc_limits)?;
+    let state = state_mx.read().await;
+    let preview = HtlcPreview::new(&state, htlc.into_update())?;
+    Ok(Json::from(preview))
+}
+
+#[post("/hedge/eclair")]
+#[openapi(
+    tags("node"),
+    summary = "Update position by a payment event of Eclair node.",
+    description = "Accepts `payment-relayed`, `trampoline-payment-relayed`, `payment-received` and `payment-sent` events of Eclair websocket or webhook as is. Amounts moved through Eclair channels that are mapped to fiat channels are recorded as HTLCs priced at the current Kollider index. Other channels and events are ignored. Returns the recorded HTLCs."
+)]
+async fn hedge_eclair(
+    #[data] writer: UpdateWriter,
+    #[data] state_mx: Arc<RwLock<State>>,
+    #[data] state_notify: Arc<Notify>,
+    #[data] api_config: ApiConfig,
+    #[body] body: Bytes,
+) -> Result<Json<Vec<HtlcInfo>>, Rejection> {
+    if api_config.read_only {
+        return Err(reject::custom(ReadOnlyMode));
+    }
+    let event: EclairEvent =
+        serde_json::from_slice(&body).map_err(|e| EclairErr::Malformed(e.to_string()))?;
+    let (htlcs, updates, ack) = {
+        let mut state = write_state(&state_mx).await;
+        let rate = state.current_price();
+        let htlcs = event.htlcs(&api_config.eclair_channels, rate.unwrap_or(0));
+        if htlcs.is_empty() {
+            debug!("Eclair event doesn't move fiat channels: {:?}", event);
+            return Ok(Json::from(htlcs));
+        }
+        if rate.is_none() {
+            return Err(reject::custom(EclairErr::NoTicker));
+        }
+        for htlc in htlcs.iter() {
+            htlc.validate(&api_config.htlc_limits)?;
+        }
+        let updates: Vec<StateUpdate> = htlcs
+            .iter()
+            .cloned()
+            .map(|htlc| StateUpdate {
+                created: Utc::now().naive_utc(),
+                body: UpdateBody::Htlc(htlc.into_update()),
+            })
+            .collect();
+        state.stage_updates(&updates)?;
+        // All HTLCs of the event are committed in a single transaction
+        let ack = writer.enqueue_all(updates.iter().map(|u| u.body.clone()).collect());
+        (htlcs, updates, ack)
+    };
+    apply_committed(&state_mx, ack, updates).await?;
+    state_notify.notify_waiters();
+    info!("Recorded HTLCs of Eclair event: {:?}", htlcs);
+    Ok(Json::from(htlcs))
+}
+
+#[post("/hedge/set")]
+#[openapi(
+    tags("node"),
+    summary = "Set hedge of a fiat channel to the absolute amount of sats and rate.",
+    description = "The service calculates HTLC that moves the channel to the target and records it as a usual HTLC update. Returns hedge of the channel after the update, the rate can differ from the target by rounding."
+)]
+async fn hedge_set(
+    #[data] writer: UpdateWriter,
+    #[data] state_mx: Arc<RwLock<State>>,
+    #[data] state_notify: Arc<Notify>,
+    #[data] api_config: ApiConfig,
+    body: Json<HedgeTarget>,
+) -> Result<Json<ChannelHedge>, Rejection> {
+    if api_config.read_only {
+        return Err(reject::custom(ReadOnlyMode));
+    }
+    let target = body.into_inner();
+    target.validate(&api_config.htlc_limits)?;
+    let mut state = write_state(&state_mx).await;
+    // The target is reached from the hedge that includes HTLCs waiting for the commit
+    let current = state
+        .staged_hedge(&target.channel_id)?
+        .unwrap_or(ChannelHedge { sats: 0, rate: 0 });
+    let delta = current
+        .delta_to(&target.channel_id, &target.hedge())
+        .map_err(StateUpdateErr::from)?;
+    if let Some(htlc) = delta {
+        debug!("Setting hedge of {} with {:?}", target.channel_id, htlc);
+        let updates = vec![StateUpdate {
+            created: Utc::now().naive_utc(),
+            body: UpdateBody::Htlc(htlc),
+        }];
+        state.stage_updates(&updates)?;
+        let ack = writer.enqueue(updates[0].body.clone());
+        drop(state);
+        state = apply_committed(&state_mx, ack, updates).await?;
+        state_notify.notify_waiters();
+    }
+    let hedge = state
+        .channels_hedge
+        .get(&target.channel_id)
+        .cloned()
+        .unwrap_or(current);
+    Ok(Json::from(hedge))
+}
+
+#[post("/config")]
+#[openapi(
+    tags("management"),
+    summary = "Change hedging configuration at runtime",
+    description = "Requires `Authorization: Bearer <token>` header with the admin token. Only provided fields are changed, the config after the change must pass validation. The symbol can't be changed, use `/admin/migrate-symbol` instead. The change is recorded in the chain of updates and replayed on restart. Command line flags of the service override the change on restart only if they differ from the flags of the previous start."
+)]
+async fn update_config(
+    #[data] pool: Pool,
+    #[data] state_mx: Arc<RwLock<State>>,
+    #[data] state_notify: Arc<Notify>,
+    #[data] api_config: ApiConfig,
+    #[header = "authorization"] auth: String,
+    body: Json<ConfigChange>,
+) -> Result<Json<HedgeConfig>, Rejection> {
+    api_config.check_admin(&auth)?;
+    if api_config.read_only {
+        return Err(reject::custom(ReadOnlyMode));
+    }
+    let change = ConfigChange {
+        startup: false,
+        ..body.into_inner()
+    };
+    let mut state = write_state(&state_mx).await;
+    check_config_change(&state, &change)?;
+    let update = StateUpdate {
+        created: Utc::now().naive_utc(),
+        body: UpdateBody::ConfigChange(change),
+    };
+    insert_update(&pool, update.body.clone()).await?;
+    state.apply_update(update)?;
+    state_notify.notify_waiters();
+    info!("Config changed to {:?}", state.config);
+    Ok(Json::from(state.config.clone()))
+}
+
+#[post("/admin/deposit-invoice")]
+#[openapi(
+    tags("admin"),
+    summary = "Request Lightning invoice that tops up margin on Kollider",
+    description = "Requires `Authorization: Bearer <token>` header with the admin token. The invoice is recorded in the chain of updates and tracked in the state until Kollider balance grows by the amount."
+)]
+async fn deposit_invoice(
+    #[data] pool: Pool,
+    #[data] state_mx: Arc<RwLock<State>>,
+    #[data] state_notify: Arc<Notify>,
+    #[data] api_config: ApiConfig,
+    #[header = "authorization"] auth: String,
+    body: Json<DepositRequest>,
+) -> Result<Json<DepositInvoice>, Rejection> {
+    api_config.check_admin(&auth)?;
+    if api_config.read_only {
+        return Err(reject::custom(ReadOnlyMode));
+    }
+    let exchange = api_config
+        .exchange
+        .as_ref()
+        .ok_or_else(|| reject::custom(ExchangeDisabled))?;
+    let sats = body.into_inner().sats;
+    let invoice = exchange.deposit_invoice(sats).await?;
+    info!("Requested deposit invoice for {} sats", sats);
+    // Pending deposits hold back funding detection, so they are replayed after restart
+    let update = StateUpdate {
+        created: Utc::now().naive_utc(),
+        body: UpdateBody::Deposit(Deposit {
+            sats,
+            invoice: invoice.clone(),
+        }),
+    };
+    insert_update(&pool, update.body.clone()).await?;
+    write_state(&state_mx).await.apply_update(update)?;
+    state_notify.notify_waiters();
+    Ok(Json::from(DepositInvoice { sats, invoice }))
+}
+
+#[post("/admin/withdraw")]
+#[openapi(
+    tags("admin"),
+    summary = "Withdraw excess margin from Kollider to the given Lightning invoice",
+    description = "Requires `Authorization: Bearer <token>` header with the admin token. Only free margin above the configured buffer can be withdrawn. The withdrawal is recorded in the chain of updates before Kollider is asked to pay the invoice, a failure is recorded as a withdrawal with the `failed` flag."
+)]
+async fn withdraw(
+    #[data] pool: Pool,
+    #[data] state_mx: Arc<RwLock<State>>,
+    #[data] state_notify: Arc<Notify>,
+    #[data] api_config: ApiConfig,
+    #[header = "authorization"] auth: String,
+    body: Json<WithdrawRequest>,
+) -> Result<Json<Withdrawal>, Rejection> {
+    api_config.check_admin(&auth)?;
+    if api_config.read_only {
+        return Err(reject::custom(ReadOnlyMode));
+    }
+    let exchange = api_config
+        .exchange
+        .as_ref()
+        .ok_or_else(|| reject::custom(ExchangeDisabled))?;
+    let req = body.into_inner();
+    // Sats are reserved under the lock, Kollider is called without holding it
+    let sats = {
+        let mut state = write_state(&state_mx).await;
+        let excess = state.excess_margin(api_config.withdraw_buffer).unwrap_or(0);
+        let sats = req.sats.unwrap_or(excess);
+        if sats == 0 || sats > excess {
+            return Err(reject::custom(WithdrawExceedsExcess {
+                requested: sats,
+                excess,
+            }));
+        }
+        state.withdrawing_sats += sats;
+        sats
+    };
+    let withdrawal = Withdrawal {
+        sats,
+        invoice: req.invoice,
+        failed: false,
+    };
+    // The intent is recorded first, so a withdrawal paid by Kollider is never missing in the
+    // chain of updates
+    let intent = StateUpdate {
+        created: Utc::now().naive_utc(),
+        body: UpdateBody::Withdrawal(withdrawal.clone()),
+    };
+    if let Err(e) = insert_update(&pool, intent.body.clone()).await {
+        write_state(&state_mx).await.withdrawing_sats -= sats;
+        return Err(e.into());
+    }
+    let res = exchange.withdraw(sats, &withdrawal.invoice).await;
+    let mut state = write_state(&state_mx).await;
+    state.withdrawing_sats -= sats;
+    state.apply_update(intent)?;
+    if let Err(e) = res {
+        error!("Kollider failed withdrawal of {} sats: {}", sats, e);
+        let failure = StateUpdate {
+            created: Utc::now().naive_utc(),
+            body: UpdateBody::Withdrawal(Withdrawal {
+                failed: true,
+                ..withdrawal
+            }),
+        };
+        match insert_update(&pool, failure.body.clone()).await {
+            Ok(()) => state.apply_update(failure)?,
+            Err(e) => error!("Failed to record failure of withdrawal: {}", e),
+        }
+        state_notify.notify_waiters();
+        return Err(e.into());
+    }
+    info!("Withdrawn {} sats from Kollider", sats);
+    state_notify.notify_waiters();
+    Ok(Json::from(withdrawal))
+}
+
+#[post("/admin/correction")]
+#[openapi(
+    tags("admin"),
+    summary = "Void or replace an erroneous HTLC update",
+    description = "Requires `Authorization: Bearer <token>` header with the admin token. The erroneous update is identified by channel and the moment of creation from `/history`. A compensating update is appended to the chain, recorded updates are never changed."
+)]
+async fn correct_update(
+    #[data] pool: Pool,
+    #[data] state_mx: Arc<RwLock<State>>,
+    #[data] state_notify: Arc<Notify>,
+    #[data] api_config: ApiConfig,
+    #[header = "authorization"] auth: String,
+    body: Json<CorrectionRequest>,
+) -> Result<Json<Correction>, Rejection> {
+    api_config.check_admin(&auth)?;
+    if api_config.read_only {
+        return Err(reject::custom(ReadOnlyMode));
+    }
+    let req = body.into_inner();
+    if let Some(htlc) = req.replacement()? {
+        htlc.validate(&api_config.htlc_limits)?;
     }
+    // The full history is loaded before the lock, so readers don't wait for it
+    let mut history = queries::query_history(&pool).await?;
+    let mut state = write_state(&state_mx).await;
+    // Updates recorded meanwhile, e.g. a concurrent correction of the same HTLC
+    let recent = queries::query_history_range(
+        &pool,
+        &HistoryQuery {
+            from: history.last().map(|u| u.created),
+            to: None,
+        },
+    )
+    .await?;
+    history.extend(recent);
+    let correction = req.into_correction(&history)?;
+    warn!(
+        "Correcting HTLC update of {} at {} by {}: {}",
+        correction.original.channel_id, correction.corrected, correction.author, correction.reason
+    );
+    let update = StateUpdate {
+        created: Utc::now().naive_utc(),
+        body: UpdateBody::Correction(correction.clone()),
+    };
+    // The state is changed only after the commit, as for HTLCs
+    state.check_updates(std::slice::from_ref(&update))?;
+    insert_update(&pool, update.body.clone()).await?;
+    state.apply_update(update)?;
+    state_notify.notify_waiters();
+    Ok(Json::from(correction))
+}
 
-    Ok(Json::from(()))
+#[post("/admin/migrate-symbol")]
+#[openapi(
+    tags("admin"),
+    summary = "Move the hedge to another contract, e.x. from an expiring one to a new perp",
+    description = "Requires `Authorization: Bearer <token>` header with the admin token. The new symbol is recorded in the chain of updates as a config change. The service cancels orders on the old symbol, closes its position with a market order and then hedges on the new symbol. Progress is reported in the `symbol_migration` field of `/state`."
+)]
+async fn migrate_symbol(
+    #[data] pool: Pool,
+    #[data] state_mx: Arc<RwLock<State>>,
+    #[data] state_notify: Arc<Notify>,
+    #[data] api_config: ApiConfig,
+    #[header = "authorization"] auth: String,
+    body: Json<SymbolMigrationRequest>,
+) -> Result<Json<Option<SymbolMigration>>, Rejection> {
+    api_config.check_admin(&auth)?;
+    if api_config.read_only {
+        return Err(reject::custom(ReadOnlyMode));
+    }
+    let req = body.into_inner();
+    req.validate(&*state_mx.read().await)?;
+    // Kollider reports orders and position of the new symbol only on request. They are
+    // fetched before the state is locked, so readers don't wait for the exchange.
+    let exchange_data = match &api_config.exchange {
+        Some(exchange) => fetch_exchange_data(exchange)
+            .await
+            .map_err(|e| {
+                warn!(
+                    "Orders and position are not fetched, waiting for the next reconnect: {}",
+                    e
+                )
+            })
+            .ok(),
+        None => None,
+    };
+    let mut state = write_state(&state_mx).await;
+    req.validate(&state)?;
+    let update = StateUpdate {
+        created: Utc::now().naive_utc(),
+        body: UpdateBody::ConfigChange(req.config_change()),
+    };
+    insert_update(&pool, update.body.clone()).await?;
+    state.apply_update(update)?;
+    if let Some((open_orders, positions)) = exchange_data {
+        if let Err(e) = state.apply_rest_snapshot(open_orders, positions) {
+            warn!("Fetched orders and position are malformed: {}", e);
+        }
+    }
+    state_notify.notify_waiters();
+    info!("Started migration {:?}", state.symbol_migration);
+    Ok(Json::from(state.symbol_migration.clone()))
+}
+
+/// Fetch opened orders and positions via REST API
+async fn fetch_exchange_data(
+    exchange: &KolliderRest,
+) -> Result<(serde_json::Value, serde_json::Value), exchange::Error> {
+    let open_orders = exchange.open_orders().await?;
+    let positions = exchange.positions().await?;
+    Ok((open_orders, positions))
+}
+
+#[post("/admin/rollback")]
+#[openapi(
+    tags("admin"),
+    summary = "Reset hedge of channels to the state at the moment",
+    description = "Requires `Authorization: Bearer <token>` header with the admin token. The full history is replayed until the moment and hedge of channels at that moment is appended to the chain as a new snapshot, recorded updates are never changed. Configuration and exchange data are not rolled back, the snapshot carries the current configuration as replay starts from it."
+)]
+async fn rollback_state(
+    #[data] pool: Pool,
+    #[data] state_mx: Arc<RwLock<State>>,
+    #[data] state_notify: Arc<Notify>,
+    #[data] api_config: ApiConfig,
+    #[header = "authorization"] auth: String,
+    body: Json<RollbackRequest>,
+) -> Result<Json<StateSnapshot>, Rejection> {
+    api_config.check_admin(&auth)?;
+    if api_config.read_only {
+        return Err(reject::custom(ReadOnlyMode));
+    }
+    let req = body.into_inner();
+    req.validate(Utc::now().naive_utc())?;
+    // Updates recorded after the history is loaded are later than the moment
+    let history = queries::query_history(&pool).await?;
+    let past = req.fold_history(history)?;
+    let mut state = write_state(&state_mx).await;
+    warn!("Rolling back hedge of channels to {}", req.to);
+    let snapshot = req.into_snapshot(past, &state);
+    let update = StateUpdate {
+        created: Utc::now().naive_utc(),
+        body: UpdateBody::Snapshot(snapshot.clone()),
+    };
+    insert_update(&pool, update.body.clone()).await?;
+    state.apply_update(update)?;
+    state_notify.notify_waiters();
+    Ok(Json::from(snapshot))
+}
+
+#[get("/admin/dump")]
+#[openapi(
+    tags("admin"),
+    summary = "Export the chain of updates as JSONL",
+    description = "Requires `Authorization: Bearer <token>` header with the admin token. Each line is a JSON object with `created`, `tag`, `version` and `body` of a stored update, bodies are exported in the stored version. Parameter `since` limits the export to updates created after the moment."
+)]
+async fn dump_updates(
+    #[data] pool: Pool,
+    #[data] api_config: ApiConfig,
+    #[header = "authorization"] auth: String,
+    #[query] filter: DumpQuery,
+) -> Result<String, Rejection> {
+    api_config.check_admin(&auth)?;
+    let records = queries::query_dump(&pool, filter.since).await?;
+    Ok(render_dump(&records))
+}
+
+#[post("/admin/restore")]
+#[openapi(
+    tags("admin"),
+    summary = "Append updates from JSONL dump to the chain",
+    description = "Requires `Authorization: Bearer <token>` header with the admin token. The body is a dump produced by `/admin/dump`. All updates of the dump must be created after the latest stored update, they are inserted in a single transaction and replayed on the current state."
+)]
+async fn restore_updates(
+    #[data] pool: Pool,
+    #[data] state_mx: Arc<RwLock<State>>,
+    #[data] state_notify: Arc<Notify>,
+    #[data] api_config: ApiConfig,
+    #[header = "authorization"] auth: String,
+    #[body] body: Bytes,
+) -> Result<Json<RestoreSummary>, Rejection> {
+    api_config.check_admin(&auth)?;
+    if api_config.read_only {
+        return Err(reject::custom(ReadOnlyMode));
+    }
+    let text = String::from_utf8_lossy(&body);
+    let records = parse_dump(&text)?;
+    let mut state = write_state(&state_mx).await;
+    let mut restored = state.clone();
+    for record in records.iter() {
+        restored.apply_update(StateUpdate {
+            created: record.created,
+            body: record.decode().map_err(queries::Error::from)?,
+        })?;
+    }
+    match queries::insert_dump(&pool, &records).await {
+        Err(queries::Error::Restore(e)) => return Err(reject::custom(e)),
+        res => res?,
+    }
+    *state = restored;
+    state_notify.notify_waiters();
+    info!("Restored {} updates from dump", records.len());
+    Ok(Json::from(RestoreSummary {
+        restored: records.len() as u64,
+        last: records.last().map(|r| r.created),
+    }))
 }
 
 #[get("/state")]
@@ -56,89 +738,751 @@ async fn hedge_htlc(
     summary = "Return current state of the plugin",
     description = "The full state of the server that can be quite slow. The en"
 )]
-async fn query_state(#[data] state_mx: Arc<Mutex<State>>) -> Result<Json<State>, Rejection> {
-    let state = state_mx.lock().await;
+async fn query_state(#[data] state_mx: Arc<RwLock<State>>) -> Result<Json<State>, Rejection> {
+    let state = state_mx.read().await;
     Ok(Json::from(state.clone()))
 }
 
+fn state_etag(revision: u64) -> String {
+    format!("\"{}\"", revision)
+}
+
+/// Revision of the state before `/state` is handled. The served state can only be newer, so the
+/// ETag never hides a change.
+fn state_revision(
+    state_mx: Arc<RwLock<State>>,
+) -> impl Filter<Extract = (u64,), Error = Rejection> + Clone {
+    warp::get()
+        .and(warp::path::peek())
+        .and_then(move |peek: warp::path::Peek| {
+            let state_mx = state_mx.clone();
+            async move {
+                if peek.as_str().trim_matches('/') != "state" {
+                    return Err(reject::not_found());
+                }
+                Ok(state_mx.read().await.revision)
+            }
+        })
+}
+
+/// Long polling of state changes for clients that can't consume streams. Responds with the
+/// revision as soon as it advances past `since_rev` or with 204 after the timeout.
+fn wait_state(
+    state_mx: Arc<RwLock<State>>,
+    state_notify: Arc<Notify>,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = Rejection> + Clone {
+    warp::path("state")
+        .and(warp::path("wait"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::<WaitQuery>())
+        .and_then(move |query: WaitQuery| {
+            let state_mx = state_mx.clone();
+            let state_notify = state_notify.clone();
+            async move {
+                let timeout = match query.timeout() {
+                    Ok(timeout) => timeout,
+                    Err(e) => return Err(reject::custom(e)),
+                };
+                let deadline = tokio::time::Instant::now() + timeout;
+                loop {
+                    // Subscribe before the check to not miss a change between them
+                    let notified = state_notify.notified();
+                    let revision = state_mx.read().await.revision;
+                    if revision > query.since_rev {
+                        let reply = warp::reply::json(&StateRevision { revision });
+                        return Ok(reply.into_response());
+                    }
+                    if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                        return Ok(StatusCode::NO_CONTENT.into_response());
+                    }
+                }
+            }
+        })
+}
+
+/// Answer `If-None-Match` of `/state` with 304 while the revision is the same
+fn state_not_modified(
+    state_mx: Arc<RwLock<State>>,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = Rejection> + Clone {
+    state_revision(state_mx)
+        .and(warp::header::<String>("if-none-match"))
+        .and_then(|revision, tags: String| async move {
+            let etag = state_etag(revision);
+            let matches = tags
+                .split(',')
+                .map(|tag| tag.trim().trim_start_matches("W/"))
+                .any(|tag| tag == etag || tag == "*");
+            if matches {
+                Ok(with_etag(StatusCode::NOT_MODIFIED, revision))
+            } else {
+                Err(reject::not_found())
+            }
+        })
+}
+
+fn with_etag<R: Reply>(reply: R, revision: u64) -> warp::reply::Response {
+    warp::reply::with_header(reply, "etag", state_etag(revision)).into_response()
+}
+
+/// Binary encoding requested by `Accept` header. Requests that accept JSON are rejected to fall
+/// through to the documented JSON endpoints.
+fn binary_encoding() -> impl Filter<Extract = (Encoding,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("accept").and_then(|accept: Option<String>| async move {
+        match accept.as_deref().and_then(Encoding::from_accept) {
+            Some(Encoding::Json) | None => Err(reject::not_found()),
+            Some(encoding) => Ok(encoding),
+        }
+    })
+}
+
+fn encoded_reply<T: Serialize>(
+    encoding: Encoding,
+    value: &T,
+) -> Result<warp::reply::Response, Rejection> {
+    let body = encoding.encode(value)?;
+    Ok(warp::reply::with_header(body, "content-type", encoding.content_type()).into_response())
+}
+
+/// `/state` in CBOR or MessagePack, see `binary_encoding`
+fn query_state_binary(
+    state_mx: Arc<RwLock<State>>,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = Rejection> + Clone {
+    warp::path("state")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(binary_encoding())
+        .and_then(move |encoding| {
+            let state_mx = state_mx.clone();
+            async move {
+                let state = state_mx.read().await;
+                encoded_reply(encoding, &*state)
+            }
+        })
+}
+
+/// `/state?fields=...` with only the listed top-level fields in JSON or the requested binary
+/// encoding
+fn query_state_fields(
+    state_mx: Arc<RwLock<State>>,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = Rejection> + Clone {
+    warp::path("state")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::<StateFieldsQuery>())
+        .and(
+            binary_encoding()
+                .or(warp::any().map(Encoding::default))
+                .unify(),
+        )
+        .and_then(move |query: StateFieldsQuery, encoding| {
+            let state_mx = state_mx.clone();
+            async move {
+                let state = state_mx.read().await;
+                // Rejection would fall through to the full `/state`
+                let selected = match query.select(&state) {
+                    Ok(selected) => selected,
+                    Err(e) => {
+                        let reply = handle_rejection(reject::custom(e)).await;
+                        return Ok(reply.map_or_else(|e| match e {}, Reply::into_response));
+                    }
+                };
+                encoded_reply(encoding, &selected)
+            }
+        })
+}
+
+#[get("/channels")]
+#[openapi(
+    tags("management"),
+    summary = "Return hedge and fiat value of each channel",
+    description = "Fiat value is calculated at the weighted rate of channel HTLCs and at the current ticker"
+)]
+async fn query_channels(
+    #[data] state_mx: Arc<RwLock<State>>,
+) -> Result<Json<HashMap<ChannelId, ChannelInfo>>, Rejection> {
+    let state = state_mx.read().await;
+    Ok(Json::from(ChannelInfo::from_state(&state)))
+}
+
 #[get("/stats")]
 #[openapi(
     tags("management"),
     summary = "Return statistics to track behavior of hedge plugin",
     description = "Endpoint returns how much sats are in hedging, how much USD balance we have in position and e.t.c"
 )]
-async fn query_stats(#[data] state_mx: Arc<Mutex<State>>) -> Result<Json<Stats>, Rejection> {
-    let state = state_mx.lock().await;
-    let channel_sats = state.hedge_capacity();
-    let avg_price = state.hedge_avg_price().unwrap_or(0);
-    fn safe_divide(numerator: f64, denominator: f64) -> f64 {
-        if denominator == 0.0 {
-            0.
-        } else {
-            numerator / denominator
-        }
+async fn query_stats(#[data] snapshot: Arc<StatsSnapshot>) -> Result<Json<Stats>, Rejection> {
+    Ok(Json::from(Stats::clone(&snapshot.load())))
+}
+
+#[get("/simulate")]
+#[openapi(
+    tags("management"),
+    summary = "Return what the stats would be at the given price",
+    description = "Parameter `price` is a hypothetical price of BTC in USD. Returns statistics, unhedged amount, margin usage and distance to liquidation of the position as if the price was the current ticker. Nothing is changed in the state."
+)]
+async fn simulate_price(
+    #[data] state_mx: Arc<RwLock<State>>,
+    #[query] query: SimulateQuery,
+) -> Result<Json<Simulation>, Rejection> {
+    let state = state_mx.read().await;
+    Ok(Json::from(Simulation::new(&state, query.price)?))
+}
+
+#[get("/estimate")]
+#[openapi(
+    tags("management"),
+    summary = "Return what an order of the given size would require",
+    description = "Parameter `sats` is the size of an order that increases the hedge. Returns price, quantity, leverage and margin of the order at the current price, leverage and spread, as the service would place it now. Responds with 503 until the first ticker is received."
+)]
+async fn estimate_order(
+    #[data] state_mx: Arc<RwLock<State>>,
+    #[query] query: EstimateQuery,
+) -> Result<Json<OrderEstimate>, Rejection> {
+    let state = state_mx.read().await;
+    Ok(Json::from(OrderEstimate::new(&state, query.sats)?))
+}
+
+#[get("/price")]
+#[openapi(
+    tags("node"),
+    summary = "Return the current price of BTC",
+    description = "Returns the last Kollider index price in USD and the matching exchange rate in sats/USD. Responds with 503 until the first ticker is received."
+)]
+async fn query_price(#[data] state_mx: Arc<RwLock<State>>) -> Result<Json<PriceInfo>, Rejection> {
+    let state = state_mx.read().await;
+    let price = PriceInfo::new(&state).ok_or_else(|| reject::custom(NoTicker))?;
+    Ok(Json::from(price))
+}
+
+#[get("/ws-status")]
+#[openapi(
+    tags("management"),
+    summary = "Return status of the websocket connection to Kollider",
+    description = "Allows to tell a dead connection from a quiet market: endpoint returns connection and authentication flags, subscribed channels, moments of the last message by channel and amount of reconnects."
+)]
+async fn query_ws_status(
+    #[data] ws_monitor: Arc<Mutex<WsMonitor>>,
+) -> Result<Json<WsStatus>, Rejection> {
+    let monitor = ws_monitor.lock().await;
+    Ok(Json::from(monitor.status.clone()))
+}
+
+#[get("/debug/messages")]
+#[openapi(
+    tags("admin"),
+    summary = "Return the last messages received from Kollider",
+    description = "Requires `Authorization: Bearer <token>` header with the admin token. Messages are kept in memory, their amount is limited by the `--debug-messages` option."
+)]
+async fn query_debug_messages(
+    #[data] ws_monitor: Arc<Mutex<WsMonitor>>,
+    #[data] api_config: ApiConfig,
+    #[header = "authorization"] auth: String,
+) -> Result<Json<Vec<RecordedMessage>>, Rejection> {
+    api_config.check_admin(&auth)?;
+    let monitor = ws_monitor.lock().await;
+    Ok(Json::from(monitor.messages()))
+}
+
+#[get("/requests")]
+#[openapi(
+    tags("management"),
+    summary = "Return raw requests to `/hedge/htlc` with their outcome",
+    description = "Requests are recorded as they were received, independently of the chain of updates, to investigate discrepancies between what the node sent and what was hedged. Parameters `from` and `to` limit the time range, `limit` the amount of the latest records (100 by default)."
+)]
+async fn query_htlc_requests(
+    #[data] pool: Pool,
+    #[query] filter: RequestsQuery,
+) -> Result<Json<Vec<HtlcRequestRecord>>, Rejection> {
+    let requests = queries::query_htlc_requests(&pool, &filter).await?;
+    Ok(Json::from(requests))
+}
+
+#[get("/stats/history")]
+#[openapi(
+    tags("management"),
+    summary = "Return recorded statistics in the given time range",
+    description = "Statistics are recorded periodically by the service. Parameters `from` and `to` limits the time range and `step` defines minimal distance between returned records in seconds."
+)]
+async fn query_stats_history(
+    #[data] pool: Pool,
+    #[query] filter: StatsHistoryQuery,
+) -> Result<Json<Vec<StatsRecord>>, Rejection> {
+    let history = queries::query_stats_history(&pool, &filter).await?;
+    Ok(Json::from(history))
+}
+
+#[get("/reports/daily")]
+#[openapi(
+    tags("management"),
+    summary = "Return daily accounting reports",
+    description = "Reports are written at each UTC midnight. Parameters `from` and `to` limits the time range of report closing."
+)]
+async fn query_daily_reports(
+    #[data] pool: Pool,
+    #[query] filter: DailyReportQuery,
+) -> Result<Json<Vec<DailyReport>>, Rejection> {
+    let reports = queries::query_daily_reports(&pool, &filter).await?;
+    Ok(Json::from(reports))
+}
+
+#[get("/history")]
+#[openapi(
+    tags("management"),
+    summary = "Return history of state updates",
+    description = "Endpoint returns HTLC updates and snapshots stored in the database ordered from the oldest to the newest. Parameters `from` and `to` limit the time range, full history is returned without them."
+)]
+async fn query_history(
+    #[data] pool: Pool,
+    #[query] filter: HistoryQuery,
+) -> Result<Json<Vec<StateUpdate>>, Rejection> {
+    let history = queries::query_history_range(&pool, &filter).await?;
+    Ok(Json::from(history))
+}
+
+/// `/history` in CBOR or MessagePack, see `binary_encoding`
+fn query_history_binary(
+    pool: Pool,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = Rejection> + Clone {
+    warp::path("history")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(binary_encoding())
+        .and(warp::query::<HistoryQuery>())
+        .and_then(move |encoding, filter: HistoryQuery| {
+            let pool = pool.clone();
+            async move {
+                let history = queries::query_history_range(&pool, &filter).await?;
+                encoded_reply(encoding, &history)
+            }
+        })
+}
+
+#[get("/channel/{id}/history")]
+#[openapi(
+    tags("management"),
+    summary = "Return history of updates of a single channel",
+    description = "Endpoint returns HTLC updates, snapshots and removals that changed the channel with hedge and fiat value of the channel after each update. Unknown channels are reported with 404."
+)]
+async fn query_channel_history(
+    #[data] pool: Pool,
+    id: String,
+) -> Result<Json<Vec<ChannelHistoryEntry>>, Rejection> {
+    let updates = queries::query_history(&pool).await?;
+    let history = ChannelHistoryEntry::from_updates(&id, updates)?;
+    if history.is_empty() {
+        return Err(reject::not_found());
     }
-    Ok(Json::from(Stats {
-        channels_sats: channel_sats,
-        channels_usd: safe_divide(channel_sats as f64, avg_price as f64),
-        position_sats: state.position_volume(),
-        position_usd: state.position_quantity(),
-        account_balance: state.balance.unwrap_or(0.),
+    Ok(Json::from(history))
+}
+
+#[get("/version")]
+#[openapi(
+    tags("management"),
+    summary = "Return version of the service",
+    description = "Endpoint returns crate version, git commit hash and enabled features of the running binary"
+)]
+async fn query_version() -> Result<Json<VersionInfo>, Rejection> {
+    Ok(Json::from(VersionInfo {
+        version: env!("CARGO_PKG_VERSION").to_owned(),
+        git_hash: env!("GIT_HASH").to_owned(),
+        features: env!("ENABLED_FEATURES")
+            .split(',')
+            .filter(|f| !f.is_empty())
+            .map(|f| f.to_owned())
+            .collect(),
+    }))
+}
+
+#[get("/health")]
+#[openapi(
+    tags("management"),
+    summary = "Return liveness and readiness of the service",
+    description = "The service is live when it responds and the database is reachable. It is ready when it is authenticated on Kollider and received ticker, orders and position."
+)]
+async fn query_health(
+    #[data] pool: Pool,
+    #[data] state_mx: Arc<RwLock<State>>,
+    #[data] ws_monitor: Arc<Mutex<WsMonitor>>,
+) -> Result<Json<Health>, Rejection> {
+    let database = match queries::ping(&pool).await {
+        Ok(()) => true,
+        Err(e) => {
+            error!("Health check of database failed: {}", e);
+            false
+        }
+    };
+    let authenticated = ws_monitor.lock().await.status.authenticated;
+    let state = state_mx.read().await;
+    Ok(Json::from(Health::new(database, authenticated, &state)))
+}
+
+#[get("/metrics")]
+#[openapi(
+    tags("management"),
+    summary = "Return internal metrics of the service",
+    description = "Endpoint returns technical information like usage of the database connection pool, fill of websocket message queues, latency histograms of database queries, API requests and execution of actions and drift of the position from the hedge"
+)]
+async fn query_metrics(
+    #[data] pool: Pool,
+    #[data] state_mx: Arc<RwLock<State>>,
+    #[data] ws_monitor: Arc<Mutex<WsMonitor>>,
+) -> Result<Json<Metrics>, Rejection> {
+    let (ws_inbound, ws_outbound) = {
+        let monitor = ws_monitor.lock().await;
+        (monitor.inbound.stats(), monitor.outbound.stats())
+    };
+    let (position_drift_sats, position_drift_checks) = {
+        let state = state_mx.read().await;
+        (state.position_drift, state.position_drift_checks)
+    };
+    Ok(Json::from(Metrics {
+        db_pool_size: pool.size(),
+        db_pool_idle: pool.num_idle() as u32,
+        ws_inbound,
+        ws_outbound,
+        latency: LATENCY.stats(),
+        position_drift_sats,
+        position_drift_checks,
     }))
 }
 
-pub async fn hedge_api_specs(pool: Pool) -> Result<Spec, Box<dyn Error>> {
-    let state = Arc::new(Mutex::new(State::default()));
+/// Combine all endpoints of the service in a single filter
+fn api_routes(
+    pool: Pool,
+    writer: UpdateWriter,
+    state: Arc<RwLock<State>>,
+    state_notify: Arc<Notify>,
+    ws_monitor: Arc<Mutex<WsMonitor>>,
+    stats: Arc<StatsSnapshot>,
+    api_config: ApiConfig,
+) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone {
+    ip_allowlist(api_config.clone())
+        .and(
+            hedge_htlc(
+                pool.clone(),
+                writer.clone(),
+                state.clone(),
+                state_notify.clone(),
+                api_config.clone(),
+            )
+            .or(hedge_htlc_preview(state.clone(), api_config.clone()))
+            .or(hedge_eclair(
+                writer.clone(),
+                state.clone(),
+                state_notify.clone(),
+                api_config.clone(),
+            ))
+            .or(hedge_set(
+                writer,
+                state.clone(),
+                state_notify.clone(),
+                api_config.clone(),
+            ))
+            .or(update_config(
+                pool.clone(),
+                state.clone(),
+                state_notify.clone(),
+                api_config.clone(),
+            ))
+            .or(deposit_invoice(
+                pool.clone(),
+                state.clone(),
+                state_notify.clone(),
+                api_config.clone(),
+            ))
+            .or(withdraw(
+                pool.clone(),
+                state.clone(),
+                state_notify.clone(),
+                api_config.clone(),
+            ))
+            .or(correct_update(
+                pool.clone(),
+                state.clone(),
+                state_notify.clone(),
+                api_config.clone(),
+            ))
+            .or(migrate_symbol(
+                pool.clone(),
+                state.clone(),
+                state_notify.clone(),
+                api_config.clone(),
+            ))
+            .or(rollback_state(
+                pool.clone(),
+                state.clone(),
+                state_notify.clone(),
+                api_config.clone(),
+            ))
+            .or(dump_updates(pool.clone(), api_config.clone()))
+            .or(restore_updates(
+                pool.clone(),
+                state.clone(),
+                state_notify.clone(),
+                api_config.clone(),
+            ))
+            .or(wait_state(state.clone(), state_notify))
+            .or(state_not_modified(state.clone()))
+            .or(state_revision(state.clone())
+                .and(query_state_fields(state.clone()))
+                .map(|revision, reply| with_etag(reply, revision)))
+            .or(state_revision(state.clone())
+                .and(query_state_binary(state.clone()))
+                .map(|revision, reply| with_etag(reply, revision)))
+            .or(state_revision(state.clone())
+                .and(query_state(state.clone()))
+                .map(|revision, reply| with_etag(reply, revision)))
+            .or(query_channels(state.clone()))
+            .or(query_stats(stats))
+            .or(simulate_price(state.clone()))
+            .or(estimate_order(state.clone()))
+            .or(query_price(state.clone()))
+            .or(query_health(
+                pool.clone(),
+                state.clone(),
+                ws_monitor.clone(),
+            ))
+            .or(query_ws_status(ws_monitor.clone()))
+            .or(query_debug_messages(ws_monitor.clone(), api_config))
+            .or(query_stats_history(pool.clone()))
+            .or(query_htlc_requests(pool.clone()))
+            .or(query_daily_reports(pool.clone()))
+            .or(query_history_binary(pool.clone()))
+            .or(query_history(pool.clone()))
+            .or(query_channel_history(pool.clone()))
+            .or(query_metrics(pool, state, ws_monitor))
+            .or(query_version()),
+        )
+        .recover(handle_rejection)
+}
+
+/// OpenAPI spec of the service. Works without the database as handlers are never called.
+pub async fn hedge_api_specs() -> Result<Spec, Box<dyn Error>> {
+    let pool = offline_pool()?;
+    let state = Arc::new(RwLock::new(State::default()));
     let state_notify = Arc::new(Notify::new());
+    let ws_monitor = Arc::new(Mutex::new(WsMonitor::default()));
+    let stats = Arc::new(StatsSnapshot::default());
+    let (writer, _) = UpdateWriter::new(pool.clone(), 1);
     let (spec, _) = openapi::spec().build(|| {
-        hedge_htlc(pool, state.clone(), state_notify.clone())
-            .or(query_state(state.clone()))
-            .or(query_stats(state))
-            .recover(handle_rejection)
+        api_routes(
+            pool,
+            writer,
+            state,
+            state_notify,
+            ws_monitor,
+            stats,
+            ApiConfig::default(),
+        )
     });
     Ok(spec)
 }
 
+/// OpenAPI spec of the running version, so clients generate code against the deployed API
+fn swagger_json(spec: Spec) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let spec = Arc::new(spec);
+    warp::path("swagger.json")
+        .and(warp::path::end())
+        .and(warp::get())
+        .map(move || warp::reply::json(&*spec))
+}
+
 pub async fn serve_api(
     host: &str,
     port: u16,
     pool: Pool,
-    state: Arc<Mutex<State>>,
+    state: Arc<RwLock<State>>,
     state_notify: Arc<Notify>,
+    ws_monitor: Arc<Mutex<WsMonitor>>,
+    stats: Arc<StatsSnapshot>,
+    api_config: ApiConfig,
 ) -> Result<(), Box<dyn Error>> {
-    let filter = hedge_htlc(pool, state.clone(), state_notify.clone())
-        .or(query_state(state.clone()))
-        .or(query_stats(state))
-        .recover(handle_rejection)
-        .with(log("kollider_hedge::api"));
-    serve(filter).run((IpAddr::from_str(host)?, port)).await;
+    let addr = (IpAddr::from_str(host)?, port);
+    let cors = api_config.cors();
+    let compression = api_config.compression;
+    // The worker stops when the routes are dropped and pending updates are written
+    let (writer, writer_worker) = UpdateWriter::new(pool.clone(), api_config.write_batch);
+    tokio::spawn(writer_worker);
+    let (spec, routes) = openapi::spec().build(|| {
+        api_routes(
+            pool,
+            writer,
+            state,
+            state_notify,
+            ws_monitor,
+            stats,
+            api_config,
+        )
+    });
+    let routes = swagger_json(spec).or(routes);
+    let filter = compressed(routes, compression)
+        .with(log("kollider_hedge::api"))
+        .with(warp::log::custom(observe_request));
+    match cors {
+        Some(cors) => serve(filter.with(cors)).run(addr).await,
+        None => serve(filter).run(addr).await,
+    }
     Ok(())
 }
 
-/// An API error serializable to JSON.
-#[derive(Serialize)]
-struct ErrorMessage {
-    code: u16,
-    message: String,
-}
-
 // This function receives a `Rejection` and tries to return a custom
 // value, otherwise simply passes the rejection along.
 async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
     let code;
     let message;
+    let error_code;
+    let mut details = None;
 
     if err.is_not_found() {
         code = StatusCode::NOT_FOUND;
         message = "NOT_FOUND";
+        error_code = ErrorCode::NotFound;
+    } else if err.find::<ReadOnlyMode>().is_some() {
+        code = StatusCode::FORBIDDEN;
+        message = "READ_ONLY_MODE";
+        error_code = ErrorCode::ReadOnlyMode;
+    } else if err.find::<IpNotAllowed>().is_some() {
+        code = StatusCode::FORBIDDEN;
+        message = "IP_NOT_ALLOWED";
+        error_code = ErrorCode::IpNotAllowed;
+    } else if err.find::<Unauthorized>().is_some() {
+        code = StatusCode::UNAUTHORIZED;
+        message = "UNAUTHORIZED";
+        error_code = ErrorCode::Unauthorized;
+    } else if err.find::<ExchangeDisabled>().is_some() {
+        code = StatusCode::SERVICE_UNAVAILABLE;
+        message = "EXCHANGE_DISABLED";
+        error_code = ErrorCode::ExchangeDisabled;
+    } else if let Some(err) = err.find::<exchange::Error>() {
+        error!("Rejection by Kollider request: {}", err);
+        code = StatusCode::BAD_GATEWAY;
+        message = "EXCHANGE_ERROR";
+        error_code = ErrorCode::ExchangeError;
+        details = Some(err.to_string());
+    } else if let Some(err) = err.find::<WithdrawExceedsExcess>() {
+        warn!("Rejection of withdrawal: {}", err);
+        code = StatusCode::BAD_REQUEST;
+        message = "WITHDRAW_EXCEEDS_EXCESS";
+        error_code = ErrorCode::WithdrawExceedsExcess;
+        details = Some(err.to_string());
+    } else if let Some(err) = err.find::<CorrectionErr>() {
+        warn!("Rejection of correction: {}", err);
+        code = StatusCode::BAD_REQUEST;
+        message = err.message();
+        error_code = err.into();
+        details = Some(err.to_string());
+    } else if let Some(err) = err.find::<SymbolMigrationErr>() {
+        warn!("Rejection of symbol migration: {}", err);
+        code = StatusCode::BAD_REQUEST;
+        message = "FIELD_ERROR: symbol";
+        error_code = ErrorCode::FieldError;
+        details = Some(err.to_string());
+    } else if let Some(err) = err.find::<ConfigChangeErr>() {
+        warn!("Rejection of config change: {}", err);
+        code = StatusCode::BAD_REQUEST;
+        if let ConfigChangeErr::SymbolChange(_) = err {
+            message = "FIELD_ERROR: hedge_sym";
+            error_code = ErrorCode::FieldError;
+        } else {
+            message = "BAD_REQUEST";
+            error_code = ErrorCode::BadRequest;
+        }
+        details = Some(err.to_string());
+    } else if let Some(err) = err.find::<RollbackErr>() {
+        warn!("Rejection of rollback: {}", err);
+        code = StatusCode::BAD_REQUEST;
+        message = "FIELD_ERROR: to";
+        error_code = ErrorCode::FieldError;
+        details = Some(err.to_string());
+    } else if let Some(err) = err.find::<RestoreErr>() {
+        warn!("Rejection of restore: {}", err);
+        code = StatusCode::BAD_REQUEST;
+        message = match err {
+            RestoreErr::Overlap(..) => "DUMP_OVERLAP",
+            RestoreErr::Chain(..) => "DUMP_CHAIN_BROKEN",
+            _ => "MALFORMED_DUMP",
+        };
+        error_code = err.into();
+        details = Some(err.to_string());
+    } else if let Some(MalformedHtlc(e)) = err.find::<MalformedHtlc>() {
+        warn!("Rejection of malformed HTLC: {}", e);
+        code = StatusCode::BAD_REQUEST;
+        message = "BAD_REQUEST";
+        error_code = ErrorCode::BadRequest;
+        details = Some(e.clone());
+    } else if let Some(err) = err.find::<WaitErr>() {
+        warn!("Rejection of state wait: {}", err);
+        code = StatusCode::BAD_REQUEST;
+        message = "FIELD_ERROR: timeout";
+        error_code = ErrorCode::FieldError;
+        details = Some(err.to_string());
+    } else if let Some(err) = err.find::<StateFieldsErr>() {
+        warn!("Rejection of state fields: {}", err);
+        code = StatusCode::BAD_REQUEST;
+        message = "FIELD_ERROR: fields";
+        error_code = ErrorCode::FieldError;
+        details = Some(err.to_string());
+    } else if let Some(err) = err.find::<EncodingErr>() {
+        error!("Failed to encode response: {}", err);
+        code = StatusCode::INTERNAL_SERVER_ERROR;
+        message = "ENCODING_ERROR";
+        error_code = ErrorCode::EncodingError;
+    } else if err.find::<NoTicker>().is_some() {
+        code = StatusCode::SERVICE_UNAVAILABLE;
+        message = "NO_TICKER";
+        error_code = ErrorCode::NoTicker;
+    } else if let Some(err) = err.find::<SimulationErr>() {
+        warn!("Rejection of simulation: {}", err);
+        code = StatusCode::BAD_REQUEST;
+        message = "FIELD_ERROR: price";
+        error_code = ErrorCode::FieldError;
+        details = Some(err.to_string());
+    } else if let Some(err) = err.find::<EstimateErr>() {
+        warn!("Rejection of order estimate: {}", err);
+        if *err == EstimateErr::NoTicker {
+            code = StatusCode::SERVICE_UNAVAILABLE;
+            message = "NO_TICKER";
+        } else {
+            code = StatusCode::BAD_REQUEST;
+            message = "FIELD_ERROR: sats";
+        }
+        error_code = err.into();
+        details = Some(err.to_string());
+    } else if let Some(err) = err.find::<PreviewErr>() {
+        warn!("Rejection of HTLC preview: {}", err);
+        code = StatusCode::BAD_REQUEST;
+        message = "PREVIEW_ERROR";
+        error_code = err.into();
+        details = Some(err.to_string());
+    } else if let Some(err) = err.find::<EclairErr>() {
+        warn!("Rejection of Eclair event: {}", err);
+        if *err == EclairErr::NoTicker {
+            code = StatusCode::SERVICE_UNAVAILABLE;
+            message = "NO_TICKER";
+        } else {
+            code = StatusCode::BAD_REQUEST;
+            message = "MALFORMED_EVENT";
+        }
+        error_code = err.into();
+        details = Some(err.to_string());
+    } else if let Some(err) = err.find::<HtlcValidationErr>() {
+        warn!("Rejection by HTLC validation: {}", err);
+        code = StatusCode::BAD_REQUEST;
+        message = err.field_error();
+        error_code = err.into();
+        details = Some(err.to_string());
     } else if let Some(err) = err.find::<StateUpdateErr>() {
         error!("Rejection by state update: {}", err);
         code = StatusCode::BAD_REQUEST;
         message = "STATE_UPDATE_ERROR";
+        error_code = err.into();
+        details = Some(err.to_string());
     } else if let Some(err) = err.find::<queries::Error>() {
         error!("Rejection by query fail: {}", err);
         code = StatusCode::BAD_REQUEST;
         message = "SERVER_DATABASE_ERROR";
+        error_code = ErrorCode::DatabaseError;
     } else if let Some(e) = err.find::<warp::filters::body::BodyDeserializeError>() {
         // This error happens if the body could not be deserialized correctly
         // We can use the cause to analyze the error and customize the error message
@@ -153,21 +1497,31 @@ async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
             None => "BAD_REQUEST",
         };
         code = StatusCode::BAD_REQUEST;
+        error_code = if message == "BAD_REQUEST" {
+            ErrorCode::BadRequest
+        } else {
+            ErrorCode::FieldError
+        };
+        details = Some(e.to_string());
     } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
         // We can handle a specific error, here METHOD_NOT_ALLOWED,
         // and render it however we want
         code = StatusCode::METHOD_NOT_ALLOWED;
         message = "METHOD_NOT_ALLOWED";
+        error_code = ErrorCode::MethodNotAllowed;
     } else {
         // We should have expected this... Just log and say its a 500
         eprintln!("unhandled rejection: {:?}", err);
         code = StatusCode::INTERNAL_SERVER_ERROR;
         message = "UNHANDLED_REJECTION";
+        error_code = ErrorCode::Unhandled;
     }
 
     let json = warp::reply::json(&ErrorMessage {
         code: code.as_u16(),
         message: message.into(),
+        error_code,
+        details,
     });
 
     Ok(warp::reply::with_status(json, code))
@@ -202,7 +1556,7 @@ mod tests {
             ..State::default()
         };
 
-        let state_mx = Arc::new(Mutex::new(init_state));
+        let state_mx = Arc::new(RwLock::new(init_state));
         let state_notify = Arc::new(Notify::new());
 
         let (sender, receiver) = tokio::sync::oneshot::channel();
@@ -216,6 +1570,9 @@ mod tests {
                     pool,
                     state,
                     state_notify,
+                    Arc::new(Mutex::new(WsMonitor::default())),
+                    Arc::new(StatsSnapshot::default()),
+                    ApiConfig::default(),
                 );
                 futures::pin_mut!(serve_task);
                 futures::future::select(serve_task, receiver.map_err(drop)).await;
@@ -241,6 +1598,195 @@ mod tests {
         assert!(res.is_ok());
     }
 
+    #[tokio::test]
+    async fn test_api_specs_offline() {
+        let spec = hedge_api_specs().await.unwrap();
+        assert!(spec.paths.contains_key("/estimate"));
+    }
+
+    #[test]
+    fn test_ip_allowlist() {
+        let open = ApiConfig::default();
+        assert!(open.is_allowed(None));
+
+        let config = ApiConfig {
+            allowed_ips: vec!["10.0.0.0/8".parse().unwrap(), "::1/128".parse().unwrap()],
+            ..ApiConfig::default()
+        };
+        assert!(config.is_allowed(Some("10.1.2.3:8081".parse().unwrap())));
+        assert!(config.is_allowed(Some("[::1]:8081".parse().unwrap())));
+        assert!(!config.is_allowed(Some("192.168.1.1:8081".parse().unwrap())));
+        assert!(!config.is_allowed(None));
+    }
+
+    #[tokio::test]
+    async fn test_state_not_modified() {
+        let state_mx = Arc::new(RwLock::new(State::default()));
+        write_state(&state_mx).await;
+        let filter = state_not_modified(state_mx.clone());
+
+        let reply = warp::test::request()
+            .path("/state")
+            .header("if-none-match", "\"1\"")
+            .reply(&filter)
+            .await;
+        assert_eq!(reply.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(reply.headers()["etag"], "\"1\"");
+
+        write_state(&state_mx).await;
+        assert!(warp::test::request()
+            .path("/state")
+            .header("if-none-match", "\"1\"")
+            .filter(&filter)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_state_fields() {
+        let state_mx = Arc::new(RwLock::new(State {
+            ticker: Some(40000.0),
+            ..State::default()
+        }));
+        let filter = query_state_fields(state_mx);
+
+        let reply = warp::test::request()
+            .path("/state?fields=ticker")
+            .reply(&filter)
+            .await;
+        assert_eq!(reply.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(reply.body()).unwrap();
+        assert_eq!(body, serde_json::json!({"ticker": 40000.0}));
+
+        let reply = warp::test::request()
+            .path("/state?fields=aboba")
+            .reply(&filter)
+            .await;
+        assert_eq!(reply.status(), StatusCode::BAD_REQUEST);
+
+        assert!(warp::test::request()
+            .path("/state")
+            .filter(&filter)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_wait_state() {
+        let state_mx = Arc::new(RwLock::new(State::default()));
+        let state_notify = Arc::new(Notify::new());
+        let filter = wait_state(state_mx.clone(), state_notify.clone());
+
+        let reply = warp::test::request()
+            .path("/state/wait?since_rev=0&timeout=1s")
+            .reply(&filter)
+            .await;
+        assert_eq!(reply.status(), StatusCode::NO_CONTENT);
+
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            write_state(&state_mx).await;
+            state_notify.notify_waiters();
+        });
+        let reply = warp::test::request()
+            .path("/state/wait?since_rev=0&timeout=5s")
+            .reply(&filter)
+            .await;
+        assert_eq!(reply.status(), StatusCode::OK);
+        let revision: StateRevision = serde_json::from_slice(reply.body()).unwrap();
+        assert_eq!(revision.revision, 1);
+    }
+
+    #[tokio::test]
+    async fn test_htlc_failed_write() {
+        // The worker is dropped, so writes fail as with unavailable database
+        let (writer, _) = UpdateWriter::new(offline_pool().unwrap(), 1);
+        let state_mx = RwLock::new(State::default());
+        let before = state_mx.read().await.clone();
+        let state_notify = Notify::new();
+        let api_config = ApiConfig::default();
+        let body = br#"{"channel_id":"aboba","sats":100,"rate":2500}"#;
+
+        // The waiter is registered before the HTLC is recorded
+        let (notified, res) = tokio::join!(
+            tokio::time::timeout(Duration::from_millis(100), state_notify.notified()),
+            record_htlc(&writer, &state_mx, &state_notify, &api_config, body)
+        );
+        let err = res.unwrap_err();
+        assert!(matches!(
+            err.find::<queries::Error>(),
+            Some(queries::Error::WriterStopped)
+        ));
+        assert!(notified.is_err(), "Failed write must not notify");
+        let after = state_mx.read().await.clone();
+        assert_eq!(
+            State {
+                revision: before.revision,
+                ..after
+            },
+            before
+        );
+    }
+
+    #[sqlx_database_tester::test(pool(
+        variable = "pool",
+        migrations = "../kollider-hedge-db/migrations"
+    ))]
+    async fn test_concurrent_requests_batched() {
+        const REQUESTS: usize = 20;
+        let (writer, writer_worker) = UpdateWriter::new(pool.clone(), REQUESTS);
+        tokio::spawn(writer_worker);
+        let state_mx = Arc::new(RwLock::new(State::default()));
+        let state_notify = Arc::new(Notify::new());
+        let api_config = ApiConfig::default();
+        let set_filter = hedge_set(
+            writer.clone(),
+            state_mx.clone(),
+            state_notify.clone(),
+            api_config.clone(),
+        );
+
+        let htlcs = (0..REQUESTS / 2).map(|i| {
+            let body = format!(r#"{{"channel_id":"htlc{}","sats":100,"rate":2500}}"#, i);
+            let (writer, state_mx, state_notify, api_config) =
+                (&writer, &state_mx, &state_notify, &api_config);
+            async move {
+                record_htlc(writer, state_mx, state_notify, api_config, body.as_bytes())
+                    .await
+                    .unwrap();
+            }
+        });
+        let sets = (0..REQUESTS / 2).map(|i| {
+            let request = warp::test::request()
+                .method("POST")
+                .path("/hedge/set")
+                .json(&HedgeTarget {
+                    channel_id: format!("set{}", i),
+                    sats: 100,
+                    rate: 2500,
+                });
+            let set_filter = &set_filter;
+            async move {
+                let reply = request.reply(set_filter).await;
+                assert_eq!(reply.status(), StatusCode::OK);
+            }
+        });
+        futures::join!(
+            futures::future::join_all(htlcs),
+            futures::future::join_all(sets)
+        );
+
+        // Rows inserted by the same transaction share `xmin`
+        let transactions =
+            sqlx::query_scalar!(r#"select count(distinct xmin::text) as "count!" from updates"#)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert!((transactions as usize) < REQUESTS);
+        assert_eq!(queries::query_history(&pool).await.unwrap().len(), REQUESTS);
+        assert_eq!(state_mx.read().await.channels_hedge.len(), REQUESTS);
+    }
+
     #[sqlx_database_tester::test(pool(
         variable = "pool",
         migrations = "../kollider-hedge-db/migrations"
@@ -285,6 +1831,9 @@ mod tests {
                         }
                     }
                 );
+                let cbor_client = HedgeClient::new(&client.server).with_encoding(Encoding::Cbor);
+                let cbor_state = cbor_client.query_state().await.unwrap();
+                assert_eq!(cbor_state.channels_hedge, state.channels_hedge);
 
                 let timeout = tokio::time::sleep(Duration::from_secs(3));
                 let (sats, price, side) = futures::select! {
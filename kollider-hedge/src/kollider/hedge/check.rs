@@ -0,0 +1,159 @@
+use crate::kollider::hedge::db::queries;
+use crate::kollider::hedge::db::Pool;
+use crate::kollider::hedge::exchange::{self, KolliderRest};
+use kollider_hedge_domain::product::Products;
+use kollider_hedge_domain::state::HedgeConfig;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckStatus {
+    Ok,
+    /// The service can run, but with degraded behavior
+    Warn,
+    /// The service must not start trading
+    Fail,
+}
+
+/// Outcome of a single startup check
+#[derive(Debug, Clone, PartialEq)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    pub details: String,
+}
+
+impl Check {
+    pub fn new(name: &'static str, status: CheckStatus, details: impl Into<String>) -> Self {
+        Check {
+            name,
+            status,
+            details: details.into(),
+        }
+    }
+}
+
+/// Report of `check` subcommand and of the validation before `serve` starts trading
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct CheckReport {
+    pub checks: Vec<Check>,
+}
+
+impl CheckReport {
+    pub fn push(&mut self, check: Check) {
+        self.checks.push(check);
+    }
+
+    /// No check failed, warnings are allowed
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|c| c.status != CheckStatus::Fail)
+    }
+}
+
+impl fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in self.checks.iter() {
+            let status = match check.status {
+                CheckStatus::Ok => "OK",
+                CheckStatus::Warn => "WARN",
+                CheckStatus::Fail => "FAIL",
+            };
+            writeln!(f, "[{:<4}] {}: {}", status, check.name, check.details)?;
+        }
+        if self.passed() {
+            write!(f, "All checks passed")
+        } else {
+            write!(f, "Some checks failed")
+        }
+    }
+}
+
+pub async fn check_database(pool: &Pool) -> Check {
+    match queries::ping(pool).await {
+        Ok(()) => Check::new("database", CheckStatus::Ok, "reachable"),
+        Err(e) => Check::new("database", CheckStatus::Fail, e.to_string()),
+    }
+}
+
+/// Fetch product specs of Kollider. Defaults are used if they can't be fetched, as before
+/// the specs were checked.
+pub async fn check_products(exchange: &KolliderRest) -> (Check, Products) {
+    match exchange.products().await {
+        Ok(products) => {
+            let check = Check::new(
+                "products",
+                CheckStatus::Ok,
+                format!("{} products are listed", products.len()),
+            );
+            (check, products)
+        }
+        Err(e) => {
+            let check = Check::new(
+                "products",
+                CheckStatus::Warn,
+                format!("failed to fetch, using defaults: {}", e),
+            );
+            (check, Products::new())
+        }
+    }
+}
+
+/// Make a signed request to check that Kollider accepts the credentials. Unreachable
+/// Kollider is a warning as the websocket reconnects until it is available.
+pub async fn check_credentials(exchange: &KolliderRest) -> Check {
+    match exchange.positions().await {
+        Ok(_) => Check::new("credentials", CheckStatus::Ok, "authenticated"),
+        Err(exchange::Error::Response(status, text)) if status.is_client_error() => Check::new(
+            "credentials",
+            CheckStatus::Fail,
+            format!("rejected with {}: {}", status, text),
+        ),
+        Err(e @ exchange::Error::Secret(_)) => {
+            Check::new("credentials", CheckStatus::Fail, e.to_string())
+        }
+        Err(e) => Check::new(
+            "credentials",
+            CheckStatus::Warn,
+            format!("not verified: {}", e),
+        ),
+    }
+}
+
+pub fn check_config(config: &HedgeConfig, products: &Products) -> Check {
+    let errs = config.validate(products);
+    if errs.is_empty() {
+        Check::new(
+            "config",
+            CheckStatus::Ok,
+            format!(
+                "{} on {} with leverage {}",
+                config.hedge_sym, config.hedge_pair, config.hedge_leverage
+            ),
+        )
+    } else {
+        let details: Vec<String> = errs.iter().map(|e| e.to_string()).collect();
+        Check::new("config", CheckStatus::Fail, details.join("; "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_report() {
+        let mut report = CheckReport::default();
+        report.push(check_config(&HedgeConfig::default(), &Products::new()));
+        report.push(Check::new("products", CheckStatus::Warn, "unreachable"));
+        assert!(report.passed());
+
+        let config = HedgeConfig {
+            hedge_leverage: 0,
+            ..HedgeConfig::default()
+        };
+        report.push(check_config(&config, &Products::new()));
+        assert!(!report.passed());
+        let printed = report.to_string();
+        assert!(printed.contains("[FAIL] config: Leverage of hedge must be positive"));
+        assert!(printed.ends_with("Some checks failed"));
+    }
+}
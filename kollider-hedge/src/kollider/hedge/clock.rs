@@ -0,0 +1,31 @@
+use crate::kollider::hedge::exchange::KolliderRest;
+use kollider_hedge_domain::state::{write_state, State};
+use log::*;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Notify, RwLock};
+use tokio::time::sleep;
+
+/// Periodically compare the clock of Kollider with the local one and store the skew in the
+/// state. Orders are held while the skew is above `max_clock_skew_secs`, as timeouts and
+/// aggregation deadlines can't be trusted then.
+pub async fn clock_skew_worker(
+    exchange: KolliderRest,
+    state_mx: Arc<RwLock<State>>,
+    state_notify: Arc<Notify>,
+    interval: Duration,
+) {
+    loop {
+        match exchange.clock_skew().await {
+            Ok(skew) => {
+                let skew_ms = skew.num_milliseconds();
+                trace!("Clock skew with Kollider: {} ms", skew_ms);
+                let mut state = write_state(&state_mx).await;
+                state.update_clock_skew(skew_ms);
+                state_notify.notify_waiters();
+            }
+            Err(e) => warn!("Failed to measure clock skew with Kollider: {}", e),
+        }
+        sleep(interval).await;
+    }
+}
@@ -0,0 +1,173 @@
+use flate2::write::{GzEncoder, ZlibEncoder};
+use log::*;
+use rweb::warp;
+use std::convert::Infallible;
+use std::io::Write;
+use warp::http::header::{
+    HeaderMap, HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, VARY,
+};
+use warp::hyper::body::{to_bytes, Body};
+use warp::{Filter, Reply};
+
+/// Smaller responses are sent as is, compression doesn't pay off for them
+pub const MIN_COMPRESSED_SIZE: usize = 1024;
+
+/// Content codings of responses that the service supports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Coding {
+    Gzip,
+    Deflate,
+}
+
+impl Coding {
+    fn name(&self) -> &'static str {
+        match self {
+            Coding::Gzip => "gzip",
+            Coding::Deflate => "deflate",
+        }
+    }
+
+    /// The first supported coding of `Accept-Encoding` header that is not refused with `q=0`
+    pub fn negotiate(headers: &HeaderMap) -> Option<Self> {
+        let accept = headers.get(ACCEPT_ENCODING)?.to_str().ok()?;
+        accept.split(',').find_map(|coding| {
+            let mut params = coding.split(';').map(str::trim);
+            let coding = match params.next()? {
+                "gzip" | "x-gzip" => Coding::Gzip,
+                "deflate" => Coding::Deflate,
+                _ => return None,
+            };
+            let refused = params.any(|p| {
+                p.strip_prefix("q=")
+                    .and_then(|q| q.parse::<f64>().ok())
+                    .map_or(false, |q| q == 0.0)
+            });
+            if refused {
+                None
+            } else {
+                Some(coding)
+            }
+        })
+    }
+
+    pub fn encode(&self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Coding::Gzip => {
+                let mut encoder = GzEncoder::new(vec![], flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+            // HTTP `deflate` is the zlib format
+            Coding::Deflate => {
+                let mut encoder = ZlibEncoder::new(vec![], flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// Compress the response if the client accepts it. Responses that are already encoded or small
+/// are passed as is.
+async fn compress_reply(
+    coding: Option<Coding>,
+    reply: warp::reply::Response,
+) -> warp::reply::Response {
+    let coding = match coding {
+        Some(coding) if !reply.headers().contains_key(CONTENT_ENCODING) => coding,
+        _ => return reply,
+    };
+    let (mut parts, body) = reply.into_parts();
+    let bytes = match to_bytes(body).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            error!("Failed to read response body for compression: {}", e);
+            return warp::http::Response::from_parts(parts, Body::empty());
+        }
+    };
+    parts
+        .headers
+        .append(VARY, HeaderValue::from_static("accept-encoding"));
+    if bytes.len() < MIN_COMPRESSED_SIZE {
+        return warp::http::Response::from_parts(parts, Body::from(bytes));
+    }
+    match coding.encode(&bytes) {
+        Ok(encoded) => {
+            parts
+                .headers
+                .insert(CONTENT_ENCODING, HeaderValue::from_static(coding.name()));
+            parts.headers.remove(CONTENT_LENGTH);
+            warp::http::Response::from_parts(parts, Body::from(encoded))
+        }
+        Err(e) => {
+            error!("Failed to compress response with {}: {}", coding.name(), e);
+            warp::http::Response::from_parts(parts, Body::from(bytes))
+        }
+    }
+}
+
+/// Wrap routes with gzip/deflate compression of responses negotiated by `Accept-Encoding`.
+/// Responses are passed as is if the compression is disabled.
+pub fn compressed<F, R>(
+    routes: F,
+    enabled: bool,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = Infallible> + Clone
+where
+    F: Filter<Extract = (R,), Error = Infallible> + Clone + Send + Sync + 'static,
+    R: Reply,
+{
+    warp::header::headers_cloned()
+        .map(move |headers: HeaderMap| {
+            if enabled {
+                Coding::negotiate(&headers)
+            } else {
+                None
+            }
+        })
+        .and(routes)
+        .then(|coding: Option<Coding>, reply: R| compress_reply(coding, reply.into_response()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn test_negotiate() {
+        let headers = |accept: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(ACCEPT_ENCODING, HeaderValue::from_str(accept).unwrap());
+            headers
+        };
+        assert_eq!(Coding::negotiate(&HeaderMap::new()), None);
+        assert_eq!(Coding::negotiate(&headers("br, gzip")), Some(Coding::Gzip));
+        assert_eq!(
+            Coding::negotiate(&headers("gzip;q=0, deflate")),
+            Some(Coding::Deflate)
+        );
+        assert_eq!(Coding::negotiate(&headers("identity")), None);
+    }
+
+    #[tokio::test]
+    async fn test_compressed() {
+        let body = "a".repeat(MIN_COMPRESSED_SIZE * 2);
+        let routes = compressed(warp::any().map(move || body.clone()), true);
+
+        let reply = warp::test::request()
+            .header("accept-encoding", "gzip")
+            .reply(&routes)
+            .await;
+        assert_eq!(reply.headers()[CONTENT_ENCODING], "gzip");
+        let mut decoded = String::new();
+        GzDecoder::new(&reply.body()[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded.len(), MIN_COMPRESSED_SIZE * 2);
+
+        let reply = warp::test::request().reply(&routes).await;
+        assert!(!reply.headers().contains_key(CONTENT_ENCODING));
+        assert_eq!(reply.body().len(), MIN_COMPRESSED_SIZE * 2);
+    }
+}
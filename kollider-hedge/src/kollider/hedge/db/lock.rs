@@ -0,0 +1,146 @@
+use log::*;
+use sqlx::postgres::PgConnection;
+use sqlx::Connection;
+use std::str::FromStr;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::time::sleep;
+
+/// How often the connection that holds the writer lock is checked
+const LOCK_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug)]
+pub enum LockErr {
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("Writer lock of the database is taken by another instance")]
+    Taken,
+}
+
+/// What to do on start when another instance holds the writer lock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockConflict {
+    /// Refuse to start
+    Fail,
+    /// Start in observer mode that never sends orders
+    ReadOnly,
+    /// Keep the state warm and take over trading when the lock is released
+    Standby,
+}
+
+#[derive(Error, Debug)]
+#[error("Unknown lock conflict policy {0}, valid are: fail, read-only, standby")]
+pub struct UnknownLockConflict(String);
+
+impl FromStr for LockConflict {
+    type Err = UnknownLockConflict;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(LockConflict::Fail),
+            "read-only" => Ok(LockConflict::ReadOnly),
+            "standby" => Ok(LockConflict::Standby),
+            _ => Err(UnknownLockConflict(s.to_owned())),
+        }
+    }
+}
+
+/// Key of the lock. Advisory locks are scoped by the database, so the key guards the single
+/// chain of updates of the database whatever symbol is traded and however it is migrated.
+const WRITER_LOCK_KEY: &str = "kollider-hedge:writer";
+
+/// Session advisory lock of the single instance that writes to the database. The lock is held
+/// on a dedicated connection, so it is released as soon as the process dies or the lock is
+/// dropped.
+pub struct WriterLock {
+    conn: PgConnection,
+    dbconnect: String,
+}
+
+impl WriterLock {
+    /// Take the lock if no other instance holds it. Returns `None` if it is taken.
+    pub async fn try_acquire(dbconnect: &str) -> Result<Option<Self>, LockErr> {
+        let conn = PgConnection::connect(dbconnect).await?;
+        WriterLock::try_acquire_on(conn, dbconnect).await
+    }
+
+    /// Take the lock on the connection, `dbconnect` is used to take it again when the
+    /// connection is lost
+    async fn try_acquire_on(
+        mut conn: PgConnection,
+        dbconnect: &str,
+    ) -> Result<Option<Self>, LockErr> {
+        if try_lock(&mut conn).await? {
+            Ok(Some(WriterLock {
+                conn,
+                dbconnect: dbconnect.to_owned(),
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Check the connection of the lock periodically. If the connection is lost, e.g. the
+    /// database restarted, the lock is taken again. Returns an error when the lock can't be
+    /// taken back, trading must stop then.
+    pub async fn hold(mut self) -> LockErr {
+        loop {
+            sleep(LOCK_CHECK_INTERVAL).await;
+            if let Err(e) = self.conn.ping().await {
+                warn!(
+                    "Connection of writer lock is lost, taking the lock again: {}",
+                    e
+                );
+                match WriterLock::try_acquire(&self.dbconnect).await {
+                    Ok(Some(lock)) => self = lock,
+                    Ok(None) => return LockErr::Taken,
+                    // Database is still unavailable, the lock is taken on the next check
+                    Err(e) => warn!("Failed to take writer lock again: {}", e),
+                }
+            }
+        }
+    }
+}
+
+async fn try_lock(conn: &mut PgConnection) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"select pg_try_advisory_lock(hashtext($1)) as "locked!""#,
+        WRITER_LOCK_KEY
+    )
+    .fetch_one(conn)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::PgPool;
+
+    async fn lock_connection(pool: &PgPool) -> PgConnection {
+        pool.acquire().await.unwrap().detach()
+    }
+
+    #[sqlx_database_tester::test(pool(
+        variable = "pool",
+        migrations = "../kollider-hedge-db/migrations"
+    ))]
+    async fn test_writer_lock() {
+        let lock = WriterLock::try_acquire_on(lock_connection(&pool).await, "")
+            .await
+            .unwrap();
+        assert!(lock.is_some());
+        let conflict = WriterLock::try_acquire_on(lock_connection(&pool).await, "")
+            .await
+            .unwrap();
+        assert!(conflict.is_none());
+        lock.unwrap().conn.close().await.unwrap();
+        let next = WriterLock::try_acquire_on(lock_connection(&pool).await, "")
+            .await
+            .unwrap();
+        assert!(next.is_some());
+        assert_eq!(
+            "read-only".parse::<LockConflict>().unwrap(),
+            LockConflict::ReadOnly
+        );
+    }
+}
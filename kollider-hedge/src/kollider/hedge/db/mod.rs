@@ -1,17 +1,74 @@
 pub mod consts;
+pub mod lock;
 pub mod migrate;
+pub mod partitions;
+pub mod projection;
 pub mod queries;
+pub mod writer;
 
 pub use self::consts::Pool;
 use sqlx::postgres::PgPoolOptions;
+use sqlx::Executor;
+use std::time::Duration;
 
-pub async fn create_db_pool(conn_string: &str) -> Result<Pool, sqlx::Error> {
+/// Tuning options for the connection pool to PostgreSQL
+#[derive(Debug, Clone)]
+pub struct DbConfig {
+    /// Maximum amount of connections in the pool
+    pub max_connections: u32,
+    /// How long we wait for a free connection or for establishing a new one
+    pub connect_timeout: Duration,
+    /// Abort any statement that takes more than the given time. `None` means no limit.
+    pub statement_timeout: Option<Duration>,
+}
+
+impl Default for DbConfig {
+    fn default() -> Self {
+        DbConfig {
+            max_connections: 5,
+            connect_timeout: Duration::from_secs(30),
+            statement_timeout: None,
+        }
+    }
+}
+
+/// Connect to the database and apply pending schema migrations
+pub async fn create_db_pool(conn_string: &str, config: &DbConfig) -> Result<Pool, sqlx::Error> {
+    let pool = connect_db_pool(conn_string, config).await?;
+    migrate_db(&pool).await?;
+    Ok(pool)
+}
+
+/// Connect to the database without touching the schema
+pub async fn connect_db_pool(conn_string: &str, config: &DbConfig) -> Result<Pool, sqlx::Error> {
+    let statement_timeout = config.statement_timeout;
     let pool = PgPoolOptions::new()
-        .max_connections(5)
+        .max_connections(config.max_connections)
+        .connect_timeout(config.connect_timeout)
+        .after_connect(move |conn| {
+            Box::pin(async move {
+                if let Some(dt) = statement_timeout {
+                    let query = format!("SET statement_timeout = {}", dt.as_millis());
+                    conn.execute(query.as_str()).await?;
+                }
+                Ok(())
+            })
+        })
         .connect(conn_string)
         .await?;
+    Ok(pool)
+}
 
-    sqlx::migrate!("../kollider-hedge-db/migrations").run(&pool).await?;
+/// Apply pending schema migrations
+pub async fn migrate_db(pool: &Pool) -> Result<(), sqlx::Error> {
+    sqlx::migrate!("../kollider-hedge-db/migrations")
+        .run(pool)
+        .await?;
+    Ok(())
+}
 
-    Ok(pool)
+/// Pool that connects only on the first query. Routes take the pool, but building them, e.g.
+/// for the OpenAPI spec, doesn't touch the database.
+pub fn offline_pool() -> Result<Pool, sqlx::Error> {
+    PgPoolOptions::new().connect_lazy("postgres://localhost/kollider_hedge")
 }
@@ -0,0 +1,112 @@
+use super::consts::Pool;
+use super::queries::Result;
+use chrono::prelude::*;
+use chrono::Duration;
+use kollider_hedge_domain::update::UpdateTag;
+use log::*;
+use tokio::time::sleep;
+
+/// How often the service checks that partitions for the next month exist
+const PARTITIONS_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(24 * 3600);
+
+/// Start of the month of the moment
+fn month_start(moment: NaiveDateTime) -> NaiveDateTime {
+    NaiveDate::from_ymd(moment.year(), moment.month(), 1).and_hms(0, 0, 0)
+}
+
+/// Parse start of the month from partition name like `updates_2022_03`
+fn partition_month(name: &str) -> Option<NaiveDateTime> {
+    let date = name.strip_prefix("updates_")?;
+    let date = NaiveDate::parse_from_str(&format!("{}_01", date), "%Y_%m_%d").ok()?;
+    Some(date.and_hms(0, 0, 0))
+}
+
+/// Create partitions of updates for the current and the next month
+pub async fn ensure_updates_partitions(pool: &Pool) -> Result<()> {
+    let current = month_start(Utc::now().naive_utc());
+    let next = month_start(current + Duration::days(32));
+    for month in [current, next] {
+        sqlx::query!("select from create_updates_partition($1)", month)
+            .execute(pool)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Create partitions ahead of time, so updates never land in the default partition
+pub async fn partitions_worker(pool: Pool) {
+    loop {
+        if let Err(e) = ensure_updates_partitions(&pool).await {
+            error!("Failed to create partitions of updates: {}", e);
+        }
+        sleep(PARTITIONS_CHECK_INTERVAL).await;
+    }
+}
+
+/// Drop monthly partitions that end before the moment. Partitions after the latest snapshot
+/// are kept, as they are required to reconstruct the state. Returns names of dropped partitions.
+pub async fn drop_updates_partitions(pool: &Pool, before: NaiveDateTime) -> Result<Vec<String>> {
+    let snapshot_tag = UpdateTag::Snapshot.to_string();
+    let last_snapshot = sqlx::query!(
+        "select max(created) as created from updates where tag = $1",
+        snapshot_tag
+    )
+    .fetch_one(pool)
+    .await?
+    .created
+    .unwrap_or_else(|| NaiveDateTime::from_timestamp(0, 0));
+    let before = std::cmp::min(before, last_snapshot);
+
+    let partitions = sqlx::query!(
+        r#"select c.relname::text as "name!" from pg_inherits i
+        join pg_class c on c.oid = i.inhrelid
+        join pg_class p on p.oid = i.inhparent
+        where p.relname = 'updates'"#
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut dropped = vec![];
+    for r in partitions {
+        let month = match partition_month(&r.name) {
+            Some(month) => month,
+            None => continue,
+        };
+        let month_end = month_start(month + Duration::days(32));
+        if month_end <= before {
+            info!("Dropping partition {}", r.name);
+            dropped.push(drop_partition(pool, month).await?);
+        }
+    }
+    Ok(dropped)
+}
+
+/// Drop the partition of the month and return its name. Table names can't be bound as
+/// parameters, so this is the only statement of the module built at runtime. The name is
+/// formatted from the month and never comes from the database or a user.
+async fn drop_partition(pool: &Pool, month: NaiveDateTime) -> Result<String> {
+    let name = month.format("updates_%Y_%m").to_string();
+    sqlx::query(&format!("drop table {}", name))
+        .execute(pool)
+        .await?;
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partition_month() {
+        assert_eq!(
+            partition_month("updates_2022_03"),
+            Some(NaiveDate::from_ymd(2022, 3, 1).and_hms(0, 0, 0))
+        );
+        assert_eq!(partition_month("updates_default"), None);
+        let january = month_start(NaiveDate::from_ymd(2022, 1, 31).and_hms(12, 0, 0));
+        assert_eq!(january, NaiveDate::from_ymd(2022, 1, 1).and_hms(0, 0, 0));
+        assert_eq!(
+            month_start(january + Duration::days(32)),
+            NaiveDate::from_ymd(2022, 2, 1).and_hms(0, 0, 0)
+        );
+    }
+}
@@ -0,0 +1,166 @@
+use super::consts::Pool;
+use super::queries::Result;
+use chrono::prelude::*;
+use kollider_hedge_domain::state::State;
+use kollider_hedge_domain::update::*;
+use sqlx::{Postgres, Transaction};
+use std::collections::HashMap;
+
+/// Apply the update to the `channel_hedges` table. Called in the transaction that appends the
+/// update to the chain, so the table always matches the chain.
+pub async fn project_update(
+    tx: &mut Transaction<'_, Postgres>,
+    created: NaiveDateTime,
+    update: &UpdateBody,
+) -> Result<()> {
+    let affected = update.affected_channels();
+    if affected.as_ref().map_or(false, |ids| ids.is_empty()) {
+        return Ok(());
+    }
+    let mut state = State::default();
+    if let Some(ids) = &affected {
+        let rows = sqlx::query!(
+            "select channel_id, sats, rate from channel_hedges where channel_id = any($1)",
+            &ids[..]
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        for r in rows {
+            state.channels_hedge.insert(
+                r.channel_id,
+                ChannelHedge {
+                    sats: r.sats,
+                    rate: r.rate,
+                },
+            );
+        }
+    }
+    state.apply_update(StateUpdate {
+        created,
+        body: update.clone(),
+    })?;
+    let ids = match affected {
+        Some(ids) => ids,
+        None => {
+            sqlx::query!("delete from channel_hedges")
+                .execute(&mut *tx)
+                .await?;
+            state.channels_hedge.keys().cloned().collect()
+        }
+    };
+    for id in ids {
+        match state.channels_hedge.get(&id) {
+            Some(hedge) => {
+                sqlx::query!(
+                    "insert into channel_hedges (channel_id, sats, rate, updated)
+                    values ($1, $2, $3, $4)
+                    on conflict (channel_id)
+                    do update set sats = $2, rate = $3, updated = $4",
+                    id,
+                    hedge.sats,
+                    hedge.rate,
+                    created
+                )
+                .execute(&mut *tx)
+                .await?;
+            }
+            None => {
+                sqlx::query!("delete from channel_hedges where channel_id = $1", id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Replace content of the `channel_hedges` table with channels of the reconstructed state
+pub async fn rebuild_channel_hedges(
+    pool: &Pool,
+    channels: &HashMap<ChannelId, ChannelHedge>,
+) -> Result<()> {
+    let now = Utc::now().naive_utc();
+    let mut tx = pool.begin().await?;
+    sqlx::query!("delete from channel_hedges")
+        .execute(&mut tx)
+        .await?;
+    for (id, hedge) in channels {
+        sqlx::query!(
+            "insert into channel_hedges (channel_id, sats, rate, updated) values ($1, $2, $3, $4)",
+            id,
+            hedge.sats,
+            hedge.rate,
+            now
+        )
+        .execute(&mut tx)
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Query current hedge of channels from the `channel_hedges` table
+pub async fn query_channel_hedges(pool: &Pool) -> Result<HashMap<ChannelId, ChannelHedge>> {
+    let rows = sqlx::query!("select channel_id, sats, rate from channel_hedges")
+        .fetch_all(pool)
+        .await?;
+    Ok(rows
+        .into_iter()
+        .map(|r| {
+            (
+                r.channel_id,
+                ChannelHedge {
+                    sats: r.sats,
+                    rate: r.rate,
+                },
+            )
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kollider::hedge::db::queries::insert_update;
+
+    #[sqlx_database_tester::test(pool(
+        variable = "pool",
+        migrations = "../kollider-hedge-db/migrations"
+    ))]
+    async fn test_channel_hedges_projection() {
+        let htlc = |channel_id: &str, sats| {
+            UpdateBody::Htlc(HtlcUpdate {
+                sats,
+                rate: 2500,
+                channel_id: channel_id.to_owned(),
+            })
+        };
+        insert_update(&pool, htlc("aboba", 100)).await.unwrap();
+        insert_update(&pool, htlc("aboba", 50)).await.unwrap();
+        insert_update(&pool, htlc("boba", 200)).await.unwrap();
+        assert_eq!(
+            query_channel_hedges(&pool).await.unwrap(),
+            hashmap! {
+                "aboba".to_owned() => ChannelHedge { sats: 150, rate: 2500 },
+                "boba".to_owned() => ChannelHedge { sats: 200, rate: 2500 },
+            }
+        );
+
+        insert_update(&pool, htlc("boba", -200)).await.unwrap();
+        insert_update(
+            &pool,
+            UpdateBody::ChannelsRemoved(ChannelsRemoval {
+                channel_ids: vec!["boba".to_owned()],
+            }),
+        )
+        .await
+        .unwrap();
+        let channels = query_channel_hedges(&pool).await.unwrap();
+        assert_eq!(channels.keys().collect::<Vec<_>>(), vec!["aboba"]);
+
+        rebuild_channel_hedges(&pool, &HashMap::new())
+            .await
+            .unwrap();
+        assert!(query_channel_hedges(&pool).await.unwrap().is_empty());
+    }
+}
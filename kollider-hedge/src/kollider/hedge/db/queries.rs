@@ -1,8 +1,16 @@
 use super::consts::Pool;
+use super::projection::project_update;
+use crate::kollider::hedge::latency;
 use chrono::prelude::*;
-use futures::StreamExt;
+use futures::{StreamExt, TryStreamExt};
+use kollider_api::kollider::KolliderMsg;
+use kollider_hedge_domain::api::*;
+use kollider_hedge_domain::chain::*;
 use kollider_hedge_domain::state::*;
 use kollider_hedge_domain::update::*;
+use log::*;
+use serde_json::Value as JsonValue;
+use sqlx::{Postgres, Transaction};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -15,15 +23,53 @@ pub enum Error {
     Encoding(#[from] serde_json::Error),
     #[error("Failed to reconstruct state: {0}")]
     StateInvalid(#[from] StateUpdateErr),
+    #[error("Batch of updates is not written: {0}")]
+    Batch(String),
+    #[error("Writer of updates is stopped")]
+    WriterStopped,
+    #[error("Failed to compress/decompress body: {0}")]
+    Compression(std::io::Error),
+    #[error("Update {0} has neither plain nor compressed body")]
+    MissingBody(i32),
+    #[error("Dump is not restored: {0}")]
+    Restore(#[from] RestoreErr),
 }
 
 /// Alias for a `Result` with the error type `self::Error`.
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Snapshots with JSON body larger than that amount of bytes are stored compressed
+const COMPRESS_THRESHOLD: usize = 64 * 1024;
+const ZSTD_LEVEL: i32 = 3;
+
+/// Split body into values for `body` and `body_zstd` columns. Only large snapshots are compressed.
+fn encode_body(tag: UpdateTag, body: JsonValue) -> Result<(Option<JsonValue>, Option<Vec<u8>>)> {
+    if tag == UpdateTag::Snapshot {
+        let raw = serde_json::to_vec(&body)?;
+        if raw.len() > COMPRESS_THRESHOLD {
+            let compressed = zstd::encode_all(&raw[..], ZSTD_LEVEL).map_err(Error::Compression)?;
+            return Ok((None, Some(compressed)));
+        }
+    }
+    Ok((Some(body), None))
+}
+
+/// Get JSON body of update from either plain or compressed column
+fn decode_body(id: i32, body: Option<JsonValue>, body_zstd: Option<Vec<u8>>) -> Result<JsonValue> {
+    match (body, body_zstd) {
+        (Some(body), _) => Ok(body),
+        (None, Some(compressed)) => {
+            let raw = zstd::decode_all(&compressed[..]).map_err(Error::Compression)?;
+            Ok(serde_json::from_slice(&raw)?)
+        }
+        (None, None) => Err(Error::MissingBody(id)),
+    }
+}
+
 /// Query all history of updates until we hit a snapshot or the begining of time
 pub async fn query_updates(pool: &Pool) -> Result<Vec<StateUpdate>> {
     let mut conn = pool.acquire().await?;
-    let res = sqlx::query!("select * from updates order by created desc")
+    let res = sqlx::query!("select * from updates order by created desc, id desc")
         .fetch(&mut conn)
         .fuse();
     futures::pin_mut!(res);
@@ -34,7 +80,8 @@ pub async fn query_updates(pool: &Pool) -> Result<Vec<StateUpdate>> {
             mmrow = res.next() => {
                 if let Some(mrow) = mmrow {
                     let r = mrow?;
-                    let body = UpdateTag::from_tag(&r.tag, r.version as u16, r.body.clone())?;
+                    let body = decode_body(r.id, r.body, r.body_zstd)?;
+                    let body = UpdateTag::from_tag(&r.tag, r.version as u16, body)?;
                     StateUpdate {
                         created: r.created,
                         body
@@ -54,16 +101,543 @@ pub async fn query_updates(pool: &Pool) -> Result<Vec<StateUpdate>> {
     Ok(parsed)
 }
 
+/// Query full history of updates from the begining of time, snapshots are included
+pub async fn query_history(pool: &Pool) -> Result<Vec<StateUpdate>> {
+    let rows = sqlx::query!("select * from updates order by created asc, id asc")
+        .fetch_all(pool)
+        .await?;
+    let mut parsed = vec![];
+    for r in rows {
+        let body = decode_body(r.id, r.body, r.body_zstd)?;
+        let body = UpdateTag::from_tag(&r.tag, r.version as u16, body)?;
+        parsed.push(StateUpdate {
+            created: r.created,
+            body,
+        });
+    }
+    Ok(parsed)
+}
+
+/// Query updates in the time range, only partitions of the range are scanned
+pub async fn query_history_range(pool: &Pool, filter: &HistoryQuery) -> Result<Vec<StateUpdate>> {
+    let from = filter
+        .from
+        .unwrap_or_else(|| NaiveDateTime::from_timestamp(0, 0));
+    let to = filter.to.unwrap_or_else(|| Utc::now().naive_utc());
+    let rows = sqlx::query!(
+        "select * from updates where created >= $1 and created <= $2 order by created asc, id asc",
+        from,
+        to
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut parsed = vec![];
+    for r in rows {
+        let body = decode_body(r.id, r.body, r.body_zstd)?;
+        let body = UpdateTag::from_tag(&r.tag, r.version as u16, body)?;
+        parsed.push(StateUpdate {
+            created: r.created,
+            body,
+        });
+    }
+    Ok(parsed)
+}
+
+/// Key of the advisory lock that serializes appends to the hash chain of updates
+const CHAIN_LOCK: i64 = 0x6b68_6564_6765;
+
+/// The last update of the hash chain
+struct ChainHead {
+    hash: Vec<u8>,
+    created: Option<NaiveDateTime>,
+}
+
+impl ChainHead {
+    /// Moment for a new update. It is taken while the chain is locked and never goes before
+    /// the head, so the order by `created` is the order of appends.
+    fn next_created(&self) -> NaiveDateTime {
+        let now = Utc::now().naive_utc();
+        self.created.map_or(now, |head| head.max(now))
+    }
+}
+
+/// Lock the hash chain until the end of the transaction and return the last update
+async fn chain_head(tx: &mut Transaction<'_, Postgres>) -> Result<ChainHead> {
+    sqlx::query("select pg_advisory_xact_lock($1)")
+        .bind(CHAIN_LOCK)
+        .execute(&mut *tx)
+        .await?;
+    let head =
+        sqlx::query!("select hash, created from updates order by created desc, id desc limit 1")
+            .fetch_optional(&mut *tx)
+            .await?;
+    Ok(ChainHead {
+        hash: head
+            .as_ref()
+            .and_then(|r| r.hash.clone())
+            .unwrap_or_else(|| GENESIS_HASH.to_vec()),
+        created: head.map(|r| r.created),
+    })
+}
+
+/// Insert update after the update with `prev_hash` and apply it to projections. The body is
+/// stored in the given version, `update` is the decoded body. Returns hash of the inserted update.
+async fn append_update(
+    tx: &mut Transaction<'_, Postgres>,
+    prev_hash: &[u8],
+    created: NaiveDateTime,
+    update: &UpdateBody,
+    version: u16,
+    body: JsonValue,
+) -> Result<Vec<u8>> {
+    let created = truncate_micros(created);
+    let tag = update.tag();
+    let tag_str = tag.to_string();
+    let hash = update_hash(prev_hash, created, &tag_str, version, &body);
+    let (body, body_zstd) = encode_body(tag, body)?;
+    sqlx::query!(
+        "insert into updates (created, version, tag, body, body_zstd, prev_hash, hash)
+        values ($1, $2, $3, $4, $5, $6, $7)",
+        created,
+        version as i16,
+        tag_str,
+        body,
+        body_zstd,
+        prev_hash,
+        hash
+    )
+    .execute(&mut *tx)
+    .await?;
+    project_update(tx, created, update).await?;
+    Ok(hash)
+}
+
 /// Insert new update in the chain of updates in database
 pub async fn insert_update(pool: &Pool, update: UpdateBody) -> Result<()> {
+    let _timer = latency::timer("db insert_update");
+    let mut tx = pool.begin().await?;
+    let head = chain_head(&mut tx).await?;
+    append_update(
+        &mut tx,
+        &head.hash,
+        head.next_created(),
+        &update,
+        CURRENT_BODY_VERSION,
+        update.json()?,
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Insert several updates in a single transaction, so they are committed at once. Returns
+/// the updates with the moment they are recorded at.
+pub async fn insert_updates(pool: &Pool, updates: &[UpdateBody]) -> Result<Vec<StateUpdate>> {
+    let _timer = latency::timer("db insert_updates");
+    let mut tx = pool.begin().await?;
+    let head = chain_head(&mut tx).await?;
+    let created = head.next_created();
+    let mut hash = head.hash;
+    for update in updates {
+        hash = append_update(
+            &mut tx,
+            &hash,
+            created,
+            update,
+            CURRENT_BODY_VERSION,
+            update.json()?,
+        )
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(updates
+        .iter()
+        .map(|body| StateUpdate {
+            created: truncate_micros(created),
+            body: body.clone(),
+        })
+        .collect())
+}
+
+/// Export raw updates created after the moment in the stored body version
+pub async fn query_dump(pool: &Pool, since: Option<NaiveDateTime>) -> Result<Vec<DumpRecord>> {
+    let since = since.unwrap_or_else(|| NaiveDateTime::from_timestamp(0, 0));
+    let rows = sqlx::query!(
+        "select * from updates where created >= $1 order by created asc, id asc",
+        since
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut records = vec![];
+    for r in rows {
+        records.push(DumpRecord {
+            created: r.created,
+            body: decode_body(r.id, r.body, r.body_zstd)?,
+            tag: r.tag,
+            version: r.version as u16,
+            prev_hash: r.prev_hash.map(hex::encode),
+            hash: r.hash.map(hex::encode),
+        });
+    }
+    Ok(records)
+}
+
+/// Append dumped updates to the chain in a single transaction. The dump must start after the
+/// latest stored update, so the chain stays ordered. Hashes are recomputed from the local chain.
+pub async fn insert_dump(pool: &Pool, records: &[DumpRecord]) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    let head = chain_head(&mut tx).await?;
+    if let (Some(first), Some(latest)) = (records.first(), head.created) {
+        if first.created <= latest {
+            return Err(RestoreErr::Overlap(first.created, latest).into());
+        }
+    }
+    let mut hash = head.hash;
+    for record in records {
+        hash = append_update(
+            &mut tx,
+            &hash,
+            record.created,
+            &record.decode()?,
+            record.version,
+            record.body.clone(),
+        )
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Compute hashes of updates starting from the first update without hash. Such updates are
+/// written before the hash chain was introduced or their bodies were migrated.
+async fn seal_updates(tx: &mut Transaction<'_, Postgres>) -> Result<u64> {
+    chain_head(tx).await?;
+    let first = sqlx::query!(
+        "select id, created from updates where hash is null order by created asc, id asc limit 1"
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    let (from_created, from_id) = match first {
+        Some(r) => (r.created, r.id),
+        None => return Ok(0),
+    };
+    let mut prev = sqlx::query!(
+        "select hash from updates where created < $1 or (created = $1 and id < $2)
+        order by created desc, id desc limit 1",
+        from_created,
+        from_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .and_then(|r| r.hash)
+    .unwrap_or_else(|| GENESIS_HASH.to_vec());
+    let rows = sqlx::query!(
+        "select id, created, tag, version, body, body_zstd from updates
+        where created > $1 or (created = $1 and id >= $2) order by created asc, id asc",
+        from_created,
+        from_id
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    let mut sealed = 0;
+    for r in rows {
+        let body = decode_body(r.id, r.body, r.body_zstd)?;
+        let hash = update_hash(&prev, r.created, &r.tag, r.version as u16, &body);
+        sqlx::query!(
+            "update updates set prev_hash = $1, hash = $2 where id = $3 and created = $4",
+            prev,
+            hash,
+            r.id,
+            r.created
+        )
+        .execute(&mut *tx)
+        .await?;
+        prev = hash;
+        sealed += 1;
+    }
+    Ok(sealed)
+}
+
+/// Add updates without hash to the hash chain. Returns amount of sealed updates.
+pub async fn seal_chain(pool: &Pool) -> Result<u64> {
+    let mut tx = pool.begin().await?;
+    let sealed = seal_updates(&mut tx).await?;
+    tx.commit().await?;
+    Ok(sealed)
+}
+
+/// Amount of updates that are loaded in memory at once by `migrate_events`
+const MIGRATE_PAGE_SIZE: i64 = 1000;
+
+/// Rewrite bodies of updates with outdated version to the current shape. Returns amount of
+/// migrated rows. Hashes of the migrated updates and all following ones are recomputed, so
+/// the head of the chain changes.
+pub async fn migrate_events(pool: &Pool) -> Result<u64> {
+    let mut tx = pool.begin().await?;
+    chain_head(&mut tx).await?;
+    let mut first = None;
+    let mut migrated = 0;
+    loop {
+        // Migrated rows get the current version, so each page selects the next ones
+        let rows = sqlx::query!(
+            "select id, created, tag, version, body, body_zstd from updates where version < $1
+            order by created asc, id asc limit $2",
+            CURRENT_BODY_VERSION as i16,
+            MIGRATE_PAGE_SIZE
+        )
+        .fetch_all(&mut tx)
+        .await?;
+        if rows.is_empty() {
+            break;
+        }
+        if first.is_none() {
+            first = rows.first().map(|r| (r.created, r.id));
+        }
+        for r in rows {
+            let tag = r.tag.parse::<UpdateTag>().map_err(UpdateBodyError::from)?;
+            let body = decode_body(r.id, r.body, r.body_zstd)?;
+            let (body, body_zstd) = encode_body(tag, tag.migrate_body(r.version as u16, body)?)?;
+            sqlx::query!(
+                "update updates set version = $1, body = $2, body_zstd = $3 where id = $4",
+                CURRENT_BODY_VERSION as i16,
+                body,
+                body_zstd,
+                r.id
+            )
+            .execute(&mut tx)
+            .await?;
+            migrated += 1;
+        }
+        debug!("Migrated {} updates", migrated);
+    }
+    if let Some((created, id)) = first {
+        warn!("Resealing hash chain of updates starting from {}", created);
+        sqlx::query!(
+            "update updates set prev_hash = null, hash = null
+            where created > $1 or (created = $1 and id >= $2)",
+            created,
+            id
+        )
+        .execute(&mut tx)
+        .await?;
+        seal_updates(&mut tx).await?;
+    }
+    tx.commit().await?;
+    Ok(migrated)
+}
+
+/// Check that the database responds
+pub async fn ping(pool: &Pool) -> Result<()> {
+    sqlx::query("select 1").execute(pool).await?;
+    Ok(())
+}
+
+/// Save snapshot of statistics to the time series table
+pub async fn insert_stats(pool: &Pool, stats: &Stats) -> Result<()> {
     let now = Utc::now().naive_utc();
-    let tag = format!("{}", update.tag());
-    let body = update.json()?;
+    let body = serde_json::to_value(stats)?;
     sqlx::query!(
-        "insert into updates (created, version, tag, body) values ($1, $2, $3, $4)",
+        "insert into stats (created, body) values ($1, $2)",
+        now,
+        body
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Query recorded statistics in the given time range. If `step` is set, return only first
+/// record in each interval of `step` seconds.
+pub async fn query_stats_history(
+    pool: &Pool,
+    filter: &StatsHistoryQuery,
+) -> Result<Vec<StatsRecord>> {
+    let from = filter
+        .from
+        .unwrap_or_else(|| NaiveDateTime::from_timestamp(0, 0));
+    let to = filter.to.unwrap_or_else(|| Utc::now().naive_utc());
+    let step = filter.step.unwrap_or(1).max(1) as f64;
+    let rows = sqlx::query!(
+        "select distinct on (floor(date_part('epoch', created) / $3)) created, body from stats
+        where created >= $1 and created <= $2
+        order by floor(date_part('epoch', created) / $3), created",
+        from,
+        to,
+        step
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut res = vec![];
+    for r in rows {
+        res.push(StatsRecord {
+            created: r.created,
+            stats: serde_json::from_value(r.body)?,
+        });
+    }
+    Ok(res)
+}
+
+/// Record raw request to `/hedge/htlc` with its outcome. `error` is `None` for accepted requests.
+pub async fn insert_htlc_request(
+    pool: &Pool,
+    created: NaiveDateTime,
+    source_ip: Option<String>,
+    body: &str,
+    error: Option<String>,
+) -> Result<()> {
+    sqlx::query!(
+        "insert into htlc_requests (created, source_ip, body, accepted, error) values ($1, $2, $3, $4, $5)",
+        created,
+        source_ip,
+        body,
+        error.is_none(),
+        error
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Query the latest raw requests to `/hedge/htlc` in the given time range, newest first
+pub async fn query_htlc_requests(
+    pool: &Pool,
+    filter: &RequestsQuery,
+) -> Result<Vec<HtlcRequestRecord>> {
+    let from = filter
+        .from
+        .unwrap_or_else(|| NaiveDateTime::from_timestamp(0, 0));
+    let to = filter.to.unwrap_or_else(|| Utc::now().naive_utc());
+    let limit = filter.limit.unwrap_or(DEFAULT_REQUESTS_LIMIT) as i64;
+    let rows = sqlx::query!(
+        "select id, created, source_ip, body, accepted, error from htlc_requests
+        where created >= $1 and created <= $2
+        order by created desc, id desc limit $3",
+        from,
+        to,
+        limit
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|r| HtlcRequestRecord {
+            id: r.id,
+            created: r.created,
+            source_ip: r.source_ip,
+            body: r.body,
+            accepted: r.accepted,
+            error: r.error,
+        })
+        .collect())
+}
+
+/// Action of the outbox with messages that are sent to Kollider for it
+#[derive(Debug, Clone)]
+pub struct OutboxEntry {
+    pub id: i32,
+    pub created: NaiveDateTime,
+    pub action: StateAction,
+    pub messages: Vec<KolliderMsg>,
+    /// The messages were put in the websocket queue
+    pub sent: Option<NaiveDateTime>,
+}
+
+/// Key that Kollider confirmations of the action are matched by
+pub fn outbox_key(action: &StateAction) -> String {
+    match action {
+        StateAction::OpenOrder(order) => order.ext_id.clone(),
+        StateAction::CloseOrder { order_id, .. } => cancel_key(*order_id),
+        StateAction::ClosePosition { ext_id, .. } => ext_id.clone(),
+    }
+}
+
+/// Outbox key of the cancel of the order
+pub fn cancel_key(order_id: u64) -> String {
+    format!("cancel:{}", order_id)
+}
+
+/// Persist the action and its messages before they are sent. Returns id of the entry.
+pub async fn insert_outbox(
+    pool: &Pool,
+    action: &StateAction,
+    messages: &[KolliderMsg],
+) -> Result<i32> {
+    let created = Utc::now().naive_utc();
+    let key = outbox_key(action);
+    let action = serde_json::to_value(action)?;
+    let messages = serde_json::to_value(messages)?;
+    let r = sqlx::query!(
+        "insert into outbox (created, key, action, messages) values ($1, $2, $3, $4) returning id",
+        created,
+        key,
+        action,
+        messages
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(r.id)
+}
+
+/// Mark messages of the outbox entry as put in the websocket queue
+pub async fn mark_outbox_sent(pool: &Pool, id: i32) -> Result<()> {
+    let now = Utc::now().naive_utc();
+    sqlx::query!("update outbox set sent = $1 where id = $2", now, id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Mark entries with the key as confirmed by Kollider. Returns `false` if there are no such
+/// unconfirmed entries, e.g. for orders placed by hand.
+pub async fn ack_outbox(pool: &Pool, key: &str) -> Result<bool> {
+    let now = Utc::now().naive_utc();
+    let res = sqlx::query!(
+        "update outbox set acked = $1 where key = $2 and acked is null",
         now,
-        CURRENT_BODY_VERSION as i16,
-        tag,
+        key
+    )
+    .execute(pool)
+    .await?;
+    Ok(res.rows_affected() > 0)
+}
+
+/// Query entries created after the moment that are not confirmed by Kollider, oldest first
+pub async fn query_unacked_outbox(pool: &Pool, since: NaiveDateTime) -> Result<Vec<OutboxEntry>> {
+    let rows = sqlx::query!(
+        "select id, created, action, messages, sent from outbox
+        where acked is null and created >= $1 order by id asc",
+        since
+    )
+    .fetch_all(pool)
+    .await?;
+    rows.into_iter()
+        .map(|r| {
+            Ok(OutboxEntry {
+                id: r.id,
+                created: r.created,
+                action: serde_json::from_value(r.action)?,
+                messages: serde_json::from_value(r.messages)?,
+                sent: r.sent,
+            })
+        })
+        .collect()
+}
+
+/// Delete outbox entries created before the moment. Returns amount of deleted entries.
+pub async fn prune_outbox(pool: &Pool, before: NaiveDateTime) -> Result<u64> {
+    let res = sqlx::query!("delete from outbox where created < $1", before)
+        .execute(pool)
+        .await?;
+    Ok(res.rows_affected())
+}
+
+/// Save the daily accounting report
+pub async fn insert_daily_report(pool: &Pool, report: &DailyReport) -> Result<()> {
+    let body = serde_json::to_value(report)?;
+    sqlx::query!(
+        "insert into daily_reports (created, body) values ($1, $2)",
+        report.to,
         body
     )
     .execute(pool)
@@ -72,16 +646,147 @@ pub async fn insert_update(pool: &Pool, update: UpdateBody) -> Result<()> {
     Ok(())
 }
 
-/// Reconstruct state from chain of updates and snapshots in the database
+/// Query daily reports that are closed in the given time range
+pub async fn query_daily_reports(
+    pool: &Pool,
+    filter: &DailyReportQuery,
+) -> Result<Vec<DailyReport>> {
+    let from = filter
+        .from
+        .unwrap_or_else(|| NaiveDateTime::from_timestamp(0, 0));
+    let to = filter.to.unwrap_or_else(|| Utc::now().naive_utc());
+    let rows = sqlx::query!(
+        "select body from daily_reports where created >= $1 and created <= $2 order by created",
+        from,
+        to
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut res = vec![];
+    for r in rows {
+        res.push(serde_json::from_value(r.body)?);
+    }
+    Ok(res)
+}
+
+/// Log progress of state reconstruction each time the amount of updates is replayed
+const REPLAY_PROGRESS_STEP: u64 = 10_000;
+
+/// Position in the chain of updates that the state is replayed up to. Updates are appended
+/// under the chain lock, so ids of later appends are always greater.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplayCursor {
+    /// The greatest id of replayed updates
+    pub id: i32,
+}
+
+/// Reconstruct state from chain of updates and snapshots in the database. Updates are
+/// streamed and applied one by one starting from the latest snapshot, so memory usage
+/// doesn't depend on the length of the chain.
 pub async fn query_state(pool: &Pool, config: HedgeConfig) -> Result<State> {
-    let updates = query_updates(pool).await?;
-    Ok(State::collect(config, updates.into_iter().rev())?)
+    Ok(query_state_cursor(pool, config).await?.0)
+}
+
+/// Reconstruct state as `query_state` and return the position of the last replayed update,
+/// so the state can be caught up with `catch_up_state` later.
+pub async fn query_state_cursor(pool: &Pool, config: HedgeConfig) -> Result<(State, ReplayCursor)> {
+    let _timer = latency::timer("db query_state");
+    let mut conn = pool.acquire().await?;
+    let snapshot_tag = UpdateTag::Snapshot.to_string();
+    let start = sqlx::query!(
+        "select id, created from updates where tag = $1 order by created desc, id desc limit 1",
+        snapshot_tag
+    )
+    .fetch_optional(&mut conn)
+    .await?;
+    let (start_created, start_id) = start
+        .map(|r| (r.created, r.id))
+        .unwrap_or_else(|| (NaiveDateTime::from_timestamp(0, 0), 0));
+    debug!("Replaying updates starting from {}", start_created);
+
+    let mut rows = sqlx::query!(
+        "select * from updates where created > $1 or (created = $1 and id >= $2)
+        order by created asc, id asc",
+        start_created,
+        start_id
+    )
+    .fetch(&mut conn);
+    let mut state = State::new(config);
+    let mut cursor = ReplayCursor { id: start_id - 1 };
+    let mut replayed: u64 = 0;
+    while let Some(r) = rows.try_next().await? {
+        let body = decode_body(r.id, r.body, r.body_zstd)?;
+        let body = UpdateTag::from_tag(&r.tag, r.version as u16, body)?;
+        state.apply_update(StateUpdate {
+            created: r.created,
+            body,
+        })?;
+        cursor.id = cursor.id.max(r.id);
+        replayed += 1;
+        if replayed % REPLAY_PROGRESS_STEP == 0 {
+            info!("Replayed {} updates", replayed);
+        }
+    }
+    info!("State is reconstructed from {} updates", replayed);
+    Ok((state, cursor))
+}
+
+/// Apply updates that were written after the cursor and move the cursor. Returns amount of
+/// applied updates.
+pub async fn catch_up_state(
+    pool: &Pool,
+    state: &mut State,
+    cursor: &mut ReplayCursor,
+) -> Result<u64> {
+    let rows = sqlx::query!(
+        "select * from updates where id > $1 order by id asc",
+        cursor.id
+    )
+    .fetch_all(pool)
+    .await?;
+    let applied = rows.len() as u64;
+    for r in rows {
+        let body = decode_body(r.id, r.body, r.body_zstd)?;
+        let body = UpdateTag::from_tag(&r.tag, r.version as u16, body)?;
+        state.apply_update(StateUpdate {
+            created: r.created,
+            body,
+        })?;
+        cursor.id = r.id;
+    }
+    Ok(applied)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::collections::HashMap;
+
+    #[test]
+    fn test_body_compression() {
+        let channels: serde_json::Map<String, JsonValue> = (0..5000)
+            .map(|i| {
+                (
+                    format!("channel{}", i),
+                    serde_json::json!({"sats": i, "rate": 2500}),
+                )
+            })
+            .collect();
+        let body = JsonValue::Object(channels);
+
+        let (plain, compressed) = encode_body(UpdateTag::Snapshot, body.clone()).unwrap();
+        assert_eq!(plain, None);
+        let compressed = compressed.unwrap();
+        assert!(compressed.len() < COMPRESS_THRESHOLD);
+        assert_eq!(decode_body(1, None, Some(compressed)).unwrap(), body);
+
+        let (plain, compressed) = encode_body(UpdateTag::Htlc, body.clone()).unwrap();
+        assert_eq!(plain, Some(body));
+        assert_eq!(compressed, None);
+        assert!(matches!(
+            decode_body(1, None, None),
+            Err(Error::MissingBody(1))
+        ));
+    }
 
     #[sqlx_database_tester::test(
         pool(
@@ -164,6 +869,7 @@ mod tests {
                     rate: 2500,
                 }
             },
+            ..StateSnapshot::default()
         };
         insert_update(&pool, UpdateBody::Snapshot(snapshot_update.clone()))
             .await
@@ -200,6 +906,43 @@ mod tests {
         );
     }
 
+    #[sqlx_database_tester::test(pool(
+        variable = "pool",
+        migrations = "../kollider-hedge-db/migrations"
+    ))]
+    async fn test_catch_up_state() {
+        let htlc = |sats| {
+            UpdateBody::Htlc(HtlcUpdate {
+                sats,
+                rate: 2500,
+                channel_id: "aboba".to_owned(),
+            })
+        };
+        insert_update(&pool, htlc(100)).await.unwrap();
+        let (mut state, mut cursor) = query_state_cursor(&pool, HedgeConfig::default())
+            .await
+            .unwrap();
+        assert_eq!(state.channels_hedge["aboba"].sats, 100);
+
+        // Updates of a batch share the moment, the cursor still finds all of them
+        insert_updates(&pool, &[htlc(200), htlc(300)])
+            .await
+            .unwrap();
+        assert_eq!(
+            catch_up_state(&pool, &mut state, &mut cursor)
+                .await
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            catch_up_state(&pool, &mut state, &mut cursor)
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(state.channels_hedge["aboba"].sats, 600);
+    }
+
     #[sqlx_database_tester::test(pool(
         variable = "pool",
         migrations = "../kollider-hedge-db/migrations"
@@ -212,6 +955,7 @@ mod tests {
                     rate: 2500,
                 }
             },
+            ..StateSnapshot::default()
         };
         insert_update(&pool, UpdateBody::Snapshot(snapshot_update.clone()))
             .await
@@ -239,20 +983,213 @@ mod tests {
             state,
             State {
                 last_changed: state.last_changed,
-                config: HedgeConfig::default(),
-                balance: None,
-                ticker: None,
                 channels_hedge: hashmap! {
                     "aboba".to_owned() => ChannelHedge {
                         sats: 900,
                         rate: 2500,
                     }
                 },
-                opened_orders: None,
-                opened_position: None,
-                opening_orders: HashMap::new(),
-                scheduled_actions: vec![],
+                channels_usd: hashmap! {
+                    "aboba".to_owned() => ChannelUsd {
+                        usd: 0.36,
+                        usd_at_ticker: None,
+                    }
+                },
+                daily: DailyCounters::new(state.daily.since),
+                ..State::new(HedgeConfig::default())
             }
         );
     }
+
+    #[sqlx_database_tester::test(pool(
+        variable = "pool",
+        migrations = "../kollider-hedge-db/migrations"
+    ))]
+    async fn test_stats_history() {
+        let history = query_stats_history(&pool, &StatsHistoryQuery::default())
+            .await
+            .unwrap();
+        assert!(history.is_empty());
+
+        let stats = Stats {
+            channels_sats: 100,
+            ..Stats::default()
+        };
+        insert_stats(&pool, &stats).await.unwrap();
+        insert_stats(&pool, &stats).await.unwrap();
+
+        let history = query_stats_history(&pool, &StatsHistoryQuery::default())
+            .await
+            .unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].stats.channels_sats, 100);
+
+        let filter = StatsHistoryQuery {
+            step: Some(3600),
+            ..StatsHistoryQuery::default()
+        };
+        let history = query_stats_history(&pool, &filter).await.unwrap();
+        assert_eq!(history.len(), 1);
+    }
+
+    #[sqlx_database_tester::test(pool(
+        variable = "pool",
+        migrations = "../kollider-hedge-db/migrations"
+    ))]
+    async fn test_htlc_requests() {
+        let created = Utc::now().naive_utc();
+        insert_htlc_request(&pool, created, None, "{}", Some("BAD_REQUEST".to_owned()))
+            .await
+            .unwrap();
+        let body = r#"{"channel_id":"aboba","sats":100,"rate":2500}"#;
+        insert_htlc_request(&pool, created, Some("127.0.0.1".to_owned()), body, None)
+            .await
+            .unwrap();
+
+        let requests = query_htlc_requests(&pool, &RequestsQuery::default())
+            .await
+            .unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].body, body);
+        assert!(requests[0].accepted);
+        assert_eq!(requests[1].error, Some("BAD_REQUEST".to_owned()));
+
+        let filter = RequestsQuery {
+            limit: Some(1),
+            ..RequestsQuery::default()
+        };
+        let requests = query_htlc_requests(&pool, &filter).await.unwrap();
+        assert_eq!(requests.len(), 1);
+    }
+
+    #[sqlx_database_tester::test(pool(
+        variable = "pool",
+        migrations = "../kollider-hedge-db/migrations"
+    ))]
+    async fn test_outbox() {
+        let start = Utc::now().naive_utc();
+        let cancel = StateAction::CloseOrder {
+            order_id: 42,
+            symbol: "BTCUSD.PERP".to_owned(),
+        };
+        let id = insert_outbox(&pool, &cancel, &[]).await.unwrap();
+        mark_outbox_sent(&pool, id).await.unwrap();
+
+        let unacked = query_unacked_outbox(&pool, start).await.unwrap();
+        assert_eq!(unacked.len(), 1);
+        assert_eq!(unacked[0].action, cancel);
+        assert!(unacked[0].sent.is_some());
+
+        assert!(ack_outbox(&pool, &cancel_key(42)).await.unwrap());
+        assert!(!ack_outbox(&pool, &cancel_key(42)).await.unwrap());
+        assert!(query_unacked_outbox(&pool, start).await.unwrap().is_empty());
+        assert_eq!(
+            prune_outbox(&pool, Utc::now().naive_utc()).await.unwrap(),
+            1
+        );
+    }
+
+    #[sqlx_database_tester::test(pool(
+        variable = "pool",
+        migrations = "../kollider-hedge-db/migrations"
+    ))]
+    async fn test_dump_restore() {
+        let htlc = UpdateBody::Htlc(HtlcUpdate {
+            sats: 100,
+            rate: 2500,
+            channel_id: "aboba".to_owned(),
+        });
+        insert_update(&pool, htlc.clone()).await.unwrap();
+        insert_update(&pool, htlc).await.unwrap();
+        let dump = query_dump(&pool, None).await.unwrap();
+        assert_eq!(dump.len(), 2);
+        assert_eq!(
+            query_dump(&pool, Some(dump[1].created))
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+
+        assert!(matches!(
+            insert_dump(&pool, &dump).await,
+            Err(Error::Restore(RestoreErr::Overlap(..)))
+        ));
+        sqlx::query!("delete from updates")
+            .execute(&pool)
+            .await
+            .unwrap();
+        insert_dump(&pool, &dump).await.unwrap();
+        assert_eq!(query_dump(&pool, None).await.unwrap(), dump);
+    }
+
+    #[sqlx_database_tester::test(pool(
+        variable = "pool",
+        migrations = "../kollider-hedge-db/migrations"
+    ))]
+    async fn test_hash_chain() {
+        let htlc = |sats| {
+            UpdateBody::Htlc(HtlcUpdate {
+                sats,
+                rate: 2500,
+                channel_id: "aboba".to_owned(),
+            })
+        };
+        insert_update(&pool, htlc(100)).await.unwrap();
+        insert_updates(&pool, &[htlc(200)]).await.unwrap();
+        let dump = query_dump(&pool, None).await.unwrap();
+        assert_eq!(dump[0].prev_hash, Some(hex::encode(GENESIS_HASH)));
+        assert_eq!(verify_chain(&dump).unwrap().records, 2);
+
+        // Updates written before the chain are sealed with the same hashes
+        sqlx::query!("update updates set prev_hash = null, hash = null")
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(seal_chain(&pool).await.unwrap(), 2);
+        assert_eq!(seal_chain(&pool).await.unwrap(), 0);
+        assert_eq!(query_dump(&pool, None).await.unwrap(), dump);
+
+        sqlx::query!("update updates set body = jsonb_set(body, '{sats}', '100500')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let dump = query_dump(&pool, None).await.unwrap();
+        assert_eq!(verify_chain(&dump), Err(ChainErr::Mismatch(1)));
+    }
+
+    #[sqlx_database_tester::test(pool(
+        variable = "pool",
+        migrations = "../kollider-hedge-db/migrations"
+    ))]
+    async fn test_migrate_events() {
+        let snapshot = StateSnapshot {
+            channels_hedge: hashmap! {
+                "aboba".to_owned() => ChannelHedge {
+                    sats: 300,
+                    rate: 2500,
+                }
+            },
+            ..StateSnapshot::default()
+        };
+        insert_update(&pool, UpdateBody::Snapshot(snapshot.clone()))
+            .await
+            .unwrap();
+        // Snapshot as it was written with body version 1
+        sqlx::query!("update updates set version = 1, body = body - 'config'")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        assert_eq!(migrate_events(&pool).await.unwrap(), 1);
+        assert_eq!(migrate_events(&pool).await.unwrap(), 0);
+        let dump = query_dump(&pool, None).await.unwrap();
+        assert_eq!(dump[0].version, CURRENT_BODY_VERSION);
+        assert_eq!(dump[0].body["config"], serde_json::json!({}));
+        assert_eq!(verify_chain(&dump).unwrap().records, 1);
+        assert_eq!(
+            query_updates(&pool).await.unwrap()[0].body,
+            UpdateBody::Snapshot(snapshot)
+        );
+    }
 }
@@ -0,0 +1,228 @@
+use super::consts::Pool;
+use super::queries::{insert_updates, Error, Result};
+use futures::Future;
+use kollider_hedge_domain::update::*;
+use log::*;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, oneshot};
+
+/// Updates that wait for commit of their batch
+struct PendingUpdate {
+    bodies: Vec<UpdateBody>,
+    ack: oneshot::Sender<Result<()>>,
+}
+
+/// Queue of updates to write. Updates that arrive while the previous batch is committed are
+/// written in the next single transaction, so bursts of updates don't wait for a commit each.
+#[derive(Clone)]
+pub struct UpdateWriter {
+    queue: mpsc::UnboundedSender<PendingUpdate>,
+    /// Released when the turn of the last enqueued update is dropped
+    last_turn: Arc<Mutex<oneshot::Receiver<()>>>,
+}
+
+/// Resolves when the update is committed to the database
+pub struct UpdateAck {
+    ack: oneshot::Receiver<Result<()>>,
+    prev_turn: oneshot::Receiver<()>,
+    turn: oneshot::Sender<()>,
+}
+
+/// Held by the owner of a committed update until it is applied to the state. The next update
+/// is reported as committed only after the turn is dropped, so updates are applied in the
+/// order they are written.
+pub struct CommitTurn {
+    _turn: oneshot::Sender<()>,
+}
+
+impl UpdateAck {
+    /// Wait for the commit and for the turns of all previously enqueued updates
+    pub async fn committed(self) -> Result<CommitTurn> {
+        let res = self.ack.await.unwrap_or(Err(Error::WriterStopped));
+        let _ = self.prev_turn.await;
+        res.map(|()| CommitTurn { _turn: self.turn })
+    }
+}
+
+impl UpdateWriter {
+    /// Create writer and the worker future that has to be spawned
+    pub fn new(pool: Pool, max_batch: usize) -> (Self, impl Future<Output = ()>) {
+        let (queue, queue_rx) = mpsc::unbounded_channel();
+        let worker = update_writer_worker(pool, queue_rx, max_batch);
+        // The first update doesn't wait for anyone
+        let (_, first_turn) = oneshot::channel();
+        let writer = UpdateWriter {
+            queue,
+            last_turn: Arc::new(Mutex::new(first_turn)),
+        };
+        (writer, worker)
+    }
+
+    /// Put the update in the queue. Order of updates in the database is the order of calls.
+    pub fn enqueue(&self, body: UpdateBody) -> UpdateAck {
+        self.enqueue_all(vec![body])
+    }
+
+    /// Put the updates in the queue as a whole, they are committed in the same transaction
+    pub fn enqueue_all(&self, bodies: Vec<UpdateBody>) -> UpdateAck {
+        let (ack, ack_rx) = oneshot::channel();
+        let (turn, next_turn) = oneshot::channel();
+        let pending = PendingUpdate { bodies, ack };
+        // Turns are taken in the order of the queue
+        let mut last_turn = self.last_turn.lock().expect("Writer turn lock is poisoned");
+        let prev_turn = std::mem::replace(&mut *last_turn, next_turn);
+        if let Err(mpsc::error::SendError(pending)) = self.queue.send(pending) {
+            let _ = pending.ack.send(Err(Error::WriterStopped));
+        }
+        UpdateAck {
+            ack: ack_rx,
+            prev_turn,
+            turn,
+        }
+    }
+
+    /// Write the update and wait for the commit
+    pub async fn insert_update(&self, body: UpdateBody) -> Result<()> {
+        self.enqueue(body).committed().await.map(|_| ())
+    }
+}
+
+async fn update_writer_worker(
+    pool: Pool,
+    mut queue: mpsc::UnboundedReceiver<PendingUpdate>,
+    max_batch: usize,
+) {
+    while let Some(first) = queue.recv().await {
+        let mut batch = vec![first];
+        let mut size = batch[0].bodies.len();
+        while size < max_batch {
+            match queue.try_recv() {
+                Ok(pending) => {
+                    size += pending.bodies.len();
+                    batch.push(pending);
+                }
+                Err(_) => break,
+            }
+        }
+        trace!("Writing batch of {} updates", size);
+        let updates: Vec<UpdateBody> = batch
+            .iter()
+            .flat_map(|p| p.bodies.iter().cloned())
+            .collect();
+        match insert_updates(&pool, &updates).await {
+            Ok(_) => {
+                for pending in batch {
+                    let _ = pending.ack.send(Ok(()));
+                }
+            }
+            Err(e) => {
+                error!("Failed to write batch of {} updates: {}", size, e);
+                let msg = e.to_string();
+                for pending in batch {
+                    let _ = pending.ack.send(Err(Error::Batch(msg.clone())));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::queries::{query_dump, query_history};
+    use super::*;
+    use kollider_hedge_domain::chain::verify_chain;
+
+    fn htlc(i: u64) -> UpdateBody {
+        UpdateBody::Htlc(HtlcUpdate {
+            sats: 100 + i as i64,
+            rate: 2500,
+            channel_id: format!("channel{}", i % 10),
+        })
+    }
+
+    #[sqlx_database_tester::test(pool(
+        variable = "pool",
+        migrations = "../kollider-hedge-db/migrations"
+    ))]
+    async fn test_update_writer() {
+        let (writer, worker) = UpdateWriter::new(pool.clone(), 16);
+        tokio::spawn(worker);
+        let acks: Vec<UpdateAck> = (0..40).map(|i| writer.enqueue(htlc(i))).collect();
+        for ack in acks {
+            ack.committed().await.unwrap();
+        }
+        let history = query_history(&pool).await.unwrap();
+        assert_eq!(history.len(), 40);
+        for i in 0..40 {
+            assert!(history.iter().any(|u| u.body == htlc(i)));
+        }
+    }
+
+    #[sqlx_database_tester::test(pool(
+        variable = "pool",
+        migrations = "../kollider-hedge-db/migrations"
+    ))]
+    async fn test_update_writer_batches() {
+        const UPDATES: u64 = 1000;
+
+        // Updates are queued before the worker starts, so they are written in full batches
+        let (writer, worker) = UpdateWriter::new(pool.clone(), 256);
+        let acks: Vec<UpdateAck> = (0..UPDATES).map(|i| writer.enqueue(htlc(i))).collect();
+        tokio::spawn(worker);
+        for ack in acks {
+            ack.committed().await.unwrap();
+        }
+        // Rows inserted by the same transaction share `xmin`
+        let transactions =
+            sqlx::query_scalar!(r#"select count(distinct xmin::text) as "count!" from updates"#)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(transactions, 4);
+        assert_eq!(query_history(&pool).await.unwrap().len(), UPDATES as usize);
+    }
+
+    #[sqlx_database_tester::test(pool(
+        variable = "pool",
+        migrations = "../kollider-hedge-db/migrations"
+    ))]
+    async fn test_update_writer_whole() {
+        // Updates enqueued together are not split between transactions by the batch size
+        let (writer, worker) = UpdateWriter::new(pool.clone(), 2);
+        let whole = writer.enqueue_all((0..3).map(htlc).collect());
+        let next = writer.enqueue(htlc(3));
+        tokio::spawn(worker);
+        whole.committed().await.unwrap();
+        next.committed().await.unwrap();
+        let transactions =
+            sqlx::query_scalar!(r#"select count(distinct xmin::text) as "count!" from updates"#)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(transactions, 2);
+        assert_eq!(query_history(&pool).await.unwrap().len(), 4);
+    }
+
+    #[sqlx_database_tester::test(pool(
+        variable = "pool",
+        migrations = "../kollider-hedge-db/migrations"
+    ))]
+    async fn test_concurrent_writers_chain() {
+        // Writers of different processes interleave their commits
+        let (first, first_worker) = UpdateWriter::new(pool.clone(), 4);
+        let (second, second_worker) = UpdateWriter::new(pool.clone(), 4);
+        tokio::spawn(first_worker);
+        tokio::spawn(second_worker);
+        let acks: Vec<UpdateAck> = (0..100)
+            .map(|i| {
+                let writer = if i % 2 == 0 { &first } else { &second };
+                writer.enqueue(htlc(i))
+            })
+            .collect();
+        for ack in acks {
+            ack.committed().await.unwrap();
+        }
+        let dump = query_dump(&pool, None).await.unwrap();
+        assert_eq!(verify_chain(&dump).unwrap().records, 100);
+    }
+}
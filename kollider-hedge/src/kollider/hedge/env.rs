@@ -0,0 +1,112 @@
+use std::path::{Path, PathBuf};
+
+/// File that settings are loaded from when `--env-file` is not set
+pub const DEFAULT_ENV_FILE: &str = ".env";
+
+/// Variables of earlier versions without `KOLLIDER_HEDGE_` prefix and their current names
+pub const LEGACY_VARS: &[(&str, &str)] = &[
+    ("KOLLIDER_API_KEY", "KOLLIDER_HEDGE_API_KEY"),
+    ("KOLLIDER_API_KEY_FILE", "KOLLIDER_HEDGE_API_KEY_FILE"),
+    ("KOLLIDER_API_SECRET", "KOLLIDER_HEDGE_API_SECRET"),
+    ("KOLLIDER_API_SECRET_FILE", "KOLLIDER_HEDGE_API_SECRET_FILE"),
+    ("KOLLIDER_API_PASSWORD", "KOLLIDER_HEDGE_API_PASSWORD"),
+    (
+        "KOLLIDER_API_PASSWORD_FILE",
+        "KOLLIDER_HEDGE_API_PASSWORD_FILE",
+    ),
+    ("KOLLIDER_API_URL", "KOLLIDER_HEDGE_API_URL"),
+    ("VAULT_ADDR", "KOLLIDER_HEDGE_VAULT_ADDR"),
+    ("VAULT_TOKEN", "KOLLIDER_HEDGE_VAULT_TOKEN"),
+    ("VAULT_TOKEN_FILE", "KOLLIDER_HEDGE_VAULT_TOKEN_FILE"),
+];
+
+/// Path of `--env-file` option or `KOLLIDER_HEDGE_ENV_FILE` variable. It is found before
+/// parsing of options as the file provides defaults of other options.
+pub fn env_file_arg<I: IntoIterator<Item = String>>(
+    args: I,
+    var: Option<String>,
+) -> Option<PathBuf> {
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "--env-file" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.strip_prefix("--env-file=") {
+            return Some(PathBuf::from(path));
+        }
+    }
+    var.map(PathBuf::from)
+}
+
+/// Legacy variables that are set while their prefixed names are not, with their values
+pub fn legacy_renames<F: Fn(&str) -> Option<String>>(
+    lookup: F,
+) -> Vec<(&'static str, &'static str, String)> {
+    LEGACY_VARS
+        .iter()
+        .filter(|(_, new)| lookup(new).is_none())
+        .filter_map(|(old, new)| lookup(old).map(|value| (*old, *new, value)))
+        .collect()
+}
+
+/// Load variables from the env file if it exists and copy legacy variables to prefixed names.
+/// Variables that are already set take precedence over the file. Returns renamed legacy
+/// variables to warn about them when logging is initialized.
+pub fn load_env(file: Option<&Path>) -> Result<Vec<(&'static str, &'static str)>, dotenv::Error> {
+    let file = file.unwrap_or_else(|| Path::new(DEFAULT_ENV_FILE));
+    if file.exists() {
+        dotenv::from_path(file)?;
+    }
+    let renames = legacy_renames(|name| std::env::var(name).ok());
+    Ok(renames
+        .into_iter()
+        .map(|(old, new, value)| {
+            std::env::set_var(new, value);
+            (old, new)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_env_file_arg() {
+        let args = |s: &str| s.split(' ').map(|a| a.to_owned()).collect::<Vec<_>>();
+        assert_eq!(
+            env_file_arg(args("--pair .BTCEUR --env-file eur.env serve"), None),
+            Some(PathBuf::from("eur.env"))
+        );
+        assert_eq!(
+            env_file_arg(args("--env-file=eur.env serve"), Some("usd.env".to_owned())),
+            Some(PathBuf::from("eur.env"))
+        );
+        assert_eq!(
+            env_file_arg(args("serve"), Some("usd.env".to_owned())),
+            Some(PathBuf::from("usd.env"))
+        );
+        assert_eq!(env_file_arg(args("serve"), None), None);
+    }
+
+    #[test]
+    fn test_legacy_renames() {
+        let vars: HashMap<&str, &str> = vec![
+            ("KOLLIDER_API_KEY", "legacy"),
+            ("KOLLIDER_API_SECRET", "legacy"),
+            ("KOLLIDER_HEDGE_API_SECRET", "current"),
+        ]
+        .into_iter()
+        .collect();
+        let renames = legacy_renames(|name| vars.get(name).map(|v| v.to_string()));
+        assert_eq!(
+            renames,
+            vec![(
+                "KOLLIDER_API_KEY",
+                "KOLLIDER_HEDGE_API_KEY",
+                "legacy".to_owned()
+            )]
+        );
+    }
+}
@@ -0,0 +1,420 @@
+use crate::kollider::hedge::proxy::ProxyConfig;
+use crate::kollider::hedge::websocket::KOLLIDER_WS_URL;
+use chrono::prelude::*;
+use hmac::{Hmac, Mac};
+use kollider_api::kollider::api::{MarginType, OrderSide, OrderType, SettlementType};
+use kollider_api::kollider::KolliderMsg;
+use kollider_hedge_domain::product::Products;
+use log::*;
+use reqwest::Method;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::fmt;
+use thiserror::Error;
+
+/// Default URL of Kollider REST API
+pub const KOLLIDER_API_URL: &str = "https://api.kollider.xyz/v1";
+/// URL of Kollider REST API of the test environment
+pub const KOLLIDER_TESTNET_API_URL: &str = "https://testnet.kollider.xyz/v1";
+/// URL of Kollider websocket of the test environment
+pub const KOLLIDER_TESTNET_WS_URL: &str = "wss://testnet.kollider.xyz/v1/ws/";
+
+/// Endpoints of Kollider the service talks to. URLs that are set explicitly take precedence
+/// over the testnet preset.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KolliderEndpoints {
+    pub api_url: String,
+    /// `None` is the production websocket that is built in the client library
+    pub ws_url: Option<String>,
+    /// SOCKS5 proxy for both REST and websocket traffic
+    pub proxy: Option<ProxyConfig>,
+}
+
+impl KolliderEndpoints {
+    pub fn new(api_url: &str, ws_url: Option<String>, testnet: bool) -> Self {
+        if !testnet {
+            return KolliderEndpoints {
+                api_url: api_url.to_owned(),
+                ws_url,
+                proxy: None,
+            };
+        }
+        let api_url = if api_url == KOLLIDER_API_URL {
+            KOLLIDER_TESTNET_API_URL
+        } else {
+            api_url
+        };
+        KolliderEndpoints {
+            api_url: api_url.to_owned(),
+            ws_url: ws_url.or_else(|| Some(KOLLIDER_TESTNET_WS_URL.to_owned())),
+            proxy: None,
+        }
+    }
+
+    pub fn with_proxy(mut self, proxy: Option<ProxyConfig>) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
+    /// URL of the websocket that is connected by the service itself, as the client library
+    /// supports neither other endpoints nor proxies. `None` leaves the connection to the library.
+    pub fn custom_ws_url(&self) -> Option<&str> {
+        match (&self.ws_url, &self.proxy) {
+            (Some(url), _) => Some(url),
+            (None, Some(_)) => Some(KOLLIDER_WS_URL),
+            (None, None) => None,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Request to Kollider failed: {0}")]
+    Reqwest(#[from] reqwest::Error),
+    #[error("API secret is not valid base64: {0}")]
+    Secret(#[from] base64::DecodeError),
+    #[error("Kollider responded with {0}: {1}")]
+    Response(reqwest::StatusCode, String),
+    #[error("Message {0} cannot be sent via REST API")]
+    Unsupported(String),
+    #[error("Ticker of {0} has no price")]
+    NoPrice(String),
+    #[error("Response has no valid Date header: {0:?}")]
+    Date(Option<String>),
+}
+
+impl rweb::reject::Reject for Error {}
+
+/// Client for wallet operations of Kollider REST API that are not available via websocket
+#[derive(Clone)]
+pub struct KolliderRest {
+    client: reqwest::Client,
+    url: String,
+    api_key: String,
+    api_secret: String,
+    password: String,
+}
+
+impl fmt::Debug for KolliderRest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KolliderRest")
+            .field("url", &self.url)
+            .finish()
+    }
+}
+
+#[derive(Serialize)]
+struct OrderBody<'a> {
+    price: u64,
+    quantity: u64,
+    symbol: &'a str,
+    leverage: u64,
+    side: &'a OrderSide,
+    margin_type: &'a MarginType,
+    order_type: &'a OrderType,
+    settlement_type: &'a SettlementType,
+    ext_order_id: &'a str,
+}
+
+#[derive(Serialize)]
+struct CancelBody<'a> {
+    order_id: u64,
+    symbol: &'a str,
+    settlement_type: &'a SettlementType,
+}
+
+#[derive(Serialize)]
+struct DepositBody {
+    #[serde(rename = "type")]
+    network: &'static str,
+    amount: u64,
+}
+
+#[derive(Deserialize)]
+struct DepositResponse {
+    payment_request: String,
+}
+
+#[derive(Serialize)]
+struct WithdrawalBody<'a> {
+    #[serde(rename = "type")]
+    network: &'static str,
+    payment_request: &'a str,
+    amount: u64,
+}
+
+impl KolliderRest {
+    /// Send requests through the SOCKS5 proxy if it is set
+    pub fn with_proxy(mut self, proxy: Option<&ProxyConfig>) -> Result<Self, Error> {
+        if let Some(proxy) = proxy {
+            self.client = reqwest::Client::builder()
+                .proxy(reqwest::Proxy::all(&proxy.url)?)
+                .build()?;
+        }
+        Ok(self)
+    }
+
+    pub fn new(url: &str, api_key: &str, api_secret: &str, password: &str) -> Self {
+        KolliderRest {
+            client: reqwest::Client::new(),
+            url: url.trim_end_matches('/').to_owned(),
+            api_key: api_key.to_owned(),
+            api_secret: api_secret.to_owned(),
+            password: password.to_owned(),
+        }
+    }
+
+    /// Sign `timestamp + method + path + body` with the API secret
+    fn signature(
+        &self,
+        timestamp: &str,
+        method: &str,
+        path: &str,
+        body: &str,
+    ) -> Result<String, Error> {
+        let secret = base64::decode(&self.api_secret)?;
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&secret).expect("HMAC accepts keys of any size");
+        mac.update(format!("{}{}{}{}", timestamp, method, path, body).as_bytes());
+        Ok(base64::encode(mac.finalize().into_bytes()))
+    }
+
+    async fn post<B: Serialize, R: DeserializeOwned>(
+        &self,
+        path: &str,
+        body: &B,
+    ) -> Result<R, Error> {
+        self.request(Method::POST, path, Some(body)).await
+    }
+
+    async fn get<R: DeserializeOwned>(&self, path: &str) -> Result<R, Error> {
+        self.request::<(), R>(Method::GET, path, None).await
+    }
+
+    /// Send signed request, requests without body are signed with empty body
+    async fn request<B: Serialize, R: DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<&B>,
+    ) -> Result<R, Error> {
+        let body = body
+            .map(|b| serde_json::to_string(b).expect("Request body is serializable"))
+            .unwrap_or_default();
+        let timestamp = chrono::Utc::now().timestamp().to_string();
+        let signature = self.signature(&timestamp, method.as_str(), path, &body)?;
+        let response = self
+            .client
+            .request(method, format!("{}{}", self.url, path))
+            .header("k-signature", signature)
+            .header("k-timestamp", timestamp)
+            .header("k-passphrase", &self.password)
+            .header("k-api-key", &self.api_key)
+            .header("Content-Type", "application/json")
+            .body(body)
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            let text = response.text().await.unwrap_or_default();
+            return Err(Error::Response(status, text));
+        }
+        Ok(response.json().await?)
+    }
+
+    /// Fetch contract specs of all products. The endpoint is public and not signed.
+    pub async fn products(&self) -> Result<Products, Error> {
+        let response = self
+            .client
+            .get(format!("{}/market/products", self.url))
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            let text = response.text().await.unwrap_or_default();
+            return Err(Error::Response(status, text));
+        }
+        Ok(response.json().await?)
+    }
+
+    /// Fetch opened orders indexed by symbol
+    pub async fn open_orders(&self) -> Result<serde_json::Value, Error> {
+        self.get("/orders/open").await
+    }
+
+    /// Fetch opened positions indexed by symbol
+    pub async fn positions(&self) -> Result<serde_json::Value, Error> {
+        self.get("/positions").await
+    }
+
+    /// Fetch recent trades of the account, that is fills of its orders
+    pub async fn fills(&self) -> Result<serde_json::Value, Error> {
+        self.get("/user/trades").await
+    }
+
+    /// Fetch the last traded price of the symbol in USD
+    pub async fn last_price(&self, symbol: &str) -> Result<f64, Error> {
+        let response = self
+            .client
+            .get(format!("{}/market/ticker", self.url))
+            .query(&[("symbol", symbol)])
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            let text = response.text().await.unwrap_or_default();
+            return Err(Error::Response(status, text));
+        }
+        let ticker: serde_json::Value = response.json().await?;
+        let price = &ticker["last_price"];
+        price
+            .as_f64()
+            .or_else(|| price.as_str().and_then(|p| p.parse().ok()))
+            .ok_or_else(|| Error::NoPrice(symbol.to_owned()))
+    }
+
+    /// Measure how far the clock of Kollider is ahead of the local one by the `Date` header of
+    /// a public request. The header has seconds precision, so the skew is accurate to about
+    /// half a second.
+    pub async fn clock_skew(&self) -> Result<chrono::Duration, Error> {
+        let sent = Utc::now();
+        let response = self
+            .client
+            .get(format!("{}/market/products", self.url))
+            .send()
+            .await?;
+        let received = Utc::now();
+        let date = response
+            .headers()
+            .get(reqwest::header::DATE)
+            .and_then(|v| v.to_str().ok());
+        skew_from_date(date, sent, received)
+    }
+
+    /// Send order or cancellation that is normally sent via websocket
+    pub async fn send_message(&self, msg: &KolliderMsg) -> Result<(), Error> {
+        match msg {
+            KolliderMsg::Order {
+                price,
+                quantity,
+                symbol,
+                leverage,
+                side,
+                margin_type,
+                order_type,
+                settlement_type,
+                ext_order_id,
+                ..
+            } => {
+                let body = OrderBody {
+                    price: *price,
+                    quantity: *quantity,
+                    symbol,
+                    leverage: *leverage,
+                    side,
+                    margin_type,
+                    order_type,
+                    settlement_type,
+                    ext_order_id,
+                };
+                let _: serde_json::Value = self.post("/orders", &body).await?;
+            }
+            KolliderMsg::CancelOrder {
+                order_id,
+                symbol,
+                settlement_type,
+                ..
+            } => {
+                let body = CancelBody {
+                    order_id: *order_id,
+                    symbol,
+                    settlement_type,
+                };
+                let _: serde_json::Value =
+                    self.request(Method::DELETE, "/orders", Some(&body)).await?;
+            }
+            other => return Err(Error::Unsupported(format!("{:?}", other))),
+        }
+        Ok(())
+    }
+
+    /// Request Lightning invoice that tops up Kollider balance by the amount of sats
+    pub async fn deposit_invoice(&self, sats: u64) -> Result<String, Error> {
+        debug!("Requesting deposit invoice for {} sats", sats);
+        let body = DepositBody {
+            network: "Ln",
+            amount: sats,
+        };
+        let response: DepositResponse = self.post("/wallet/deposit", &body).await?;
+        Ok(response.payment_request)
+    }
+
+    /// Withdraw sats from Kollider balance to the given Lightning invoice
+    pub async fn withdraw(&self, sats: u64, invoice: &str) -> Result<(), Error> {
+        debug!("Requesting withdrawal of {} sats", sats);
+        let body = WithdrawalBody {
+            network: "Ln",
+            payment_request: invoice,
+            amount: sats,
+        };
+        let _: serde_json::Value = self.post("/wallet/withdrawal", &body).await?;
+        Ok(())
+    }
+}
+
+/// Skew of the server clock by its `Date` header. The server is assumed to answer in the
+/// middle of the round trip and the truncated fraction of the second is taken as a half.
+fn skew_from_date(
+    date: Option<&str>,
+    sent: DateTime<Utc>,
+    received: DateTime<Utc>,
+) -> Result<chrono::Duration, Error> {
+    let server = date
+        .and_then(|d| DateTime::parse_from_rfc2822(d).ok())
+        .ok_or_else(|| Error::Date(date.map(str::to_owned)))?;
+    let server = server.with_timezone(&Utc) + chrono::Duration::milliseconds(500);
+    Ok(server - (sent + (received - sent) / 2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kollider_endpoints() {
+        let mainnet = KolliderEndpoints::new(KOLLIDER_API_URL, None, false);
+        assert_eq!(mainnet.api_url, KOLLIDER_API_URL);
+        assert_eq!(mainnet.ws_url, None);
+
+        let testnet = KolliderEndpoints::new(KOLLIDER_API_URL, None, true);
+        assert_eq!(testnet.api_url, KOLLIDER_TESTNET_API_URL);
+        assert_eq!(testnet.ws_url.as_deref(), Some(KOLLIDER_TESTNET_WS_URL));
+
+        let mock = KolliderEndpoints::new(
+            "http://127.0.0.1:9000/v1",
+            Some("ws://127.0.0.1:9000/ws".to_owned()),
+            true,
+        );
+        assert_eq!(mock.api_url, "http://127.0.0.1:9000/v1");
+        assert_eq!(mock.ws_url.as_deref(), Some("ws://127.0.0.1:9000/ws"));
+
+        let proxied = mainnet.with_proxy(Some("socks5h://127.0.0.1:9050".parse().unwrap()));
+        assert_eq!(proxied.custom_ws_url(), Some(KOLLIDER_WS_URL));
+    }
+
+    #[test]
+    fn test_skew_from_date() {
+        let sent = Utc.ymd(2022, 3, 1).and_hms_milli(12, 0, 0, 100);
+        let received = Utc.ymd(2022, 3, 1).and_hms_milli(12, 0, 0, 300);
+        let date = "Tue, 01 Mar 2022 12:00:10 GMT";
+        let skew = skew_from_date(Some(date), sent, received).unwrap();
+        assert_eq!(skew.num_milliseconds(), 10_300);
+
+        let behind = "Tue, 01 Mar 2022 11:59:58 GMT";
+        let skew = skew_from_date(Some(behind), sent, received).unwrap();
+        assert_eq!(skew.num_milliseconds(), -1_700);
+        assert!(skew_from_date(None, sent, received).is_err());
+        assert!(skew_from_date(Some("yesterday"), sent, received).is_err());
+    }
+}
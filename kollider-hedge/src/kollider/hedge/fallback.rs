@@ -0,0 +1,70 @@
+use crate::kollider::hedge::exchange::KolliderRest;
+use crate::kollider::hedge::monitor::WsMonitor;
+use chrono::prelude::*;
+use kollider_hedge_domain::product::Products;
+use kollider_hedge_domain::state::{write_state, State};
+use log::*;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, RwLock};
+use tokio::time::sleep;
+
+/// How often we check the websocket and hedge via REST API during an outage
+const FALLBACK_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Fetch orders, position and price via REST API and place the orders that the hedge requires.
+/// Orders are not tracked, the next cycle sees them in the fetched orders or position.
+async fn rest_hedge_cycle(
+    exchange: &KolliderRest,
+    state_mx: &RwLock<State>,
+    products: &Products,
+) -> Result<(), Box<dyn Error>> {
+    let symbol = state_mx.read().await.config.hedge_sym.clone();
+    let open_orders = exchange.open_orders().await?;
+    let positions = exchange.positions().await?;
+    let price = exchange.last_price(&symbol).await?;
+    let (actions, settlement) = {
+        let mut state = write_state(&state_mx).await;
+        state.apply_rest_snapshot(open_orders, positions)?;
+        state.update_ticker(price, Utc::now().naive_utc());
+        state.calculate_next_actions()?;
+        (
+            std::mem::take(&mut state.scheduled_actions),
+            state.config.settlement,
+        )
+    };
+    for action in actions {
+        warn!("Executing action via REST API: {:?}", action);
+        for msg in action.to_kollider_messages(products, settlement)? {
+            exchange.send_message(&msg).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Hedge via REST API while the websocket is not authenticated for longer than `outage`. The
+/// action executor waits for the websocket, so only one of them places orders at a time.
+pub async fn rest_fallback_worker(
+    exchange: KolliderRest,
+    state_mx: Arc<RwLock<State>>,
+    ws_monitor: Arc<Mutex<WsMonitor>>,
+    products: Products,
+    outage: Duration,
+) {
+    let outage =
+        chrono::Duration::from_std(outage).unwrap_or_else(|_| chrono::Duration::max_value());
+    loop {
+        sleep(FALLBACK_CHECK_INTERVAL).await;
+        let since = ws_monitor.lock().await.status.unauthenticated_since;
+        match since {
+            Some(since) if Utc::now().naive_utc() - since >= outage => {
+                warn!("Websocket is down since {}, hedging via REST API", since);
+                if let Err(e) = rest_hedge_cycle(&exchange, &state_mx, &products).await {
+                    error!("Failed to hedge via REST API: {}", e);
+                }
+            }
+            _ => (),
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use crate::kollider::hedge::db::queries::{self, insert_update};
+use crate::kollider::hedge::db::Pool;
+use chrono::prelude::*;
+use kollider_hedge_domain::state::{write_state, State};
+use kollider_hedge_domain::update::{ChannelsRemoval, StateUpdate, UpdateBody};
+use log::*;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+use tokio::time::sleep;
+
+/// How often we check for channels that can be removed
+const GC_CHECK_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Periodically remove channels which balance is zero for longer than `retention`
+pub async fn channels_gc_worker(
+    pool: Pool,
+    state_mx: Arc<RwLock<State>>,
+    retention: Duration,
+) -> Result<(), queries::Error> {
+    let retention =
+        chrono::Duration::from_std(retention).unwrap_or_else(|_| chrono::Duration::max_value());
+    loop {
+        sleep(GC_CHECK_INTERVAL).await;
+        let mut state = write_state(&state_mx).await;
+        let channel_ids = state.expired_empty_channels(retention);
+        if channel_ids.is_empty() {
+            continue;
+        }
+        info!(
+            "Removing {} empty channels: {:?}",
+            channel_ids.len(),
+            channel_ids
+        );
+        let update = StateUpdate {
+            created: Utc::now().naive_utc(),
+            body: UpdateBody::ChannelsRemoved(ChannelsRemoval { channel_ids }),
+        };
+        insert_update(&pool, update.body.clone()).await?;
+        state.apply_update(update)?;
+    }
+}
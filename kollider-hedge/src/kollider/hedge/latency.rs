@@ -0,0 +1,200 @@
+use kollider_hedge_domain::api::{LatencyBucket, LatencyHistogram};
+use rweb::warp;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use warp::http::Method;
+use warp::log::Info;
+
+/// Upper bounds of histogram buckets in milliseconds
+const BUCKETS_MS: [u64; 12] = [1, 5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+/// Routes of the API that requests are labeled with. Other paths are counted together, so
+/// scanning of the API can't grow the metrics.
+const ROUTES: &[&str] = &[
+    "/hedge/htlc",
+    "/hedge/htlc/preview",
+    "/hedge/eclair",
+    "/hedge/set",
+    "/config",
+    "/admin/deposit-invoice",
+    "/admin/withdraw",
+    "/admin/correction",
+    "/admin/rollback",
+    "/admin/dump",
+    "/admin/restore",
+    "/state",
+    "/state/wait",
+    "/channels",
+    "/stats",
+    "/simulate",
+    "/estimate",
+    "/price",
+    "/ws-status",
+    "/debug/messages",
+    "/requests",
+    "/stats/history",
+    "/reports/daily",
+    "/history",
+    "/version",
+    "/health",
+    "/metrics",
+    "/swagger.json",
+];
+
+/// Latencies of all operations of the process. Database queries are called from every
+/// thread of the service, so the registry is global instead of being passed around.
+pub static LATENCY: Latency = Latency::new();
+
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+    /// Not cumulative, operations above the last bound are in the last element
+    buckets: [u64; BUCKETS_MS.len() + 1],
+    count: u64,
+    sum: Duration,
+}
+
+impl Histogram {
+    fn observe(&mut self, elapsed: Duration) {
+        let ms = elapsed.as_millis();
+        let i = BUCKETS_MS
+            .iter()
+            .position(|bound| ms <= *bound as u128)
+            .unwrap_or(BUCKETS_MS.len());
+        self.buckets[i] += 1;
+        self.count += 1;
+        self.sum += elapsed;
+    }
+
+    fn stats(&self) -> LatencyHistogram {
+        let mut total = 0;
+        let buckets = BUCKETS_MS
+            .iter()
+            .zip(self.buckets.iter())
+            .map(|(bound, count)| {
+                total += count;
+                LatencyBucket {
+                    le_ms: *bound,
+                    count: total,
+                }
+            })
+            .collect();
+        LatencyHistogram {
+            count: self.count,
+            sum_ms: self.sum.as_secs_f64() * 1000.0,
+            buckets,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Latency {
+    histograms: Mutex<BTreeMap<String, Histogram>>,
+}
+
+impl Latency {
+    pub const fn new() -> Self {
+        Latency {
+            histograms: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn observe(&self, name: &str, elapsed: Duration) {
+        let mut histograms = self.histograms.lock().unwrap_or_else(|e| e.into_inner());
+        match histograms.get_mut(name) {
+            Some(histogram) => histogram.observe(elapsed),
+            None => histograms
+                .entry(name.to_owned())
+                .or_default()
+                .observe(elapsed),
+        }
+    }
+
+    /// Start measuring the operation, the duration is recorded when the timer is dropped
+    pub fn timer(&'static self, name: &'static str) -> LatencyTimer {
+        LatencyTimer {
+            latency: self,
+            name,
+            start: Instant::now(),
+        }
+    }
+
+    pub fn stats(&self) -> HashMap<String, LatencyHistogram> {
+        let histograms = self.histograms.lock().unwrap_or_else(|e| e.into_inner());
+        histograms
+            .iter()
+            .map(|(name, histogram)| (name.clone(), histogram.stats()))
+            .collect()
+    }
+}
+
+impl Default for Latency {
+    fn default() -> Self {
+        Latency::new()
+    }
+}
+
+pub struct LatencyTimer {
+    latency: &'static Latency,
+    name: &'static str,
+    start: Instant,
+}
+
+impl Drop for LatencyTimer {
+    fn drop(&mut self) {
+        self.latency.observe(self.name, self.start.elapsed());
+    }
+}
+
+/// Measure the operation with the global registry
+pub fn timer(name: &'static str) -> LatencyTimer {
+    LATENCY.timer(name)
+}
+
+/// Name of the histogram of API requests to the path
+fn route_label(method: &Method, path: &str) -> String {
+    let route = if path.starts_with("/channel/") && path.ends_with("/history") {
+        Some("/channel/{id}/history")
+    } else {
+        ROUTES.iter().find(|route| **route == path).copied()
+    };
+    match route {
+        Some(route) if *method == Method::GET || *method == Method::POST => {
+            format!("api {} {}", method, route)
+        }
+        _ => "api other".to_owned(),
+    }
+}
+
+/// Record duration of the API request, used with `warp::log::custom`
+pub fn observe_request(info: Info) {
+    LATENCY.observe(&route_label(info.method(), info.path()), info.elapsed());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_histogram() {
+        let latency = Latency::new();
+        latency.observe("db", Duration::from_micros(500));
+        latency.observe("db", Duration::from_millis(30));
+        latency.observe("db", Duration::from_secs(20));
+        let stats = latency.stats();
+        let db = &stats["db"];
+        assert_eq!(db.count, 3);
+        assert_eq!(db.buckets[0].count, 1);
+        assert_eq!(db.buckets[3].count, 1);
+        assert_eq!(db.buckets[4].le_ms, 50);
+        assert_eq!(db.buckets[4].count, 2);
+        assert_eq!(db.buckets.last().unwrap().count, 2);
+
+        assert_eq!(route_label(&Method::GET, "/state"), "api GET /state");
+        assert_eq!(
+            route_label(&Method::GET, "/channel/abc/history"),
+            "api GET /channel/{id}/history"
+        );
+        assert_eq!(route_label(&Method::GET, "/wp-admin"), "api other");
+    }
+}
@@ -0,0 +1,102 @@
+use flexi_logger::writers::FileLogWriter;
+use flexi_logger::{
+    detailed_format, Age, Cleanup, Criterion, FileSpec, FlexiLoggerError, Logger, LoggerHandle,
+    Naming,
+};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use thiserror::Error;
+
+/// Set when the audit writer is configured
+static AUDIT_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Write record about order activity to the audit log. Does nothing if the audit log is not
+/// configured.
+pub fn audit(args: std::fmt::Arguments) {
+    if AUDIT_ENABLED.load(Ordering::Relaxed) {
+        log::info!(target: "{audit}", "{}", args);
+    }
+}
+
+/// How often log files are rotated regardless of their size
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RotationAge {
+    Never,
+    Hour,
+    Day,
+}
+
+#[derive(Error, Debug)]
+#[error("Unknown rotation age {0}, valid are: never, hour, day")]
+pub struct UnknownRotationAge(String);
+
+impl FromStr for RotationAge {
+    type Err = UnknownRotationAge;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(RotationAge::Never),
+            "hour" => Ok(RotationAge::Hour),
+            "day" => Ok(RotationAge::Day),
+            _ => Err(UnknownRotationAge(s.to_owned())),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LogConfig {
+    /// Write logs to the file instead of stderr
+    pub file: Option<PathBuf>,
+    /// Separate file for order activity
+    pub audit_file: Option<PathBuf>,
+    /// Rotate files that are larger than the amount of bytes
+    pub max_size: u64,
+    pub max_age: RotationAge,
+    /// Amount of rotated files to keep
+    pub keep: usize,
+}
+
+impl LogConfig {
+    fn criterion(&self) -> Criterion {
+        match self.max_age {
+            RotationAge::Never => Criterion::Size(self.max_size),
+            RotationAge::Hour => Criterion::AgeOrSize(Age::Hour, self.max_size),
+            RotationAge::Day => Criterion::AgeOrSize(Age::Day, self.max_size),
+        }
+    }
+}
+
+/// Initialize logging. Filter is taken from `RUST_LOG` in both modes. Without files `env_logger`
+/// is used as before, otherwise the returned handle has to be kept alive until exit.
+pub fn init_logging(config: &LogConfig) -> Result<Option<LoggerHandle>, FlexiLoggerError> {
+    if config.file.is_none() && config.audit_file.is_none() {
+        env_logger::init();
+        return Ok(None);
+    }
+    let mut logger = Logger::try_with_env_or_str("error")?.format(detailed_format);
+    if let Some(file) = &config.file {
+        logger = logger
+            .log_to_file(FileSpec::try_from(file)?)
+            .rotate(
+                config.criterion(),
+                Naming::Numbers,
+                Cleanup::KeepLogFiles(config.keep),
+            )
+            .append();
+    }
+    if let Some(file) = &config.audit_file {
+        let writer = FileLogWriter::builder(FileSpec::try_from(file)?)
+            .rotate(
+                config.criterion(),
+                Naming::Numbers,
+                Cleanup::KeepLogFiles(config.keep),
+            )
+            .append()
+            .format(detailed_format)
+            .try_build()?;
+        logger = logger.add_writer("audit", Box::new(writer));
+        AUDIT_ENABLED.store(true, Ordering::Relaxed);
+    }
+    Ok(Some(logger.start()?))
+}
@@ -1,2 +1,26 @@
+pub mod alert;
 pub mod api;
+pub mod check;
+pub mod clock;
+pub mod compression;
 pub mod db;
+pub mod env;
+pub mod exchange;
+pub mod fallback;
+pub mod gc;
+pub mod init;
+pub mod latency;
+pub mod logging;
+pub mod monitor;
+pub mod outbox;
+pub mod price_feed;
+pub mod probes;
+pub mod proxy;
+pub mod queue;
+pub mod report;
+pub mod secrets;
+pub mod standby;
+pub mod stats;
+pub mod systemd;
+pub mod vault;
+pub mod websocket;
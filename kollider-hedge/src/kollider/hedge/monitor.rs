@@ -0,0 +1,74 @@
+use crate::kollider::hedge::queue::QueueMetrics;
+use chrono::prelude::*;
+use kollider_api::kollider::{KolliderMsg, KolliderTaggedMsg};
+use kollider_hedge_domain::api::{RecordedMessage, WsStatus};
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+/// Status of the websocket connection and the last received messages. Shared between the
+/// websocket thread and the API.
+#[derive(Debug, Default)]
+pub struct WsMonitor {
+    pub status: WsStatus,
+    messages: VecDeque<RecordedMessage>,
+    /// How many messages to keep, 0 disables recording
+    capacity: usize,
+    /// Queue of messages received from Kollider
+    pub inbound: Arc<QueueMetrics>,
+    /// Queue of messages to send to Kollider
+    pub outbound: Arc<QueueMetrics>,
+}
+
+impl WsMonitor {
+    pub fn new(capacity: usize) -> Self {
+        WsMonitor {
+            status: WsStatus {
+                unauthenticated_since: Some(Utc::now().naive_utc()),
+                ..WsStatus::default()
+            },
+            messages: VecDeque::with_capacity(capacity),
+            capacity,
+            inbound: Arc::default(),
+            outbound: Arc::default(),
+        }
+    }
+
+    pub fn received(&mut self, message: &KolliderMsg) {
+        self.status.received(message_channel(message));
+        if self.capacity == 0 {
+            return;
+        }
+        if self.messages.len() >= self.capacity {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(RecordedMessage {
+            received: Utc::now().naive_utc(),
+            message: format!("{:?}", message),
+        });
+    }
+
+    /// Recorded messages from the oldest to the newest
+    pub fn messages(&self) -> Vec<RecordedMessage> {
+        self.messages.iter().cloned().collect()
+    }
+}
+
+/// Name of the channel of websocket message for the connection status
+pub fn message_channel(message: &KolliderMsg) -> &'static str {
+    match message {
+        KolliderMsg::Tagged(tmsg) => match tmsg {
+            KolliderTaggedMsg::IndexValues(_) => "index_values",
+            KolliderTaggedMsg::Positions { .. } => "positions",
+            KolliderTaggedMsg::OpenOrders { .. } => "open_orders",
+            KolliderTaggedMsg::Balances { .. } => "balances",
+            KolliderTaggedMsg::Authenticate { .. } => "authenticate",
+            KolliderTaggedMsg::Received { .. }
+            | KolliderTaggedMsg::Open { .. }
+            | KolliderTaggedMsg::Fill { .. }
+            | KolliderTaggedMsg::Done { .. }
+            | KolliderTaggedMsg::OrderRejection { .. } => "orders",
+            _ => "other",
+        },
+        _ => "other",
+    }
+}
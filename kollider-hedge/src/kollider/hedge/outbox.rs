@@ -0,0 +1,186 @@
+use crate::kollider::hedge::db::queries::{
+    self, ack_outbox, cancel_key, insert_outbox, mark_outbox_sent, prune_outbox,
+    query_unacked_outbox,
+};
+use crate::kollider::hedge::db::Pool;
+use crate::kollider::hedge::exchange::{self, KolliderRest};
+use crate::kollider::hedge::queue::{self, QueueSender};
+use chrono::prelude::*;
+use kollider_api::kollider::{KolliderMsg, KolliderTaggedMsg};
+use kollider_hedge_domain::state::{write_state, State, StateAction};
+use log::*;
+use serde_json::Value;
+use std::collections::HashSet;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::RwLock;
+
+/// Entries of the outbox are kept for the amount of days to investigate incidents
+const OUTBOX_RETENTION_DAYS: i64 = 7;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Outbox is not written: {0}")]
+    Database(#[from] queries::Error),
+    #[error("Message is not sent: {0}")]
+    Queue(#[from] queue::Error),
+    #[error("Placed orders are not fetched: {0}")]
+    Exchange(#[from] exchange::Error),
+}
+
+/// Persist messages of the action, put them in the websocket queue and mark them as sent. The
+/// messages are written before sending, so a crash in between doesn't lose them.
+pub async fn send_action(
+    pool: &Pool,
+    stdin_tx: &QueueSender<KolliderMsg>,
+    action: &StateAction,
+    messages: Vec<KolliderMsg>,
+) -> Result<(), Error> {
+    let id = insert_outbox(pool, action, &messages).await?;
+    for msg in messages {
+        stdin_tx.send(msg).await?;
+    }
+    mark_outbox_sent(pool, id).await?;
+    Ok(())
+}
+
+/// Outbox key that the message of Kollider confirms. Any response to an order confirms its
+/// delivery, and an order that left the book doesn't need a cancel anymore.
+pub fn confirmed_key(msg: &KolliderMsg) -> Option<String> {
+    match msg {
+        KolliderMsg::Tagged(KolliderTaggedMsg::Received { ext_order_id, .. })
+        | KolliderMsg::Tagged(KolliderTaggedMsg::Open { ext_order_id, .. })
+        | KolliderMsg::Tagged(KolliderTaggedMsg::OrderRejection { ext_order_id, .. }) => {
+            Some(ext_order_id.clone())
+        }
+        KolliderMsg::Tagged(KolliderTaggedMsg::Done { order_id, .. }) => {
+            Some(cancel_key(*order_id))
+        }
+        _ => None,
+    }
+}
+
+/// Collect `ext_order_id` fields at any depth of the responses of Kollider
+pub fn placed_ext_ids<'a, I>(responses: I) -> HashSet<String>
+where
+    I: IntoIterator<Item = &'a Value>,
+{
+    fn collect(value: &Value, ids: &mut HashSet<String>) {
+        match value {
+            Value::Object(fields) => {
+                for (name, field) in fields {
+                    match field {
+                        Value::String(id) if name == "ext_order_id" => {
+                            ids.insert(id.clone());
+                        }
+                        _ => collect(field, ids),
+                    }
+                }
+            }
+            Value::Array(items) => items.iter().for_each(|item| collect(item, ids)),
+            _ => (),
+        }
+    }
+    let mut ids = HashSet::new();
+    responses
+        .into_iter()
+        .for_each(|response| collect(response, &mut ids));
+    ids
+}
+
+/// External ids of orders that Kollider knows about: opened ones and filled ones
+pub async fn placed_orders(exchange: &KolliderRest) -> Result<HashSet<String>, Error> {
+    let open_orders = exchange.open_orders().await?;
+    let fills = exchange.fills().await?;
+    Ok(placed_ext_ids([&open_orders, &fills]))
+}
+
+/// Send again messages of actions that Kollider didn't confirm before restart or reconnect,
+/// oldest first. Actions are tracked in the state as if they were just executed. Entries older
+/// than `max_age` are not replayed, as the opening orders watchdog has already given up on them
+/// and the planner hedges again. Entries created after `connected` went over the current
+/// connection and are left to Kollider.
+///
+/// Orders which `ext_order_id` is among opened or filled orders of Kollider reached the exchange
+/// and only the confirmation was lost, so they are marked as confirmed instead of placed twice.
+/// Nothing is replayed if the orders can't be fetched. Returns amount of replayed actions.
+pub async fn replay_outbox(
+    pool: &Pool,
+    exchange: &KolliderRest,
+    state_mx: &RwLock<State>,
+    stdin_tx: &QueueSender<KolliderMsg>,
+    max_age: Option<Duration>,
+    connected: NaiveDateTime,
+) -> Result<usize, Error> {
+    let now = Utc::now().naive_utc();
+    let retention = chrono::Duration::days(OUTBOX_RETENTION_DAYS);
+    prune_outbox(pool, now - retention).await?;
+    let max_age = max_age
+        .and_then(|age| chrono::Duration::from_std(age).ok())
+        .map_or(retention, |age| age.min(retention));
+    let mut entries = query_unacked_outbox(pool, now - max_age).await?;
+    entries.retain(|entry| entry.created < connected);
+    if entries.is_empty() {
+        return Ok(0);
+    }
+    let placed = match placed_orders(exchange).await {
+        Ok(placed) => placed,
+        Err(e) => {
+            error!(
+                "{} actions are not confirmed by Kollider, not sending them again: {}",
+                entries.len(),
+                e
+            );
+            return Ok(0);
+        }
+    };
+    let mut replayed = 0;
+    for entry in entries.iter() {
+        let ext_id = match &entry.action {
+            StateAction::OpenOrder(order) => Some(&order.ext_id),
+            StateAction::ClosePosition { ext_id, .. } => Some(ext_id),
+            StateAction::CloseOrder { .. } => None,
+        };
+        if let Some(ext_id) = ext_id.filter(|id| placed.contains(*id)) {
+            info!(
+                "Order {} from {} is already placed on Kollider, discarding duplicate",
+                ext_id, entry.created
+            );
+            ack_outbox(pool, ext_id).await?;
+            continue;
+        }
+        warn!(
+            "Action from {} is not confirmed by Kollider, sending again: {:?}",
+            entry.created, entry.action
+        );
+        write_state(state_mx).await.finalize_action(&entry.action);
+        for msg in entry.messages.iter().cloned() {
+            stdin_tx.send(msg).await?;
+        }
+        mark_outbox_sent(pool, entry.id).await?;
+        replayed += 1;
+    }
+    Ok(replayed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_placed_ext_ids() {
+        let open_orders = json!({
+            "BTCUSD.PERP": [
+                {"order_id": 1, "ext_order_id": "a", "quantity": 10},
+                {"order_id": 2, "ext_order_id": "b", "quantity": 5}
+            ]
+        });
+        let fills = json!({
+            "data": [{"order_id": 3, "ext_order_id": "c", "symbol": "BTCUSD.PERP"}]
+        });
+        let expected: HashSet<String> = ["a", "b", "c"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(placed_ext_ids([&open_orders, &fills]), expected);
+        assert!(placed_ext_ids([&json!([])]).is_empty());
+    }
+}
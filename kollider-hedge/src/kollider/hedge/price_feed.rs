@@ -0,0 +1,73 @@
+use chrono::prelude::*;
+use kollider_hedge_domain::state::{write_state, ExternalTicker, State};
+use log::*;
+use serde_json::Value;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::{Notify, RwLock};
+use tokio::time::sleep;
+
+/// Secondary source of BTC/USD price that is used to cross-check Kollider index
+#[derive(Debug, Clone)]
+pub struct PriceFeedConfig {
+    /// URL that returns JSON with the price
+    pub url: String,
+    /// JSON pointer to the price field, the value can be a number or a string
+    pub pointer: String,
+    pub interval: Duration,
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to request price: {0}")]
+    Request(#[from] reqwest::Error),
+    #[error("Response has no field {0}")]
+    MissingField(String),
+    #[error("Field {0} is not a number: {1}")]
+    NotNumber(String, Value),
+}
+
+async fn fetch_price(client: &reqwest::Client, config: &PriceFeedConfig) -> Result<f64, Error> {
+    let body: Value = client
+        .get(&config.url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    let field = body
+        .pointer(&config.pointer)
+        .ok_or_else(|| Error::MissingField(config.pointer.clone()))?;
+    match field {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+    .ok_or_else(|| Error::NotNumber(config.pointer.clone(), field.clone()))
+}
+
+/// Periodically poll the external price source and store the price in the state. Failures
+/// are logged and retried, stale price eventually blocks placing orders.
+pub async fn price_feed_worker(
+    config: PriceFeedConfig,
+    state_mx: Arc<RwLock<State>>,
+    state_notify: Arc<Notify>,
+) {
+    let client = reqwest::Client::new();
+    loop {
+        match fetch_price(&client, &config).await {
+            Ok(price) => {
+                trace!("External price: {}", price);
+                let mut state = write_state(&state_mx).await;
+                state.external_ticker = Some(ExternalTicker {
+                    price,
+                    updated: Utc::now().naive_utc(),
+                });
+                state_notify.notify_waiters();
+            }
+            Err(e) => warn!("Failed to get external price from {}: {}", config.url, e),
+        }
+        sleep(config.interval).await;
+    }
+}
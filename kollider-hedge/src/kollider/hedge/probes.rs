@@ -0,0 +1,124 @@
+use crate::kollider::hedge::db::queries;
+use crate::kollider::hedge::db::Pool;
+use crate::kollider::hedge::monitor::WsMonitor;
+use kollider_hedge_domain::api::Readiness;
+use log::*;
+use rweb::warp;
+use std::convert::Infallible;
+use std::error::Error;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use warp::http::StatusCode;
+use warp::{Filter, Reply};
+
+/// Progress of startup that survives restarts of the service logic
+#[derive(Default)]
+pub struct Startup {
+    /// Database pool of the current run, `None` until connected
+    pool: Mutex<Option<Pool>>,
+    /// State is reconstructed from the chain of updates
+    state_loaded: AtomicBool,
+}
+
+impl Startup {
+    pub async fn connected(&self, pool: Pool) {
+        *self.pool.lock().await = Some(pool);
+    }
+
+    pub fn set_loaded(&self, loaded: bool) {
+        self.state_loaded.store(loaded, Ordering::Relaxed);
+    }
+
+    async fn readiness(&self, ws_monitor: &Mutex<WsMonitor>) -> Readiness {
+        let pool = self.pool.lock().await.clone();
+        let database = match pool {
+            Some(pool) => match queries::ping(&pool).await {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!("Readiness check of database failed: {}", e);
+                    false
+                }
+            },
+            None => false,
+        };
+        let authenticated = ws_monitor.lock().await.status.authenticated;
+        Readiness::new(
+            database,
+            authenticated,
+            self.state_loaded.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// `/live` responds while the process is responsive, it doesn't depend on the database or
+/// replay of updates. `/ready` responds with 503 until the service can receive traffic.
+fn probe_routes(
+    startup: Arc<Startup>,
+    ws_monitor: Arc<Mutex<WsMonitor>>,
+) -> impl Filter<Extract = (impl Reply,), Error = warp::Rejection> + Clone {
+    let live = warp::path("live")
+        .and(warp::path::end())
+        .and(warp::get())
+        .map(|| "OK");
+    let ready = warp::path("ready")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and_then(move || {
+            let startup = startup.clone();
+            let ws_monitor = ws_monitor.clone();
+            async move {
+                let readiness = startup.readiness(&ws_monitor).await;
+                let code = if readiness.ready {
+                    StatusCode::OK
+                } else {
+                    StatusCode::SERVICE_UNAVAILABLE
+                };
+                Ok::<_, Infallible>(warp::reply::with_status(
+                    warp::reply::json(&readiness),
+                    code,
+                ))
+            }
+        });
+    live.map(Reply::into_response)
+        .or(ready.map(Reply::into_response))
+        .unify()
+}
+
+/// Serve probes on a separate port that is available from the start of the process
+pub async fn serve_probes(
+    host: &str,
+    port: u16,
+    startup: Arc<Startup>,
+    ws_monitor: Arc<Mutex<WsMonitor>>,
+) -> Result<(), Box<dyn Error>> {
+    let addr = (IpAddr::from_str(host)?, port);
+    info!("Serving probes on port {}", port);
+    warp::serve(probe_routes(startup, ws_monitor))
+        .run(addr)
+        .await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_probes() {
+        let startup = Arc::new(Startup::default());
+        let ws_monitor = Arc::new(Mutex::new(WsMonitor::default()));
+        let routes = probe_routes(startup.clone(), ws_monitor);
+
+        let res = warp::test::request().path("/live").reply(&routes).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        startup.set_loaded(true);
+        let res = warp::test::request().path("/ready").reply(&routes).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let readiness: Readiness = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(readiness, Readiness::new(false, false, true));
+    }
+}
@@ -0,0 +1,191 @@
+use futures::Stream;
+use kollider_hedge_domain::api::QueueStats;
+use log::*;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use thiserror::Error;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+
+/// What to do with a message when the queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Wait until the consumer frees space
+    Block,
+    /// Drop the message
+    Drop,
+    /// Fail the sender. For websocket that means reconnection and resync of the state.
+    Disconnect,
+}
+
+#[derive(Error, Debug)]
+#[error("Unknown overflow policy {0}, valid are: block, drop, disconnect")]
+pub struct UnknownOverflowPolicy(String);
+
+impl FromStr for OverflowPolicy {
+    type Err = UnknownOverflowPolicy;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(OverflowPolicy::Block),
+            "drop" => Ok(OverflowPolicy::Drop),
+            "disconnect" => Ok(OverflowPolicy::Disconnect),
+            _ => Err(UnknownOverflowPolicy(s.to_owned())),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Queue {0} is full")]
+    Overflow(&'static str),
+    #[error("Queue {0} is closed")]
+    Closed(&'static str),
+}
+
+/// Counters of the queue. Outlive the queue itself to accumulate over reconnections.
+#[derive(Debug, Default)]
+pub struct QueueMetrics {
+    capacity: AtomicUsize,
+    len: AtomicUsize,
+    max_len: AtomicUsize,
+    dropped: AtomicU64,
+}
+
+impl QueueMetrics {
+    pub fn stats(&self) -> QueueStats {
+        QueueStats {
+            capacity: self.capacity.load(Ordering::Relaxed),
+            len: self.len.load(Ordering::Relaxed),
+            max_len: self.max_len.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+
+    fn pushed(&self) {
+        let len = self.len.fetch_add(1, Ordering::Relaxed) + 1;
+        self.max_len.fetch_max(len, Ordering::Relaxed);
+    }
+
+    fn popped(&self) {
+        self.len.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Sending half of bounded queue that applies the overflow policy
+pub struct QueueSender<T> {
+    name: &'static str,
+    policy: OverflowPolicy,
+    tx: mpsc::Sender<T>,
+    metrics: Arc<QueueMetrics>,
+}
+
+impl<T> Clone for QueueSender<T> {
+    fn clone(&self) -> Self {
+        QueueSender {
+            name: self.name,
+            policy: self.policy,
+            tx: self.tx.clone(),
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+pub struct QueueReceiver<T> {
+    rx: mpsc::Receiver<T>,
+    metrics: Arc<QueueMetrics>,
+}
+
+/// Create bounded queue that reports to the given metrics
+pub fn bounded<T>(
+    name: &'static str,
+    capacity: usize,
+    policy: OverflowPolicy,
+    metrics: Arc<QueueMetrics>,
+) -> (QueueSender<T>, QueueReceiver<T>) {
+    let (tx, rx) = mpsc::channel(capacity);
+    metrics.capacity.store(capacity, Ordering::Relaxed);
+    metrics.len.store(0, Ordering::Relaxed);
+    let sender = QueueSender {
+        name,
+        policy,
+        tx,
+        metrics: metrics.clone(),
+    };
+    (sender, QueueReceiver { rx, metrics })
+}
+
+impl<T> QueueSender<T> {
+    pub async fn send(&self, msg: T) -> Result<(), Error> {
+        match self.policy {
+            OverflowPolicy::Block => self
+                .tx
+                .send(msg)
+                .await
+                .map_err(|_| Error::Closed(self.name))?,
+            OverflowPolicy::Drop | OverflowPolicy::Disconnect => match self.tx.try_send(msg) {
+                Ok(()) => (),
+                Err(TrySendError::Closed(_)) => return Err(Error::Closed(self.name)),
+                Err(TrySendError::Full(_)) => {
+                    self.metrics.dropped.fetch_add(1, Ordering::Relaxed);
+                    if self.policy == OverflowPolicy::Disconnect {
+                        return Err(Error::Overflow(self.name));
+                    }
+                    warn!("Queue {} is full, dropping message", self.name);
+                    return Ok(());
+                }
+            },
+        }
+        self.metrics.pushed();
+        Ok(())
+    }
+}
+
+impl<T> QueueReceiver<T> {
+    pub async fn recv(&mut self) -> Option<T> {
+        let msg = self.rx.recv().await;
+        if msg.is_some() {
+            self.metrics.popped();
+        }
+        msg
+    }
+}
+
+impl<T> Stream for QueueReceiver<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let poll = self.rx.poll_recv(cx);
+        if let Poll::Ready(Some(_)) = &poll {
+            self.metrics.popped();
+        }
+        poll
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_overflow_policy() {
+        let metrics = Arc::new(QueueMetrics::default());
+        let (tx, mut rx) = bounded("test", 2, OverflowPolicy::Drop, metrics.clone());
+        for i in 0..3 {
+            tx.send(i).await.unwrap();
+        }
+        assert_eq!(metrics.stats().dropped, 1);
+        assert_eq!(metrics.stats().len, 2);
+        assert_eq!(rx.recv().await, Some(0));
+        assert_eq!(metrics.stats().len, 1);
+        assert_eq!(metrics.stats().max_len, 2);
+
+        let (tx, _rx) = bounded("test", 1, OverflowPolicy::Disconnect, metrics.clone());
+        tx.send(0).await.unwrap();
+        assert!(matches!(tx.send(1).await, Err(Error::Overflow("test"))));
+        assert_eq!(metrics.stats().dropped, 2);
+    }
+}
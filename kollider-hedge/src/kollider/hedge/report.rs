@@ -0,0 +1,45 @@
+use crate::kollider::hedge::db::queries::{self, insert_daily_report};
+use crate::kollider::hedge::db::Pool;
+use chrono::prelude::*;
+use kollider_hedge_domain::api::{DailyReport, ReportSnapshot};
+use kollider_hedge_domain::state::{write_state, State};
+use log::*;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tokio::time::sleep;
+
+/// Write accounting report at each UTC midnight. The first report after start covers only the
+/// part of the day since the start.
+pub async fn daily_report_worker(
+    pool: Pool,
+    state_mx: Arc<RwLock<State>>,
+) -> Result<(), queries::Error> {
+    let mut opening = {
+        let state = state_mx.read().await;
+        ReportSnapshot::from_state(&state)
+    };
+    loop {
+        let now = Utc::now();
+        let midnight = (now.date() + chrono::Duration::days(1)).and_hms(0, 0, 0);
+        sleep((midnight - now).to_std().unwrap_or_default()).await;
+        let report = {
+            let mut state = write_state(&state_mx).await;
+            let counters = state.take_daily_counters();
+            let closing = ReportSnapshot::from_state(&state);
+            let unrealized = state.unrealized_pnl().unwrap_or(0.);
+            DailyReport::new(
+                opening,
+                closing,
+                counters,
+                unrealized,
+                Utc::now().naive_utc(),
+            )
+        };
+        info!(
+            "Writing daily report: {} orders, {} fills, realized PnL {} sats",
+            report.orders_placed, report.fills, report.realized_pnl
+        );
+        insert_daily_report(&pool, &report).await?;
+        opening = report.closing;
+    }
+}
@@ -0,0 +1,89 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Secret {0} is not set, pass --{0} or --{0}-file")]
+    Missing(&'static str),
+    #[error("Secret {0} is set both directly and by file")]
+    Ambiguous(&'static str),
+    #[error("Failed to read secret {0} from {1:?}: {2}")]
+    Read(&'static str, PathBuf, std::io::Error),
+}
+
+/// Take the secret from the value or read it from the file, e.x. mounted Docker secret.
+/// Trailing newline in the file is ignored.
+pub fn load_secret(
+    name: &'static str,
+    value: Option<&str>,
+    file: Option<&Path>,
+) -> Result<String, Error> {
+    match (value, file) {
+        (Some(_), Some(_)) => Err(Error::Ambiguous(name)),
+        (Some(value), None) => Ok(value.to_owned()),
+        (None, Some(file)) => {
+            let content =
+                fs::read_to_string(file).map_err(|e| Error::Read(name, file.to_owned(), e))?;
+            Ok(content.trim_end_matches(&['\r', '\n'][..]).to_owned())
+        }
+        (None, None) => Err(Error::Missing(name)),
+    }
+}
+
+/// Credentials of Kollider API
+#[derive(Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub api_key: String,
+    pub api_secret: String,
+    pub password: String,
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials").finish_non_exhaustive()
+    }
+}
+
+/// Secrets that can be rotated while the service is running
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secrets {
+    pub credentials: Credentials,
+    /// Overrides token for `/admin` endpoints from the options
+    pub admin_token: Option<String>,
+}
+
+impl fmt::Debug for Secrets {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Secrets").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_secret() {
+        let path = std::env::temp_dir().join("kollider-hedge-test-secret");
+        fs::write(&path, "aboba\n").unwrap();
+        assert_eq!(
+            load_secret("api-key", None, Some(&path)).unwrap(),
+            "aboba".to_owned()
+        );
+        assert_eq!(
+            load_secret("api-key", Some("value"), None).unwrap(),
+            "value".to_owned()
+        );
+        assert!(matches!(
+            load_secret("api-key", Some("value"), Some(&path)),
+            Err(Error::Ambiguous(_))
+        ));
+        assert!(matches!(
+            load_secret("api-key", None, None),
+            Err(Error::Missing(_))
+        ));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,50 @@
+use crate::kollider::hedge::db::lock::{LockErr, WriterLock};
+use crate::kollider::hedge::db::queries::{self, catch_up_state, query_state_cursor};
+use crate::kollider::hedge::db::Pool;
+use kollider_hedge_domain::state::{HedgeConfig, State};
+use log::*;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::time::sleep;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to replay updates of the leader: {0}")]
+    Replay(#[from] queries::Error),
+    #[error("{0}")]
+    Lock(#[from] LockErr),
+}
+
+/// Replay updates written by the leader each `interval` to keep the state warm until the writer
+/// lock of the database is released, i.e. the leader died. Returns the taken lock and the state
+/// that is caught up with the chain, so trading resumes without a full replay.
+pub async fn standby(
+    pool: &Pool,
+    dbconnect: &str,
+    config: HedgeConfig,
+    interval: Duration,
+) -> Result<(WriterLock, State), Error> {
+    let symbol = config.hedge_sym.clone();
+    let (mut state, mut cursor) = query_state_cursor(pool, config).await?;
+    info!("Standby for {}, following updates of the leader", symbol);
+    loop {
+        sleep(interval).await;
+        match catch_up_state(pool, &mut state, &mut cursor).await {
+            Ok(0) => (),
+            Ok(applied) => debug!("Standby applied {} updates of the leader", applied),
+            // Database is unavailable, the state is caught up on the next tick
+            Err(queries::Error::Database(e)) => warn!("Standby failed to read updates: {}", e),
+            Err(e) => return Err(e.into()),
+        }
+        match WriterLock::try_acquire(dbconnect).await {
+            Ok(Some(lock)) => {
+                // Updates written by the leader just before it died
+                catch_up_state(pool, &mut state, &mut cursor).await?;
+                warn!("Leader of {} is gone, taking over trading", symbol);
+                return Ok((lock, state));
+            }
+            Ok(None) => (),
+            Err(e) => warn!("Standby failed to check writer lock: {}", e),
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use crate::kollider::hedge::db::queries::insert_stats;
+use crate::kollider::hedge::db::Pool;
+use arc_swap::ArcSwap;
+use kollider_hedge_domain::api::Stats;
+use kollider_hedge_domain::state::State;
+use log::*;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Notify, RwLock};
+use tokio::time::{sleep, timeout};
+
+/// Max delay of the snapshot for changes of the state that don't trigger notification
+const SNAPSHOT_REFRESH: Duration = Duration::from_secs(1);
+
+/// Latest statistics of the state that are read without taking the state lock
+pub struct StatsSnapshot(ArcSwap<Stats>);
+
+impl StatsSnapshot {
+    pub fn new() -> Self {
+        StatsSnapshot(ArcSwap::from_pointee(Stats::new()))
+    }
+
+    pub fn load(&self) -> Arc<Stats> {
+        self.0.load_full()
+    }
+
+    pub fn update(&self, state: &State) {
+        self.0.store(Arc::new(Stats::from_state(state)));
+    }
+}
+
+impl Default for StatsSnapshot {
+    fn default() -> Self {
+        StatsSnapshot::new()
+    }
+}
+
+/// Recalculate the snapshot of statistics on every state change
+pub async fn stats_snapshot_worker(
+    state_mx: Arc<RwLock<State>>,
+    state_notify: Arc<Notify>,
+    snapshot: Arc<StatsSnapshot>,
+) {
+    loop {
+        let notified = state_notify.notified();
+        snapshot.update(&*state_mx.read().await);
+        let _ = timeout(SNAPSHOT_REFRESH, notified).await;
+    }
+}
+
+/// Periodically record current statistics to the database to build time series. Failed
+/// records leave a gap in the series, the recorder keeps going.
+pub async fn stats_recorder(pool: Pool, state_mx: Arc<RwLock<State>>, interval: Duration) {
+    loop {
+        sleep(interval).await;
+        let stats = {
+            let state = state_mx.read().await;
+            Stats::from_state(&state)
+        };
+        trace!("Recording stats snapshot");
+        if let Err(e) = insert_stats(&pool, &stats).await {
+            error!("Failed to record stats: {}", e);
+        }
+    }
+}
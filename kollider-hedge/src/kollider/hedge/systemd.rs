@@ -0,0 +1,29 @@
+use log::*;
+use sd_notify::NotifyState;
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Tell systemd that the service is started. Does nothing if the service is not run by systemd.
+pub fn notify_ready() {
+    match sd_notify::notify(false, &[NotifyState::Ready]) {
+        Ok(()) => debug!("Notified systemd that the service is ready"),
+        Err(e) => warn!("Failed to notify systemd: {}", e),
+    }
+}
+
+/// Ping systemd watchdog at half of the configured interval. Returns immediately if the watchdog
+/// is not enabled for the unit.
+pub async fn watchdog_worker() {
+    let mut usec = 0;
+    if !sd_notify::watchdog_enabled(false, &mut usec) {
+        return;
+    }
+    let interval = Duration::from_micros(usec / 2);
+    info!("Pinging systemd watchdog each {:?}", interval);
+    loop {
+        if let Err(e) = sd_notify::notify(false, &[NotifyState::Watchdog]) {
+            warn!("Failed to ping systemd watchdog: {}", e);
+        }
+        sleep(interval).await;
+    }
+}
@@ -0,0 +1,160 @@
+use super::secrets::{Credentials, Secrets};
+use log::*;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::watch;
+use tokio::time::sleep;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Request to Vault failed: {0}")]
+    Reqwest(#[from] reqwest::Error),
+    #[error("Vault secret {0} doesn't have field {1}")]
+    MissingField(String, &'static str),
+}
+
+/// Location of the secrets in Vault
+#[derive(Clone)]
+pub struct VaultConfig {
+    /// Address of Vault server, e.x. `https://vault.example.com:8200`
+    pub addr: String,
+    pub token: String,
+    /// Path of the secret, e.x. `secret/data/kollider-hedge` for KV v2 engine
+    pub path: String,
+    /// Interval between refetching the secrets
+    pub refresh: Duration,
+}
+
+#[derive(Deserialize)]
+struct SecretResponse {
+    data: Map<String, Value>,
+}
+
+/// Client that reads Kollider credentials and API tokens from Vault
+#[derive(Clone)]
+pub struct VaultClient {
+    client: reqwest::Client,
+    config: VaultConfig,
+}
+
+impl VaultClient {
+    pub fn new(config: VaultConfig) -> Self {
+        VaultClient {
+            client: reqwest::Client::new(),
+            config,
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!(
+            "{}/v1/{}",
+            self.config.addr.trim_end_matches('/'),
+            path.trim_start_matches('/')
+        )
+    }
+
+    /// Read the secret at the configured path
+    pub async fn fetch(&self) -> Result<Secrets, Error> {
+        debug!("Fetching secrets from Vault at {}", self.config.path);
+        let response: SecretResponse = self
+            .client
+            .get(self.url(&self.config.path))
+            .header("X-Vault-Token", &self.config.token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        parse_secrets(&self.config.path, response.data)
+    }
+
+    /// Extend TTL of the Vault token
+    pub async fn renew_token(&self) -> Result<(), Error> {
+        self.client
+            .post(self.url("auth/token/renew-self"))
+            .header("X-Vault-Token", &self.config.token)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Take fields `api_key`, `api_secret`, `password` and optional `admin_token` from the secret.
+/// Supports both KV v1 and KV v2 layout of the response.
+fn parse_secrets(path: &str, data: Map<String, Value>) -> Result<Secrets, Error> {
+    let data = match data.get("data") {
+        Some(Value::Object(inner)) => inner.clone(),
+        _ => data,
+    };
+    let field = |name: &str| data.get(name).and_then(Value::as_str).map(str::to_owned);
+    let required =
+        |name: &'static str| field(name).ok_or_else(|| Error::MissingField(path.to_owned(), name));
+    Ok(Secrets {
+        credentials: Credentials {
+            api_key: required("api_key")?,
+            api_secret: required("api_secret")?,
+            password: required("password")?,
+        },
+        admin_token: field("admin_token"),
+    })
+}
+
+/// Periodically renew the token and refetch the secrets. Changed secrets are published to the
+/// channel, failed requests keep the last known secrets.
+pub async fn vault_worker(client: VaultClient, secrets_tx: watch::Sender<Secrets>) {
+    loop {
+        sleep(client.config.refresh).await;
+        if let Err(e) = client.renew_token().await {
+            debug!("Vault token is not renewed: {}", e);
+        }
+        match client.fetch().await {
+            Ok(secrets) => {
+                if *secrets_tx.borrow() != secrets {
+                    info!("Secrets in Vault are rotated");
+                    if secrets_tx.send(secrets).is_err() {
+                        return;
+                    }
+                }
+            }
+            Err(e) => warn!("Failed to refresh secrets from Vault: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn object(value: Value) -> Map<String, Value> {
+        match value {
+            Value::Object(map) => map,
+            _ => panic!("Not an object"),
+        }
+    }
+
+    #[test]
+    fn test_parse_secrets() {
+        let v1 = object(json!({"api_key": "key", "api_secret": "secret", "password": "pass"}));
+        let secrets = parse_secrets("secret/hedge", v1).unwrap();
+        assert_eq!(secrets.credentials.api_key, "key");
+        assert_eq!(secrets.admin_token, None);
+
+        let v2 = object(json!({
+            "data": {"api_key": "key", "api_secret": "secret", "password": "pass", "admin_token": "admin"},
+            "metadata": {"version": 2}
+        }));
+        let secrets = parse_secrets("secret/data/hedge", v2).unwrap();
+        assert_eq!(secrets.credentials.password, "pass");
+        assert_eq!(secrets.admin_token, Some("admin".to_owned()));
+
+        let missing = object(json!({"api_key": "key", "password": "pass"}));
+        assert!(matches!(
+            parse_secrets("secret/hedge", missing),
+            Err(Error::MissingField(_, "api_secret"))
+        ));
+    }
+}
@@ -0,0 +1,79 @@
+use crate::kollider::hedge::proxy::{self, ProxyConfig};
+use futures::{SinkExt, StreamExt};
+use futures_channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use kollider_api::kollider::KolliderMsg;
+use log::*;
+use thiserror::Error;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::{client_async_tls, connect_async};
+use url::Url;
+
+/// Production websocket of Kollider, the client library connects to it when no other URL or
+/// proxy is configured
+pub const KOLLIDER_WS_URL: &str = "wss://api.kollider.xyz/v1/ws/";
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Websocket error: {0}")]
+    Websocket(#[from] tungstenite::Error),
+    #[error("Failed to encode message: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("{0}")]
+    Proxy(#[from] proxy::Error),
+    #[error("Websocket URL {0} is malformed")]
+    Url(String),
+}
+
+/// Websocket of Kollider at the given URL, e.g. testnet or a local mock, optionally through a
+/// SOCKS5 proxy. The client library connects only to the production endpoint directly.
+/// Messages from `stdin_rx` are sent to Kollider and received messages are forwarded to
+/// `stdout_tx` until either side is closed.
+pub async fn kollider_websocket_url(
+    url: &str,
+    proxy: Option<&ProxyConfig>,
+    mut stdin_rx: UnboundedReceiver<KolliderMsg>,
+    stdout_tx: UnboundedSender<KolliderMsg>,
+) -> Result<(), Error> {
+    info!("Connecting to Kollider websocket at {}", url);
+    let socket = match proxy {
+        Some(proxy) => {
+            let parsed = Url::parse(url).map_err(|_| Error::Url(url.to_owned()))?;
+            let host = parsed
+                .host_str()
+                .ok_or_else(|| Error::Url(url.to_owned()))?;
+            let port = parsed.port_or_known_default().unwrap_or(443);
+            let stream = proxy.connect(host, port).await?;
+            client_async_tls(url, stream).await?.0
+        }
+        None => connect_async(url).await?.0,
+    };
+    let (mut sink, mut stream) = socket.split();
+    let outbound = async {
+        while let Some(msg) = stdin_rx.next().await {
+            sink.send(Message::Text(serde_json::to_string(&msg)?))
+                .await?;
+        }
+        Ok::<(), Error>(())
+    };
+    let inbound = async {
+        while let Some(msg) = stream.next().await {
+            match msg? {
+                Message::Text(text) => match serde_json::from_str::<KolliderMsg>(&text) {
+                    Ok(msg) => {
+                        if stdout_tx.unbounded_send(msg).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => warn!("Skipping unknown message from Kollider: {}: {}", e, text),
+                },
+                Message::Close(_) => break,
+                _ => (),
+            }
+        }
+        Ok::<(), Error>(())
+    };
+    tokio::select! {
+        res = outbound => res,
+        res = inbound => res,
+    }
+}
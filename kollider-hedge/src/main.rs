@@ -4,36 +4,138 @@ mod kollider;
 #[macro_use]
 extern crate maplit;
 
-use crate::kollider::hedge::api::{hedge_api_specs, serve_api};
-use crate::kollider::hedge::db::{create_db_pool, queries::query_state};
+use crate::kollider::hedge::alert::{
+    drift_alarm_worker, position_drift_worker, AlertConfig, Alerter, TelegramConfig,
+};
+use crate::kollider::hedge::api::{hedge_api_specs, serve_api, ApiConfig};
+use crate::kollider::hedge::check::{
+    check_config, check_credentials, check_database, check_products, Check, CheckReport,
+    CheckStatus,
+};
+use crate::kollider::hedge::clock::clock_skew_worker;
+use crate::kollider::hedge::db::lock::{LockConflict, LockErr, WriterLock};
+use crate::kollider::hedge::db::partitions::{drop_updates_partitions, partitions_worker};
+use crate::kollider::hedge::db::projection::rebuild_channel_hedges;
+use crate::kollider::hedge::db::queries::{
+    ack_outbox, insert_update, migrate_events, query_state, seal_chain,
+};
+use crate::kollider::hedge::db::{connect_db_pool, create_db_pool, migrate_db, DbConfig, Pool};
+use crate::kollider::hedge::env::{env_file_arg, load_env, DEFAULT_ENV_FILE};
+use crate::kollider::hedge::exchange::{KolliderEndpoints, KolliderRest, KOLLIDER_API_URL};
+use crate::kollider::hedge::fallback::rest_fallback_worker;
+use crate::kollider::hedge::gc::channels_gc_worker;
+use crate::kollider::hedge::init::{env_file, run_wizard, systemd_unit, write_new_file};
+use crate::kollider::hedge::latency;
+use crate::kollider::hedge::logging::{audit, init_logging, LogConfig, RotationAge};
+use crate::kollider::hedge::monitor::{message_channel, WsMonitor};
+use crate::kollider::hedge::outbox::{self, confirmed_key, replay_outbox, send_action};
+use crate::kollider::hedge::price_feed::{price_feed_worker, PriceFeedConfig};
+use crate::kollider::hedge::probes::{serve_probes, Startup};
+use crate::kollider::hedge::proxy::ProxyConfig;
+use crate::kollider::hedge::queue::{self, OverflowPolicy, QueueReceiver, QueueSender};
+use crate::kollider::hedge::report::daily_report_worker;
+use crate::kollider::hedge::secrets::{self, load_secret, Credentials, Secrets};
+use crate::kollider::hedge::standby::standby;
+use crate::kollider::hedge::stats::{stats_recorder, stats_snapshot_worker, StatsSnapshot};
+use crate::kollider::hedge::systemd::{notify_ready, watchdog_worker};
+use crate::kollider::hedge::vault::{vault_worker, VaultClient, VaultConfig};
+use crate::kollider::hedge::websocket::kollider_websocket_url;
+use chrono::prelude::*;
 use clap::Parser;
-use futures::future::{AbortHandle, Abortable, Aborted};
+use futures::future::{select, AbortHandle, Abortable, Aborted, Either};
 use futures::StreamExt;
-use futures_channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use ipnet::IpNet;
 use kollider_api::kollider::{websocket::*, ChannelName};
 use kollider_api::kollider::{KolliderMsg, KolliderTaggedMsg};
-use kollider_hedge_domain::state::{state_action_worker, HedgeConfig, State};
+use kollider_hedge_domain::api::HtlcLimits;
+use kollider_hedge_domain::eclair::ChannelMapping;
+use kollider_hedge_domain::maintenance::MaintenanceWindow;
+use kollider_hedge_domain::order::Settlement;
+use kollider_hedge_domain::product::Products;
+use kollider_hedge_domain::replay::{parse_prices, replay};
+use kollider_hedge_domain::state::{
+    opening_orders_watchdog, state_action_worker, write_state, HedgeConfig, State,
+};
+use kollider_hedge_domain::strategy::StrategyKind;
+use kollider_hedge_domain::update::{
+    OrderRejection, StateUpdate, UpdateBody, CURRENT_BODY_VERSION,
+};
 use log::*;
+use std::collections::HashMap;
 use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::{Mutex, Notify};
+use tokio::sync::{watch, Mutex, Notify, RwLock};
 use tokio::time::{sleep, timeout};
 
 #[derive(Parser, Debug, Clone)]
 #[clap(about, version, author)]
 struct Args {
-    #[clap(long, env = "KOLLIDER_API_KEY", hide_env_values = true)]
-    api_key: String,
-    #[clap(long, env = "KOLLIDER_API_SECRET", hide_env_values = true)]
-    api_secret: String,
-    #[clap(long, env = "KOLLIDER_API_PASSWORD", hide_env_values = true)]
-    password: String,
-    /// Port to bind the service to
-    #[clap(long, short('c'), default_value = ".BTCUSD")]
+    /// Load settings from the file instead of `.env` in the working directory. Variables that
+    /// are already set take precedence.
+    #[clap(long, env = "KOLLIDER_HEDGE_ENV_FILE")]
+    env_file: Option<PathBuf>,
+    #[clap(long, env = "KOLLIDER_HEDGE_API_KEY", hide_env_values = true)]
+    api_key: Option<String>,
+    /// Read API key from the file instead of `--api-key`
+    #[clap(long, env = "KOLLIDER_HEDGE_API_KEY_FILE")]
+    api_key_file: Option<PathBuf>,
+    #[clap(long, env = "KOLLIDER_HEDGE_API_SECRET", hide_env_values = true)]
+    api_secret: Option<String>,
+    /// Read API secret from the file instead of `--api-secret`
+    #[clap(long, env = "KOLLIDER_HEDGE_API_SECRET_FILE")]
+    api_secret_file: Option<PathBuf>,
+    #[clap(long, env = "KOLLIDER_HEDGE_API_PASSWORD", hide_env_values = true)]
+    password: Option<String>,
+    /// Read API password from the file instead of `--password`
+    #[clap(long, env = "KOLLIDER_HEDGE_API_PASSWORD_FILE")]
+    password_file: Option<PathBuf>,
+    /// Address of Vault server. When set, Kollider credentials and admin token are read from
+    /// Vault and refreshed periodically.
+    #[clap(long, env = "KOLLIDER_HEDGE_VAULT_ADDR")]
+    vault_addr: Option<String>,
+    #[clap(long, env = "KOLLIDER_HEDGE_VAULT_TOKEN", hide_env_values = true)]
+    vault_token: Option<String>,
+    /// Read Vault token from the file instead of `--vault-token`
+    #[clap(long, env = "KOLLIDER_HEDGE_VAULT_TOKEN_FILE")]
+    vault_token_file: Option<PathBuf>,
+    /// Path of the secret in Vault with `api_key`, `api_secret`, `password` and optional
+    /// `admin_token` fields
+    #[clap(
+        long,
+        default_value = "secret/data/kollider-hedge",
+        env = "KOLLIDER_HEDGE_VAULT_PATH"
+    )]
+    vault_path: String,
+    /// Seconds between refetching secrets from Vault
+    #[clap(long, default_value = "300", env = "KOLLIDER_HEDGE_VAULT_REFRESH")]
+    vault_refresh: u64,
+    /// Index of BTC price in the hedge currency
+    #[clap(
+        long,
+        short('c'),
+        default_value = ".BTCUSD",
+        env = "KOLLIDER_HEDGE_PAIR"
+    )]
     pair: String,
-    #[clap(long, short('s'), default_value = "BTCUSD.PERP")]
+    /// Kollider product the position is opened in
+    #[clap(
+        long,
+        short('s'),
+        default_value = "BTCUSD.PERP",
+        env = "KOLLIDER_HEDGE_SYMBOL"
+    )]
     symbol: String,
+    /// Use test environment of Kollider unless `--kollider-api-url` or `--kollider-ws-url`
+    /// are set explicitly
+    #[clap(long, env = "KOLLIDER_HEDGE_TESTNET")]
+    testnet: bool,
+    /// SOCKS5 proxy for REST and websocket traffic to Kollider, e.g. socks5h://127.0.0.1:9050
+    /// of a local Tor daemon
+    #[clap(long, env = "KOLLIDER_HEDGE_PROXY")]
+    proxy: Option<ProxyConfig>,
     /// PostgreSQL connection string
     #[clap(
         long,
@@ -42,10 +144,96 @@ struct Args {
         env = "KOLLIDER_HEDGE_POSTGRES"
     )]
     dbconnect: String,
+    /// Maximum amount of connections in the PostgreSQL pool
+    #[clap(long, default_value = "5", env = "KOLLIDER_HEDGE_DB_MAX_CONNECTIONS")]
+    db_max_connections: u32,
+    /// Seconds to wait for a free or new database connection
+    #[clap(long, default_value = "30", env = "KOLLIDER_HEDGE_DB_CONNECT_TIMEOUT")]
+    db_connect_timeout: u64,
+    /// Seconds after which a single SQL statement is aborted. 0 disables the limit.
+    #[clap(long, default_value = "0", env = "KOLLIDER_HEDGE_DB_STATEMENT_TIMEOUT")]
+    db_statement_timeout: u64,
+    /// Write logs to the file instead of stderr. Filter is still defined by `RUST_LOG`.
+    #[clap(long, env = "KOLLIDER_HEDGE_LOG_FILE")]
+    log_file: Option<PathBuf>,
+    /// Write order activity to the separate audit log file
+    #[clap(long, env = "KOLLIDER_HEDGE_AUDIT_LOG_FILE")]
+    audit_log_file: Option<PathBuf>,
+    /// Rotate log files when they grow larger than the amount of megabytes
+    #[clap(long, default_value = "100", env = "KOLLIDER_HEDGE_LOG_MAX_SIZE")]
+    log_max_size: u64,
+    /// Rotate log files by age: never, hour or day
+    #[clap(long, default_value = "day", env = "KOLLIDER_HEDGE_LOG_ROTATE_AGE")]
+    log_rotate_age: RotationAge,
+    /// Amount of rotated log files to keep
+    #[clap(long, default_value = "7", env = "KOLLIDER_HEDGE_LOG_KEEP")]
+    log_keep: usize,
     #[clap(subcommand)]
     subcmd: SubCommand,
 }
 
+impl Args {
+    fn db_config(&self) -> DbConfig {
+        DbConfig {
+            max_connections: self.db_max_connections,
+            connect_timeout: Duration::from_secs(self.db_connect_timeout),
+            statement_timeout: if self.db_statement_timeout == 0 {
+                None
+            } else {
+                Some(Duration::from_secs(self.db_statement_timeout))
+            },
+        }
+    }
+
+    /// Kollider credentials from options or files
+    fn credentials(&self) -> Result<Credentials, secrets::Error> {
+        Ok(Credentials {
+            api_key: load_secret(
+                "api-key",
+                self.api_key.as_deref(),
+                self.api_key_file.as_deref(),
+            )?,
+            api_secret: load_secret(
+                "api-secret",
+                self.api_secret.as_deref(),
+                self.api_secret_file.as_deref(),
+            )?,
+            password: load_secret(
+                "password",
+                self.password.as_deref(),
+                self.password_file.as_deref(),
+            )?,
+        })
+    }
+
+    /// Vault options if Vault is enabled
+    fn vault_config(&self) -> Result<Option<VaultConfig>, secrets::Error> {
+        match &self.vault_addr {
+            None => Ok(None),
+            Some(addr) => Ok(Some(VaultConfig {
+                addr: addr.clone(),
+                token: load_secret(
+                    "vault-token",
+                    self.vault_token.as_deref(),
+                    self.vault_token_file.as_deref(),
+                )?,
+                path: self.vault_path.clone(),
+                refresh: Duration::from_secs(self.vault_refresh),
+            })),
+        }
+    }
+
+    fn log_config(&self) -> LogConfig {
+        LogConfig {
+            file: self.log_file.clone(),
+            audit_file: self.audit_log_file.clone(),
+            max_size: self.log_max_size * 1024 * 1024,
+            max_age: self.log_rotate_age,
+            keep: self.log_keep,
+        }
+    }
+}
+
 #[derive(Parser, Debug, Clone)]
 enum SubCommand {
     /// Start listening incoming API requests
@@ -61,6 +249,10 @@ enum SubCommand {
         /// Port to bind the service to
         #[clap(long, short, default_value = "8081", env = "KOLLIDER_HEDGE_PORT")]
         port: u16,
+        /// Port for `/live` and `/ready` probes. The probes are served from the start of the
+        /// process, so replay of a long chain of updates doesn't fail liveness checks.
+        #[clap(long, env = "KOLLIDER_HEDGE_PROBE_PORT")]
+        probe_port: Option<u16>,
         /// That percent is added and subtructed from current price to ensure that order is executed
         #[clap(long, default_value = "0.1", env = "KOLLIDER_HEDGE_SPREAD")]
         spread_percent: f64,
@@ -69,160 +261,1187 @@ enum SubCommand {
         /// loose you money at 50% dropdowns.
         #[clap(long, default_value = "100", env = "KOLLIDER_HEDGE_LEVERAGE")]
         leverage: u64,
+        /// Observer mode. The service tracks state and exchange position, but never sends orders,
+        /// doesn't write to the database and rejects all API requests that modify state.
+        #[clap(long, env = "KOLLIDER_HEDGE_READ_ONLY")]
+        read_only: bool,
+        /// What to do when another instance trades the same symbol against the database:
+        /// `fail` refuses to start, `read-only` starts in observer mode, `standby` follows
+        /// updates of the leader and takes over trading when the leader dies
+        #[clap(long, default_value = "fail", env = "KOLLIDER_HEDGE_LOCK_CONFLICT")]
+        lock_conflict: LockConflict,
+        /// Seconds between catching up with updates of the leader and checks of its lock in
+        /// standby mode
+        #[clap(long, default_value = "5", env = "KOLLIDER_HEDGE_STANDBY_INTERVAL")]
+        standby_interval: u64,
+        /// Unhedged amount of sats below the value is accumulated until `htlc-aggregation-secs`
+        /// passes. That allows to avoid order churn from tiny HTLCs. 0 disables aggregation.
+        #[clap(long, default_value = "0", env = "KOLLIDER_HEDGE_MIN_HTLC_AGGREGATE")]
+        min_htlc_aggregate: u64,
+        /// Maximum time in seconds small unhedged amounts are accumulated before placing an order
+        #[clap(
+            long,
+            default_value = "60",
+            env = "KOLLIDER_HEDGE_HTLC_AGGREGATION_SECS"
+        )]
+        htlc_aggregation_secs: u64,
+        /// Maximum absolute amount of sats in a single HTLC update
+        #[clap(
+            long,
+            default_value = "1000000000",
+            env = "KOLLIDER_HEDGE_MAX_HTLC_SATS"
+        )]
+        max_htlc_sats: u64,
+        /// Minimum allowed rate of HTLC in sats/USD
+        #[clap(long, default_value = "10", env = "KOLLIDER_HEDGE_MIN_HTLC_RATE")]
+        min_htlc_rate: u64,
+        /// Maximum allowed rate of HTLC in sats/USD
+        #[clap(long, default_value = "1000000", env = "KOLLIDER_HEDGE_MAX_HTLC_RATE")]
+        max_htlc_rate: u64,
+        /// Interval in seconds between recording of statistics to the database. 0 disables recording.
+        #[clap(long, default_value = "60", env = "KOLLIDER_HEDGE_STATS_INTERVAL")]
+        stats_interval: u64,
+        /// Amount of the last Kollider messages that are kept for `/debug/messages`
+        #[clap(long, default_value = "100", env = "KOLLIDER_HEDGE_DEBUG_MESSAGES")]
+        debug_messages: usize,
+        /// Capacity of queues of messages to and from Kollider websocket
+        #[clap(long, default_value = "1024", env = "KOLLIDER_HEDGE_WS_QUEUE_SIZE")]
+        ws_queue_size: usize,
+        /// What to do with a message from Kollider when the queue is full: `disconnect` reconnects
+        /// and resyncs the state, `drop` loses the message, `block` waits for the handler.
+        #[clap(long, default_value = "disconnect", env = "KOLLIDER_HEDGE_WS_OVERFLOW")]
+        ws_overflow: OverflowPolicy,
+        /// Max amount of HTLC updates that are committed to the database in a single transaction.
+        /// Updates queued while the previous transaction is committed are batched together.
+        #[clap(long, default_value = "256", env = "KOLLIDER_HEDGE_DB_WRITE_BATCH")]
+        db_write_batch: usize,
+        /// Don't write daily accounting reports to the database
+        #[clap(long, env = "KOLLIDER_HEDGE_DISABLE_DAILY_REPORT")]
+        disable_daily_report: bool,
+        /// Channels with zero balance are removed from state after the amount of seconds.
+        /// 0 disables removal.
+        #[clap(long, default_value = "0", env = "KOLLIDER_HEDGE_CHANNEL_GC_RETENTION")]
+        channel_gc_retention: u64,
+        /// URL of secondary BTC/USD price source that returns JSON. Kollider index is
+        /// cross-checked against it when `max-index-deviation` is set.
+        #[clap(long, env = "KOLLIDER_HEDGE_PRICE_FEED_URL")]
+        price_feed_url: Option<String>,
+        /// JSON pointer to the price in the response of the price source
+        #[clap(
+            long,
+            default_value = "/data/amount",
+            env = "KOLLIDER_HEDGE_PRICE_FEED_POINTER"
+        )]
+        price_feed_pointer: String,
+        /// Interval in seconds between requests to the price source
+        #[clap(long, default_value = "10", env = "KOLLIDER_HEDGE_PRICE_FEED_INTERVAL")]
+        price_feed_interval: u64,
+        /// Orders are not placed when Kollider index deviates from the secondary price source
+        /// by more percents than the value. 0 disables the check.
+        #[clap(long, default_value = "0", env = "KOLLIDER_HEDGE_MAX_INDEX_DEVIATION")]
+        max_index_deviation: f64,
+        /// Seconds to wait for Kollider confirmation of a sent order before it is forgotten and
+        /// the position is hedged again. 0 disables eviction.
+        #[clap(
+            long,
+            default_value = "60",
+            env = "KOLLIDER_HEDGE_OPENING_ORDER_TIMEOUT"
+        )]
+        opening_order_timeout: u64,
+        /// Hedge orders larger than the amount of sats are split into chunks. 0 disables splitting.
+        #[clap(long, default_value = "0", env = "KOLLIDER_HEDGE_MAX_ORDER_SATS")]
+        max_order_sats: u64,
+        /// Seconds between placing consecutive chunks of a large order (TWAP). 0 places all
+        /// chunks at once.
+        #[clap(long, default_value = "0", env = "KOLLIDER_HEDGE_ORDER_CHUNK_SECS")]
+        order_chunk_secs: u64,
+        /// Maximum amount of hedge orders placed per minute. Changes of the hedge above the limit
+        /// are accumulated until the next slot. 0 disables the limit.
+        #[clap(
+            long,
+            default_value = "0",
+            env = "KOLLIDER_HEDGE_MAX_ORDERS_PER_MINUTE"
+        )]
+        max_orders_per_minute: u64,
+        /// How hedge orders are priced: `limit` crosses the spread, `passive` waits at the index price
+        #[clap(long, default_value = "limit", env = "KOLLIDER_HEDGE_STRATEGY")]
+        strategy: StrategyKind,
+        /// Hard cap on the position in sats. Orders that increase the position above the cap are
+        /// not placed. 0 disables the limit.
+        #[clap(long, default_value = "0", env = "KOLLIDER_HEDGE_MAX_POSITION_SATS")]
+        max_position_sats: u64,
+        /// Half-life in seconds of the moving average of the index that orders are priced from.
+        /// Smooths out single-tick spikes. 0 prices orders from the last tick.
+        #[clap(long, default_value = "0", env = "KOLLIDER_HEDGE_EMA_HALF_LIFE")]
+        ema_half_life: u64,
+        /// Period when no new orders are placed: cron expression of the start in UTC and
+        /// duration in minutes, e.g. `0 2 * * 0 60`. Can be repeated or separated by `;`.
+        #[clap(
+            long = "maintenance-window",
+            env = "KOLLIDER_HEDGE_MAINTENANCE_WINDOWS",
+            multiple_occurrences = true,
+            use_delimiter = true,
+            value_delimiter = ';'
+        )]
+        maintenance_windows: Vec<MaintenanceWindow>,
+        /// Orders priced further from the index than the percents are not placed and alert is
+        /// raised. Protects from hedging into a crashed order book. 0 disables the check.
+        #[clap(long, default_value = "0", env = "KOLLIDER_HEDGE_MAX_SLIPPAGE")]
+        max_slippage: f64,
+        /// Orders are held while the clock of Kollider differs from the local one by more than
+        /// the amount of seconds. The skew is always reported in `/health`. 0 disables the check.
+        #[clap(long, default_value = "0", env = "KOLLIDER_HEDGE_MAX_CLOCK_SKEW")]
+        max_clock_skew: u64,
+        /// Seconds between measurements of the clock skew with Kollider
+        #[clap(long, default_value = "60", env = "KOLLIDER_HEDGE_CLOCK_SKEW_INTERVAL")]
+        clock_skew_interval: u64,
+        /// Alert when the unhedged amount stays above the amount of USD in either direction for
+        /// longer than `--drift-alarm-minutes`. 0 disables the alarm.
+        #[clap(long, default_value = "0", env = "KOLLIDER_HEDGE_DRIFT_ALARM_USD")]
+        drift_alarm_usd: f64,
+        /// Minutes the unhedged amount must stay above `--drift-alarm-usd` before the alarm is
+        /// raised, so that normal latency of order fills is not reported
+        #[clap(long, default_value = "10", env = "KOLLIDER_HEDGE_DRIFT_ALARM_MINUTES")]
+        drift_alarm_minutes: u64,
+        /// Force recalculation of orders when the position with open orders stays outside of
+        /// the allowed gap from the hedge for the amount of minute checks. The drift is always
+        /// reported in `/metrics`. 0 disables the correction.
+        #[clap(
+            long,
+            default_value = "3",
+            env = "KOLLIDER_HEDGE_DRIFT_CORRECTION_CHECKS"
+        )]
+        drift_correction_checks: u32,
+        /// URL that receives alerts as JSON in POST requests
+        #[clap(long, env = "KOLLIDER_HEDGE_ALERT_WEBHOOK", hide_env_values = true)]
+        alert_webhook: Option<String>,
+        /// Token of Telegram bot that posts alerts to `--telegram-chat`
+        #[clap(
+            long,
+            env = "KOLLIDER_HEDGE_TELEGRAM_TOKEN",
+            hide_env_values = true,
+            requires = "telegram_chat"
+        )]
+        telegram_token: Option<String>,
+        /// Id of Telegram chat that receives alerts
+        #[clap(long, env = "KOLLIDER_HEDGE_TELEGRAM_CHAT")]
+        telegram_chat: Option<String>,
+        /// Orders rejected by Kollider for insufficient margin are retried with half of the size
+        /// down to the amount of sats. 0 disables retries.
+        #[clap(long, default_value = "1000", env = "KOLLIDER_HEDGE_MIN_DOWNSIZE_SATS")]
+        min_downsize_sats: u64,
+        /// Sats of Kollider balance that are never committed to new orders
+        #[clap(long, default_value = "0", env = "KOLLIDER_HEDGE_MARGIN_BUFFER")]
+        margin_buffer: u64,
+        /// Settlement of orders on Kollider: `delayed` or `instant`. Instant settlement credits
+        /// realized PnL to the Kollider balance right away.
+        #[clap(long, default_value = "delayed", env = "KOLLIDER_HEDGE_SETTLEMENT")]
+        settlement: Settlement,
+        /// Index of BTC in the currency that `/stats` are additionally reported in, e.g. `.BTCEUR`
+        #[clap(long, env = "KOLLIDER_HEDGE_REPORTING_PAIR")]
+        reporting_pair: Option<String>,
+        /// Eclair channel that is hedged by events posted to `/hedge/eclair`, given as
+        /// `<eclair channel id>=<fiat channel id>`. Can be repeated or comma separated.
+        #[clap(
+            long = "eclair-channel",
+            env = "KOLLIDER_HEDGE_ECLAIR_CHANNELS",
+            multiple_occurrences = true,
+            use_delimiter = true
+        )]
+        eclair_channels: Vec<ChannelMapping>,
+        /// Place and cancel orders via Kollider REST API when the websocket is down
+        #[clap(long, env = "KOLLIDER_HEDGE_REST_FALLBACK")]
+        rest_fallback: bool,
+        /// Seconds the websocket must be down before orders are placed via REST API
+        #[clap(long, default_value = "60", env = "KOLLIDER_HEDGE_REST_FALLBACK_AFTER")]
+        rest_fallback_after: u64,
+        /// Token for `/admin` endpoints passed as `Authorization: Bearer <token>` header.
+        /// Admin endpoints are disabled when the token is not set.
+        #[clap(long, env = "KOLLIDER_HEDGE_ADMIN_TOKEN", hide_env_values = true)]
+        admin_token: Option<String>,
+        /// URL of Kollider REST API that is used for wallet operations
+        #[clap(long, default_value = KOLLIDER_API_URL, env = "KOLLIDER_HEDGE_API_URL")]
+        kollider_api_url: String,
+        /// URL of Kollider websocket, e.g. a local mock. The production endpoint is used when
+        /// not set.
+        #[clap(long, env = "KOLLIDER_HEDGE_WS_URL")]
+        kollider_ws_url: Option<String>,
+        /// Sats of free margin on Kollider that `/admin/withdraw` never withdraws
+        #[clap(long, default_value = "0", env = "KOLLIDER_HEDGE_WITHDRAW_BUFFER")]
+        withdraw_buffer: u64,
+        /// Network in CIDR notation that is allowed to call mutating endpoints (`/hedge/*`,
+        /// `/admin/*`, `/config`). Can be repeated or comma separated. All addresses are
+        /// allowed when not set.
+        #[clap(
+            long = "allow-ip",
+            env = "KOLLIDER_HEDGE_ALLOWED_IPS",
+            multiple_occurrences = true,
+            use_delimiter = true
+        )]
+        allowed_ips: Vec<IpNet>,
+        /// Origin that browsers are allowed to call the API from, e.x. `https://dash.example.com`.
+        /// `*` allows any origin. Can be repeated or comma separated. CORS is disabled when not set.
+        #[clap(
+            long = "cors-origin",
+            env = "KOLLIDER_HEDGE_CORS_ORIGINS",
+            multiple_occurrences = true,
+            use_delimiter = true
+        )]
+        cors_origins: Vec<String>,
+        /// HTTP method allowed for cross-origin requests. Can be repeated or comma separated.
+        #[clap(
+            long = "cors-method",
+            env = "KOLLIDER_HEDGE_CORS_METHODS",
+            default_value = "GET",
+            possible_values = &["GET", "POST"],
+            multiple_occurrences = true,
+            use_delimiter = true
+        )]
+        cors_methods: Vec<String>,
+        /// Compress API responses with gzip or deflate for clients that accept it
+        #[clap(long, env = "KOLLIDER_HEDGE_COMPRESSION")]
+        compression: bool,
+    },
+    /// Validate the configuration, database and Kollider credentials and print the report.
+    /// Exits with 1 if any check fails. The same checks are run before `serve` starts trading.
+    Check {
+        /// That percent is added and subtructed from current price to ensure that order is executed
+        #[clap(long, default_value = "0.1", env = "KOLLIDER_HEDGE_SPREAD")]
+        spread_percent: f64,
+        /// leverage * 100, 100 means 1x, 200 means 2x
+        #[clap(long, default_value = "100", env = "KOLLIDER_HEDGE_LEVERAGE")]
+        leverage: u64,
+        /// URL of Kollider REST API
+        #[clap(long, default_value = KOLLIDER_API_URL, env = "KOLLIDER_HEDGE_API_URL")]
+        kollider_api_url: String,
+    },
+    /// Interactively ask for credentials, database, currency and risk parameters and write
+    /// them to `--env-file` with a systemd unit template
+    Init {
+        /// Path of the systemd unit template
+        #[clap(long, default_value = "kollider-hedge.service")]
+        unit_file: PathBuf,
+        /// Overwrite existing files
+        #[clap(long)]
+        force: bool,
     },
     /// Output swagger spec
     Swagger,
+    /// Upgrade bodies of stored updates to the current version
+    MigrateEvents,
+    /// Drop monthly partitions of updates that end before the moment. Updates after the latest
+    /// snapshot are never dropped.
+    PruneUpdates {
+        /// e.x. 2022-01-01T00:00:00
+        #[clap(long)]
+        before: NaiveDateTime,
+    },
+    /// Replay dumped updates against a price series and report orders, fees and PnL
+    Replay {
+        /// JSON array of updates in the format of `/history` endpoint
+        #[clap(long)]
+        events: PathBuf,
+        /// CSV file with `timestamp,price` lines, price is in USD per BTC
+        #[clap(long)]
+        prices: PathBuf,
+        /// Taker fee of the exchange in percents
+        #[clap(long, default_value = "0.075")]
+        fee_percent: f64,
+        /// That percent is added and subtructed from current price for simulated orders
+        #[clap(long, default_value = "0.1")]
+        spread_percent: f64,
+        /// leverage * 100, 100 means 1x, 200 means 2x.
+        #[clap(long, default_value = "100")]
+        leverage: u64,
+        /// Half-life in seconds of the moving average of the price for simulated orders
+        #[clap(long, default_value = "0")]
+        ema_half_life: u64,
+    },
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
+    let env_file = env_file_arg(
+        std::env::args().skip(1),
+        std::env::var("KOLLIDER_HEDGE_ENV_FILE").ok(),
+    );
+    let legacy_vars = load_env(env_file.as_deref())?;
     let args = Args::parse();
-    env_logger::init();
+    let _logger = init_logging(&args.log_config())?;
+    for (old, new) in legacy_vars {
+        warn!("Environment variable {} is deprecated, use {}", old, new);
+    }
+    if let Some(path) = &args.env_file {
+        // `init` creates the file
+        if !path.exists() && !matches!(args.subcmd, SubCommand::Init { .. }) {
+            return Err(format!("Env file {} doesn't exist", path.display()).into());
+        }
+    }
 
     match args.subcmd.clone() {
         SubCommand::Serve {
             host,
             port,
+            probe_port,
             spread_percent,
             leverage,
-        } => loop {
-            let args = args.clone();
-
-            info!("Connecting to database");
-            let pool = create_db_pool(&args.dbconnect).await?;
-            info!("Connected");
-            let config = HedgeConfig {
-                hedge_pair: args.pair,
-                spread_percent,
-                hedge_leverage: leverage,
-                hedge_sym: args.symbol,
+            read_only,
+            lock_conflict,
+            standby_interval,
+            stats_interval,
+            disable_daily_report,
+            debug_messages,
+            ws_queue_size,
+            ws_overflow,
+            db_write_batch,
+            channel_gc_retention,
+            min_htlc_aggregate,
+            htlc_aggregation_secs,
+            max_htlc_sats,
+            min_htlc_rate,
+            max_htlc_rate,
+            price_feed_url,
+            price_feed_pointer,
+            price_feed_interval,
+            max_index_deviation,
+            opening_order_timeout,
+            max_order_sats,
+            order_chunk_secs,
+            max_orders_per_minute,
+            strategy,
+            max_position_sats,
+            ema_half_life,
+            maintenance_windows,
+            max_slippage,
+            max_clock_skew,
+            clock_skew_interval,
+            drift_alarm_usd,
+            drift_alarm_minutes,
+            drift_correction_checks,
+            alert_webhook,
+            telegram_token,
+            telegram_chat,
+            min_downsize_sats,
+            margin_buffer,
+            settlement,
+            reporting_pair,
+            eclair_channels,
+            rest_fallback,
+            rest_fallback_after,
+            admin_token,
+            kollider_api_url,
+            kollider_ws_url,
+            withdraw_buffer,
+            allowed_ips,
+            cors_origins,
+            cors_methods,
+            compression,
+        } => {
+            let secrets_rx = match args.vault_config()? {
+                Some(vault_config) => {
+                    info!("Reading secrets from Vault");
+                    let vault = VaultClient::new(vault_config);
+                    let (secrets_tx, secrets_rx) = watch::channel(vault.fetch().await?);
+                    tokio::spawn(vault_worker(vault, secrets_tx));
+                    secrets_rx
+                }
+                None => {
+                    let secrets = Secrets {
+                        credentials: args.credentials()?,
+                        admin_token: None,
+                    };
+                    watch::channel(secrets).1
+                }
             };
+            let endpoints =
+                KolliderEndpoints::new(&kollider_api_url, kollider_ws_url, args.testnet)
+                    .with_proxy(args.proxy.clone());
+            if args.testnet {
+                warn!("Running against test environment of Kollider");
+            }
+            info!(
+                "Kollider API is {}, websocket is {}",
+                endpoints.api_url,
+                endpoints.custom_ws_url().unwrap_or("default")
+            );
+            if let Some(proxy) = &endpoints.proxy {
+                info!("Connecting to Kollider via SOCKS5 proxy {}", proxy.addr);
+            }
+            let ws_monitor = Arc::new(Mutex::new(WsMonitor::new(debug_messages)));
+            let startup = Arc::new(Startup::default());
+            if let Some(probe_port) = probe_port {
+                let host = host.clone();
+                let startup = startup.clone();
+                let ws_monitor = ws_monitor.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = serve_probes(&host, probe_port, startup, ws_monitor).await {
+                        error!("Probes server error: {}", e);
+                    }
+                });
+            }
+            loop {
+                let args = args.clone();
+                let secrets = secrets_rx.borrow().clone();
+                let credentials = secrets.credentials.clone();
+                let shared_admin_token = Arc::new(std::sync::RwLock::new(
+                    secrets.admin_token.or_else(|| admin_token.clone()),
+                ));
+                let exchange = KolliderRest::new(
+                    &endpoints.api_url,
+                    &credentials.api_key,
+                    &credentials.api_secret,
+                    &credentials.password,
+                )
+                .with_proxy(endpoints.proxy.as_ref())?;
 
-            info!("Reconstructing state from database");
-            let state = query_state(&pool, config).await?;
-            let state_mx = Arc::new(Mutex::new(state));
-            let state_notify = Arc::new(Notify::new());
-            let (stdin_tx, stdin_rx) = futures_channel::mpsc::unbounded();
-            let auth_notify = Arc::new(Notify::new());
-            let (abort_ws_handle, abort_ws_reg) = AbortHandle::new_pair();
-            let (abort_exe_handle, abort_exe_reg) = AbortHandle::new_pair();
-            let (abort_api_handle, abort_api_reg) = AbortHandle::new_pair();
-            info!("Spawning websocket control thread");
-            tokio::spawn({
-                let state = state_mx.clone();
-                let state_notify = state_notify.clone();
-                let stdin_tx = stdin_tx.clone();
-                let auth_notify = auth_notify.clone();
-                let abort_api_handle = abort_api_handle.clone();
-                let future = async move {
-                    let ws_auth = WebsocketAuth {
-                        api_secret: &args.api_secret,
-                        api_key: &args.api_key,
-                        password: &args.password,
-                    };
-                    if let Err(e) = listen_websocket(
-                        stdin_tx,
-                        stdin_rx,
-                        state,
-                        state_notify,
-                        auth_notify,
-                        ws_auth,
-                    )
-                    .await
-                    {
-                        error!("Websocket control thread error: {}", e);
-                        abort_exe_handle.abort();
-                        abort_api_handle.abort();
+                info!("Connecting to database");
+                // Schema is migrated only after the instance takes the writer lock
+                let pool = connect_db_pool(&args.dbconnect, &args.db_config()).await?;
+                info!("Connected");
+                startup.connected(pool.clone()).await;
+                let config = HedgeConfig {
+                    hedge_pair: args.pair,
+                    spread_percent,
+                    hedge_leverage: leverage,
+                    hedge_sym: args.symbol,
+                    min_htlc_aggregate,
+                    htlc_aggregation_secs,
+                    max_index_deviation,
+                    max_order_sats,
+                    order_chunk_secs,
+                    max_orders_per_minute,
+                    strategy,
+                    max_position_sats,
+                    ema_half_life_secs: ema_half_life,
+                    maintenance_windows: maintenance_windows.clone(),
+                    max_slippage_percent: max_slippage,
+                    settlement,
+                    max_clock_skew_secs: max_clock_skew,
+                    drift_alarm_usd,
+                    drift_alarm_secs: drift_alarm_minutes * 60,
+                    min_downsize_sats,
+                    margin_buffer_sats: margin_buffer,
+                    reporting_pair: reporting_pair.clone(),
+                    unhedged_channels: vec![],
+                    channel_leverage: HashMap::new(),
+                };
+                if max_index_deviation > 0.0 && price_feed_url.is_none() {
+                    warn!(
+                        "Max index deviation is set without price feed URL, orders won't be placed"
+                    );
+                }
+
+                // Observers don't take the lock, so they never block the trading instance
+                let (writer_lock, warm_state) = if read_only {
+                    (None, None)
+                } else {
+                    match WriterLock::try_acquire(&args.dbconnect).await? {
+                        Some(lock) => (Some(lock), None),
+                        None => match lock_conflict {
+                            LockConflict::Fail => return Err(LockErr::Taken.into()),
+                            LockConflict::ReadOnly => {
+                                warn!(
+                                    "Another instance writes to the database, starting in read-only mode"
+                                );
+                                (None, None)
+                            }
+                            LockConflict::Standby => {
+                                // Standby is healthy for systemd, but `/ready` fails until
+                                // it takes over
+                                notify_ready();
+                                let watchdog = tokio::spawn(watchdog_worker());
+                                let res = standby(
+                                    &pool,
+                                    &args.dbconnect,
+                                    config.clone(),
+                                    Duration::from_secs(standby_interval),
+                                )
+                                .await;
+                                watchdog.abort();
+                                let (lock, state) = res?;
+                                (Some(lock), Some(state))
+                            }
+                        },
                     }
                 };
-                Abortable::new(future, abort_ws_reg)
-            });
-            info!("Spawning action executor thread");
-            tokio::spawn({
-                let state_mx = state_mx.clone();
-                let state_notify = state_notify.clone();
-                let stdin_tx = stdin_tx.clone();
-                let auth_notify = auth_notify.clone();
-                let abort_api_handle = abort_api_handle.clone();
-                let future = async move {
-                    auth_notify.notified().await;
-                    let res = state_action_worker(state_mx, state_notify, |action| {
-                        let stdin_tx = stdin_tx.clone();
-                        async move {
-                            log::info!("Executing action: {:?}", action);
-                            for msg in action.to_kollider_messages() {
-                                stdin_tx.unbounded_send(msg)?;
+                let read_only = read_only || writer_lock.is_none();
+
+                if !read_only {
+                    migrate_db(&pool).await?;
+                    let sealed = seal_chain(&pool).await?;
+                    if sealed > 0 {
+                        info!("Added {} updates to the hash chain", sealed);
+                    }
+                }
+                let mut state = match warm_state {
+                    Some(state) => state,
+                    None => {
+                        info!("Reconstructing state from database");
+                        query_state(&pool, config.clone()).await?
+                    }
+                };
+                if !read_only {
+                    rebuild_channel_hedges(&pool, &state.channels_hedge).await?;
+                }
+                startup.set_loaded(true);
+                let mut report = CheckReport::default();
+                report.push(check_database(&pool).await);
+                report.push(check_credentials(&exchange).await);
+                let (products_check, products) = check_products(&exchange).await;
+                report.push(products_check);
+                state.products = products.clone();
+                report.push(check_config(&config, &products));
+                info!("Startup checks:\n{}", report);
+                if !report.passed() {
+                    return Err("Startup checks failed, refusing to trade".into());
+                }
+                // Flags are recorded when they differ from the previous startup, the state
+                // applies only the changed ones. Read-only instances follow the recorded config.
+                let flags = config.full_change();
+                if !read_only && state.startup_config.as_ref() != Some(&flags) {
+                    info!("Recording configuration flags {:?}", flags);
+                    let update = StateUpdate {
+                        created: Utc::now().naive_utc(),
+                        body: UpdateBody::ConfigChange(flags),
+                    };
+                    insert_update(&pool, update.body.clone()).await?;
+                    state.apply_update(update)?;
+                }
+                let state_mx = Arc::new(RwLock::new(state));
+                let state_notify = Arc::new(Notify::new());
+                let (stdin_tx, stdin_rx) = queue::bounded(
+                    "ws_outbound",
+                    ws_queue_size,
+                    OverflowPolicy::Block,
+                    ws_monitor.lock().await.outbound.clone(),
+                );
+                let auth_notify = Arc::new(Notify::new());
+                let (abort_ws_handle, abort_ws_reg) = AbortHandle::new_pair();
+                let (abort_exe_handle, abort_exe_reg) = AbortHandle::new_pair();
+                let (abort_api_handle, abort_api_reg) = AbortHandle::new_pair();
+                let (abort_systemd_handle, abort_systemd_reg) = AbortHandle::new_pair();
+                let (abort_secrets_handle, abort_secrets_reg) = AbortHandle::new_pair();
+                tokio::spawn({
+                    let mut secrets_rx = secrets_rx.clone();
+                    let shared_admin_token = shared_admin_token.clone();
+                    let admin_token = admin_token.clone();
+                    let credentials = credentials.clone();
+                    let abort_ws_handle = abort_ws_handle.clone();
+                    let abort_exe_handle = abort_exe_handle.clone();
+                    let abort_api_handle = abort_api_handle.clone();
+                    let future = async move {
+                        while secrets_rx.changed().await.is_ok() {
+                            let secrets = secrets_rx.borrow().clone();
+                            *shared_admin_token
+                                .write()
+                                .expect("Admin token lock is poisoned") =
+                                secrets.admin_token.or_else(|| admin_token.clone());
+                            if secrets.credentials != credentials {
+                                info!("Kollider credentials are rotated, reconnecting");
+                                abort_ws_handle.abort();
+                                abort_exe_handle.abort();
+                                abort_api_handle.abort();
+                                return;
                             }
-                            Ok(())
                         }
-                    })
-                    .await;
-                    if res.is_err() {
-                        error!("Aborting WS and API thread");
-                        abort_ws_handle.abort();
-                        abort_api_handle.abort();
-                    }
+                    };
+                    Abortable::new(future, abort_secrets_reg)
+                });
+                tokio::spawn({
+                    let auth_notify = auth_notify.clone();
+                    let future = async move {
+                        // Database is migrated and state is reconstructed at the moment
+                        auth_notify.notified().await;
+                        notify_ready();
+                        watchdog_worker().await;
+                    };
+                    Abortable::new(future, abort_systemd_reg)
+                });
+                let (abort_lock_handle, abort_lock_reg) = AbortHandle::new_pair();
+                if let Some(writer_lock) = writer_lock {
+                    tokio::spawn({
+                        let abort_ws_handle = abort_ws_handle.clone();
+                        let abort_exe_handle = abort_exe_handle.clone();
+                        let abort_api_handle = abort_api_handle.clone();
+                        let future = async move {
+                            let e = writer_lock.hold().await;
+                            error!("ALERT: {}, stopping trading", e);
+                            abort_ws_handle.abort();
+                            abort_exe_handle.abort();
+                            abort_api_handle.abort();
+                        };
+                        Abortable::new(future, abort_lock_reg)
+                    });
+                }
+                info!("Spawning websocket control thread");
+                tokio::spawn({
+                    let pool = Some(pool.clone()).filter(|_| !read_only);
+                    let state = state_mx.clone();
+                    let state_notify = state_notify.clone();
+                    let stdin_tx = stdin_tx.clone();
+                    let auth_notify = auth_notify.clone();
+                    let abort_api_handle = abort_api_handle.clone();
+                    let ws_monitor = ws_monitor.clone();
+                    let endpoints = endpoints.clone();
+                    let future = async move {
+                        let ws_auth = WebsocketAuth {
+                            api_secret: &credentials.api_secret,
+                            api_key: &credentials.api_key,
+                            password: &credentials.password,
+                        };
+                        let res = listen_websocket(
+                            pool,
+                            stdin_tx,
+                            stdin_rx,
+                            state,
+                            state_notify,
+                            auth_notify,
+                            ws_monitor.clone(),
+                            ws_auth,
+                            &endpoints,
+                            WsQueueConfig {
+                                size: ws_queue_size,
+                                overflow: ws_overflow,
+                            },
+                        )
+                        .await;
+                        ws_monitor.lock().await.status.disconnected();
+                        if let Err(e) = res {
+                            error!("Websocket control thread error: {}", e);
+                            abort_exe_handle.abort();
+                            abort_api_handle.abort();
+                        }
+                    };
+                    Abortable::new(future, abort_ws_reg)
+                });
+                if read_only {
+                    info!("Read-only mode, action executor is disabled");
+                } else {
+                    info!("Spawning action executor thread");
+                    tokio::spawn({
+                        let pool = pool.clone();
+                        let exchange = exchange.clone();
+                        let state_mx = state_mx.clone();
+                        let state_notify = state_notify.clone();
+                        let stdin_tx = stdin_tx.clone();
+                        let auth_notify = auth_notify.clone();
+                        let abort_api_handle = abort_api_handle.clone();
+                        let products = Arc::new(products);
+                        let settlement = config.settlement;
+                        let future = async move {
+                            let max_age = Some(Duration::from_secs(opening_order_timeout))
+                                .filter(|_| opening_order_timeout > 0);
+                            // Actions are executed only after the outbox is replayed
+                            auth_notify.notified().await;
+                            let connected = Utc::now().naive_utc();
+                            if let Err(e) = replay_unconfirmed(
+                                &pool, &exchange, &state_mx, &stdin_tx, max_age, connected,
+                            )
+                            .await
+                            {
+                                error!(
+                                    "Failed to replay outbox, aborting WS and API thread: {}",
+                                    e
+                                );
+                                abort_ws_handle.abort();
+                                abort_api_handle.abort();
+                                return;
+                            }
+                            // Messages sent before a reconnect are lost with the old socket
+                            let replays = {
+                                let state_mx = state_mx.clone();
+                                let (pool, exchange, stdin_tx) = (&pool, &exchange, &stdin_tx);
+                                async move {
+                                    loop {
+                                        auth_notify.notified().await;
+                                        let connected = Utc::now().naive_utc();
+                                        let res = replay_unconfirmed(
+                                            pool, exchange, &state_mx, stdin_tx, max_age, connected,
+                                        )
+                                        .await;
+                                        if let Err(e) = res {
+                                            return e;
+                                        }
+                                    }
+                                }
+                            };
+                            let worker = state_action_worker(state_mx, state_notify, |action| {
+                                let pool = pool.clone();
+                                let stdin_tx = stdin_tx.clone();
+                                let products = products.clone();
+                                async move {
+                                    let _timer = latency::timer("action execute");
+                                    log::info!("Executing action: {:?}", action);
+                                    audit(format_args!("Executing action: {:?}", action));
+                                    let msgs = match action
+                                        .to_kollider_messages(&products, settlement)
+                                    {
+                                        Ok(msgs) => msgs,
+                                        Err(e) => {
+                                            error!("Action {:?} is not executed: {}", action, e);
+                                            return Ok(());
+                                        }
+                                    };
+                                    send_action(&pool, &stdin_tx, &action, msgs).await?;
+                                    Ok(())
+                                }
+                            });
+                            let failed = match select(Box::pin(replays), Box::pin(worker)).await {
+                                Either::Left((e, _)) => {
+                                    error!("Failed to replay outbox after reconnect: {}", e);
+                                    true
+                                }
+                                Either::Right((res, _)) => res.is_err(),
+                            };
+                            if failed {
+                                error!("Aborting WS and API thread");
+                                abort_ws_handle.abort();
+                                abort_api_handle.abort();
+                            }
+                        };
+                        Abortable::new(future, abort_exe_reg)
+                    });
+                }
+                let (abort_stats_handle, abort_stats_reg) = AbortHandle::new_pair();
+                if stats_interval > 0 && !read_only {
+                    info!("Spawning stats recorder thread");
+                    tokio::spawn(Abortable::new(
+                        stats_recorder(
+                            pool.clone(),
+                            state_mx.clone(),
+                            Duration::from_secs(stats_interval),
+                        ),
+                        abort_stats_reg,
+                    ));
+                }
+                let (abort_report_handle, abort_report_reg) = AbortHandle::new_pair();
+                if !disable_daily_report && !read_only {
+                    info!("Spawning daily report thread");
+                    tokio::spawn({
+                        let pool = pool.clone();
+                        let state_mx = state_mx.clone();
+                        let future = async move {
+                            if let Err(e) = daily_report_worker(pool, state_mx).await {
+                                error!("Daily report thread error: {}", e);
+                            }
+                        };
+                        Abortable::new(future, abort_report_reg)
+                    });
+                }
+                let (abort_partitions_handle, abort_partitions_reg) = AbortHandle::new_pair();
+                if !read_only {
+                    tokio::spawn(Abortable::new(
+                        partitions_worker(pool.clone()),
+                        abort_partitions_reg,
+                    ));
+                }
+                let (abort_gc_handle, abort_gc_reg) = AbortHandle::new_pair();
+                if channel_gc_retention > 0 && !read_only {
+                    info!("Spawning empty channels GC thread");
+                    tokio::spawn({
+                        let pool = pool.clone();
+                        let state_mx = state_mx.clone();
+                        let future = async move {
+                            let retention = Duration::from_secs(channel_gc_retention);
+                            if let Err(e) = channels_gc_worker(pool, state_mx, retention).await {
+                                error!("Empty channels GC thread error: {}", e);
+                            }
+                        };
+                        Abortable::new(future, abort_gc_reg)
+                    });
+                }
+                let (abort_watchdog_handle, abort_watchdog_reg) = AbortHandle::new_pair();
+                if opening_order_timeout > 0 && !read_only {
+                    info!("Spawning opening orders watchdog thread");
+                    tokio::spawn(Abortable::new(
+                        opening_orders_watchdog(
+                            state_mx.clone(),
+                            state_notify.clone(),
+                            Duration::from_secs(opening_order_timeout),
+                        ),
+                        abort_watchdog_reg,
+                    ));
+                }
+                let (abort_feed_handle, abort_feed_reg) = AbortHandle::new_pair();
+                if let Some(url) = price_feed_url.clone() {
+                    info!("Spawning external price feed thread");
+                    let feed_config = PriceFeedConfig {
+                        url,
+                        pointer: price_feed_pointer.clone(),
+                        interval: Duration::from_secs(price_feed_interval),
+                    };
+                    tokio::spawn(Abortable::new(
+                        price_feed_worker(feed_config, state_mx.clone(), state_notify.clone()),
+                        abort_feed_reg,
+                    ));
+                }
+                let (abort_clock_handle, abort_clock_reg) = AbortHandle::new_pair();
+                info!("Spawning clock skew thread");
+                tokio::spawn(Abortable::new(
+                    clock_skew_worker(
+                        exchange.clone(),
+                        state_mx.clone(),
+                        state_notify.clone(),
+                        Duration::from_secs(clock_skew_interval),
+                    ),
+                    abort_clock_reg,
+                ));
+                let alerter = Alerter::new(AlertConfig {
+                    webhook: alert_webhook.clone(),
+                    telegram: telegram_token
+                        .clone()
+                        .zip(telegram_chat.clone())
+                        .map(|(token, chat_id)| TelegramConfig { token, chat_id }),
+                });
+                let (abort_drift_handle, abort_drift_reg) = AbortHandle::new_pair();
+                if drift_alarm_usd > 0.0 {
+                    info!("Spawning hedge drift alarm thread");
+                    tokio::spawn(Abortable::new(
+                        drift_alarm_worker(alerter.clone(), state_mx.clone(), state_notify.clone()),
+                        abort_drift_reg,
+                    ));
+                }
+                let (abort_position_drift_handle, abort_position_drift_reg) =
+                    AbortHandle::new_pair();
+                info!("Spawning position drift thread");
+                tokio::spawn(Abortable::new(
+                    position_drift_worker(
+                        alerter,
+                        state_mx.clone(),
+                        state_notify.clone(),
+                        drift_correction_checks,
+                    ),
+                    abort_position_drift_reg,
+                ));
+                let (abort_fallback_handle, abort_fallback_reg) = AbortHandle::new_pair();
+                if rest_fallback && !read_only {
+                    info!("Spawning REST fallback thread");
+                    tokio::spawn(Abortable::new(
+                        rest_fallback_worker(
+                            exchange.clone(),
+                            state_mx.clone(),
+                            ws_monitor.clone(),
+                            state_mx.read().await.products.clone(),
+                            Duration::from_secs(rest_fallback_after),
+                        ),
+                        abort_fallback_reg,
+                    ));
+                }
+                let stats_snapshot = Arc::new(StatsSnapshot::default());
+                let (abort_snapshot_handle, abort_snapshot_reg) = AbortHandle::new_pair();
+                tokio::spawn(Abortable::new(
+                    stats_snapshot_worker(
+                        state_mx.clone(),
+                        state_notify.clone(),
+                        stats_snapshot.clone(),
+                    ),
+                    abort_snapshot_reg,
+                ));
+                info!("Serving API");
+                let api_config = ApiConfig {
+                    read_only,
+                    htlc_limits: HtlcLimits {
+                        max_sats: max_htlc_sats,
+                        min_rate: min_htlc_rate,
+                        max_rate: max_htlc_rate,
+                    },
+                    admin_token: shared_admin_token,
+                    exchange: Some(exchange),
+                    withdraw_buffer,
+                    allowed_ips: allowed_ips.clone(),
+                    cors_origins: cors_origins.clone(),
+                    cors_methods: cors_methods.clone(),
+                    write_batch: db_write_batch,
+                    eclair_channels: eclair_channels
+                        .iter()
+                        .map(|m| (m.eclair.clone(), m.hedge.clone()))
+                        .collect(),
+                    compression,
                 };
-                Abortable::new(future, abort_exe_reg)
-            });
-            info!("Serving API");
-            let api_future = serve_api(&host, port, pool, state_mx, state_notify);
-            match Abortable::new(api_future, abort_api_reg).await {
-                Ok(mres) => mres?,
-                Err(Aborted) => {
-                    error!("API thread aborted");
+                let api_future = serve_api(
+                    &host,
+                    port,
+                    pool,
+                    state_mx,
+                    state_notify,
+                    ws_monitor.clone(),
+                    stats_snapshot,
+                    api_config,
+                );
+                match Abortable::new(api_future, abort_api_reg).await {
+                    Ok(mres) => mres?,
+                    Err(Aborted) => {
+                        error!("API thread aborted");
+                    }
                 }
-            }
+                startup.set_loaded(false);
+                abort_stats_handle.abort();
+                abort_snapshot_handle.abort();
+                abort_systemd_handle.abort();
+                abort_secrets_handle.abort();
+                abort_report_handle.abort();
+                abort_partitions_handle.abort();
+                abort_gc_handle.abort();
+                abort_feed_handle.abort();
+                abort_clock_handle.abort();
+                abort_drift_handle.abort();
+                abort_position_drift_handle.abort();
+                abort_watchdog_handle.abort();
+                abort_fallback_handle.abort();
+                abort_lock_handle.abort();
 
-            let restart_dt = Duration::from_secs(5);
-            info!("Adding {:?} delay before restarting logic", restart_dt);
-            sleep(restart_dt).await;
-        },
+                let restart_dt = Duration::from_secs(5);
+                info!("Adding {:?} delay before restarting logic", restart_dt);
+                sleep(restart_dt).await;
+            }
+        }
+        SubCommand::Check {
+            spread_percent,
+            leverage,
+            kollider_api_url,
+        } => {
+            let endpoints = KolliderEndpoints::new(&kollider_api_url, None, args.testnet)
+                .with_proxy(args.proxy.clone());
+            let config = HedgeConfig {
+                hedge_pair: args.pair.clone(),
+                hedge_sym: args.symbol.clone(),
+                spread_percent,
+                hedge_leverage: leverage,
+                ..HedgeConfig::default()
+            };
+            let mut report = CheckReport::default();
+            // Checks run next to the serving instance, so the schema is left to it
+            match connect_db_pool(&args.dbconnect, &args.db_config()).await {
+                Ok(pool) => report.push(check_database(&pool).await),
+                Err(e) => report.push(Check::new("database", CheckStatus::Fail, e.to_string())),
+            }
+            let credentials = match args.vault_config()? {
+                Some(vault_config) => VaultClient::new(vault_config)
+                    .fetch()
+                    .await
+                    .map(|secrets| secrets.credentials)
+                    .map_err(|e| e.to_string()),
+                None => args.credentials().map_err(|e| e.to_string()),
+            };
+            let products = match credentials {
+                Ok(credentials) => {
+                    let exchange = KolliderRest::new(
+                        &endpoints.api_url,
+                        &credentials.api_key,
+                        &credentials.api_secret,
+                        &credentials.password,
+                    )
+                    .with_proxy(endpoints.proxy.as_ref())?;
+                    report.push(check_credentials(&exchange).await);
+                    let (products_check, products) = check_products(&exchange).await;
+                    report.push(products_check);
+                    products
+                }
+                Err(e) => {
+                    report.push(Check::new("credentials", CheckStatus::Fail, e));
+                    Products::new()
+                }
+            };
+            report.push(check_config(&config, &products));
+            println!("{}", report);
+            if !report.passed() {
+                std::process::exit(1);
+            }
+        }
+        SubCommand::Init { unit_file, force } => {
+            let env_path = args
+                .env_file
+                .clone()
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_ENV_FILE));
+            for path in [&env_path, &unit_file] {
+                if path.exists() && !force {
+                    return Err(format!(
+                        "{} already exists, pass --force to overwrite",
+                        path.display()
+                    )
+                    .into());
+                }
+            }
+            let stdin = std::io::stdin();
+            let answers = run_wizard(&mut stdin.lock(), &mut std::io::stdout())?;
+            // The unit refers to absolute paths as systemd doesn't resolve relative ones
+            write_new_file(&env_path, &env_file(&answers), force, true)?;
+            let env_path = fs::canonicalize(&env_path)?;
+            let exe = std::env::current_exe()?;
+            write_new_file(
+                &unit_file,
+                &systemd_unit(&answers, &env_path, &exe),
+                force,
+                false,
+            )?;
+            println!("Settings are written to {}", env_path.display());
+            println!(
+                "Run `kollider-hedge check` in the directory of the settings to validate them and install the unit with `sudo cp {} /etc/systemd/system/`",
+                unit_file.display()
+            );
+        }
         SubCommand::Swagger => {
-            let pool = create_db_pool(&args.dbconnect).await?;
-            let specs = hedge_api_specs(pool).await?;
+            let specs = hedge_api_specs().await?;
             let specs_str = serde_json::to_string_pretty(&specs)?;
             println!("{}", specs_str);
         }
+        SubCommand::MigrateEvents => {
+            let (_lock, pool) = locked_db_pool(&args).await?;
+            info!("Migrating updates to version {}", CURRENT_BODY_VERSION);
+            let migrated = migrate_events(&pool).await?;
+            info!("Migrated {} updates", migrated);
+        }
+        SubCommand::PruneUpdates { before } => {
+            let (_lock, pool) = locked_db_pool(&args).await?;
+            let dropped = drop_updates_partitions(&pool, before).await?;
+            info!("Dropped {} partitions: {:?}", dropped.len(), dropped);
+        }
+        SubCommand::Replay {
+            events,
+            prices,
+            fee_percent,
+            spread_percent,
+            leverage,
+            ema_half_life,
+        } => {
+            let updates: Vec<StateUpdate> = serde_json::from_str(&fs::read_to_string(events)?)?;
+            let prices = parse_prices(&fs::read_to_string(prices)?)?;
+            let config = HedgeConfig {
+                hedge_pair: args.pair,
+                spread_percent,
+                hedge_leverage: leverage,
+                hedge_sym: args.symbol,
+                ema_half_life_secs: ema_half_life,
+                ..HedgeConfig::default()
+            };
+            let report = replay(config, updates, &prices, fee_percent)?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        }
     }
     Ok(())
 }
 
+/// Migrated pool for subcommands that write to the database. The writer lock is held while the
+/// subcommand runs, so its writes never interleave with a serving instance.
+async fn locked_db_pool(args: &Args) -> Result<(WriterLock, Pool), Box<dyn Error>> {
+    let lock = WriterLock::try_acquire(&args.dbconnect)
+        .await?
+        .ok_or(LockErr::Taken)?;
+    let pool = create_db_pool(&args.dbconnect, &args.db_config()).await?;
+    Ok((lock, pool))
+}
+
 struct WebsocketAuth<'a> {
     api_secret: &'a str,
     api_key: &'a str,
     password: &'a str,
 }
 
+/// Send again actions that Kollider didn't confirm over connections before `connected`
+async fn replay_unconfirmed(
+    pool: &Pool,
+    exchange: &KolliderRest,
+    state_mx: &RwLock<State>,
+    stdin_tx: &QueueSender<KolliderMsg>,
+    max_age: Option<Duration>,
+    connected: NaiveDateTime,
+) -> Result<(), outbox::Error> {
+    match replay_outbox(pool, exchange, state_mx, stdin_tx, max_age, connected).await? {
+        0 => (),
+        n => info!("Replayed {} unconfirmed actions", n),
+    }
+    Ok(())
+}
+
+/// Inbound queue of websocket messages
+struct WsQueueConfig {
+    size: usize,
+    overflow: OverflowPolicy,
+}
+
+/// Updates from Kollider messages are recorded to `pool`, read-only instances pass `None` and
+/// keep them only in memory.
+#[allow(clippy::too_many_arguments)]
 async fn listen_websocket(
-    stdin_tx: UnboundedSender<KolliderMsg>,
-    stdin_rx: UnboundedReceiver<KolliderMsg>,
-    state_mx: Arc<Mutex<State>>,
+    pool: Option<Pool>,
+    stdin_tx: QueueSender<KolliderMsg>,
+    mut stdin_rx: QueueReceiver<KolliderMsg>,
+    state_mx: Arc<RwLock<State>>,
     state_notify: Arc<Notify>,
     auth_notify: Arc<Notify>,
+    ws_monitor: Arc<Mutex<WsMonitor>>,
     ws_auth: WebsocketAuth<'_>,
+    endpoints: &KolliderEndpoints,
+    queue_config: WsQueueConfig,
 ) -> Result<(), Box<dyn Error>> {
-    let (msg_sender, msg_receiver) = futures_channel::mpsc::unbounded();
+    let (socket_in_tx, mut socket_in_rx) = futures_channel::mpsc::unbounded();
+    let (socket_out_tx, socket_out_rx) = futures_channel::mpsc::unbounded();
+    let (msg_sender, msg_receiver) = queue::bounded(
+        "ws_inbound",
+        queue_config.size,
+        queue_config.overflow,
+        ws_monitor.lock().await.inbound.clone(),
+    );
     let auth_msg = make_user_auth(ws_auth.api_secret, ws_auth.api_key, ws_auth.password)?;
     trace!("Sending Auth message to websocket");
-    stdin_tx.unbounded_send(auth_msg)?;
+    stdin_tx.send(auth_msg).await?;
 
     let (abort_handle, abort_reg) = AbortHandle::new_pair();
     let (abort_socket_handle, abort_socket_reg) = AbortHandle::new_pair();
     let (abort_ping_handle, abort_ping_reg) = AbortHandle::new_pair();
+    let (abort_pipe_handle, abort_pipe_reg) = AbortHandle::new_pair();
     tokio::spawn({
         let abort_handle = abort_handle.clone();
         let abort_ping_handle = abort_ping_handle.clone();
+        let abort_pipe_handle = abort_pipe_handle.clone();
+        let ws_url = endpoints.custom_ws_url().map(|url| url.to_owned());
+        let proxy = endpoints.proxy.clone();
         let future = async move {
-            let res = kollider_websocket(stdin_rx, msg_sender).await;
+            let res = match ws_url {
+                Some(url) => {
+                    kollider_websocket_url(&url, proxy.as_ref(), socket_out_rx, socket_in_tx)
+                        .await
+                        .map_err(|e| e.to_string())
+                }
+                None => kollider_websocket(socket_out_rx, socket_in_tx)
+                    .await
+                    .map_err(|e| e.to_string()),
+            };
             if let Err(e) = res {
                 error!("Websocket thread failed: {}", e);
             }
             abort_handle.abort();
             abort_ping_handle.abort();
+            abort_pipe_handle.abort();
         };
         Abortable::new(future, abort_socket_reg)
     });
+    // Move messages between bounded queues and the socket, the queues apply overflow policies
+    tokio::spawn({
+        let abort_handle = abort_handle.clone();
+        let abort_socket_handle = abort_socket_handle.clone();
+        let future = async move {
+            let outbound = async {
+                while let Some(msg) = stdin_rx.recv().await {
+                    if socket_out_tx.unbounded_send(msg).is_err() {
+                        break;
+                    }
+                }
+            };
+            let inbound = async {
+                while let Some(msg) = socket_in_rx.next().await {
+                    if let Err(e) = msg_sender.send(msg).await {
+                        error!("Failed to queue message from websocket: {}", e);
+                        break;
+                    }
+                }
+            };
+            futures::future::select(Box::pin(outbound), Box::pin(inbound)).await;
+            abort_handle.abort();
+            abort_socket_handle.abort();
+        };
+        Abortable::new(future, abort_pipe_reg)
+    });
     let ping_notify = Arc::new(Notify::new());
     tokio::spawn({
         let auth_notify = auth_notify.clone();
@@ -234,9 +1453,11 @@ async fn listen_websocket(
             auth_notify.notified().await;
             loop {
                 debug!("Sending ping message");
-                let ping_res = stdin_tx.unbounded_send(KolliderMsg::FetchPositions {
-                    _type: FetchPositionsTag::Tag,
-                });
+                let ping_res = stdin_tx
+                    .send(KolliderMsg::FetchPositions {
+                        _type: FetchPositionsTag::Tag,
+                    })
+                    .await;
                 if let Err(_) = ping_res {
                     error!("Ping failed, aborting everything");
                     abort_handle.abort();
@@ -257,12 +1478,23 @@ async fn listen_websocket(
 
     let mut counter = 0;
     let listen_future = msg_receiver.for_each(|message| {
+        let pool = pool.clone();
         let state_mx = state_mx.clone();
         let state_notify = state_notify.clone();
         let auth_notify = auth_notify.clone();
         let ping_notify = ping_notify.clone();
         let stdin_tx = stdin_tx.clone();
+        let ws_monitor = ws_monitor.clone();
         async move {
+            ws_monitor.lock().await.received(&message);
+            if let Some((pool, key)) = pool.as_ref().zip(confirmed_key(&message)) {
+                if let Err(e) = ack_outbox(pool, &key).await {
+                    error!("Failed to confirm outbox entry {}: {}", key, e);
+                }
+            }
+            if message_channel(&message) == "orders" {
+                audit(format_args!("Received message: {:?}", message));
+            }
             if let KolliderMsg::Tagged(KolliderTaggedMsg::IndexValues(v)) = &message {
                 counter += 1;
                 if counter % 10 == 0 {
@@ -271,7 +1503,27 @@ async fn listen_websocket(
             } else {
                 info!("Received message: {:?}", message);
             }
-            let mut state = state_mx.lock().await;
+            let mut state = write_state(&state_mx).await;
+            if let Some(body) = state.balance_event(&message) {
+                info!("Recording balance event: {:?}", body);
+                let update = StateUpdate {
+                    created: Utc::now().naive_utc(),
+                    body,
+                };
+                // Only recorded events are applied, so the state matches its replay after restart
+                let recorded = match &pool {
+                    Some(pool) => insert_update(pool, update.body.clone())
+                        .await
+                        .map_err(|e| error!("Failed to record balance event: {}", e))
+                        .is_ok(),
+                    None => true,
+                };
+                if recorded {
+                    if let Err(e) = state.apply_update(update) {
+                        error!("Failed to apply balance event: {}", e);
+                    }
+                }
+            }
             let changed = state.apply_kollider_message(message.clone());
             if changed {
                 state_notify.notify_waiters();
@@ -286,29 +1538,38 @@ async fn listen_websocket(
                     debug!("Notified state that auth is passed");
 
                     let channels = vec![ChannelName::IndexValues];
-                    let symbols = vec![state.config.hedge_pair.to_owned()];
+                    let mut symbols = vec![state.config.hedge_pair.to_owned()];
+                    symbols.extend(state.config.reporting_pair.iter().cloned());
+                    ws_monitor
+                        .lock()
+                        .await
+                        .status
+                        .subscribed(vec!["index_values".to_owned()]);
                     stdin_tx
-                        .unbounded_send(KolliderMsg::Subscribe {
+                        .send(KolliderMsg::Subscribe {
                             _type: SubscribeTag::Tag,
                             channels,
                             symbols,
                         })
+                        .await
                         .map_err(|e| {
                             error!("Failed to send subscribe message: {}", e);
                         })
                         .ok();
                     stdin_tx
-                        .unbounded_send(KolliderMsg::FetchOpenOrders {
+                        .send(KolliderMsg::FetchOpenOrders {
                             _type: FetchOpenOrdersTag::Tag,
                         })
+                        .await
                         .map_err(|e| {
                             error!("Failed to send fetch orders message: {}", e);
                         })
                         .ok();
                     stdin_tx
-                        .unbounded_send(KolliderMsg::FetchPositions {
+                        .send(KolliderMsg::FetchPositions {
                             _type: FetchPositionsTag::Tag,
                         })
+                        .await
                         .map_err(|e| {
                             error!("Failed to send fetch positions message: {}", e);
                         })
@@ -317,6 +1578,36 @@ async fn listen_websocket(
                 KolliderMsg::Tagged(KolliderTaggedMsg::Positions{..}) => {
                     ping_notify.notify_waiters();
                 }
+                KolliderMsg::Tagged(KolliderTaggedMsg::OrderRejection {
+                    ext_order_id,
+                    reason,
+                    ..
+                }) => {
+                    warn!("Order {} is rejected: {:?}", ext_order_id, reason);
+                    let update = StateUpdate {
+                        created: Utc::now().naive_utc(),
+                        body: UpdateBody::OrderRejected(OrderRejection {
+                            order: state.orders.get(&ext_order_id).map(|o| o.order.clone()),
+                            ext_id: ext_order_id,
+                            reason: format!("{:?}", reason),
+                        }),
+                    };
+                    // An unrecorded rejection leaves the order opening until the watchdog
+                    // evicts it, so the state still matches its replay
+                    let recorded = match &pool {
+                        Some(pool) => insert_update(pool, update.body.clone())
+                            .await
+                            .map_err(|e| error!("Failed to record order rejection: {}", e))
+                            .is_ok(),
+                        None => true,
+                    };
+                    if recorded {
+                        if let Err(e) = state.apply_update(update) {
+                            error!("Failed to apply order rejection: {}", e);
+                        }
+                        state_notify.notify_waiters();
+                    }
+                }
                 _ => (),
             }
         }